@@ -0,0 +1,22 @@
+//! Drive any of the state machines from chapter 1 interactively.
+//!
+//! Usage: `cargo run --bin bfs-repl -- <machine name>`
+//!
+//! For example `cargo run --bin bfs-repl -- "Accounted Currency"`. Run it without a machine name to
+//! see which machines are available.
+
+use blockchain_from_scratch::c1_state_machine::repl;
+use std::{env, io, process};
+
+fn main() {
+	let Some(name) = env::args().nth(1) else {
+		eprintln!("Usage: bfs-repl <machine name>");
+		eprintln!("Available machines: {}", repl::machine_names().join(", "));
+		process::exit(1)
+	};
+
+	if let Err(e) = repl::run_by_name(&name, &mut io::stdin().lock(), &mut io::stdout()) {
+		eprintln!("{e}");
+		process::exit(1)
+	}
+}
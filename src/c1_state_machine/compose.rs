@@ -0,0 +1,465 @@
+//! Real blockchains run many modules side by side. A single chain may have an accounted currency,
+//! a digital cash system, governance, staking, and more, all in one state. Rather than writing a
+//! new machine for every such combination, this module provides generic combinators that build
+//! bigger state machines out of smaller ones.
+//!
+//! - `Product<A, B>` runs both machines at once. Its state holds one state for each inner machine,
+//!   and each transition is routed to exactly one of them.
+//! - `Sum<A, B>` is in either one machine's state or the other's. Transitions only apply when they
+//!   are meant for the machine that the state currently belongs to.
+//!
+//! Combinators nest, so three modules can be combined as `Product<A, Product<B, C>>`.
+
+use super::{
+	diff::StateDiff, p22_multisig::Currency, p8_nonces::Authorized, BlockContext, BlockHooks,
+	StateMachine, TryStateMachine, User,
+};
+use std::{collections::BTreeMap, marker::PhantomData};
+
+/// One of two things. Used to route transitions, errors, and states to one of two inner machines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<A, B> {
+	/// Something belonging to the first machine.
+	First(A),
+	/// Something belonging to the second machine.
+	Second(B),
+}
+
+/// A state machine that runs two inner machines side by side.
+pub struct Product<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Product<A, B>
+where
+	A: StateMachine,
+	B: StateMachine,
+	A::State: Clone,
+	B::State: Clone,
+{
+	type State = (A::State, B::State);
+	type Transition = Either<A::Transition, B::Transition>;
+
+	fn next_state((a, b): &Self::State, t: &Self::Transition) -> Self::State {
+		match t {
+			Either::First(t) => (A::next_state(a, t), b.clone()),
+			Either::Second(t) => (a.clone(), B::next_state(b, t)),
+		}
+	}
+
+	fn human_name() -> String {
+		format!("{} + {}", A::human_name(), B::human_name())
+	}
+}
+
+impl<A, B> TryStateMachine for Product<A, B>
+where
+	A: TryStateMachine,
+	B: TryStateMachine,
+	A::State: Clone,
+	B::State: Clone,
+{
+	type Error = Either<A::Error, B::Error>;
+
+	fn try_next_state(
+		(a, b): &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error> {
+		match t {
+			Either::First(t) => Ok((A::try_next_state(a, t).map_err(Either::First)?, b.clone())),
+			Either::Second(t) => Ok((a.clone(), B::try_next_state(b, t).map_err(Either::Second)?)),
+		}
+	}
+}
+
+impl<A, B> StateDiff for Product<A, B>
+where
+	A: StateDiff,
+	B: StateDiff,
+	A::State: Clone,
+	B::State: Clone,
+{
+	type Diff = (A::Diff, B::Diff);
+
+	fn diff((old_a, old_b): &Self::State, (new_a, new_b): &Self::State) -> Self::Diff {
+		(A::diff(old_a, new_a), B::diff(old_b, new_b))
+	}
+}
+
+/// Both inner machines' hooks run in every block, first machine first.
+impl<A, B> BlockHooks for Product<A, B>
+where
+	A: BlockHooks,
+	B: BlockHooks,
+	A::State: Clone,
+	B::State: Clone,
+{
+	fn on_initialize((a, b): &Self::State, block: &BlockContext) -> Self::State {
+		(A::on_initialize(a, block), B::on_initialize(b, block))
+	}
+
+	fn on_finalize((a, b): &Self::State, block: &BlockContext) -> Self::State {
+		(A::on_finalize(a, block), B::on_finalize(b, block))
+	}
+}
+
+/// A call into either machine is signed by whoever signs the inner call.
+impl<A, B> Authorized for Product<A, B>
+where
+	A: Authorized,
+	B: Authorized,
+	A::State: Clone,
+	B::State: Clone,
+{
+	fn signer(t: &Self::Transition) -> User {
+		match t {
+			Either::First(t) => A::signer(t),
+			Either::Second(t) => B::signer(t),
+		}
+	}
+}
+
+/// The funds of the combined machine are those of the first machine, so that a currency can be
+/// combined with modules that have none.
+impl<A, B> Currency for Product<A, B>
+where
+	A: Currency,
+	B: TryStateMachine,
+	A::State: Clone,
+	B::State: Clone,
+{
+	fn withdraw((a, b): &Self::State, user: User, amount: u64) -> Result<Self::State, Self::Error> {
+		Ok((A::withdraw(a, user, amount).map_err(Either::First)?, b.clone()))
+	}
+
+	fn deposit((a, b): &Self::State, user: User, amount: u64) -> Result<Self::State, Self::Error> {
+		Ok((A::deposit(a, user, amount).map_err(Either::First)?, b.clone()))
+	}
+}
+
+/// A state machine that is in the state of exactly one of two inner machines.
+///
+/// A transition meant for the other machine leaves the state unchanged.
+pub struct Sum<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Sum<A, B>
+where
+	A: StateMachine,
+	B: StateMachine,
+	A::State: Clone,
+	B::State: Clone,
+{
+	type State = Either<A::State, B::State>;
+	type Transition = Either<A::Transition, B::Transition>;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		match (starting_state, t) {
+			(Either::First(a), Either::First(t)) => Either::First(A::next_state(a, t)),
+			(Either::Second(b), Either::Second(t)) => Either::Second(B::next_state(b, t)),
+			_ => starting_state.clone(),
+		}
+	}
+
+	fn human_name() -> String {
+		format!("{} | {}", A::human_name(), B::human_name())
+	}
+}
+
+/// The reasons a transition may be rejected by a `Sum` machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SumError<A, B> {
+	/// The first machine rejected the transition.
+	First(A),
+	/// The second machine rejected the transition.
+	Second(B),
+	/// The transition is meant for the machine that the state does not currently belong to.
+	WrongMachine,
+}
+
+impl<A, B> TryStateMachine for Sum<A, B>
+where
+	A: TryStateMachine,
+	B: TryStateMachine,
+	A::State: Clone,
+	B::State: Clone,
+{
+	type Error = SumError<A::Error, B::Error>;
+
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error> {
+		match (starting_state, t) {
+			(Either::First(a), Either::First(t)) =>
+				A::try_next_state(a, t).map(Either::First).map_err(SumError::First),
+			(Either::Second(b), Either::Second(t)) =>
+				B::try_next_state(b, t).map(Either::Second).map_err(SumError::Second),
+			_ => Err(SumError::WrongMachine),
+		}
+	}
+}
+
+/// Only the hooks of the machine that the state currently belongs to run.
+impl<A, B> BlockHooks for Sum<A, B>
+where
+	A: BlockHooks,
+	B: BlockHooks,
+	A::State: Clone,
+	B::State: Clone,
+{
+	fn on_initialize(state: &Self::State, block: &BlockContext) -> Self::State {
+		match state {
+			Either::First(a) => Either::First(A::on_initialize(a, block)),
+			Either::Second(b) => Either::Second(B::on_initialize(b, block)),
+		}
+	}
+
+	fn on_finalize(state: &Self::State, block: &BlockContext) -> Self::State {
+		match state {
+			Either::First(a) => Either::First(A::on_finalize(a, block)),
+			Either::Second(b) => Either::Second(B::on_finalize(b, block)),
+		}
+	}
+}
+
+/// A state machine that runs many independent instances of an inner machine, each identified by a
+/// key. Instances are kept in key order so that the state hashes deterministically.
+pub struct Many<K, SM>(PhantomData<(K, SM)>);
+
+/// A transition of a `Many` machine.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ManyTransition<K, S, T> {
+	/// Create a new instance in the given state. Has no effect if the key is already in use.
+	Create { key: K, state: S },
+	/// Remove an instance entirely. Has no effect if there is no instance with this key.
+	Destroy { key: K },
+	/// Apply a transition to a single instance. Has no effect if there is no instance with this
+	/// key.
+	Act { key: K, transition: T },
+}
+
+impl<K, SM> StateMachine for Many<K, SM>
+where
+	K: Ord + Clone,
+	SM: StateMachine,
+	SM::State: Clone,
+{
+	type State = BTreeMap<K, SM::State>;
+	type Transition = ManyTransition<K, SM::State, SM::Transition>;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		Self::try_apply(starting_state, t, |state, t| Ok::<_, ()>(SM::next_state(state, t)))
+			.unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		format!("Many {}", SM::human_name())
+	}
+}
+
+/// The reasons a transition may be rejected by a `Many` machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManyError<K, E> {
+	/// There is already an instance with this key.
+	AlreadyExists(K),
+	/// There is no instance with this key.
+	UnknownKey(K),
+	/// The instance with this key rejected the transition.
+	Inner { key: K, error: E },
+}
+
+impl<K, SM> Many<K, SM>
+where
+	K: Ord + Clone,
+	SM: StateMachine,
+	SM::State: Clone,
+{
+	/// Apply a transition using the given function to transition individual instances. This is
+	/// shared by the infallible and fallible implementations.
+	fn try_apply<E>(
+		starting_state: &BTreeMap<K, SM::State>,
+		t: &ManyTransition<K, SM::State, SM::Transition>,
+		inner: impl Fn(&SM::State, &SM::Transition) -> Result<SM::State, E>,
+	) -> Result<BTreeMap<K, SM::State>, ManyError<K, E>> {
+		let mut instances = starting_state.clone();
+		match t {
+			ManyTransition::Create { key, state } => {
+				if instances.contains_key(key) {
+					return Err(ManyError::AlreadyExists(key.clone()))
+				}
+				instances.insert(key.clone(), state.clone());
+			},
+			ManyTransition::Destroy { key } => {
+				instances.remove(key).ok_or_else(|| ManyError::UnknownKey(key.clone()))?;
+			},
+			ManyTransition::Act { key, transition } => {
+				let instance =
+					instances.get_mut(key).ok_or_else(|| ManyError::UnknownKey(key.clone()))?;
+				*instance = inner(instance, transition)
+					.map_err(|error| ManyError::Inner { key: key.clone(), error })?;
+			},
+		}
+		Ok(instances)
+	}
+}
+
+impl<K, SM> TryStateMachine for Many<K, SM>
+where
+	K: Ord + Clone,
+	SM: TryStateMachine,
+	SM::State: Clone,
+{
+	type Error = ManyError<K, SM::Error>;
+
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error> {
+		Self::try_apply(starting_state, t, SM::try_next_state)
+	}
+}
+
+/// Every instance's hooks run in every block, in key order.
+impl<K, SM> BlockHooks for Many<K, SM>
+where
+	K: Ord + Clone,
+	SM: BlockHooks,
+	SM::State: Clone,
+{
+	fn on_initialize(state: &Self::State, block: &BlockContext) -> Self::State {
+		state.iter().map(|(k, s)| (k.clone(), SM::on_initialize(s, block))).collect()
+	}
+
+	fn on_finalize(state: &Self::State, block: &BlockContext) -> Self::State {
+		state.iter().map(|(k, s)| (k.clone(), SM::on_finalize(s, block))).collect()
+	}
+}
+
+#[test]
+fn sm_compose_product_human_name() {
+	use super::{p4_accounted_currency::AccountedCurrency, p5_digital_cash::DigitalCashSystem};
+
+	assert_eq!(
+		Product::<AccountedCurrency, DigitalCashSystem>::human_name(),
+		"Accounted Currency + Digital Cash"
+	);
+	assert_eq!(
+		Sum::<AccountedCurrency, DigitalCashSystem>::human_name(),
+		"Accounted Currency | Digital Cash"
+	);
+}
+
+#[test]
+fn sm_compose_product_routes_transitions() {
+	use super::test_machines::CappedCounter;
+
+	type Pair = Product<CappedCounter, CappedCounter>;
+
+	let state = Pair::try_next_state(&(0, 0), &Either::First(3)).unwrap();
+	assert_eq!(state, (3, 0));
+
+	let state = Pair::try_next_state(&state, &Either::Second(5)).unwrap();
+	assert_eq!(state, (3, 5));
+
+	assert_eq!(Pair::diff(&(0, 0), &state), (3, 5));
+}
+
+#[test]
+fn sm_compose_product_errors_identify_the_machine() {
+	use super::test_machines::{CappedCounter, CounterError};
+
+	type Pair = Product<CappedCounter, CappedCounter>;
+
+	let start = (9, 2);
+
+	assert_eq!(
+		Pair::try_next_state(&start, &Either::First(2)),
+		Err(Either::First(CounterError::AboveCap { total: 11 }))
+	);
+	assert_eq!(
+		Pair::try_next_state(&start, &Either::Second(9)),
+		Err(Either::Second(CounterError::AboveCap { total: 11 }))
+	);
+	assert_eq!(Pair::try_next_state(&start, &Either::Second(8)), Ok((9, 10)));
+}
+
+#[test]
+fn sm_compose_sum_rejects_transitions_for_the_other_machine() {
+	use super::test_machines::CappedCounter;
+
+	type OneOrTheOther = Sum<CappedCounter, CappedCounter>;
+
+	let start = Either::First(0);
+
+	assert_eq!(OneOrTheOther::try_next_state(&start, &Either::First(4)), Ok(Either::First(4)));
+	assert_eq!(
+		OneOrTheOther::try_next_state(&start, &Either::Second(4)),
+		Err(SumError::WrongMachine)
+	);
+}
+
+#[test]
+fn sm_compose_many_create_and_destroy() {
+	use super::p2_laundry_machine::{ClothesMachine, ClothesState};
+
+	type Wardrobe = Many<&'static str, ClothesMachine>;
+
+	assert_eq!(Wardrobe::human_name(), "Many Clothes");
+
+	let start = BTreeMap::new();
+	let shirt = ManyTransition::Create { key: "shirt", state: ClothesState::Clean(3) };
+	let state = Wardrobe::next_state(&start, &shirt);
+
+	assert_eq!(state, BTreeMap::from([("shirt", ClothesState::Clean(3))]));
+
+	// Creating the same garment twice does nothing
+	let again = ManyTransition::Create { key: "shirt", state: ClothesState::Tattered };
+	assert_eq!(Wardrobe::next_state(&state, &again), state);
+
+	let end = Wardrobe::next_state(&state, &ManyTransition::Destroy { key: "shirt" });
+	assert_eq!(end, start);
+}
+
+#[test]
+fn sm_compose_many_acts_on_one_instance() {
+	use super::test_machines::CappedCounter;
+
+	type Counters = Many<&'static str, CappedCounter>;
+
+	let start = BTreeMap::from([("apples", 3), ("pears", 3)]);
+	let pick = ManyTransition::Act { key: "apples", transition: 4 };
+	let end = Counters::next_state(&start, &pick);
+
+	assert_eq!(end, BTreeMap::from([("apples", 7), ("pears", 3)]));
+}
+
+#[test]
+fn sm_compose_many_act_on_unknown_instance_does_nothing() {
+	use super::p2_laundry_machine::{ClothesAction, ClothesMachine, ClothesState};
+
+	type Wardrobe = Many<&'static str, ClothesMachine>;
+
+	let start = BTreeMap::from([("shirt", ClothesState::Clean(3))]);
+	let wear = ManyTransition::Act { key: "hat", transition: ClothesAction::Wear };
+
+	assert_eq!(Wardrobe::next_state(&start, &wear), start);
+}
+
+#[test]
+fn sm_compose_many_try_errors() {
+	use super::test_machines::{CappedCounter, CounterError};
+
+	type Counters = Many<u8, CappedCounter>;
+
+	let start = BTreeMap::from([(1, 8)]);
+	let create = ManyTransition::Create { key: 1, state: 0 };
+	let destroy = ManyTransition::Destroy { key: 2 };
+	let too_many = ManyTransition::Act { key: 1, transition: 5 };
+	let just_enough = ManyTransition::Act { key: 1, transition: 2 };
+
+	assert_eq!(Counters::try_next_state(&start, &create), Err(ManyError::AlreadyExists(1)));
+	assert_eq!(Counters::try_next_state(&start, &destroy), Err(ManyError::UnknownKey(2)));
+	assert_eq!(
+		Counters::try_next_state(&start, &too_many),
+		Err(ManyError::Inner { key: 1, error: CounterError::AboveCap { total: 13 } })
+	);
+	assert_eq!(Counters::try_next_state(&start, &just_enough), Ok(BTreeMap::from([(1, 10)])));
+}
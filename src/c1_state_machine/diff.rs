@@ -0,0 +1,22 @@
+//! Printing two complete states side by side is a poor way to see what a transition did,
+//! especially as states grow large. This module defines a common interface for computing a
+//! structured change set between two states of the same machine.
+//!
+//! Each machine chooses its own diff type that describes changes in terms that make sense for
+//! that machine. For example, the accounted currency describes balance deltas and accounts that
+//! were created or reaped, while digital cash describes bills that were created or destroyed.
+//! These diffs power the dry-run output of tools like the repl, and let block explorers show what
+//! a block changed without dumping the entire state.
+
+use super::StateMachine;
+
+/// A state machine whose states can be compared to produce a structured change set.
+pub trait StateDiff: StateMachine {
+	/// A structured description of the changes between two states.
+	type Diff;
+
+	/// Calculate the changes required to go from the old state to the new state.
+	///
+	/// The diff of a state with itself should be empty.
+	fn diff(old: &Self::State, new: &Self::State) -> Self::Diff;
+}
@@ -0,0 +1,231 @@
+//! Small state machines like the light switches, the laundry machine, and the ATM can be explored
+//! exhaustively. Starting from some state, we try every transition, then every transition from
+//! each of the resulting states, and so on, until no new states turn up. This breadth-first
+//! exploration gives us the complete transition graph of the machine, which is handy for spotting
+//! design mistakes:
+//!
+//! - Dead states, which can never be left once entered (like tattered clothes).
+//! - Cycles, which let the machine return to a state it was in before.
+//! - Unreachable states, which are perfectly valid states that can never actually occur.
+//!
+//! The graph can also be exported in Graphviz's DOT format to be rendered for design reviews.
+//! For example `dot -Tsvg clothes.dot > clothes.svg`.
+
+use super::{repl::Interactive, StateMachine};
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	hash::Hash,
+};
+
+/// A state machine whose transitions can be enumerated.
+///
+/// Many machines have infinitely many possible transitions (e.g. an ATM card can carry any pin
+/// hash), so this needs only return a representative finite set. Exploration then finds every
+/// state reachable using the transitions in this set.
+pub trait Explorable: Interactive {
+	/// The transitions to try from every state.
+	fn transitions() -> Vec<Self::Transition>;
+}
+
+/// An edge in the transition graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Edge {
+	/// The index of the state this edge leaves.
+	pub from: usize,
+	/// The index of the state this edge enters.
+	pub to: usize,
+	/// The index of the transition that causes this edge.
+	pub transition: usize,
+}
+
+/// The transition graph discovered by exploring a machine from a start state.
+pub struct Exploration<SM: StateMachine> {
+	/// Every state that was reached, in the order it was discovered. The start state is always
+	/// at index 0.
+	pub states: Vec<SM::State>,
+	/// The transitions that were tried from every state.
+	pub transitions: Vec<SM::Transition>,
+	/// One edge for every transition tried from every explored state, including self-loops.
+	pub edges: Vec<Edge>,
+	/// Whether the exploration finished. This is false when the state limit was reached before
+	/// running out of new states, in which case the graph is only a partial view.
+	pub complete: bool,
+}
+
+/// Explore all states reachable from the start state breadth first. At most `max_states` states
+/// will be explored, which guards against machines with very large or infinite state spaces.
+pub fn explore<SM: Explorable>(start: SM::State, max_states: usize) -> Exploration<SM>
+where
+	SM::State: Clone + Eq + Hash,
+{
+	let transitions = SM::transitions();
+	let mut states = vec![start.clone()];
+	let mut index = HashMap::from([(start, 0)]);
+	let mut edges = Vec::new();
+	let mut queue = VecDeque::from([0]);
+	let mut complete = true;
+
+	while let Some(from) = queue.pop_front() {
+		for (transition, t) in transitions.iter().enumerate() {
+			let next = SM::next_state(&states[from], t);
+			let to = match index.get(&next) {
+				Some(to) => *to,
+				None if states.len() >= max_states => {
+					complete = false;
+					continue
+				},
+				None => {
+					let to = states.len();
+					index.insert(next.clone(), to);
+					states.push(next);
+					queue.push_back(to);
+					to
+				},
+			};
+			edges.push(Edge { from, to, transition });
+		}
+	}
+
+	Exploration { states, transitions, edges, complete }
+}
+
+impl<SM: StateMachine> Exploration<SM> {
+	/// The indices of states that can never be left once entered. Every transition from a dead
+	/// state leads straight back to it.
+	///
+	/// When the exploration is incomplete, states on the frontier have no recorded edges and are
+	/// not reported as dead.
+	pub fn dead_states(&self) -> Vec<usize> {
+		(0..self.states.len())
+			.filter(|state| {
+				let mut outgoing = self.edges.iter().filter(|edge| edge.from == *state).peekable();
+				outgoing.peek().is_some() && outgoing.all(|edge| edge.to == *state)
+			})
+			.collect()
+	}
+
+	/// The cycles in the graph, reported as groups of states that can all reach each other
+	/// (strongly connected components). Self-loops alone are not reported as cycles since they
+	/// are just transitions that leave the state unchanged.
+	pub fn cycles(&self) -> Vec<Vec<usize>> {
+		let mut successors = vec![Vec::new(); self.states.len()];
+		for edge in &self.edges {
+			successors[edge.from].push(edge.to);
+		}
+
+		let mut tarjan = Tarjan {
+			successors,
+			index: vec![None; self.states.len()],
+			low_link: vec![0; self.states.len()],
+			on_stack: vec![false; self.states.len()],
+			stack: Vec::new(),
+			next_index: 0,
+			components: Vec::new(),
+		};
+		for state in 0..self.states.len() {
+			if tarjan.index[state].is_none() {
+				tarjan.visit(state);
+			}
+		}
+
+		tarjan.components.into_iter().filter(|component| component.len() > 1).collect()
+	}
+
+	/// The states from the given universe that were never reached. This only makes sense for a
+	/// complete exploration.
+	pub fn unreachable_states(
+		&self,
+		universe: impl IntoIterator<Item = SM::State>,
+	) -> Vec<SM::State>
+	where
+		SM::State: Eq + Hash,
+	{
+		let reached: HashSet<_> = self.states.iter().collect();
+		universe.into_iter().filter(|state| !reached.contains(state)).collect()
+	}
+}
+
+impl<SM: Interactive> Exploration<SM> {
+	/// Export the transition graph in Graphviz DOT format. The start state is drawn bold, dead
+	/// states are drawn with a double border, and the frontier of an incomplete exploration is
+	/// drawn dashed.
+	pub fn to_dot(&self) -> String {
+		let dead: HashSet<_> = self.dead_states().into_iter().collect();
+		let explored: HashSet<_> = self.edges.iter().map(|edge| edge.from).collect();
+
+		let mut dot = format!("digraph \"{}\" {{\n", escape(&SM::human_name()));
+		for (i, state) in self.states.iter().enumerate() {
+			let mut attributes = vec![format!("label=\"{}\"", escape(&SM::format_state(state)))];
+			if i == 0 {
+				attributes.push("style=bold".into());
+			}
+			if dead.contains(&i) {
+				attributes.push("peripheries=2".into());
+			}
+			if !explored.contains(&i) {
+				attributes.push("style=dashed".into());
+			}
+			dot += &format!("\ts{i} [{}];\n", attributes.join(", "));
+		}
+		for edge in &self.edges {
+			let label = SM::format_transition(&self.transitions[edge.transition]);
+			dot += &format!("\ts{} -> s{} [label=\"{}\"];\n", edge.from, edge.to, escape(&label));
+		}
+		dot += "}\n";
+
+		dot
+	}
+}
+
+/// Escape a string for use inside a quoted DOT identifier.
+fn escape(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Bookkeeping for Tarjan's strongly connected components algorithm.
+struct Tarjan {
+	successors: Vec<Vec<usize>>,
+	index: Vec<Option<usize>>,
+	low_link: Vec<usize>,
+	on_stack: Vec<bool>,
+	stack: Vec<usize>,
+	next_index: usize,
+	components: Vec<Vec<usize>>,
+}
+
+impl Tarjan {
+	fn visit(&mut self, v: usize) {
+		self.index[v] = Some(self.next_index);
+		self.low_link[v] = self.next_index;
+		self.next_index += 1;
+		self.stack.push(v);
+		self.on_stack[v] = true;
+
+		for w in self.successors[v].clone() {
+			match self.index[w] {
+				None => {
+					self.visit(w);
+					self.low_link[v] = self.low_link[v].min(self.low_link[w]);
+				},
+				Some(w_index) if self.on_stack[w] => {
+					self.low_link[v] = self.low_link[v].min(w_index);
+				},
+				Some(_) => {},
+			}
+		}
+
+		if Some(self.low_link[v]) == self.index[v] {
+			let mut component = Vec::new();
+			loop {
+				let w = self.stack.pop().expect("v is still on the stack; qed");
+				self.on_stack[w] = false;
+				component.push(w);
+				if w == v {
+					break
+				}
+			}
+			component.sort();
+			self.components.push(component);
+		}
+	}
+}
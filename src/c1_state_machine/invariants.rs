@@ -0,0 +1,307 @@
+//! Hand-written tests like `sm_4_transfer` check that a machine behaves correctly in one specific
+//! situation. But many properties of a state machine are supposed to hold in _every_ situation.
+//! For example, a transfer in the accounted currency should never change the total amount of money
+//! in existence.
+//!
+//! This module provides a small property testing harness for checking such invariants. The
+//! harness generates many random sequences of transitions, applies them one at a time, and checks
+//! every declared invariant after each step. When an invariant is violated, the failing sequence
+//! is shrunk by repeatedly removing transitions until it is a minimal reproducer.
+//!
+//! Randomness comes from a small seeded generator in this module, so every run is deterministic
+//! and a failure can always be reproduced by re-running with the same seed.
+
+use super::StateMachine;
+use core::fmt::Debug;
+
+/// A tiny deterministic pseudo random number generator (splitmix64). It is nowhere near suitable
+/// for cryptography, but it is perfectly good for generating test cases.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rng(u64);
+
+impl Rng {
+	/// Create a new generator from the given seed. The same seed always produces the same stream.
+	pub fn new(seed: u64) -> Self {
+		Rng(seed)
+	}
+
+	/// The next pseudo random number.
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+
+	/// A pseudo random number strictly less than `bound`. The bound must not be zero.
+	pub fn below(&mut self, bound: u64) -> u64 {
+		self.next_u64() % bound
+	}
+
+	/// Flip a coin
+	pub fn coin(&mut self) -> bool {
+		self.next_u64() & 1 == 1
+	}
+
+	/// Choose a random element from the slice, or `None` if it is empty.
+	pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+		if items.is_empty() {
+			return None
+		}
+		items.get(self.below(items.len() as u64) as usize)
+	}
+}
+
+/// A property that must hold for every individual step a state machine takes.
+///
+/// The check receives the state before the step, the transition, and the state after the step.
+/// This makes it possible to express both invariants about a single state ("no account has a
+/// zero balance") and invariants about how states change ("transfers don't change the total
+/// supply").
+pub struct Invariant<SM: StateMachine> {
+	/// A short name used to report which invariant failed.
+	pub name: &'static str,
+	/// Returns whether the invariant holds for this step.
+	pub holds: fn(pre: &SM::State, t: &SM::Transition, post: &SM::State) -> bool,
+}
+
+/// A description of a sequence of transitions that violates an invariant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure<T> {
+	/// The name of the invariant that was violated.
+	pub invariant: &'static str,
+	/// The (shrunk) sequence of transitions that leads to the violation. The violation happens
+	/// on the final transition.
+	pub transitions: Vec<T>,
+	/// The seed that the failing property test was run with.
+	pub seed: u64,
+}
+
+/// A property test over a state machine.
+///
+/// Each test case starts from the same initial state and applies a random sequence of
+/// transitions produced by the generator. The generator is given the current state so that it can
+/// produce plausible transitions, such as spending bills that actually exist.
+pub struct PropertyTest<SM: StateMachine> {
+	/// The state every test case begins from.
+	pub initial_state: SM::State,
+	/// Produces a random transition from the current state.
+	pub generate: fn(&mut Rng, &SM::State) -> SM::Transition,
+	/// The invariants checked after every step.
+	pub invariants: Vec<Invariant<SM>>,
+	/// How many random sequences to try.
+	pub cases: usize,
+	/// The maximum length of each random sequence.
+	pub max_length: usize,
+	/// The seed for the random number generator.
+	pub seed: u64,
+}
+
+impl<SM: StateMachine> PropertyTest<SM>
+where
+	SM::State: Clone,
+	SM::Transition: Clone,
+{
+	/// Create a property test with no invariants and some reasonable defaults.
+	pub fn new(
+		initial_state: SM::State,
+		generate: fn(&mut Rng, &SM::State) -> SM::Transition,
+	) -> Self {
+		PropertyTest {
+			initial_state,
+			generate,
+			invariants: Vec::new(),
+			cases: 256,
+			max_length: 32,
+			seed: 0,
+		}
+	}
+
+	/// Declare an additional invariant to check.
+	pub fn invariant(
+		mut self,
+		name: &'static str,
+		holds: fn(&SM::State, &SM::Transition, &SM::State) -> bool,
+	) -> Self {
+		self.invariants.push(Invariant { name, holds });
+		self
+	}
+
+	/// Run all the test cases. Returns the first violation found, shrunk to a minimal sequence.
+	pub fn run(&self) -> Result<(), Failure<SM::Transition>> {
+		let mut rng = Rng::new(self.seed);
+
+		for _ in 0..self.cases {
+			let length = rng.below(self.max_length as u64 + 1) as usize;
+			let mut state = self.initial_state.clone();
+			let mut transitions = Vec::with_capacity(length);
+
+			for _ in 0..length {
+				let t = (self.generate)(&mut rng, &state);
+				let post = SM::next_state(&state, &t);
+				let violation = self.violated_invariant(&state, &t, &post);
+				transitions.push(t);
+
+				if let Some(invariant) = violation {
+					return Err(Failure {
+						invariant,
+						transitions: self.shrink(transitions, invariant),
+						seed: self.seed,
+					})
+				}
+				state = post;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Run all the test cases, and panic with a readable report if any invariant is violated.
+	/// This is the method to call from within a `#[test]`.
+	pub fn check(&self)
+	where
+		SM::Transition: Debug,
+	{
+		if let Err(failure) = self.run() {
+			panic!(
+				"Invariant `{}` of {} violated (seed {}) by the transitions:\n{:#?}",
+				failure.invariant,
+				SM::human_name(),
+				failure.seed,
+				failure.transitions,
+			);
+		}
+	}
+
+	/// Find the first invariant that does not hold for the given step.
+	fn violated_invariant(
+		&self,
+		pre: &SM::State,
+		t: &SM::Transition,
+		post: &SM::State,
+	) -> Option<&'static str> {
+		self.invariants
+			.iter()
+			.find(|inv| !(inv.holds)(pre, t, post))
+			.map(|inv| inv.name)
+	}
+
+	/// Replay the given transitions from the initial state, and report whether the named
+	/// invariant is violated at some point.
+	fn violates(&self, transitions: &[SM::Transition], invariant: &'static str) -> bool {
+		let mut state = self.initial_state.clone();
+		for t in transitions {
+			let post = SM::next_state(&state, t);
+			if self.violated_invariant(&state, t, &post) == Some(invariant) {
+				return true
+			}
+			state = post;
+		}
+		false
+	}
+
+	/// Shrink a failing sequence by repeatedly removing chunks of transitions, keeping each removal
+	/// only if the same invariant is still violated. Chunks start at half the sequence and halve
+	/// down to single transitions, so the result is minimal in the sense that removing any one
+	/// transition makes the failure go away.
+	fn shrink(
+		&self,
+		mut transitions: Vec<SM::Transition>,
+		invariant: &'static str,
+	) -> Vec<SM::Transition> {
+		let mut chunk = (transitions.len() / 2).max(1);
+
+		loop {
+			let mut start = 0;
+			let mut removed_any = false;
+
+			while start < transitions.len() {
+				let end = (start + chunk).min(transitions.len());
+				let candidate: Vec<_> =
+					transitions[..start].iter().chain(&transitions[end..]).cloned().collect();
+
+				if self.violates(&candidate, invariant) {
+					transitions = candidate;
+					removed_any = true;
+				} else {
+					start += chunk;
+				}
+			}
+
+			if !removed_any {
+				if chunk == 1 {
+					return transitions
+				}
+				chunk /= 2;
+			}
+		}
+	}
+}
+
+/// A deliberately buggy machine used to test the harness itself. It is a counter that is supposed
+/// to stay below 10, except that it forgets to check the limit when adding 3.
+#[cfg(test)]
+struct BuggyCounter;
+
+#[cfg(test)]
+impl StateMachine for BuggyCounter {
+	type State = u64;
+	type Transition = u64;
+
+	fn next_state(starting_state: &u64, t: &u64) -> u64 {
+		match starting_state + t {
+			total if total < 10 || *t == 3 => total,
+			_ => *starting_state,
+		}
+	}
+}
+
+#[test]
+fn sm_invariants_rng_is_deterministic() {
+	let mut a = Rng::new(42);
+	let mut b = Rng::new(42);
+
+	for _ in 0..100 {
+		assert_eq!(a.next_u64(), b.next_u64());
+	}
+	assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+}
+
+#[test]
+fn sm_invariants_holding_invariant_passes() {
+	let test = PropertyTest::<BuggyCounter>::new(0, |rng, _| rng.below(3))
+		.invariant("below ten", |_, _, post| *post < 10);
+
+	assert_eq!(test.run(), Ok(()));
+}
+
+#[test]
+fn sm_invariants_violation_is_found_and_shrunk() {
+	let test = PropertyTest::<BuggyCounter>::new(0, |rng, _| rng.below(5))
+		.invariant("below ten", |_, _, post| *post < 10);
+	let failure = test.run().unwrap_err();
+
+	assert_eq!(failure.invariant, "below ten");
+	assert!(test.violates(&failure.transitions, "below ten"));
+
+	// The only way to reach ten is to add 3 from 7 or more, which takes at least two earlier steps.
+	assert!(failure.transitions.len() >= 3);
+	assert_eq!(failure.transitions.last(), Some(&3));
+
+	// Removing any single transition makes the failure go away.
+	for i in 0..failure.transitions.len() {
+		let mut shorter = failure.transitions.clone();
+		shorter.remove(i);
+		assert!(!test.violates(&shorter, "below ten"));
+	}
+}
+
+#[test]
+fn sm_invariants_reports_the_invariant_that_failed() {
+	let test = PropertyTest::<BuggyCounter>::new(0, |rng, _| rng.below(5))
+		.invariant("never decreases", |pre, _, post| post >= pre)
+		.invariant("below ten", |_, _, post| *post < 10);
+
+	assert_eq!(test.run().unwrap_err().invariant, "below ten");
+}
@@ -2,12 +2,39 @@
 //! We begin with a few simple examples, and then proceed to build bigger and more complex state
 //! machines all implementing the same simple interface.
 
+pub mod compose;
+pub mod diff;
+pub mod explorer;
+pub mod invariants;
+mod p10_locks;
+mod p11_scripts;
+mod p12_wallet;
+mod p13_coinjoin;
+mod p14_ecash;
+mod p15_confidential;
+mod p16_bridge;
+mod p17_channels;
+mod p18_vm;
+mod p19_staking;
+mod p20_governance;
+mod p21_slashing;
+mod p22_multisig;
 mod p1_switches;
 mod p2_laundry_machine;
 mod p3_atm;
 mod p4_accounted_currency;
 mod p5_digital_cash;
 mod p6_open_ended;
+mod p7_multi_asset;
+mod p8_nonces;
+mod p9_fees;
+pub mod repl;
+pub mod simulate;
+#[cfg(test)]
+pub(crate) mod test_machines;
+pub mod trace;
+
+use std::{fmt, hash::Hash, str::FromStr};
 
 /// A state machine - Generic over the transition type
 pub trait StateMachine {
@@ -21,20 +48,124 @@ pub trait StateMachine {
 	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State;
 
 	/// A human-readable name for this state machine. This may be used in user-facing
-	/// programs such as the repl described below. This is not in any way related to
+	/// programs such as the repl in the `repl` module. This is not in any way related to
 	/// the correctness of the state machine.
 	fn human_name() -> String {
 		"Unnamed state machine".into()
 	}
 }
 
+/// A state machine whose transitions may be rejected, along with a reason for the rejection.
+///
+/// `StateMachine::next_state` must always return some state, so machines like the accounted
+/// currency simply return the unchanged starting state when a transition is invalid. That is
+/// exactly what a blockchain needs to stay deterministic, but it leaves callers unable to tell
+/// whether (or why) a transition did nothing. Machines that implement this trait additionally
+/// explain what went wrong through a machine-specific error type.
+///
+/// Implementations should agree with `next_state`. That is, whenever `try_next_state` returns
+/// `Ok(state)`, `next_state` returns that same state, and whenever it returns an `Err`,
+/// `next_state` returns the starting state unchanged.
+pub trait TryStateMachine: StateMachine {
+	/// The reasons a transition may be rejected by this machine
+	type Error;
+
+	/// Calculate the resulting state when this state undergoes the given transition, or
+	/// return the reason that the transition is invalid from this state.
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error>;
+}
+
+/// Information about the block in which transitions are being executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockContext {
+	/// The height of the block being executed
+	pub height: u64,
+	/// The user who authored the block, if the consensus engine identifies authors at all.
+	pub author: Option<User>,
+}
+
+/// A state machine that wants to do some work at the beginning or end of every block, in addition
+/// to executing the block's transitions. Typical examples are paying the block author or
+/// releasing funds that were locked until a certain height.
+///
+/// Both hooks leave the state unchanged by default, so machines that don't care about blocks can
+/// opt in with an empty implementation.
+pub trait BlockHooks: StateMachine {
+	/// Called at the beginning of every block, before any of its transitions are executed.
+	fn on_initialize(state: &Self::State, _block: &BlockContext) -> Self::State
+	where
+		Self::State: Clone,
+	{
+		state.clone()
+	}
+
+	/// Called at the end of every block, after all of its transitions are executed.
+	fn on_finalize(state: &Self::State, _block: &BlockContext) -> Self::State
+	where
+		Self::State: Clone,
+	{
+		state.clone()
+	}
+}
+
 /// A set of play users for experimenting with the multi-user state machines
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy, PartialOrd, Ord)]
 pub enum User {
 	Alice,
 	Bob,
 	Charlie,
 }
 
-// TODO Some kind of main program that allows users to interact with their state machine in a
-// repl-like way. Might require From<String> implementation for the transition type.
+impl User {
+	/// Every play user, handy for iterating or choosing one at random.
+	pub const ALL: [User; 3] = [User::Alice, User::Bob, User::Charlie];
+
+	/// Sign the given payload as this user.
+	pub fn sign<T: Hash>(&self, payload: &T) -> Signature {
+		Signature { signer: *self, payload_hash: crate::hash(payload) }
+	}
+}
+
+/// A toy signature. Just like the consensus authorities in chapter 3, users "sign" by simply
+/// attaching their identity. We also attach the hash of the signed payload so that a signature
+/// for one payload can't be reused for another. Real signatures would also make it impossible to
+/// sign on behalf of somebody else, but this is enough to model the flow of signed messages.
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy, PartialOrd, Ord)]
+pub struct Signature {
+	pub signer: User,
+	pub payload_hash: u64,
+}
+
+impl Signature {
+	/// Check that this is the given user's signature of the given payload.
+	pub fn verify<T: Hash>(&self, signer: User, payload: &T) -> bool {
+		*self == signer.sign(payload)
+	}
+}
+
+impl fmt::Display for User {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			User::Alice => write!(f, "alice"),
+			User::Bob => write!(f, "bob"),
+			User::Charlie => write!(f, "charlie"),
+		}
+	}
+}
+
+/// Users are parsed from their names, ignoring case. This is mostly useful in the repl.
+impl FromStr for User {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"alice" => Ok(User::Alice),
+			"bob" => Ok(User::Bob),
+			"charlie" => Ok(User::Charlie),
+			_ => Err(format!("`{s}` is not a known user. Try alice, bob, or charlie")),
+		}
+	}
+}
@@ -0,0 +1,526 @@
+//! In the accounted currency, every token in an account can be spent at any time. Real-world
+//! chains often need balances that exist, but can't be spent (at least not yet):
+//!
+//! - Reserved balance is set aside entirely, for example as a deposit that is returned when some
+//!   on-chain data is deleted again. It no longer counts as free balance at all.
+//! - Locked balance stays in the free balance, but can't be transferred away until the lock expires
+//!   at a given block height. Several locks may overlap the same tokens, so only the largest lock
+//!   matters. This is how staking and voting usually restrict funds: the same tokens may be staked
+//!   and used to vote at the same time, but can't be sent elsewhere.
+//! - Vesting balance is locked as well, but released linearly, a little more in every block.
+//!
+//! This machine keeps an `AccountData` for every account, and supports the mint and transfer
+//! transactions of the accounted currency in addition to the new ones.
+//!
+//! This machine needs to know the current block height, which it learns from the `on_initialize`
+//! block hook. Other modules, such as staking and governance, are expected to manipulate reserves
+//! and locks directly through the methods on `LockState` rather than through user transactions.
+
+use super::{p8_nonces::Authorized, BlockContext, BlockHooks, StateMachine, TryStateMachine, User};
+use std::collections::BTreeMap;
+
+/// Locks are identified by a short name so that each module can manage its own lock.
+pub type LockId = [u8; 8];
+
+/// A lock on part of an account's free balance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Lock {
+	/// How much of the free balance is locked
+	pub amount: u64,
+	/// The first block height at which the lock no longer applies
+	pub until: u64,
+}
+
+/// A linear vesting schedule. The locked amount decreases by `per_block` in every block after
+/// the schedule starts, until nothing is locked any more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Vesting {
+	/// The amount locked when the schedule starts
+	pub locked: u64,
+	/// How much is released in every block
+	pub per_block: u64,
+	/// The height at which the schedule starts
+	pub start: u64,
+}
+
+impl Vesting {
+	/// The amount that is still locked at the given height.
+	pub fn locked_at(&self, height: u64) -> u64 {
+		let released = height.saturating_sub(self.start).saturating_mul(self.per_block);
+		self.locked.saturating_sub(released)
+	}
+}
+
+/// Everything the system knows about a single account.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AccountData {
+	/// Balance that is available, subject to locks and vesting
+	pub free: u64,
+	/// Balance that is set aside, and can't be used at all until it is unreserved
+	pub reserved: u64,
+	/// Named locks on the free balance
+	pub locks: BTreeMap<LockId, Lock>,
+	/// The vesting schedule for this account, if any
+	pub vesting: Option<Vesting>,
+}
+
+impl AccountData {
+	/// The part of the free balance that is locked or still vesting at the given height.
+	pub fn frozen(&self, height: u64) -> u64 {
+		let locked = self
+			.locks
+			.values()
+			.filter(|lock| lock.until > height)
+			.map(|lock| lock.amount)
+			.max()
+			.unwrap_or(0);
+		let vesting = self.vesting.map(|v| v.locked_at(height)).unwrap_or(0);
+		locked.max(vesting)
+	}
+
+	/// The part of the free balance that may actually be spent at the given height.
+	pub fn usable(&self, height: u64) -> u64 {
+		self.free.saturating_sub(self.frozen(height))
+	}
+
+	/// Whether there is nothing left in this account, so it can be removed.
+	fn is_dead(&self) -> bool {
+		self.free == 0 && self.reserved == 0
+	}
+}
+
+/// This state machine models a multi-user currency whose balances can be reserved, locked,
+/// and vested.
+pub struct LockableCurrency;
+
+/// The state of the lockable currency.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LockState {
+	/// The height of the block currently being executed
+	pub height: u64,
+	/// All accounts with a non-zero free or reserved balance
+	pub accounts: BTreeMap<User, AccountData>,
+}
+
+/// The state transitions that users can make in a lockable currency.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockTransaction {
+	/// Create some new free balance for the minter
+	Mint { minter: User, amount: u64 },
+	/// Send some usable balance from one account to another
+	Transfer { sender: User, receiver: User, amount: u64 },
+	/// Move some usable balance into the reserve
+	Reserve { who: User, amount: u64 },
+	/// Move some reserved balance back into the free balance
+	Unreserve { who: User, amount: u64 },
+	/// Lock some of your own free balance until the given height. Users may only ever extend a
+	/// lock. When a lock with this id exists, the larger amount and later height are kept.
+	Lock { who: User, id: LockId, amount: u64, until: u64 },
+	/// Send some usable balance to another account, where it vests linearly from this block on.
+	Vest { sender: User, receiver: User, amount: u64, per_block: u64 },
+}
+
+/// The reasons a lockable currency transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockError {
+	/// Zero amounts have no effect and are not allowed.
+	ZeroAmount,
+	/// The account does not exist.
+	UnknownAccount,
+	/// A user may not transfer tokens to themselves.
+	SelfTransfer,
+	/// The account's usable balance is smaller than the requested amount.
+	InsufficientUsable { usable: u64, requested: u64 },
+	/// The account's reserved balance is smaller than the requested amount.
+	InsufficientReserved { reserved: u64, requested: u64 },
+	/// A lock may not exceed the account's free balance.
+	LockExceedsFree { free: u64, requested: u64 },
+	/// A lock must expire in the future.
+	LockExpired { until: u64, height: u64 },
+	/// The receiver already has a vesting schedule.
+	AlreadyVesting,
+	/// A vesting schedule must release something in every block, or it would never end.
+	ZeroVestingRate,
+	/// The transaction would push a balance beyond `u64::MAX`.
+	Overflow,
+}
+
+impl LockState {
+	/// A fresh state at genesis, with the given free balances.
+	pub fn new(free: impl IntoIterator<Item = (User, u64)>) -> Self {
+		let accounts = free
+			.into_iter()
+			.filter(|(_, free)| *free > 0)
+			.map(|(user, free)| (user, AccountData { free, ..Default::default() }))
+			.collect();
+		LockState { height: 0, accounts }
+	}
+
+	/// The data of the given account, if it exists.
+	pub fn account(&self, who: User) -> Option<&AccountData> {
+		self.accounts.get(&who)
+	}
+
+	/// The balance the given user may currently spend.
+	pub fn usable(&self, who: User) -> u64 {
+		self.account(who).map(|account| account.usable(self.height)).unwrap_or(0)
+	}
+
+	/// Add to a free balance, creating the account if necessary.
+	pub fn deposit(&mut self, who: User, amount: u64) -> Result<(), LockError> {
+		let account = self.accounts.entry(who).or_default();
+		account.free = account.free.checked_add(amount).ok_or(LockError::Overflow)?;
+		self.reap(who);
+		Ok(())
+	}
+
+	/// Remove some usable balance, reaping the account if nothing is left in it.
+	pub fn withdraw(&mut self, who: User, amount: u64) -> Result<(), LockError> {
+		let height = self.height;
+		let account = self.accounts.get_mut(&who).ok_or(LockError::UnknownAccount)?;
+		let usable = account.usable(height);
+		if usable < amount {
+			return Err(LockError::InsufficientUsable { usable, requested: amount })
+		}
+		account.free -= amount;
+		self.reap(who);
+		Ok(())
+	}
+
+	/// Move some usable balance into the reserve.
+	pub fn reserve(&mut self, who: User, amount: u64) -> Result<(), LockError> {
+		self.withdraw(who, amount)?;
+		let account = self.accounts.entry(who).or_default();
+		account.reserved = account.reserved.checked_add(amount).ok_or(LockError::Overflow)?;
+		Ok(())
+	}
+
+	/// Move some reserved balance back into the free balance.
+	pub fn unreserve(&mut self, who: User, amount: u64) -> Result<(), LockError> {
+		let account = self.accounts.get_mut(&who).ok_or(LockError::UnknownAccount)?;
+		if account.reserved < amount {
+			return Err(LockError::InsufficientReserved {
+				reserved: account.reserved,
+				requested: amount,
+			})
+		}
+		account.reserved -= amount;
+		account.free = account.free.checked_add(amount).ok_or(LockError::Overflow)?;
+		Ok(())
+	}
+
+	/// Destroy up to the given amount of reserved balance, and return how much was destroyed.
+	pub fn slash_reserved(&mut self, who: User, amount: u64) -> u64 {
+		let Some(account) = self.accounts.get_mut(&who) else { return 0 };
+		let slashed = account.reserved.min(amount);
+		account.reserved -= slashed;
+		self.reap(who);
+		slashed
+	}
+
+	/// Destroy up to the given amount of free balance, even if it is locked, and return how much
+	/// was destroyed. Locks are left alone, so they may now exceed the free balance.
+	pub fn slash(&mut self, who: User, amount: u64) -> u64 {
+		let Some(account) = self.accounts.get_mut(&who) else { return 0 };
+		let slashed = account.free.min(amount);
+		account.free -= slashed;
+		self.reap(who);
+		slashed
+	}
+
+	/// Set (or replace) a named lock. Unlike the user-facing `Lock` transaction, this may shrink
+	/// or shorten an existing lock, so it is meant for modules that manage their own locks.
+	pub fn set_lock(&mut self, who: User, id: LockId, lock: Lock) -> Result<(), LockError> {
+		let account = self.accounts.get_mut(&who).ok_or(LockError::UnknownAccount)?;
+		if lock.amount > account.free {
+			return Err(LockError::LockExceedsFree { free: account.free, requested: lock.amount })
+		}
+		account.locks.insert(id, lock);
+		Ok(())
+	}
+
+	/// Remove a named lock, if it exists.
+	pub fn remove_lock(&mut self, who: User, id: LockId) {
+		if let Some(account) = self.accounts.get_mut(&who) {
+			account.locks.remove(&id);
+		}
+	}
+
+	/// Remove the account entirely if there is nothing left in it.
+	fn reap(&mut self, who: User) {
+		if self.accounts.get(&who).is_some_and(AccountData::is_dead) {
+			self.accounts.remove(&who);
+		}
+	}
+}
+
+impl StateMachine for LockableCurrency {
+	type State = LockState;
+	type Transition = LockTransaction;
+
+	fn next_state(starting_state: &LockState, t: &LockTransaction) -> LockState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Lockable Currency".into()
+	}
+}
+
+impl TryStateMachine for LockableCurrency {
+	type Error = LockError;
+
+	fn try_next_state(
+		starting_state: &LockState,
+		t: &LockTransaction,
+	) -> Result<LockState, LockError> {
+		let mut state = starting_state.clone();
+
+		match *t {
+			LockTransaction::Mint { amount: 0, .. } |
+			LockTransaction::Transfer { amount: 0, .. } |
+			LockTransaction::Reserve { amount: 0, .. } |
+			LockTransaction::Unreserve { amount: 0, .. } |
+			LockTransaction::Lock { amount: 0, .. } |
+			LockTransaction::Vest { amount: 0, .. } => return Err(LockError::ZeroAmount),
+			LockTransaction::Mint { minter, amount } => state.deposit(minter, amount)?,
+			LockTransaction::Transfer { sender, receiver, amount } => {
+				if sender == receiver {
+					return Err(LockError::SelfTransfer)
+				}
+				state.withdraw(sender, amount)?;
+				state.deposit(receiver, amount)?;
+			},
+			LockTransaction::Reserve { who, amount } => state.reserve(who, amount)?,
+			LockTransaction::Unreserve { who, amount } => state.unreserve(who, amount)?,
+			LockTransaction::Lock { who, id, amount, until } => {
+				if until <= state.height {
+					return Err(LockError::LockExpired { until, height: state.height })
+				}
+				let existing = state.account(who).and_then(|account| account.locks.get(&id));
+				let lock = match existing {
+					Some(old) =>
+						Lock { amount: old.amount.max(amount), until: old.until.max(until) },
+					None => Lock { amount, until },
+				};
+				state.set_lock(who, id, lock)?;
+			},
+			LockTransaction::Vest { sender, receiver, amount, per_block } => {
+				if sender == receiver {
+					return Err(LockError::SelfTransfer)
+				}
+				if per_block == 0 {
+					return Err(LockError::ZeroVestingRate)
+				}
+				if state.account(receiver).is_some_and(|account| account.vesting.is_some()) {
+					return Err(LockError::AlreadyVesting)
+				}
+				state.withdraw(sender, amount)?;
+				state.deposit(receiver, amount)?;
+				let vesting = Vesting { locked: amount, per_block, start: state.height };
+				state.accounts.get_mut(&receiver).expect("deposit was just made; qed").vesting =
+					Some(vesting);
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// At the beginning of every block, we note the new height and clean up locks and vesting
+/// schedules that no longer restrict anything.
+impl BlockHooks for LockableCurrency {
+	fn on_initialize(state: &LockState, block: &BlockContext) -> LockState {
+		let mut state = state.clone();
+		state.height = block.height;
+		for account in state.accounts.values_mut() {
+			account.locks.retain(|_, lock| lock.until > block.height);
+			if account.vesting.is_some_and(|v| v.locked_at(block.height) == 0) {
+				account.vesting = None;
+			}
+		}
+		state
+	}
+}
+
+/// Every lockable currency transaction acts on the balance of the user who makes it.
+impl Authorized for LockableCurrency {
+	fn signer(t: &LockTransaction) -> User {
+		match t {
+			LockTransaction::Mint { minter, .. } => *minter,
+			LockTransaction::Transfer { sender, .. } => *sender,
+			LockTransaction::Reserve { who, .. } => *who,
+			LockTransaction::Unreserve { who, .. } => *who,
+			LockTransaction::Lock { who, .. } => *who,
+			LockTransaction::Vest { sender, .. } => *sender,
+		}
+	}
+}
+
+#[test]
+fn sm_10_reserved_balance_cannot_be_spent() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let reserve = LockTransaction::Reserve { who: User::Alice, amount: 60 };
+	let reserved = LockableCurrency::try_next_state(&start, &reserve).unwrap();
+	let transfer =
+		LockTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 50 };
+
+	assert_eq!(reserved.account(User::Alice).unwrap().free, 40);
+	assert_eq!(reserved.account(User::Alice).unwrap().reserved, 60);
+	assert_eq!(
+		LockableCurrency::try_next_state(&reserved, &transfer),
+		Err(LockError::InsufficientUsable { usable: 40, requested: 50 })
+	);
+}
+
+#[test]
+fn sm_10_unreserve() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let reserve = LockTransaction::Reserve { who: User::Alice, amount: 60 };
+	let reserved = LockableCurrency::try_next_state(&start, &reserve).unwrap();
+	let too_much = LockTransaction::Unreserve { who: User::Alice, amount: 70 };
+	let unreserve = LockTransaction::Unreserve { who: User::Alice, amount: 60 };
+
+	assert_eq!(
+		LockableCurrency::try_next_state(&reserved, &too_much),
+		Err(LockError::InsufficientReserved { reserved: 60, requested: 70 })
+	);
+	assert_eq!(LockableCurrency::try_next_state(&reserved, &unreserve), Ok(start));
+}
+
+#[test]
+fn sm_10_fully_reserved_account_is_not_reaped() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let reserve = LockTransaction::Reserve { who: User::Alice, amount: 100 };
+	let end = LockableCurrency::try_next_state(&start, &reserve).unwrap();
+
+	assert_eq!(end.account(User::Alice).unwrap().free, 0);
+	assert_eq!(end.usable(User::Alice), 0);
+}
+
+#[test]
+fn sm_10_lock_until_height() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let lock = LockTransaction::Lock { who: User::Alice, id: *b"savings ", amount: 70, until: 5 };
+	let locked = LockableCurrency::try_next_state(&start, &lock).unwrap();
+	let transfer =
+		LockTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 50 };
+
+	assert_eq!(
+		LockableCurrency::try_next_state(&locked, &transfer),
+		Err(LockError::InsufficientUsable { usable: 30, requested: 50 })
+	);
+
+	let later = LockableCurrency::on_initialize(&locked, &BlockContext { height: 5, author: None });
+	assert!(later.account(User::Alice).unwrap().locks.is_empty());
+	assert!(LockableCurrency::try_next_state(&later, &transfer).is_ok());
+}
+
+#[test]
+fn sm_10_overlapping_locks_use_the_largest() {
+	let mut state = LockState::new([(User::Alice, 100)]);
+	state
+		.set_lock(User::Alice, *b"staking ", Lock { amount: 70, until: 10 })
+		.unwrap();
+	state
+		.set_lock(User::Alice, *b"voting  ", Lock { amount: 50, until: 10 })
+		.unwrap();
+
+	assert_eq!(state.usable(User::Alice), 30);
+}
+
+#[test]
+fn sm_10_users_can_only_extend_locks() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let first = LockTransaction::Lock { who: User::Alice, id: *b"savings ", amount: 70, until: 5 };
+	let second = LockTransaction::Lock { who: User::Alice, id: *b"savings ", amount: 10, until: 8 };
+	let state = LockableCurrency::try_next_state(&start, &first).unwrap();
+	let state = LockableCurrency::try_next_state(&state, &second).unwrap();
+
+	assert_eq!(
+		state.account(User::Alice).unwrap().locks[b"savings "],
+		Lock { amount: 70, until: 8 }
+	);
+}
+
+#[test]
+fn sm_10_lock_cannot_exceed_free_balance() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let lock = LockTransaction::Lock { who: User::Alice, id: *b"savings ", amount: 170, until: 5 };
+
+	assert_eq!(
+		LockableCurrency::try_next_state(&start, &lock),
+		Err(LockError::LockExceedsFree { free: 100, requested: 170 })
+	);
+}
+
+#[test]
+fn sm_10_vesting_releases_linearly() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let vest = LockTransaction::Vest {
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 100,
+		per_block: 10,
+	};
+	let vested = LockableCurrency::try_next_state(&start, &vest).unwrap();
+
+	assert_eq!(vested.usable(User::Bob), 0);
+
+	let block = |height| BlockContext { height, author: None };
+	let later = LockableCurrency::on_initialize(&vested, &block(3));
+	assert_eq!(later.usable(User::Bob), 30);
+
+	let done = LockableCurrency::on_initialize(&later, &block(10));
+	assert_eq!(done.usable(User::Bob), 100);
+	assert_eq!(done.account(User::Bob).unwrap().vesting, None);
+}
+
+#[test]
+fn sm_10_cannot_vest_twice() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let vest = LockTransaction::Vest {
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 50,
+		per_block: 10,
+	};
+	let vested = LockableCurrency::try_next_state(&start, &vest).unwrap();
+
+	assert_eq!(LockableCurrency::try_next_state(&vested, &vest), Err(LockError::AlreadyVesting));
+}
+
+#[test]
+fn sm_10_vesting_must_release_something() {
+	let start = LockState::new([(User::Alice, 100)]);
+	let vest = LockTransaction::Vest {
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 50,
+		per_block: 0,
+	};
+
+	assert_eq!(LockableCurrency::try_next_state(&start, &vest), Err(LockError::ZeroVestingRate));
+}
+
+#[test]
+fn sm_10_slash_reserved() {
+	let mut state = LockState::new([(User::Alice, 100)]);
+	state.reserve(User::Alice, 100).unwrap();
+
+	assert_eq!(state.slash_reserved(User::Alice, 30), 30);
+	assert_eq!(state.slash_reserved(User::Alice, 300), 70);
+	assert_eq!(state.account(User::Alice), None);
+}
+
+#[test]
+fn sm_10_slash_ignores_locks() {
+	let mut state = LockState::new([(User::Alice, 100)]);
+	let lock = Lock { amount: 100, until: 10 };
+	state.set_lock(User::Alice, *b"staking ", lock).unwrap();
+
+	assert_eq!(state.slash(User::Alice, 30), 30);
+	assert_eq!(state.account(User::Alice).unwrap().free, 70);
+	assert_eq!(state.slash(User::Alice, 300), 70);
+	assert_eq!(state.account(User::Alice), None);
+}
@@ -0,0 +1,469 @@
+//! In the digital cash system from part 5, anybody can spend any bill simply by naming it in a
+//! transfer. Nothing checks that the owner actually agreed to the spend.
+//!
+//! UTXO chains like Bitcoin solve this by attaching a spending condition, often called a locking
+//! script, to every output. To spend the output, a transaction must provide a witness, such as a
+//! signature, that satisfies the condition. Conditions are not limited to a single owner though.
+//! Small scripts can express shared ownership, payments that only unlock once a secret is revealed,
+//! and payments that can't be spent before a certain time. These building blocks are enough to
+//! build surprisingly rich protocols such as payment channels and atomic swaps.
+//!
+//! Our scripts are trees of conditions, which are evaluated by a tiny interpreter. The interpreter
+//! is completely deterministic: its outcome depends only on the script, the witness, the
+//! transaction being signed, and the current block height.
+
+use super::{BlockContext, BlockHooks, Signature, StateMachine, TryStateMachine, User};
+use crate::hash;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A spending condition.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Script {
+	/// Satisfied by a signature of the given user.
+	Signature(User),
+	/// Satisfied by revealing a secret whose hash is the given value.
+	HashLock(u64),
+	/// Satisfied by signatures of at least `threshold` of the given users.
+	Multisig { threshold: usize, signers: Vec<User> },
+	/// Satisfied once the chain has reached the given height.
+	After(u64),
+	/// Satisfied once the given number of blocks have passed since the bill was created.
+	Older(u64),
+	/// Satisfied when every one of the inner scripts is satisfied.
+	All(Vec<Script>),
+	/// Satisfied when at least one of the inner scripts is satisfied.
+	Any(Vec<Script>),
+}
+
+/// The evidence provided to satisfy the script of a single input.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Witness {
+	/// Signatures over the transaction's signing hash
+	pub signatures: Vec<Signature>,
+	/// Secrets whose hashes may appear in hash locks
+	pub preimages: Vec<u64>,
+}
+
+/// Everything a script may depend on besides the witness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptContext {
+	/// The hash of the transaction that all signatures must sign
+	pub signing_hash: u64,
+	/// The current block height
+	pub height: u64,
+	/// The height at which the bill being spent was created
+	pub created: u64,
+}
+
+/// The reasons a script may not be satisfied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+	/// The witness doesn't contain the user's signature of this transaction.
+	MissingSignature(User),
+	/// The witness doesn't reveal the secret behind the hash lock.
+	MissingPreimage(u64),
+	/// Fewer than the required number of signers have signed.
+	NotEnoughSignatures { required: usize, found: usize },
+	/// The script can't be spent before the given height.
+	TooEarly { spendable_at: u64 },
+	/// None of the alternatives is satisfied. The reason each alternative failed is given in
+	/// order.
+	NoAlternative(Vec<ScriptError>),
+}
+
+impl Script {
+	/// Check whether the witness satisfies this script. On failure, the reason is returned.
+	pub fn evaluate(&self, witness: &Witness, context: &ScriptContext) -> Result<(), ScriptError> {
+		let signed =
+			|user: &User| witness.signatures.iter().any(|s| s.verify(*user, &context.signing_hash));
+
+		match self {
+			Script::Signature(user) =>
+				if signed(user) {
+					Ok(())
+				} else {
+					Err(ScriptError::MissingSignature(*user))
+				},
+			Script::HashLock(expected) =>
+				if witness.preimages.iter().any(|preimage| hash(preimage) == *expected) {
+					Ok(())
+				} else {
+					Err(ScriptError::MissingPreimage(*expected))
+				},
+			Script::Multisig { threshold, signers } => {
+				let unique: BTreeSet<_> = signers.iter().collect();
+				let found = unique.into_iter().filter(|user| signed(user)).count();
+				if found >= *threshold {
+					Ok(())
+				} else {
+					Err(ScriptError::NotEnoughSignatures { required: *threshold, found })
+				}
+			},
+			Script::After(height) =>
+				if context.height >= *height {
+					Ok(())
+				} else {
+					Err(ScriptError::TooEarly { spendable_at: *height })
+				},
+			Script::Older(blocks) => {
+				let spendable_at = context.created.saturating_add(*blocks);
+				if context.height >= spendable_at {
+					Ok(())
+				} else {
+					Err(ScriptError::TooEarly { spendable_at })
+				}
+			},
+			Script::All(scripts) =>
+				scripts.iter().try_for_each(|script| script.evaluate(witness, context)),
+			Script::Any(scripts) => {
+				let mut errors = Vec::new();
+				for script in scripts {
+					match script.evaluate(witness, context) {
+						Ok(()) => return Ok(()),
+						Err(e) => errors.push(e),
+					}
+				}
+				Err(ScriptError::NoAlternative(errors))
+			},
+		}
+	}
+}
+
+/// A bill in circulation, locked by a script rather than owned by a single user.
+///
+/// This is a separate type from the bill of part 5 because that one is the subject of an exercise
+/// that students solve with an owner field. Replacing the owner with a script would change the
+/// exercise itself.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockedBill {
+	pub amount: u64,
+	pub script: Script,
+	/// The block height at which the bill was created, for relative timelocks
+	pub created: u64,
+}
+
+/// A new bill to be created by a transaction
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Output {
+	pub amount: u64,
+	pub script: Script,
+}
+
+/// This state machine models digital cash whose bills are protected by spending conditions.
+pub struct ScriptedCash;
+
+/// The state of the scripted cash system.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ScriptState {
+	/// The height of the block currently being executed
+	pub height: u64,
+	/// The bills in circulation, by serial number
+	pub bills: BTreeMap<u64, LockedBill>,
+	/// The serial number of the next bill to be created
+	pub next_serial: u64,
+}
+
+impl ScriptState {
+	fn create(&mut self, output: &Output) {
+		let bill = LockedBill {
+			amount: output.amount,
+			script: output.script.clone(),
+			created: self.height,
+		};
+		self.bills.insert(self.next_serial, bill);
+		self.next_serial += 1;
+	}
+}
+
+/// The state transitions that users can make in the scripted cash system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ScriptTransaction {
+	/// Mint a single new bill locked by the given script
+	Mint(Output),
+	/// Spend some bills, identified by serial number, and create new ones. Each spent bill must
+	/// have a witness at the same index that satisfies its script. The new bills are given
+	/// consecutive serial numbers in order.
+	Transfer { spends: Vec<u64>, receives: Vec<Output>, witnesses: Vec<Witness> },
+}
+
+/// The hash that signatures must sign to authorize a transfer. It commits to everything except
+/// the witnesses, which contain the signatures themselves.
+pub fn signing_hash(spends: &[u64], receives: &[Output]) -> u64 {
+	hash(&(spends, receives))
+}
+
+/// The reasons a scripted cash transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptedCashError {
+	/// A transfer must spend at least one bill.
+	NoSpends,
+	/// There is no bill in circulation with this serial.
+	UnknownBill(u64),
+	/// The same bill is spent twice in a single transfer.
+	DoubleSpend(u64),
+	/// Every spent bill needs exactly one witness.
+	WitnessCountMismatch { spends: usize, witnesses: usize },
+	/// Bills may not be created with a value of zero.
+	ZeroValueOutput,
+	/// The total value of the bills being spent or received exceeds `u64::MAX`.
+	Overflow,
+	/// The bills received are worth more than the bills spent.
+	OutputsExceedInputs { spent: u64, received: u64 },
+	/// The witness of the input at this index does not satisfy the bill's script.
+	Unsatisfied { input: usize, error: ScriptError },
+}
+
+impl StateMachine for ScriptedCash {
+	type State = ScriptState;
+	type Transition = ScriptTransaction;
+
+	fn next_state(starting_state: &ScriptState, t: &ScriptTransaction) -> ScriptState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Scripted Cash".into()
+	}
+}
+
+impl TryStateMachine for ScriptedCash {
+	type Error = ScriptedCashError;
+
+	fn try_next_state(
+		starting_state: &ScriptState,
+		t: &ScriptTransaction,
+	) -> Result<ScriptState, ScriptedCashError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			ScriptTransaction::Mint(output) => {
+				if output.amount == 0 {
+					return Err(ScriptedCashError::ZeroValueOutput)
+				}
+				state.create(output);
+			},
+			ScriptTransaction::Transfer { spends, receives, witnesses } => {
+				if spends.is_empty() {
+					return Err(ScriptedCashError::NoSpends)
+				}
+				if spends.len() != witnesses.len() {
+					return Err(ScriptedCashError::WitnessCountMismatch {
+						spends: spends.len(),
+						witnesses: witnesses.len(),
+					})
+				}
+
+				let signing_hash = signing_hash(spends, receives);
+				let mut spent = 0u64;
+				for (input, (serial, witness)) in spends.iter().zip(witnesses).enumerate() {
+					let bill = match state.bills.remove(serial) {
+						Some(bill) => bill,
+						None if starting_state.bills.contains_key(serial) =>
+							return Err(ScriptedCashError::DoubleSpend(*serial)),
+						None => return Err(ScriptedCashError::UnknownBill(*serial)),
+					};
+					let context =
+						ScriptContext { signing_hash, height: state.height, created: bill.created };
+					bill.script
+						.evaluate(witness, &context)
+						.map_err(|error| ScriptedCashError::Unsatisfied { input, error })?;
+					spent = spent.checked_add(bill.amount).ok_or(ScriptedCashError::Overflow)?;
+				}
+
+				let mut received = 0u64;
+				for output in receives {
+					if output.amount == 0 {
+						return Err(ScriptedCashError::ZeroValueOutput)
+					}
+					received =
+						received.checked_add(output.amount).ok_or(ScriptedCashError::Overflow)?;
+					state.create(output);
+				}
+				if received > spent {
+					return Err(ScriptedCashError::OutputsExceedInputs { spent, received })
+				}
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// Timelocks need to know the current height, which we note at the beginning of every block.
+impl BlockHooks for ScriptedCash {
+	fn on_initialize(state: &ScriptState, block: &BlockContext) -> ScriptState {
+		ScriptState { height: block.height, ..state.clone() }
+	}
+}
+
+/// Build a transfer and sign it as each of the given users. Every input receives the same
+/// witness, which is enough for most simple scripts.
+pub fn signed_transfer(
+	spends: Vec<u64>,
+	receives: Vec<Output>,
+	signers: &[User],
+	preimages: Vec<u64>,
+) -> ScriptTransaction {
+	let signing_hash = signing_hash(&spends, &receives);
+	let witness = Witness {
+		signatures: signers.iter().map(|user| user.sign(&signing_hash)).collect(),
+		preimages,
+	};
+	let witnesses = vec![witness; spends.len()];
+	ScriptTransaction::Transfer { spends, receives, witnesses }
+}
+
+/// A state with a single bill worth 100, locked by the given script, at the given height.
+#[cfg(test)]
+fn single_bill(script: Script, height: u64) -> ScriptState {
+	let mut state = ScriptState::default();
+	state.create(&Output { amount: 100, script });
+	state.height = height;
+	state
+}
+
+/// A transfer of the single bill's entire value to Charlie.
+#[cfg(test)]
+fn pay_charlie(signers: &[User], preimages: Vec<u64>) -> ScriptTransaction {
+	let output = Output { amount: 100, script: Script::Signature(User::Charlie) };
+	signed_transfer(vec![0], vec![output], signers, preimages)
+}
+
+#[test]
+fn sm_11_signature_required() {
+	let start = single_bill(Script::Signature(User::Alice), 0);
+
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Alice], vec![])).is_ok());
+	assert_eq!(
+		ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Bob], vec![])),
+		Err(ScriptedCashError::Unsatisfied {
+			input: 0,
+			error: ScriptError::MissingSignature(User::Alice)
+		})
+	);
+}
+
+#[test]
+fn sm_11_signature_does_not_authorize_other_outputs() {
+	let start = single_bill(Script::Signature(User::Alice), 0);
+	let mut transfer = pay_charlie(&[User::Alice], vec![]);
+	if let ScriptTransaction::Transfer { receives, .. } = &mut transfer {
+		receives[0].script = Script::Signature(User::Bob);
+	}
+
+	assert!(matches!(
+		ScriptedCash::try_next_state(&start, &transfer),
+		Err(ScriptedCashError::Unsatisfied {
+			error: ScriptError::MissingSignature(User::Alice),
+			..
+		})
+	));
+}
+
+#[test]
+fn sm_11_hash_lock() {
+	let start = single_bill(Script::HashLock(hash(&42u64)), 0);
+
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[], vec![42])).is_ok());
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[], vec![41])).is_err());
+}
+
+#[test]
+fn sm_11_multisig_two_of_three() {
+	let script = Script::Multisig { threshold: 2, signers: User::ALL.to_vec() };
+	let start = single_bill(script, 0);
+
+	assert!(ScriptedCash::try_next_state(
+		&start,
+		&pay_charlie(&[User::Alice, User::Charlie], vec![])
+	)
+	.is_ok());
+	assert_eq!(
+		ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Bob, User::Bob], vec![])),
+		Err(ScriptedCashError::Unsatisfied {
+			input: 0,
+			error: ScriptError::NotEnoughSignatures { required: 2, found: 1 }
+		})
+	);
+}
+
+#[test]
+fn sm_11_absolute_timelock() {
+	let script = Script::All(vec![Script::Signature(User::Alice), Script::After(10)]);
+
+	assert_eq!(
+		ScriptedCash::try_next_state(
+			&single_bill(script.clone(), 9),
+			&pay_charlie(&[User::Alice], vec![])
+		),
+		Err(ScriptedCashError::Unsatisfied {
+			input: 0,
+			error: ScriptError::TooEarly { spendable_at: 10 }
+		})
+	);
+	assert!(ScriptedCash::try_next_state(
+		&single_bill(script, 10),
+		&pay_charlie(&[User::Alice], vec![])
+	)
+	.is_ok());
+}
+
+#[test]
+fn sm_11_relative_timelock() {
+	let mut start = ScriptState { height: 5, ..Default::default() };
+	start.create(&Output { amount: 100, script: Script::Older(3) });
+	let early = ScriptedCash::on_initialize(&start, &BlockContext { height: 7, author: None });
+	let late = ScriptedCash::on_initialize(&start, &BlockContext { height: 8, author: None });
+
+	assert_eq!(
+		ScriptedCash::try_next_state(&early, &pay_charlie(&[], vec![])),
+		Err(ScriptedCashError::Unsatisfied {
+			input: 0,
+			error: ScriptError::TooEarly { spendable_at: 8 }
+		})
+	);
+	assert!(ScriptedCash::try_next_state(&late, &pay_charlie(&[], vec![])).is_ok());
+}
+
+#[test]
+fn sm_11_hashed_timelock_contract() {
+	// Bob can claim with the secret, or Alice can take her money back after height 20.
+	let script = Script::Any(vec![
+		Script::All(vec![Script::Signature(User::Bob), Script::HashLock(hash(&7u64))]),
+		Script::All(vec![Script::Signature(User::Alice), Script::After(20)]),
+	]);
+	let start = single_bill(script, 10);
+
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Bob], vec![7])).is_ok());
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Bob], vec![])).is_err());
+	assert!(ScriptedCash::try_next_state(&start, &pay_charlie(&[User::Alice], vec![])).is_err());
+
+	let refund = ScriptedCash::on_initialize(&start, &BlockContext { height: 20, author: None });
+	assert!(ScriptedCash::try_next_state(&refund, &pay_charlie(&[User::Alice], vec![])).is_ok());
+}
+
+#[test]
+fn sm_11_witness_per_input() {
+	let start = single_bill(Script::Signature(User::Alice), 0);
+	let transfer = ScriptTransaction::Transfer {
+		spends: vec![0],
+		receives: vec![Output { amount: 100, script: Script::Signature(User::Charlie) }],
+		witnesses: vec![],
+	};
+
+	assert_eq!(
+		ScriptedCash::try_next_state(&start, &transfer),
+		Err(ScriptedCashError::WitnessCountMismatch { spends: 1, witnesses: 0 })
+	);
+}
+
+#[test]
+fn sm_11_double_spend() {
+	let start = single_bill(Script::After(0), 0);
+	let transfer = signed_transfer(vec![0, 0], vec![], &[], vec![]);
+
+	assert_eq!(
+		ScriptedCash::try_next_state(&start, &transfer),
+		Err(ScriptedCashError::DoubleSpend(0))
+	);
+}
@@ -0,0 +1,352 @@
+//! Building a digital cash transfer by hand is tedious. The sender has to pick which of their bills
+//! to spend, work out how much change to send back to themselves, and number every new bill with
+//! the correct serial. A wallet does all of this for its owner.
+//!
+//! The most interesting part is coin selection: deciding which bills to spend. There is no single
+//! right answer, so the wallet supports a few strategies with different trade-offs:
+//!
+//! - Largest first spends the biggest bills until the payment is covered. It uses few bills, but
+//!   almost always creates change.
+//! - Branch and bound searches for a set of bills that adds up to the payment exactly, so that no
+//!   change is needed at all. When there is no such set, it falls back to largest first.
+//! - Privacy preserving avoids combining several bills in one transfer whenever possible, because
+//!   spending bills together tells everybody watching that they have the same owner.
+
+use super::{
+	p5_digital_cash::{Bill, CashTransaction, State},
+	User,
+};
+use crate::hash;
+
+/// How the wallet chooses which bills to spend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Strategy {
+	/// Spend the biggest bills first.
+	LargestFirst,
+	/// Look for bills that add up to the target exactly, and fall back to largest first.
+	BranchAndBound,
+	/// Spend a single bill if any is big enough, and fall back to largest first.
+	PrivacyPreserving,
+}
+
+/// The most subsets that branch and bound will consider before giving up. Without a limit, the
+/// search could take exponentially long in the number of bills.
+pub const MAX_TRIES: usize = 100_000;
+
+/// The reasons the wallet may be unable to build a transfer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WalletError {
+	/// There is nobody to pay.
+	NoPayments,
+	/// Payments must be for a non-zero amount.
+	ZeroPayment { payee: User },
+	/// The payments and fee add up to more than `u64::MAX`.
+	Overflow,
+	/// The owner's bills are not worth enough to make the payments and pay the fee.
+	InsufficientFunds { available: u128, required: u64 },
+}
+
+/// A wallet builds transfers on behalf of a single user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Wallet {
+	/// The user whose bills are spent, and who receives the change
+	pub owner: User,
+	/// How to choose which bills to spend
+	pub strategy: Strategy,
+	/// The amount that is left unspent in every transfer, for the block author to collect
+	pub fee: u64,
+	/// Randomness known only to the owner, which decides where privacy preserving transfers put
+	/// the change. It should be chosen at random and never published, since anybody who knows it
+	/// can tell which output is the change.
+	pub secret: u64,
+}
+
+impl Wallet {
+	/// A wallet for the given user and secret that spends largest bills first and pays no fee.
+	pub fn new(owner: User, secret: u64) -> Self {
+		Wallet { owner, strategy: Strategy::LargestFirst, fee: 0, secret }
+	}
+
+	/// The owner's bills in the given state, ordered by serial.
+	pub fn bills(&self, state: &State) -> Vec<Bill> {
+		let mut bills: Vec<_> =
+			state.bills().filter(|bill| bill.owner() == self.owner).cloned().collect();
+		bills.sort_by_key(Bill::serial);
+		bills
+	}
+
+	/// The total value of the owner's bills in the given state.
+	pub fn balance(&self, state: &State) -> u128 {
+		state
+			.bills()
+			.filter(|bill| bill.owner() == self.owner)
+			.map(|bill| bill.amount() as u128)
+			.sum()
+	}
+
+	/// Build a transfer that pays each payee the given amount from the owner's bills in the given
+	/// state. Change, if any, goes back to the owner. The new bills are numbered starting from the
+	/// state's next serial, so the transfer is valid if it is applied directly to this state.
+	pub fn pay(
+		&self,
+		state: &State,
+		payments: &[(User, u64)],
+	) -> Result<CashTransaction, WalletError> {
+		if payments.is_empty() {
+			return Err(WalletError::NoPayments)
+		}
+
+		let mut target = self.fee;
+		for (payee, amount) in payments {
+			if *amount == 0 {
+				return Err(WalletError::ZeroPayment { payee: *payee })
+			}
+			target = target.checked_add(*amount).ok_or(WalletError::Overflow)?;
+		}
+
+		let available = self.balance(state);
+		if available < target as u128 {
+			return Err(WalletError::InsufficientFunds { available, required: target })
+		}
+
+		let bills = self.bills(state);
+		let spends = match self.strategy {
+			Strategy::LargestFirst => largest_first(&bills, target),
+			Strategy::BranchAndBound =>
+				branch_and_bound(&bills, target).unwrap_or_else(|| largest_first(&bills, target)),
+			Strategy::PrivacyPreserving =>
+				single_bill(&bills, target).unwrap_or_else(|| largest_first(&bills, target)),
+		};
+
+		// The spent bills are worth at least the target, which fits in a u64, so the change does as
+		// well.
+		let spent: u128 = spends.iter().map(|bill| bill.amount() as u128).sum();
+		let change = (spent - target as u128) as u64;
+
+		let mut outputs: Vec<(User, u64)> = payments.to_vec();
+		if change > 0 {
+			// Always putting change last would tell observers which output it is. Instead, its
+			// position is derived from the wallet's secret and the bills being spent, so that it
+			// differs between transfers but can't be recomputed by anybody else.
+			let position = match self.strategy {
+				Strategy::PrivacyPreserving =>
+					hash(&(self.secret, &spends)) as usize % (outputs.len() + 1),
+				_ => outputs.len(),
+			};
+			outputs.insert(position, (self.owner, change));
+		}
+
+		let receives = outputs
+			.into_iter()
+			.zip(state.next_serial()..)
+			.map(|((owner, amount), serial)| Bill::new(owner, amount, serial))
+			.collect();
+
+		Ok(CashTransaction::Transfer { spends, receives })
+	}
+}
+
+/// Take the biggest bills until they cover the target. The caller ensures that all bills together
+/// cover the target.
+fn largest_first(bills: &[Bill], target: u64) -> Vec<Bill> {
+	let mut sorted = bills.to_vec();
+	sorted.sort_by(|a, b| b.amount().cmp(&a.amount()).then(a.serial().cmp(&b.serial())));
+
+	let mut selected = Vec::new();
+	let mut total: u128 = 0;
+	for bill in sorted {
+		if total >= target as u128 {
+			break
+		}
+		total += bill.amount() as u128;
+		selected.push(bill);
+	}
+	selected
+}
+
+/// The smallest single bill that covers the target, if there is one.
+fn single_bill(bills: &[Bill], target: u64) -> Option<Vec<Bill>> {
+	bills
+		.iter()
+		.filter(|bill| bill.amount() >= target)
+		.min_by_key(|bill| (bill.amount(), bill.serial()))
+		.map(|bill| vec![bill.clone()])
+}
+
+/// Search for a set of bills worth exactly the target. Bills are tried from largest to smallest,
+/// and a branch is abandoned as soon as it overshoots or the remaining bills can't reach the
+/// target any more.
+fn branch_and_bound(bills: &[Bill], target: u64) -> Option<Vec<Bill>> {
+	let mut sorted = bills.to_vec();
+	sorted.sort_by(|a, b| b.amount().cmp(&a.amount()).then(a.serial().cmp(&b.serial())));
+
+	// remaining[i] is the total value of the bills from index i onwards.
+	let mut remaining = vec![0u128; sorted.len() + 1];
+	for i in (0..sorted.len()).rev() {
+		remaining[i] = remaining[i + 1] + sorted[i].amount() as u128;
+	}
+
+	fn search(
+		bills: &[Bill],
+		remaining: &[u128],
+		index: usize,
+		missing: u128,
+		selected: &mut Vec<usize>,
+		tries: &mut usize,
+	) -> bool {
+		if missing == 0 {
+			return true
+		}
+		if index == bills.len() || remaining[index] < missing || *tries == 0 {
+			return false
+		}
+		*tries -= 1;
+
+		let amount = bills[index].amount() as u128;
+		if amount <= missing {
+			selected.push(index);
+			if search(bills, remaining, index + 1, missing - amount, selected, tries) {
+				return true
+			}
+			selected.pop();
+		}
+		search(bills, remaining, index + 1, missing, selected, tries)
+	}
+
+	let mut selected = Vec::new();
+	let mut tries = MAX_TRIES;
+	search(&sorted, &remaining, 0, target as u128, &mut selected, &mut tries)
+		.then(|| selected.into_iter().map(|i| sorted[i].clone()).collect())
+}
+
+/// Alice owns bills worth 50, 30, 20, and 5. Bob owns a bill worth 100.
+#[cfg(test)]
+fn wallet_state() -> State {
+	State::from([
+		Bill::new(User::Alice, 50, 0),
+		Bill::new(User::Alice, 30, 1),
+		Bill::new(User::Alice, 20, 2),
+		Bill::new(User::Alice, 5, 3),
+		Bill::new(User::Bob, 100, 4),
+	])
+}
+
+#[test]
+fn sm_12_largest_first() {
+	let state = wallet_state();
+	let wallet = Wallet::new(User::Alice, 7);
+
+	assert_eq!(
+		wallet.pay(&state, &[(User::Bob, 60)]),
+		Ok(CashTransaction::Transfer {
+			spends: vec![Bill::new(User::Alice, 50, 0), Bill::new(User::Alice, 30, 1)],
+			receives: vec![Bill::new(User::Bob, 60, 5), Bill::new(User::Alice, 20, 6)],
+		})
+	);
+}
+
+#[test]
+fn sm_12_branch_and_bound_avoids_change() {
+	let state = wallet_state();
+	let wallet = Wallet { strategy: Strategy::BranchAndBound, ..Wallet::new(User::Alice, 7) };
+
+	assert_eq!(
+		wallet.pay(&state, &[(User::Bob, 55)]),
+		Ok(CashTransaction::Transfer {
+			spends: vec![Bill::new(User::Alice, 50, 0), Bill::new(User::Alice, 5, 3)],
+			receives: vec![Bill::new(User::Bob, 55, 5)],
+		})
+	);
+}
+
+#[test]
+fn sm_12_branch_and_bound_falls_back() {
+	let state = wallet_state();
+	let wallet = Wallet { strategy: Strategy::BranchAndBound, ..Wallet::new(User::Alice, 7) };
+
+	assert_eq!(
+		wallet.pay(&state, &[(User::Bob, 54)]),
+		Wallet::new(User::Alice, 7).pay(&state, &[(User::Bob, 54)])
+	);
+}
+
+#[test]
+fn sm_12_privacy_preserving_spends_single_bill() {
+	let state = wallet_state();
+	let wallet = Wallet { strategy: Strategy::PrivacyPreserving, ..Wallet::new(User::Alice, 7) };
+
+	let Ok(CashTransaction::Transfer { spends, receives }) = wallet.pay(&state, &[(User::Bob, 25)])
+	else {
+		panic!("Alice can afford the payment")
+	};
+	assert_eq!(spends, vec![Bill::new(User::Alice, 30, 1)]);
+	assert_eq!(receives.len(), 2);
+	assert!(
+		receives.contains(&Bill::new(User::Bob, 25, 5)) ||
+			receives.contains(&Bill::new(User::Bob, 25, 6))
+	);
+}
+
+#[test]
+fn sm_12_change_position_depends_on_the_secret() {
+	let state = wallet_state();
+	let positions: Vec<_> = (0..32)
+		.map(|secret| {
+			let wallet = Wallet {
+				strategy: Strategy::PrivacyPreserving,
+				..Wallet::new(User::Alice, secret)
+			};
+			let Ok(CashTransaction::Transfer { receives, .. }) =
+				wallet.pay(&state, &[(User::Bob, 25)])
+			else {
+				panic!("Alice can afford the payment")
+			};
+			receives.iter().position(|bill| bill.owner() == User::Alice)
+		})
+		.collect();
+
+	assert!(positions.contains(&Some(0)));
+	assert!(positions.contains(&Some(1)));
+}
+
+#[test]
+fn sm_12_transfers_are_valid() {
+	use super::{p5_digital_cash::DigitalCashSystem, TryStateMachine};
+
+	let state = wallet_state();
+	let payments = [(User::Bob, 42), (User::Charlie, 17)];
+
+	for strategy in [Strategy::LargestFirst, Strategy::BranchAndBound, Strategy::PrivacyPreserving]
+	{
+		let wallet = Wallet { strategy, fee: 3, ..Wallet::new(User::Alice, 7) };
+		let transfer = wallet.pay(&state, &payments).unwrap();
+		let end = DigitalCashSystem::try_next_state(&state, &transfer).unwrap();
+
+		assert_eq!(wallet.balance(&end), wallet.balance(&state) - 42 - 17 - 3);
+	}
+}
+
+#[test]
+fn sm_12_insufficient_funds() {
+	let wallet = Wallet { fee: 1, ..Wallet::new(User::Alice, 7) };
+
+	assert_eq!(
+		wallet.pay(&wallet_state(), &[(User::Bob, 105)]),
+		Err(WalletError::InsufficientFunds { available: 105, required: 106 })
+	);
+}
+
+#[test]
+fn sm_12_invalid_payments() {
+	let wallet = Wallet::new(User::Alice, 7);
+
+	assert_eq!(wallet.pay(&wallet_state(), &[]), Err(WalletError::NoPayments));
+	assert_eq!(
+		wallet.pay(&wallet_state(), &[(User::Bob, 5), (User::Charlie, 0)]),
+		Err(WalletError::ZeroPayment { payee: User::Charlie })
+	);
+	assert_eq!(
+		wallet.pay(&wallet_state(), &[(User::Bob, u64::MAX), (User::Charlie, 1)]),
+		Err(WalletError::Overflow)
+	);
+}
@@ -0,0 +1,559 @@
+//! A digital cash transfer may spend bills from several users and create bills for several users.
+//! CoinJoin uses this to improve privacy. Several users combine their payments into a single
+//! transfer in which every payment has the same value, called the denomination. In a real
+//! CoinJoin the outputs are paid to fresh addresses, so an observer can see which bills went in
+//! and which came out, but can't tell which input paid for which output.
+//!
+//! Our digital cash has no addresses, and every bill names its owner in plain sight. The outputs
+//! of a CoinJoin here are therefore just as linkable as any other transfer: anybody can read who
+//! received each output, and the outputs even appear in the order of their owners. This module
+//! does not provide any actual privacy. It models the coordination protocol, which stays the same
+//! once outputs become unlinkable.
+//!
+//! Assembling such a transfer takes some coordination. In this module a coordinator runs the
+//! protocol in three phases:
+//!
+//! 1. Registration: every participant tells the coordinator which of their bills they want to spend
+//!    and how many outputs of the denomination they want. The remaining value comes back to them as
+//!    change.
+//! 2. Construction: the coordinator builds one transfer from all the valid registrations.
+//! 3. Signing: every participant checks that the transfer spends exactly the bills they registered
+//!    and pays them what they asked for. Only then do they sign it.
+//!
+//! Participants can misbehave, for example by disappearing before the signing phase. Rather than
+//! giving up, the coordinator excludes anybody who fails to sign and builds a new transfer without
+//! them, until everybody left has signed.
+
+use super::{
+	p5_digital_cash::{Bill, CashTransaction, State},
+	Signature, User,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// What a participant asks the coordinator for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Registration {
+	/// The bills the participant wants to spend
+	pub inputs: Vec<Bill>,
+	/// How many outputs of the denomination the participant wants
+	pub outputs: u64,
+}
+
+impl Registration {
+	/// The total value of the registered bills.
+	pub fn value(&self) -> u128 {
+		self.inputs.iter().map(|bill| bill.amount() as u128).sum()
+	}
+
+	/// Check that the given transfer treats the given user fairly. It must spend exactly the
+	/// user's registered bills, none of their other bills, and pay them the requested number of
+	/// outputs with the rest as change.
+	pub fn is_respected_by(
+		&self,
+		user: User,
+		transaction: &CashTransaction,
+		denomination: u64,
+	) -> bool {
+		let CashTransaction::Transfer { spends, receives } = transaction else { return false };
+
+		let registered: BTreeSet<_> = self.inputs.iter().map(Bill::serial).collect();
+		let spent: BTreeSet<_> =
+			spends.iter().filter(|bill| bill.owner() == user).map(Bill::serial).collect();
+		let all_registered_spent =
+			self.inputs.iter().all(|bill| spends.contains(bill)) && registered == spent;
+
+		let mut mine: Vec<_> =
+			receives.iter().filter(|bill| bill.owner() == user).map(Bill::amount).collect();
+		let mut expected = vec![denomination; self.outputs as usize];
+		let change = self.value().saturating_sub(denomination as u128 * self.outputs as u128);
+		if change > 0 {
+			let Ok(change) = u64::try_from(change) else { return false };
+			expected.push(change);
+		}
+		mine.sort();
+		expected.sort();
+
+		all_registered_spent && mine == expected
+	}
+}
+
+/// A user taking part in a CoinJoin. The coordinator talks to participants only through this
+/// trait, so misbehaving participants can be simulated.
+pub trait Participant {
+	/// The user this participant acts for.
+	fn user(&self) -> User;
+
+	/// Decide what to register for a CoinJoin with the given denomination.
+	fn register(&self, state: &State, denomination: u64) -> Registration;
+
+	/// Check the assembled transfer and sign it if it is acceptable.
+	fn authorize(&self, transaction: &CashTransaction, denomination: u64) -> Option<Signature>;
+}
+
+/// The ways a simulated participant can behave during signing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Behaviour {
+	/// Verify the transfer and sign it if it is acceptable.
+	Honest,
+	/// Register, but never sign anything.
+	Unresponsive,
+	/// Sign something other than the transfer.
+	BadSignature,
+}
+
+/// A participant that registers a fixed set of bills and behaves as instructed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SimulatedParticipant {
+	pub user: User,
+	pub registration: Registration,
+	pub behaviour: Behaviour,
+}
+
+impl SimulatedParticipant {
+	/// An honest participant who spends the given bills for the given number of outputs.
+	pub fn honest(user: User, inputs: Vec<Bill>, outputs: u64) -> Self {
+		SimulatedParticipant {
+			user,
+			registration: Registration { inputs, outputs },
+			behaviour: Behaviour::Honest,
+		}
+	}
+}
+
+impl Participant for SimulatedParticipant {
+	fn user(&self) -> User {
+		self.user
+	}
+
+	fn register(&self, _state: &State, _denomination: u64) -> Registration {
+		self.registration.clone()
+	}
+
+	fn authorize(&self, transaction: &CashTransaction, denomination: u64) -> Option<Signature> {
+		match self.behaviour {
+			Behaviour::Honest => self
+				.registration
+				.is_respected_by(self.user, transaction, denomination)
+				.then(|| self.user.sign(transaction)),
+			Behaviour::Unresponsive => None,
+			Behaviour::BadSignature => Some(self.user.sign(&denomination)),
+		}
+	}
+}
+
+/// The reasons a registration may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistrationError {
+	/// The user has already registered in this round.
+	AlreadyRegistered,
+	/// A participant must spend at least one bill.
+	NoInputs,
+	/// A participant must ask for at least one output of the denomination.
+	NoOutputs,
+	/// The participant asked for more outputs than the coordinator allows.
+	TooManyOutputs { requested: u64, max: u64 },
+	/// The bill does not belong to the participant.
+	NotOwner(Bill),
+	/// The bill is not in circulation.
+	UnknownBill(Bill),
+	/// The bill has already been registered, either by this participant or somebody else.
+	DuplicateInput(Bill),
+	/// The registered bills are not worth enough for the requested outputs.
+	InsufficientValue { available: u128, required: u128 },
+	/// The change left over after the requested outputs is too large for a single bill.
+	ChangeOverflow { change: u128 },
+}
+
+/// Why a participant was excluded from the CoinJoin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+	/// The registration was invalid.
+	InvalidRegistration(RegistrationError),
+	/// The participant did not sign the transfer.
+	RefusedToSign,
+	/// The participant's signature was not a valid signature of the transfer.
+	BadSignature,
+}
+
+/// The reasons a CoinJoin may fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoinJoinError {
+	/// The denomination must be non-zero.
+	ZeroDenomination,
+	/// Too few participants remained after excluding the misbehaving ones.
+	NotEnoughParticipants { required: usize, found: usize, excluded: Vec<(User, Misbehaviour)> },
+}
+
+/// The result of a successful CoinJoin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoinJoin {
+	/// The jointly assembled transfer
+	pub transaction: CashTransaction,
+	/// A signature of the transfer from every remaining participant
+	pub signatures: Vec<Signature>,
+	/// The participants that were excluded along the way, and why
+	pub excluded: Vec<(User, Misbehaviour)>,
+}
+
+/// A simulated CoinJoin coordinator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Coordinator {
+	/// The value of every equal output
+	pub denomination: u64,
+	/// The fewest participants worth mixing with. With only one participant, there is nobody to
+	/// hide among.
+	pub min_participants: usize,
+	/// The most outputs of the denomination a single participant may ask for. This keeps the
+	/// transfer a reasonable size, however small the denomination.
+	pub max_outputs: u64,
+}
+
+impl Coordinator {
+	/// Run the protocol with the given participants against the given state.
+	pub fn run(
+		&self,
+		state: &State,
+		participants: &[&dyn Participant],
+	) -> Result<CoinJoin, CoinJoinError> {
+		if self.denomination == 0 {
+			return Err(CoinJoinError::ZeroDenomination)
+		}
+
+		let mut excluded = Vec::new();
+		let mut registered: BTreeMap<User, (&dyn Participant, Registration)> = BTreeMap::new();
+		let mut inputs = BTreeSet::new();
+		for participant in participants {
+			let user = participant.user();
+			let registration = participant.register(state, self.denomination);
+			match self.check_registration(state, user, &registration, &registered, &inputs) {
+				Ok(()) => {
+					inputs.extend(registration.inputs.iter().map(Bill::serial));
+					registered.insert(user, (*participant, registration));
+				},
+				Err(e) => excluded.push((user, Misbehaviour::InvalidRegistration(e))),
+			}
+		}
+
+		loop {
+			if registered.len() < self.min_participants {
+				return Err(CoinJoinError::NotEnoughParticipants {
+					required: self.min_participants,
+					found: registered.len(),
+					excluded,
+				})
+			}
+
+			let transaction = self.construct(state, registered.values().map(|(_, r)| r));
+
+			let mut signatures = Vec::new();
+			let mut refused = Vec::new();
+			for (user, (participant, _)) in &registered {
+				match participant.authorize(&transaction, self.denomination) {
+					Some(signature) if signature.verify(*user, &transaction) =>
+						signatures.push(signature),
+					Some(_) => refused.push((*user, Misbehaviour::BadSignature)),
+					None => refused.push((*user, Misbehaviour::RefusedToSign)),
+				}
+			}
+
+			if refused.is_empty() {
+				return Ok(CoinJoin { transaction, signatures, excluded })
+			}
+			for (user, misbehaviour) in refused {
+				registered.remove(&user);
+				excluded.push((user, misbehaviour));
+			}
+		}
+	}
+
+	fn check_registration(
+		&self,
+		state: &State,
+		user: User,
+		registration: &Registration,
+		registered: &BTreeMap<User, (&dyn Participant, Registration)>,
+		inputs: &BTreeSet<u64>,
+	) -> Result<(), RegistrationError> {
+		if registered.contains_key(&user) {
+			return Err(RegistrationError::AlreadyRegistered)
+		}
+		if registration.inputs.is_empty() {
+			return Err(RegistrationError::NoInputs)
+		}
+		if registration.outputs == 0 {
+			return Err(RegistrationError::NoOutputs)
+		}
+		if registration.outputs > self.max_outputs {
+			return Err(RegistrationError::TooManyOutputs {
+				requested: registration.outputs,
+				max: self.max_outputs,
+			})
+		}
+
+		let mut seen = BTreeSet::new();
+		for bill in &registration.inputs {
+			if bill.owner() != user {
+				return Err(RegistrationError::NotOwner(bill.clone()))
+			}
+			if !state.bills().any(|b| b == bill) {
+				return Err(RegistrationError::UnknownBill(bill.clone()))
+			}
+			if inputs.contains(&bill.serial()) || !seen.insert(bill.serial()) {
+				return Err(RegistrationError::DuplicateInput(bill.clone()))
+			}
+		}
+
+		let available = registration.value();
+		let required = self.denomination as u128 * registration.outputs as u128;
+		if available < required {
+			return Err(RegistrationError::InsufficientValue { available, required })
+		}
+		if u64::try_from(available - required).is_err() {
+			return Err(RegistrationError::ChangeOverflow { change: available - required })
+		}
+
+		Ok(())
+	}
+
+	/// Build a single transfer from the given registrations. All the outputs of the denomination
+	/// come first so that they are indistinguishable, followed by the change outputs.
+	fn construct<'a>(
+		&self,
+		state: &State,
+		registrations: impl Iterator<Item = &'a Registration>,
+	) -> CashTransaction {
+		let mut spends = Vec::new();
+		let mut equal = Vec::new();
+		let mut change = Vec::new();
+		for registration in registrations {
+			let owner = registration.inputs[0].owner();
+			spends.extend(registration.inputs.iter().cloned());
+			for _ in 0..registration.outputs {
+				equal.push((owner, self.denomination));
+			}
+			// Registration checked that the inputs cover the outputs, and that the rest fits in a
+			// u64.
+			let rest = u64::try_from(
+				registration.value() - self.denomination as u128 * registration.outputs as u128,
+			)
+			.expect("registration checked that the change fits in a u64; qed");
+			if rest > 0 {
+				change.push((owner, rest));
+			}
+		}
+		spends.sort_by_key(Bill::serial);
+
+		let receives = equal
+			.into_iter()
+			.chain(change)
+			.zip(state.next_serial()..)
+			.map(|((owner, amount), serial)| Bill::new(owner, amount, serial))
+			.collect();
+
+		CashTransaction::Transfer { spends, receives }
+	}
+}
+
+/// Alice, Bob, and Charlie each own two bills.
+#[cfg(test)]
+fn coinjoin_state() -> State {
+	State::from([
+		Bill::new(User::Alice, 10, 0),
+		Bill::new(User::Alice, 7, 1),
+		Bill::new(User::Bob, 20, 2),
+		Bill::new(User::Bob, 5, 3),
+		Bill::new(User::Charlie, 10, 4),
+		Bill::new(User::Charlie, 1, 5),
+	])
+}
+
+#[cfg(test)]
+fn coordinator() -> Coordinator {
+	Coordinator { denomination: 10, min_participants: 2, max_outputs: 4 }
+}
+
+#[test]
+fn sm_13_honest_coinjoin() {
+	use super::{p5_digital_cash::DigitalCashSystem, TryStateMachine};
+
+	let state = coinjoin_state();
+	let alice = SimulatedParticipant::honest(User::Alice, vec![Bill::new(User::Alice, 10, 0)], 1);
+	let bob = SimulatedParticipant::honest(User::Bob, vec![Bill::new(User::Bob, 20, 2)], 1);
+	let charlie =
+		SimulatedParticipant::honest(User::Charlie, vec![Bill::new(User::Charlie, 10, 4)], 1);
+
+	let coinjoin = coordinator().run(&state, &[&alice, &bob, &charlie]).unwrap();
+
+	assert_eq!(
+		coinjoin.transaction,
+		CashTransaction::Transfer {
+			spends: vec![
+				Bill::new(User::Alice, 10, 0),
+				Bill::new(User::Bob, 20, 2),
+				Bill::new(User::Charlie, 10, 4),
+			],
+			receives: vec![
+				Bill::new(User::Alice, 10, 6),
+				Bill::new(User::Bob, 10, 7),
+				Bill::new(User::Charlie, 10, 8),
+				Bill::new(User::Bob, 10, 9),
+			],
+		}
+	);
+	assert_eq!(coinjoin.signatures.len(), 3);
+	assert!(coinjoin.excluded.is_empty());
+	assert!(DigitalCashSystem::try_next_state(&state, &coinjoin.transaction).is_ok());
+}
+
+#[test]
+fn sm_13_invalid_registrations_are_excluded() {
+	let state = coinjoin_state();
+	let alice = SimulatedParticipant::honest(User::Alice, vec![Bill::new(User::Alice, 10, 0)], 1);
+	let bob = SimulatedParticipant::honest(User::Bob, vec![Bill::new(User::Bob, 5, 3)], 1);
+	let charlie =
+		SimulatedParticipant::honest(User::Charlie, vec![Bill::new(User::Alice, 7, 1)], 1);
+
+	assert_eq!(
+		coordinator().run(&state, &[&alice, &bob, &charlie]),
+		Err(CoinJoinError::NotEnoughParticipants {
+			required: 2,
+			found: 1,
+			excluded: vec![
+				(
+					User::Bob,
+					Misbehaviour::InvalidRegistration(RegistrationError::InsufficientValue {
+						available: 5,
+						required: 10
+					})
+				),
+				(
+					User::Charlie,
+					Misbehaviour::InvalidRegistration(RegistrationError::NotOwner(Bill::new(
+						User::Alice,
+						7,
+						1
+					)))
+				),
+			],
+		})
+	);
+}
+
+#[test]
+fn sm_13_change_too_large_for_a_bill_is_excluded() {
+	let state = State::from([
+		Bill::new(User::Alice, u64::MAX, 0),
+		Bill::new(User::Alice, u64::MAX, 1),
+		Bill::new(User::Bob, 20, 2),
+	]);
+	let alice = SimulatedParticipant::honest(
+		User::Alice,
+		vec![Bill::new(User::Alice, u64::MAX, 0), Bill::new(User::Alice, u64::MAX, 1)],
+		1,
+	);
+	let bob = SimulatedParticipant::honest(User::Bob, vec![Bill::new(User::Bob, 20, 2)], 1);
+
+	assert_eq!(
+		coordinator().run(&state, &[&alice, &bob]),
+		Err(CoinJoinError::NotEnoughParticipants {
+			required: 2,
+			found: 1,
+			excluded: vec![(
+				User::Alice,
+				Misbehaviour::InvalidRegistration(RegistrationError::ChangeOverflow {
+					change: 2 * u64::MAX as u128 - 10
+				})
+			)],
+		})
+	);
+}
+
+#[test]
+fn sm_13_too_many_outputs_are_excluded() {
+	let state = State::from([
+		Bill::new(User::Alice, u64::MAX, 0),
+		Bill::new(User::Alice, u64::MAX, 1),
+		Bill::new(User::Bob, 20, 2),
+	]);
+	let alice = SimulatedParticipant::honest(
+		User::Alice,
+		vec![Bill::new(User::Alice, u64::MAX, 0), Bill::new(User::Alice, u64::MAX, 1)],
+		u64::MAX,
+	);
+	let bob = SimulatedParticipant::honest(User::Bob, vec![Bill::new(User::Bob, 20, 2)], 1);
+	let coordinator = Coordinator { denomination: 1, ..coordinator() };
+
+	assert_eq!(
+		coordinator.run(&state, &[&alice, &bob]),
+		Err(CoinJoinError::NotEnoughParticipants {
+			required: 2,
+			found: 1,
+			excluded: vec![(
+				User::Alice,
+				Misbehaviour::InvalidRegistration(RegistrationError::TooManyOutputs {
+					requested: u64::MAX,
+					max: 4
+				})
+			)],
+		})
+	);
+}
+
+#[test]
+fn sm_13_non_signers_are_excluded() {
+	let state = coinjoin_state();
+	let alice = SimulatedParticipant::honest(User::Alice, vec![Bill::new(User::Alice, 10, 0)], 1);
+	let bob = SimulatedParticipant {
+		behaviour: Behaviour::Unresponsive,
+		..SimulatedParticipant::honest(User::Bob, vec![Bill::new(User::Bob, 20, 2)], 2)
+	};
+	let charlie = SimulatedParticipant {
+		behaviour: Behaviour::BadSignature,
+		..SimulatedParticipant::honest(User::Charlie, vec![Bill::new(User::Charlie, 10, 4)], 1)
+	};
+
+	assert_eq!(
+		coordinator().run(&state, &[&alice, &bob, &charlie]),
+		Err(CoinJoinError::NotEnoughParticipants {
+			required: 2,
+			found: 1,
+			excluded: vec![
+				(User::Bob, Misbehaviour::RefusedToSign),
+				(User::Charlie, Misbehaviour::BadSignature)
+			],
+		})
+	);
+
+	let coinjoin = Coordinator { min_participants: 1, ..coordinator() }
+		.run(&state, &[&alice, &bob, &charlie])
+		.unwrap();
+	assert_eq!(
+		coinjoin.transaction,
+		CashTransaction::Transfer {
+			spends: vec![Bill::new(User::Alice, 10, 0)],
+			receives: vec![Bill::new(User::Alice, 10, 6)],
+		}
+	);
+	assert_eq!(coinjoin.signatures, vec![User::Alice.sign(&coinjoin.transaction)]);
+}
+
+#[test]
+fn sm_13_participant_rejects_unfair_transfer() {
+	let alice = Registration { inputs: vec![Bill::new(User::Alice, 17, 0)], outputs: 1 };
+	let fair = CashTransaction::Transfer {
+		spends: vec![Bill::new(User::Alice, 17, 0)],
+		receives: vec![Bill::new(User::Alice, 10, 1), Bill::new(User::Alice, 7, 2)],
+	};
+	let short_change = CashTransaction::Transfer {
+		spends: vec![Bill::new(User::Alice, 17, 0)],
+		receives: vec![Bill::new(User::Alice, 10, 1), Bill::new(User::Bob, 7, 2)],
+	};
+	let extra_input = CashTransaction::Transfer {
+		spends: vec![Bill::new(User::Alice, 17, 0), Bill::new(User::Alice, 3, 1)],
+		receives: vec![Bill::new(User::Alice, 10, 2), Bill::new(User::Alice, 7, 3)],
+	};
+
+	assert!(alice.is_respected_by(User::Alice, &fair, 10));
+	assert!(!alice.is_respected_by(User::Alice, &short_change, 10));
+	assert!(!alice.is_respected_by(User::Alice, &extra_input, 10));
+}
@@ -0,0 +1,495 @@
+//! The digital cash system from part 5 is modelled after paper cash, but unlike paper cash, every
+//! bill names its owner and every transfer is public. Anybody can follow the money.
+//!
+//! In 1982 David Chaum proposed e-cash that is as private as paper cash. A mint keeps ordinary
+//! accounts. When a user withdraws, the mint signs a token for them and debits their account. Later
+//! anybody holding the token can redeem it, and the mint credits their account. The trick is that
+//! the mint signs the token blindly. The user disguises the token with a random blinding factor
+//! before sending it to the mint, and removes the disguise from the signature afterwards. The
+//! result is a valid signature on a token that the mint has never seen. So when the token is
+//! redeemed, the mint knows it is genuine, but can't tell which withdrawal it came from.
+//!
+//! Since the mint never learns who holds which token, it can't check ownership on redemption.
+//! Instead, every token has a unique serial number and the mint remembers the serials of all the
+//! tokens that were redeemed. A token whose serial was already redeemed is a double spend.
+//!
+//! The ledger is public, so the mint's secret key can't be part of its state. Withdrawal happens in
+//! two steps instead. The user pays for a blinded message, which the ledger records as a request.
+//! The mint signs the request with its secret key outside of the state machine, and publishes the
+//! blind signature in a separate transaction. The ledger only needs the public key to check it.
+//!
+//! The mint signs with textbook RSA. The keys here are tiny so that all arithmetic fits in
+//! machine integers, which makes them easy to break. Don't use them for anything real.
+
+use super::{StateMachine, TryStateMachine, User};
+use crate::hash;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+};
+
+/// Multiply two numbers modulo `n` without overflowing.
+fn mul_mod(a: u64, b: u64, n: u64) -> u64 {
+	((a as u128 * b as u128) % n as u128) as u64
+}
+
+/// Raise `base` to the power `exponent` modulo `n` by repeated squaring.
+fn pow_mod(mut base: u64, mut exponent: u64, n: u64) -> u64 {
+	let mut result = 1 % n;
+	base %= n;
+	while exponent > 0 {
+		if exponent & 1 == 1 {
+			result = mul_mod(result, base, n);
+		}
+		base = mul_mod(base, base, n);
+		exponent >>= 1;
+	}
+	result
+}
+
+/// The multiplicative inverse of `a` modulo `n`, if there is one. This is the extended Euclidean
+/// algorithm.
+fn inverse_mod(a: u64, n: u64) -> Option<u64> {
+	let (mut old_r, mut r) = (a as i128, n as i128);
+	let (mut old_s, mut s) = (1i128, 0i128);
+	while r != 0 {
+		let quotient = old_r / r;
+		(old_r, r) = (r, old_r - quotient * r);
+		(old_s, s) = (s, old_s - quotient * s);
+	}
+	(old_r == 1).then(|| old_s.rem_euclid(n as i128) as u64)
+}
+
+/// The message that is signed for a token with the given serial.
+fn message(serial: u64, n: u64) -> u64 {
+	hash(&serial) % n
+}
+
+/// The public half of an RSA key. Anybody can use it to verify the mint's signatures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey {
+	/// The modulus, which is the product of two secret primes
+	pub n: u64,
+	/// The public exponent
+	pub e: u64,
+}
+
+impl PublicKey {
+	/// Check that the token carries the mint's signature.
+	pub fn verify(&self, token: &Token) -> bool {
+		token.signature < self.n &&
+			pow_mod(token.signature, self.e, self.n) == message(token.serial, self.n)
+	}
+}
+
+/// An RSA key pair. Only the mint knows the secret exponent, so it is kept out of the ledger and
+/// out of debug output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KeyPair {
+	pub public: PublicKey,
+	/// The secret exponent
+	d: u64,
+}
+
+impl KeyPair {
+	/// Derive a key pair from two distinct primes and a public exponent. The primes must be small
+	/// enough that their product fits in a `u64`. Returns `None` if the primes are not at least 2
+	/// and distinct, or if the exponent has no inverse, in which case nobody could sign with it.
+	pub fn new(p: u64, q: u64, e: u64) -> Option<Self> {
+		if p < 2 || q < 2 || p == q {
+			return None
+		}
+		let n = p.checked_mul(q)?;
+		let phi = (p - 1).checked_mul(q - 1)?;
+		let d = inverse_mod(e, phi)?;
+		Some(KeyPair { public: PublicKey { n, e }, d })
+	}
+
+	/// A fixed key pair built from the two largest primes below 2^31.
+	pub fn toy() -> Self {
+		KeyPair::new(2_147_483_647, 2_147_483_629, 65_537)
+			.expect("65537 is coprime to (p - 1)(q - 1) for these primes; qed")
+	}
+
+	/// Sign a message. The mint has no idea what the message means.
+	fn sign(&self, message: u64) -> u64 {
+		pow_mod(message, self.d, self.public.n)
+	}
+
+	/// Answer a withdrawal request by signing the blinded message.
+	pub fn issue(&self, blinded: u64) -> EcashTransaction {
+		EcashTransaction::Issue { blinded, blind_signature: self.sign(blinded) }
+	}
+}
+
+impl fmt::Debug for KeyPair {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("KeyPair").field("public", &self.public).finish_non_exhaustive()
+	}
+}
+
+/// A token that can be redeemed at the mint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token {
+	/// A unique serial, chosen by the user at random
+	pub serial: u64,
+	/// The mint's signature of the serial
+	pub signature: u64,
+}
+
+/// A token that the user has disguised before asking the mint to sign it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlindedToken {
+	/// The serial of the eventual token. Kept secret by the user.
+	pub serial: u64,
+	/// The random factor that disguises the serial. Kept secret by the user.
+	pub blinding: u64,
+	/// What the user sends to the mint
+	pub blinded: u64,
+}
+
+impl BlindedToken {
+	/// Disguise a token with the given serial using the given blinding factor. Returns `None` if
+	/// the blinding factor can't be removed again, which, for a random factor, is very unlikely.
+	pub fn new(key: &PublicKey, serial: u64, blinding: u64) -> Option<Self> {
+		let blinding = blinding % key.n;
+		inverse_mod(blinding, key.n)?;
+		let blinded = mul_mod(message(serial, key.n), pow_mod(blinding, key.e, key.n), key.n);
+		Some(BlindedToken { serial, blinding, blinded })
+	}
+
+	/// Remove the blinding factor from the mint's signature, leaving a signature of the token
+	/// itself. Returns `None` if the blind signature is not valid.
+	pub fn unblind(&self, key: &PublicKey, blind_signature: u64) -> Option<Token> {
+		let inverse = inverse_mod(self.blinding, key.n)?;
+		let token =
+			Token { serial: self.serial, signature: mul_mod(blind_signature, inverse, key.n) };
+		key.verify(&token).then_some(token)
+	}
+}
+
+/// This state machine models the ledger of a Chaumian e-cash mint.
+pub struct ChaumianMint;
+
+/// The state of the mint.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MintState {
+	/// The mint's public key, which every signature is checked against
+	pub key: PublicKey,
+	/// The value of every token
+	pub denomination: u64,
+	/// Account balances, which are debited on withdrawal and credited on redemption
+	pub balances: BTreeMap<User, u64>,
+	/// The blinded messages that were paid for, but not signed by the mint yet
+	pub requests: BTreeSet<u64>,
+	/// The signatures issued so far, by blinded message. Users collect their signature from here.
+	pub blind_signatures: BTreeMap<u64, u64>,
+	/// The serials of all redeemed tokens
+	pub spent: BTreeSet<u64>,
+}
+
+impl MintState {
+	/// A mint with no accounts and no tokens.
+	pub fn new(key: PublicKey, denomination: u64) -> Self {
+		MintState {
+			key,
+			denomination,
+			balances: BTreeMap::new(),
+			requests: BTreeSet::new(),
+			blind_signatures: BTreeMap::new(),
+			spent: BTreeSet::new(),
+		}
+	}
+
+	/// The number of tokens that were withdrawn but not redeemed yet.
+	pub fn outstanding(&self) -> usize {
+		self.blind_signatures.len().saturating_sub(self.spent.len())
+	}
+}
+
+/// The state transitions that users can make at the mint
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EcashTransaction {
+	/// Create some new money in the given account
+	Mint { minter: User, amount: u64 },
+	/// Pay one denomination from the account for a blind signature of the message
+	Withdraw { user: User, blinded: u64 },
+	/// The mint's answer to a withdrawal request, signed with its secret key
+	Issue { blinded: u64, blind_signature: u64 },
+	/// Redeem a token and credit its value to the receiver's account
+	Redeem { receiver: User, token: Token },
+}
+
+/// The reasons an e-cash transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EcashError {
+	/// Minting zero is pointless.
+	ZeroAmount,
+	/// The account can't pay for the withdrawal.
+	InsufficientBalance { balance: u64, requested: u64 },
+	/// The blinded message is not a number the mint can sign.
+	InvalidMessage,
+	/// The mint was already asked to sign this exact message. Signing it again would let the user
+	/// pay once for two identical tokens.
+	AlreadyRequested,
+	/// Nobody paid for a signature of this message.
+	UnknownRequest,
+	/// The blind signature was not made with the mint's key.
+	InvalidBlindSignature,
+	/// The token does not carry the mint's signature.
+	InvalidSignature,
+	/// A token with this serial was already redeemed.
+	DoubleSpend(u64),
+	/// The receiving balance would exceed `u64::MAX`.
+	Overflow,
+}
+
+impl StateMachine for ChaumianMint {
+	type State = MintState;
+	type Transition = EcashTransaction;
+
+	fn next_state(starting_state: &MintState, t: &EcashTransaction) -> MintState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Chaumian E-Cash".into()
+	}
+}
+
+impl TryStateMachine for ChaumianMint {
+	type Error = EcashError;
+
+	fn try_next_state(
+		starting_state: &MintState,
+		t: &EcashTransaction,
+	) -> Result<MintState, EcashError> {
+		let mut state = starting_state.clone();
+
+		match *t {
+			EcashTransaction::Mint { minter, amount } => {
+				if amount == 0 {
+					return Err(EcashError::ZeroAmount)
+				}
+				let balance = state.balances.entry(minter).or_insert(0);
+				*balance = balance.checked_add(amount).ok_or(EcashError::Overflow)?;
+			},
+			EcashTransaction::Withdraw { user, blinded } => {
+				if blinded == 0 || blinded >= state.key.n {
+					return Err(EcashError::InvalidMessage)
+				}
+				if state.requests.contains(&blinded) ||
+					state.blind_signatures.contains_key(&blinded)
+				{
+					return Err(EcashError::AlreadyRequested)
+				}
+				let balance = state.balances.get(&user).copied().unwrap_or(0);
+				if balance < state.denomination {
+					return Err(EcashError::InsufficientBalance {
+						balance,
+						requested: state.denomination,
+					})
+				}
+				if balance == state.denomination {
+					state.balances.remove(&user);
+				} else {
+					state.balances.insert(user, balance - state.denomination);
+				}
+				state.requests.insert(blinded);
+			},
+			EcashTransaction::Issue { blinded, blind_signature } => {
+				if !state.requests.contains(&blinded) {
+					return Err(EcashError::UnknownRequest)
+				}
+				if blind_signature >= state.key.n ||
+					pow_mod(blind_signature, state.key.e, state.key.n) != blinded
+				{
+					return Err(EcashError::InvalidBlindSignature)
+				}
+				state.requests.remove(&blinded);
+				state.blind_signatures.insert(blinded, blind_signature);
+			},
+			EcashTransaction::Redeem { receiver, token } => {
+				if !state.key.verify(&token) {
+					return Err(EcashError::InvalidSignature)
+				}
+				if !state.spent.insert(token.serial) {
+					return Err(EcashError::DoubleSpend(token.serial))
+				}
+				let balance = state.balances.entry(receiver).or_insert(0);
+				*balance = balance.checked_add(state.denomination).ok_or(EcashError::Overflow)?;
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// A mint for tokens worth 10, where Alice has 30 in her account.
+#[cfg(test)]
+fn funded_mint() -> MintState {
+	let mut state = MintState::new(KeyPair::toy().public, 10);
+	state.balances.insert(User::Alice, 30);
+	state
+}
+
+/// Withdraw a token with the given serial and blinding factor as Alice, and have the mint sign it.
+#[cfg(test)]
+fn withdraw(state: &MintState, serial: u64, blinding: u64) -> (MintState, Token) {
+	let key = state.key;
+	let blinded = BlindedToken::new(&key, serial, blinding).unwrap();
+	let withdrawal = EcashTransaction::Withdraw { user: User::Alice, blinded: blinded.blinded };
+	let state = ChaumianMint::try_next_state(state, &withdrawal).unwrap();
+	let issue = KeyPair::toy().issue(blinded.blinded);
+	let state = ChaumianMint::try_next_state(&state, &issue).unwrap();
+	let token = blinded.unblind(&key, state.blind_signatures[&blinded.blinded]).unwrap();
+	(state, token)
+}
+
+#[test]
+fn sm_14_rsa_round_trip() {
+	let key = KeyPair::toy();
+	let message = 123_456_789;
+
+	assert_eq!(pow_mod(key.sign(message), key.public.e, key.public.n), message);
+	assert_eq!(inverse_mod(3, 7), Some(5));
+	assert_eq!(inverse_mod(6, 9), None);
+}
+
+#[test]
+fn sm_14_key_pair_needs_two_distinct_primes() {
+	assert_eq!(KeyPair::new(0, 7, 5), None);
+	assert_eq!(KeyPair::new(11, 1, 3), None);
+	assert_eq!(KeyPair::new(11, 11, 3), None);
+	assert!(KeyPair::new(11, 7, 7).is_some());
+}
+
+#[test]
+fn sm_14_secret_exponent_is_not_printed() {
+	let key = KeyPair::toy();
+
+	assert!(!format!("{key:?}").contains(&key.d.to_string()));
+}
+
+#[test]
+fn sm_14_only_the_mint_can_issue() {
+	let key = KeyPair::toy().public;
+	let blinded = BlindedToken::new(&key, 42, 987_654_321).unwrap().blinded;
+	let withdrawal = EcashTransaction::Withdraw { user: User::Alice, blinded };
+	let requested = ChaumianMint::try_next_state(&funded_mint(), &withdrawal).unwrap();
+
+	assert_eq!(requested.balances[&User::Alice], 20);
+	assert_eq!(requested.requests, BTreeSet::from([blinded]));
+
+	let forged = EcashTransaction::Issue { blinded, blind_signature: blinded };
+	assert_eq!(
+		ChaumianMint::try_next_state(&requested, &forged),
+		Err(EcashError::InvalidBlindSignature)
+	);
+
+	let other_mint = KeyPair::new(2_147_483_587, 2_147_483_579, 65_537).unwrap();
+	assert_eq!(
+		ChaumianMint::try_next_state(&requested, &other_mint.issue(blinded)),
+		Err(EcashError::InvalidBlindSignature)
+	);
+
+	let issued = ChaumianMint::try_next_state(&requested, &KeyPair::toy().issue(blinded)).unwrap();
+	assert!(issued.requests.is_empty());
+	assert_eq!(issued.outstanding(), 1);
+}
+
+#[test]
+fn sm_14_issue_needs_a_paid_request() {
+	let key = KeyPair::toy();
+	let blinded = BlindedToken::new(&key.public, 42, 987_654_321).unwrap().blinded;
+
+	assert_eq!(
+		ChaumianMint::try_next_state(&funded_mint(), &key.issue(blinded)),
+		Err(EcashError::UnknownRequest)
+	);
+}
+
+#[test]
+fn sm_14_message_cannot_be_requested_twice() {
+	let key = KeyPair::toy().public;
+	let blinded = BlindedToken::new(&key, 42, 987_654_321).unwrap().blinded;
+	let withdrawal = EcashTransaction::Withdraw { user: User::Alice, blinded };
+	let requested = ChaumianMint::try_next_state(&funded_mint(), &withdrawal).unwrap();
+
+	assert_eq!(
+		ChaumianMint::try_next_state(&requested, &withdrawal),
+		Err(EcashError::AlreadyRequested)
+	);
+}
+
+#[test]
+fn sm_14_withdraw_and_redeem() {
+	let (state, token) = withdraw(&funded_mint(), 42, 987_654_321);
+
+	assert_eq!(state.balances[&User::Alice], 20);
+	assert_eq!(state.outstanding(), 1);
+
+	let redeem = EcashTransaction::Redeem { receiver: User::Bob, token };
+	let state = ChaumianMint::try_next_state(&state, &redeem).unwrap();
+
+	assert_eq!(state.balances, BTreeMap::from([(User::Alice, 20), (User::Bob, 10)]));
+	assert_eq!(state.outstanding(), 0);
+}
+
+#[test]
+fn sm_14_double_spend_detected() {
+	let (state, token) = withdraw(&funded_mint(), 42, 987_654_321);
+	let redeem = EcashTransaction::Redeem { receiver: User::Bob, token };
+	let state = ChaumianMint::try_next_state(&state, &redeem).unwrap();
+
+	let redeem_again = EcashTransaction::Redeem { receiver: User::Charlie, token };
+	assert_eq!(
+		ChaumianMint::try_next_state(&state, &redeem_again),
+		Err(EcashError::DoubleSpend(42))
+	);
+}
+
+#[test]
+fn sm_14_forged_token_rejected() {
+	let state = funded_mint();
+	let forged = Token { serial: 42, signature: 12345 };
+	let redeem = EcashTransaction::Redeem { receiver: User::Bob, token: forged };
+
+	assert_eq!(ChaumianMint::try_next_state(&state, &redeem), Err(EcashError::InvalidSignature));
+}
+
+#[test]
+fn sm_14_insufficient_balance() {
+	let key = KeyPair::toy().public;
+	let blinded = BlindedToken::new(&key, 1, 2).unwrap().blinded;
+	let withdrawal = EcashTransaction::Withdraw { user: User::Bob, blinded };
+
+	assert_eq!(
+		ChaumianMint::try_next_state(&funded_mint(), &withdrawal),
+		Err(EcashError::InsufficientBalance { balance: 0, requested: 10 })
+	);
+}
+
+#[test]
+fn sm_14_mint_cannot_link_redemptions() {
+	let (state, first) = withdraw(&funded_mint(), 42, 987_654_321);
+	let (state, second) = withdraw(&state, 43, 123_456_789);
+	let key = state.key;
+
+	// The mint saw neither the serials nor the signatures during withdrawal.
+	for (blinded, blind_signature) in &state.blind_signatures {
+		assert!(![first.serial, second.serial, first.signature, second.signature]
+			.iter()
+			.any(|x| x == blinded || x == blind_signature));
+	}
+
+	// Worse for the mint, each token could have come from either withdrawal. For every pair there
+	// is a blinding factor that explains it perfectly.
+	for token in [first, second] {
+		for (blinded, blind_signature) in &state.blind_signatures {
+			let inverse = inverse_mod(token.signature, key.n).unwrap();
+			let blinding = mul_mod(*blind_signature, inverse, key.n);
+			assert_eq!(BlindedToken::new(&key, token.serial, blinding).unwrap().blinded, *blinded);
+		}
+	}
+}
@@ -0,0 +1,556 @@
+//! The digital cash system from part 5 publishes the amount of every bill. In this module we hide
+//! the amounts, while still making sure that nobody can create money out of thin air.
+//!
+//! Each bill carries a Pedersen commitment to its amount instead of the amount itself. To commit to
+//! an amount `v`, the owner picks a random blinding factor `r` and publishes `g^v * h^r`. The
+//! blinding factor hides the amount completely, but the owner can't later claim that the
+//! commitment holds a different amount. Better still, multiplying commitments adds the amounts
+//! inside them. So the validator can divide the commitments of the spent bills by those of the new
+//! bills and the fee. If the amounts balance, all that is left is `h^x` for some `x` that only the
+//! sender knows. The sender proves they know `x` with a Schnorr signature.
+//!
+//! There is a catch. Amounts live in a finite group, so a "negative" amount is just a very large
+//! one. A sender could create one bill worth a lot and another worth minus a lot. To rule this out,
+//! every new bill carries a range proof showing that its amount fits in `RANGE_BITS` bits. The
+//! range proof commits to each bit of the amount separately and proves that each of those
+//! commitments holds either zero or one, without revealing which.
+//!
+//! The group here is the subgroup of squares modulo a safe prime just below 2^62. It is far too
+//! small to be secure, and the proofs are the simplest textbook ones, but the validation pipeline
+//! is the same as in real confidential transactions.
+//!
+//! Blinding factors and proof nonces must be unpredictable, or the amounts leak. Everything that
+//! needs them takes a source of random numbers from the caller, which should be a cryptographically
+//! secure generator in anything real.
+
+use super::{StateMachine, TryStateMachine, User};
+use crate::hash;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The modulus of the group. It is a safe prime, which means `(P - 1) / 2` is prime as well.
+pub const P: u64 = 4_611_686_018_427_377_339;
+
+/// The order of the group of squares modulo `P`. Exponents, including amounts and blinding
+/// factors, are taken modulo `Q`.
+pub const Q: u64 = (P - 1) / 2;
+
+/// Amounts must fit in this many bits. Summing many such amounts still doesn't come anywhere near
+/// `Q`, so sums can't wrap around.
+pub const RANGE_BITS: u32 = 32;
+
+fn mul(a: u64, b: u64, modulus: u64) -> u64 {
+	((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+fn add(a: u64, b: u64, modulus: u64) -> u64 {
+	((a as u128 + b as u128) % modulus as u128) as u64
+}
+
+fn sub(a: u64, b: u64, modulus: u64) -> u64 {
+	add(a, modulus - b % modulus, modulus)
+}
+
+fn pow(mut base: u64, mut exponent: u64, modulus: u64) -> u64 {
+	let mut result = 1;
+	base %= modulus;
+	while exponent > 0 {
+		if exponent & 1 == 1 {
+			result = mul(result, base, modulus);
+		}
+		base = mul(base, base, modulus);
+		exponent >>= 1;
+	}
+	result
+}
+
+/// The inverse of a non-zero number modulo a prime, by Fermat's little theorem.
+fn inverse(a: u64, modulus: u64) -> u64 {
+	pow(a, modulus - 2, modulus)
+}
+
+/// The generator that amounts are committed with.
+fn g() -> u64 {
+	4
+}
+
+/// The generator that blinding factors are committed with. It is derived from a hash so that
+/// nobody knows its discrete logarithm with respect to `g`.
+fn h() -> u64 {
+	pow(hash(&"confidential cash h") % P, 2, P)
+}
+
+/// A random exponent.
+fn random_scalar(random: &mut impl FnMut() -> u64) -> u64 {
+	random() % Q
+}
+
+/// A Fiat-Shamir challenge derived from everything the prover has committed to.
+fn challenge<T: std::hash::Hash>(transcript: &T) -> u64 {
+	hash(transcript) % Q
+}
+
+/// A commitment to a hidden amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Commitment(pub u64);
+
+impl Commitment {
+	/// Commit to the given amount with the given blinding factor.
+	pub fn new(value: u64, blinding: u64) -> Self {
+		Commitment(mul(pow(g(), value % Q, P), pow(h(), blinding % Q, P), P))
+	}
+
+	/// A commitment to the sum of the amounts in both commitments.
+	pub fn add(&self, other: &Commitment) -> Commitment {
+		Commitment(mul(self.0, other.0, P))
+	}
+
+	/// A commitment to the difference of the amounts in both commitments.
+	pub fn sub(&self, other: &Commitment) -> Commitment {
+		Commitment(mul(self.0, inverse(other.0, P), P))
+	}
+}
+
+/// Everything needed to open a commitment. Only the owner of a bill knows this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Opening {
+	pub value: u64,
+	pub blinding: u64,
+}
+
+impl Opening {
+	/// An opening of the given amount with a random blinding factor.
+	pub fn random(random: &mut impl FnMut() -> u64, value: u64) -> Self {
+		Opening { value, blinding: random_scalar(random) }
+	}
+
+	/// The commitment that this opens.
+	pub fn commit(&self) -> Commitment {
+		Commitment::new(self.value, self.blinding)
+	}
+}
+
+/// A Schnorr proof of knowledge of `x` such that some public value equals `h^x`. The proof is tied
+/// to a message, so it can't be reused for another transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SchnorrProof {
+	pub nonce: u64,
+	pub response: u64,
+}
+
+impl SchnorrProof {
+	/// Prove knowledge of `x` for the given message.
+	pub fn prove(random: &mut impl FnMut() -> u64, x: u64, message: u64) -> Self {
+		let k = random_scalar(random);
+		let nonce = pow(h(), k, P);
+		let c = challenge(&(pow(h(), x, P), nonce, message));
+		SchnorrProof { nonce, response: add(k, mul(c, x, Q), Q) }
+	}
+
+	/// Check that the prover knows the discrete logarithm of `public` with respect to `h`.
+	pub fn verify(&self, public: u64, message: u64) -> bool {
+		let c = challenge(&(public, self.nonce, message));
+		pow(h(), self.response, P) == mul(self.nonce, pow(public, c, P), P)
+	}
+}
+
+/// A proof that a commitment holds either zero or one. It proves that either the commitment or the
+/// commitment divided by `g` is a power of `h`, without revealing which.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BitProof {
+	pub commitment: Commitment,
+	pub nonces: [u64; 2],
+	pub challenges: [u64; 2],
+	pub responses: [u64; 2],
+}
+
+impl BitProof {
+	fn prove(random: &mut impl FnMut() -> u64, bit: bool, blinding: u64) -> Self {
+		let commitment = Commitment::new(bit as u64, blinding);
+		let publics = Self::publics(&commitment);
+		let (real, fake) = if bit { (1, 0) } else { (0, 1) };
+
+		// Simulate the branch we can't prove, by picking its challenge and response first.
+		let mut challenges = [0; 2];
+		let mut responses = [0; 2];
+		let mut nonces = [0; 2];
+		challenges[fake] = random_scalar(random);
+		responses[fake] = random_scalar(random);
+		nonces[fake] = mul(
+			pow(h(), responses[fake], P),
+			inverse(pow(publics[fake], challenges[fake], P), P),
+			P,
+		);
+
+		// Prove the real branch honestly, with whatever challenge is left over.
+		let k = random_scalar(random);
+		nonces[real] = pow(h(), k, P);
+		let c = challenge(&(commitment, nonces));
+		challenges[real] = sub(c, challenges[fake], Q);
+		responses[real] = add(k, mul(challenges[real], blinding, Q), Q);
+
+		BitProof { commitment, nonces, challenges, responses }
+	}
+
+	fn publics(commitment: &Commitment) -> [u64; 2] {
+		[commitment.0, commitment.sub(&Commitment::new(1, 0)).0]
+	}
+
+	fn verify(&self) -> bool {
+		let publics = Self::publics(&self.commitment);
+		let c = challenge(&(self.commitment, self.nonces));
+
+		add(self.challenges[0], self.challenges[1], Q) == c &&
+			(0..2).all(|i| {
+				pow(h(), self.responses[i], P) ==
+					mul(self.nonces[i], pow(publics[i], self.challenges[i], P), P)
+			})
+	}
+}
+
+/// A proof that a commitment holds an amount below `2^RANGE_BITS`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RangeProof {
+	/// A proof for every bit of the amount, least significant first
+	pub bits: Vec<BitProof>,
+}
+
+impl RangeProof {
+	/// Prove that the opened commitment is in range. If it isn't, the proof won't verify.
+	pub fn prove(random: &mut impl FnMut() -> u64, opening: &Opening) -> Self {
+		// The bit commitments, weighted by powers of two, must multiply to the full commitment. So
+		// their blinding factors, weighted the same way, must add up to its blinding factor.
+		let mut blindings: Vec<u64> = (1..RANGE_BITS).map(|_| random_scalar(random)).collect();
+		let covered = blindings
+			.iter()
+			.enumerate()
+			.fold(0, |acc, (i, r)| add(acc, mul(*r, pow(2, i as u64, Q), Q), Q));
+		let last_weight = pow(2, RANGE_BITS as u64 - 1, Q);
+		blindings.push(mul(sub(opening.blinding, covered, Q), inverse(last_weight, Q), Q));
+
+		let bits = blindings
+			.into_iter()
+			.enumerate()
+			.map(|(i, r)| BitProof::prove(random, (opening.value >> i) & 1 == 1, r))
+			.collect();
+		RangeProof { bits }
+	}
+
+	/// Check that the commitment holds an amount below `2^RANGE_BITS`.
+	pub fn verify(&self, commitment: &Commitment) -> bool {
+		let combined = self.bits.iter().enumerate().fold(Commitment(1), |acc, (i, bit)| {
+			acc.add(&Commitment(pow(bit.commitment.0, 1 << i, P)))
+		});
+
+		self.bits.len() == RANGE_BITS as usize &&
+			self.bits.iter().all(BitProof::verify) &&
+			combined == *commitment
+	}
+}
+
+/// A new bill to be created by a transfer
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConfidentialOutput {
+	pub owner: User,
+	pub commitment: Commitment,
+	pub range_proof: RangeProof,
+}
+
+/// A bill in circulation. Its amount is hidden inside the commitment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConfidentialBill {
+	pub owner: User,
+	pub commitment: Commitment,
+}
+
+/// This state machine models a digital cash system with hidden amounts.
+pub struct ConfidentialCash;
+
+/// The bills in circulation, by serial, and the serial of the next bill to be created.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ConfidentialState {
+	pub bills: BTreeMap<u64, ConfidentialBill>,
+	pub next_serial: u64,
+}
+
+/// The state transitions that users can make in the confidential cash system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConfidentialTransaction {
+	/// Mint a single new bill. The minted amount is public, so the bill is committed with a
+	/// blinding factor of zero.
+	Mint { minter: User, amount: u64 },
+	/// Spend some bills, identified by serial, and create new ones. The fee is public, and is
+	/// destroyed just like the discrepancy in part 5. The excess proof shows that the amounts
+	/// balance.
+	Transfer { spends: Vec<u64>, receives: Vec<ConfidentialOutput>, fee: u64, excess: SchnorrProof },
+}
+
+/// The message that the excess proof of a transfer signs.
+pub fn transfer_message(spends: &[u64], receives: &[ConfidentialOutput], fee: u64) -> u64 {
+	let commitments: Vec<_> =
+		receives.iter().map(|output| (output.owner, output.commitment)).collect();
+	hash(&(spends, commitments, fee))
+}
+
+/// The reasons a confidential transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfidentialError {
+	/// Minting zero is pointless.
+	ZeroAmount,
+	/// A public amount does not fit in `RANGE_BITS` bits.
+	AmountOutOfRange(u64),
+	/// A transfer must spend at least one bill.
+	NoSpends,
+	/// There is no bill in circulation with this serial.
+	UnknownBill(u64),
+	/// The same bill is spent twice in a single transfer.
+	DoubleSpend(u64),
+	/// The range proof of the output at this index is not valid.
+	InvalidRangeProof { output: usize },
+	/// The amounts spent do not equal the amounts received plus the fee.
+	Unbalanced,
+}
+
+impl StateMachine for ConfidentialCash {
+	type State = ConfidentialState;
+	type Transition = ConfidentialTransaction;
+
+	fn next_state(
+		starting_state: &ConfidentialState,
+		t: &ConfidentialTransaction,
+	) -> ConfidentialState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Confidential Cash".into()
+	}
+}
+
+impl TryStateMachine for ConfidentialCash {
+	type Error = ConfidentialError;
+
+	fn try_next_state(
+		starting_state: &ConfidentialState,
+		t: &ConfidentialTransaction,
+	) -> Result<ConfidentialState, ConfidentialError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			ConfidentialTransaction::Mint { minter, amount } => {
+				if *amount == 0 {
+					return Err(ConfidentialError::ZeroAmount)
+				}
+				if *amount >> RANGE_BITS != 0 {
+					return Err(ConfidentialError::AmountOutOfRange(*amount))
+				}
+				let bill =
+					ConfidentialBill { owner: *minter, commitment: Commitment::new(*amount, 0) };
+				state.bills.insert(state.next_serial, bill);
+				state.next_serial += 1;
+			},
+			ConfidentialTransaction::Transfer { spends, receives, fee, excess } => {
+				if spends.is_empty() {
+					return Err(ConfidentialError::NoSpends)
+				}
+				if *fee >> RANGE_BITS != 0 {
+					return Err(ConfidentialError::AmountOutOfRange(*fee))
+				}
+
+				let mut seen = BTreeSet::new();
+				let mut balance = Commitment(1);
+				for serial in spends {
+					if !seen.insert(serial) {
+						return Err(ConfidentialError::DoubleSpend(*serial))
+					}
+					let bill = state
+						.bills
+						.remove(serial)
+						.ok_or(ConfidentialError::UnknownBill(*serial))?;
+					balance = balance.add(&bill.commitment);
+				}
+
+				for (index, output) in receives.iter().enumerate() {
+					if !output.range_proof.verify(&output.commitment) {
+						return Err(ConfidentialError::InvalidRangeProof { output: index })
+					}
+					balance = balance.sub(&output.commitment);
+					let bill =
+						ConfidentialBill { owner: output.owner, commitment: output.commitment };
+					state.bills.insert(state.next_serial, bill);
+					state.next_serial += 1;
+				}
+
+				// Whatever is left after removing the fee must be a commitment to zero, which is a
+				// power of `h` whose exponent only the sender knows.
+				balance = balance.sub(&Commitment::new(*fee, 0));
+				if !excess.verify(balance.0, transfer_message(spends, receives, *fee)) {
+					return Err(ConfidentialError::Unbalanced)
+				}
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// Build a transfer that spends the given bills, whose openings the sender knows, and pays the
+/// given amounts. The openings of the new bills are returned so that they can be passed on to
+/// their owners. Nothing is checked here: if the amounts don't balance or are out of range, the
+/// resulting transaction is simply invalid.
+pub fn build_transfer(
+	random: &mut impl FnMut() -> u64,
+	inputs: &[(u64, Opening)],
+	payments: &[(User, u64)],
+	fee: u64,
+) -> (ConfidentialTransaction, Vec<Opening>) {
+	let spends: Vec<_> = inputs.iter().map(|(serial, _)| *serial).collect();
+	let openings: Vec<_> =
+		payments.iter().map(|(_, value)| Opening::random(random, *value)).collect();
+	let receives: Vec<_> = payments
+		.iter()
+		.zip(&openings)
+		.map(|((owner, _), opening)| ConfidentialOutput {
+			owner: *owner,
+			commitment: opening.commit(),
+			range_proof: RangeProof::prove(random, opening),
+		})
+		.collect();
+
+	let blinding_in = inputs.iter().fold(0, |acc, (_, opening)| add(acc, opening.blinding, Q));
+	let blinding_out = openings.iter().fold(0, |acc, opening| add(acc, opening.blinding, Q));
+	let excess = SchnorrProof::prove(
+		random,
+		sub(blinding_in, blinding_out, Q),
+		transfer_message(&spends, &receives, fee),
+	);
+
+	(ConfidentialTransaction::Transfer { spends, receives, fee, excess }, openings)
+}
+
+/// A state where Alice has minted a single bill worth 100, along with the bill's opening.
+#[cfg(test)]
+fn minted() -> (ConfidentialState, Opening) {
+	let mint = ConfidentialTransaction::Mint { minter: User::Alice, amount: 100 };
+	let state = ConfidentialCash::try_next_state(&ConfidentialState::default(), &mint).unwrap();
+	(state, Opening { value: 100, blinding: 0 })
+}
+
+#[test]
+fn sm_15_commitments_are_homomorphic() {
+	let a = Commitment::new(30, 7);
+	let b = Commitment::new(12, 5);
+
+	assert_eq!(a.add(&b), Commitment::new(42, 12));
+	assert_eq!(a.sub(&b), Commitment::new(18, 2));
+	assert_ne!(Commitment::new(30, 7), Commitment::new(30, 8));
+}
+
+#[test]
+fn sm_15_range_proofs() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(0);
+	let mut random = || rng.next_u64();
+	let opening = Opening::random(&mut random, 123_456);
+	let proof = RangeProof::prove(&mut random, &opening);
+
+	assert!(proof.verify(&opening.commit()));
+	assert!(!proof.verify(&Opening { value: 123_457, ..opening }.commit()));
+
+	let too_big = Opening::random(&mut random, 1 << RANGE_BITS);
+	assert!(!RangeProof::prove(&mut random, &too_big).verify(&too_big.commit()));
+
+	let negative = Opening::random(&mut random, Q - 1);
+	assert!(!RangeProof::prove(&mut random, &negative).verify(&negative.commit()));
+}
+
+#[test]
+fn sm_15_confidential_transfer() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(1);
+	let mut random = || rng.next_u64();
+	let (state, opening) = minted();
+	let (transfer, openings) =
+		build_transfer(&mut random, &[(0, opening)], &[(User::Bob, 60), (User::Alice, 35)], 5);
+	let end = ConfidentialCash::try_next_state(&state, &transfer).unwrap();
+
+	assert_eq!(end.next_serial, 3);
+	assert_eq!(end.bills.len(), 2);
+	assert_eq!(
+		end.bills[&1],
+		ConfidentialBill { owner: User::Bob, commitment: openings[0].commit() }
+	);
+	// The amounts don't appear in the state in any recognizable form.
+	assert_ne!(end.bills[&1].commitment, Commitment::new(60, 0));
+
+	// Bob can spend his new bill onwards, since he was told its opening.
+	let (onwards, _) = build_transfer(&mut random, &[(1, openings[0])], &[(User::Charlie, 60)], 0);
+	assert!(ConfidentialCash::try_next_state(&end, &onwards).is_ok());
+}
+
+#[test]
+fn sm_15_unbalanced_transfer_rejected() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(2);
+	let mut random = || rng.next_u64();
+	let (state, opening) = minted();
+	let (transfer, _) = build_transfer(&mut random, &[(0, opening)], &[(User::Bob, 101)], 0);
+
+	assert_eq!(
+		ConfidentialCash::try_next_state(&state, &transfer),
+		Err(ConfidentialError::Unbalanced)
+	);
+}
+
+#[test]
+fn sm_15_negative_output_rejected() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(3);
+	let mut random = || rng.next_u64();
+	let (state, opening) = minted();
+	// Balances modulo the group order, since Q - 1 is minus one.
+	let (transfer, _) =
+		build_transfer(&mut random, &[(0, opening)], &[(User::Bob, 101), (User::Alice, Q - 1)], 0);
+
+	assert_eq!(
+		ConfidentialCash::try_next_state(&state, &transfer),
+		Err(ConfidentialError::InvalidRangeProof { output: 1 })
+	);
+}
+
+#[test]
+fn sm_15_tampered_transfer_rejected() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(4);
+	let mut random = || rng.next_u64();
+	let (state, opening) = minted();
+	let (mut transfer, _) = build_transfer(&mut random, &[(0, opening)], &[(User::Bob, 100)], 0);
+	if let ConfidentialTransaction::Transfer { receives, .. } = &mut transfer {
+		receives[0].owner = User::Charlie;
+	}
+
+	assert_eq!(
+		ConfidentialCash::try_next_state(&state, &transfer),
+		Err(ConfidentialError::Unbalanced)
+	);
+}
+
+#[test]
+fn sm_15_double_spend_rejected() {
+	use super::invariants::Rng;
+
+	let mut rng = Rng::new(5);
+	let mut random = || rng.next_u64();
+	let (state, opening) = minted();
+	let (transfer, _) =
+		build_transfer(&mut random, &[(0, opening), (0, opening)], &[(User::Bob, 200)], 0);
+
+	assert_eq!(
+		ConfidentialCash::try_next_state(&state, &transfer),
+		Err(ConfidentialError::DoubleSpend(0))
+	);
+}
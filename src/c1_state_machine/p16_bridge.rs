@@ -0,0 +1,267 @@
+//! We have now modelled money with accounts in part 4 and with bills in part 5, but there is no
+//! way to move value from one to the other. In this module we build a bridge: a single machine
+//! that holds both states and lets users move money between them.
+//!
+//! Moving money onto the cash side locks it in an escrow owned by the bridge and mints a bill of
+//! the same value. Moving money back spends bills and releases the same value from the escrow to
+//! an account. As long as the cash side can only create bills through the bridge, every bill in
+//! circulation is backed by locked balance, and moving value across never changes the total
+//! supply.
+//!
+//! This machine is similar to the `Product` of the two currencies from the `compose` module, but
+//! it needs transitions that touch both halves at once, so it is written out by hand.
+
+use super::{
+	invariants::Rng,
+	p4_accounted_currency::{
+		self, AccountedCurrency, AccountingError, AccountingTransaction, Balances,
+	},
+	p5_digital_cash::{self, Bill, CashError, CashTransaction, DigitalCashSystem, State},
+	BlockHooks, StateMachine, TryStateMachine, User,
+};
+
+/// This state machine holds an accounted currency and a digital cash system side by side.
+pub struct Bridge;
+
+/// The state of both halves of the bridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeState {
+	/// The account balances
+	pub accounts: Balances,
+	/// The bills in circulation
+	pub cash: State,
+	/// The account balance locked in the bridge's escrow, which backs the bills in circulation
+	pub locked: u64,
+}
+
+impl BridgeState {
+	/// A bridge with the given balances and no bills.
+	pub fn new(accounts: Balances) -> Self {
+		BridgeState { accounts, cash: State::new(), locked: 0 }
+	}
+
+	/// The total value of all bills in circulation.
+	pub fn cash_value(&self) -> u128 {
+		self.cash.bills().map(|bill| bill.amount() as u128).sum()
+	}
+
+	/// The total supply across both halves: every account balance plus every bill.
+	pub fn total_supply(&self) -> u128 {
+		self.accounts.values().map(|balance| *balance as u128).sum::<u128>() + self.cash_value()
+	}
+}
+
+/// The state transitions that users can make on the bridge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BridgeTransaction {
+	/// A transaction on the account side only
+	Account(AccountingTransaction),
+	/// A transaction on the cash side only. Minting is not allowed, because the new bill would not
+	/// be backed.
+	Cash(CashTransaction),
+	/// Lock some of the user's balance and mint a bill of the same value for them
+	Lock { user: User, amount: u64 },
+	/// Spend some bills and credit their total value to the receiver's account
+	Release { bills: Vec<Bill>, receiver: User },
+}
+
+/// The reasons a bridge transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BridgeError {
+	/// Locking zero is pointless.
+	ZeroAmount,
+	/// The user's balance is smaller than the amount they tried to lock.
+	InsufficientBalance { balance: u64, requested: u64 },
+	/// Bills may only be created by locking account balance.
+	UnbackedMint,
+	/// The escrow would exceed `u64::MAX`.
+	Overflow,
+	/// The account side rejected the transaction.
+	Account(AccountingError),
+	/// The cash side rejected the transaction.
+	Cash(CashError),
+}
+
+impl StateMachine for Bridge {
+	type State = BridgeState;
+	type Transition = BridgeTransaction;
+
+	fn next_state(starting_state: &BridgeState, t: &BridgeTransaction) -> BridgeState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Account-Cash Bridge".into()
+	}
+}
+
+impl TryStateMachine for Bridge {
+	type Error = BridgeError;
+
+	fn try_next_state(
+		starting_state: &BridgeState,
+		t: &BridgeTransaction,
+	) -> Result<BridgeState, BridgeError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			BridgeTransaction::Account(call) => {
+				state.accounts = AccountedCurrency::try_next_state(&state.accounts, call)
+					.map_err(BridgeError::Account)?;
+			},
+			BridgeTransaction::Cash(CashTransaction::Mint { .. }) =>
+				return Err(BridgeError::UnbackedMint),
+			BridgeTransaction::Cash(call @ CashTransaction::Transfer { spends, receives }) => {
+				state.cash = DigitalCashSystem::try_next_state(&state.cash, call)
+					.map_err(BridgeError::Cash)?;
+				// Whatever the transfer destroyed no longer needs backing, so it is burned from
+				// the escrow too. The cash side already checked that the sums fit.
+				let spent: u64 = spends.iter().map(Bill::amount).sum();
+				let received: u64 = receives.iter().map(Bill::amount).sum();
+				state.locked -= spent - received;
+			},
+			BridgeTransaction::Lock { user, amount } => {
+				if *amount == 0 {
+					return Err(BridgeError::ZeroAmount)
+				}
+				let balance = state.accounts.get(user).copied().unwrap_or(0);
+				if balance < *amount {
+					return Err(BridgeError::InsufficientBalance { balance, requested: *amount })
+				}
+				if balance == *amount {
+					state.accounts.remove(user);
+				} else {
+					state.accounts.insert(*user, balance - amount);
+				}
+				state.locked = state.locked.checked_add(*amount).ok_or(BridgeError::Overflow)?;
+
+				let mint = CashTransaction::Mint { minter: *user, amount: *amount };
+				state.cash = DigitalCashSystem::try_next_state(&state.cash, &mint)
+					.map_err(BridgeError::Cash)?;
+			},
+			BridgeTransaction::Release { bills, receiver } => {
+				let burn =
+					CashTransaction::Transfer { spends: bills.clone(), receives: Vec::new() };
+				state.cash = DigitalCashSystem::try_next_state(&state.cash, &burn)
+					.map_err(BridgeError::Cash)?;
+				let amount: u64 = bills.iter().map(Bill::amount).sum();
+				state.locked -= amount;
+
+				let credit = AccountingTransaction::Mint { minter: *receiver, amount };
+				state.accounts = AccountedCurrency::try_next_state(&state.accounts, &credit)
+					.map_err(BridgeError::Account)?;
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// The bridge doesn't do anything special at block boundaries.
+impl BlockHooks for Bridge {}
+
+/// Generate a random bridge transaction for property testing, mostly by reusing the generators of
+/// both halves.
+pub fn arbitrary_transaction(rng: &mut Rng, state: &BridgeState) -> BridgeTransaction {
+	let user = *rng.choose(&User::ALL).expect("there are several users; qed");
+
+	match rng.below(4) {
+		0 => BridgeTransaction::Account(p4_accounted_currency::arbitrary_transaction(
+			rng,
+			&state.accounts,
+		)),
+		1 => BridgeTransaction::Cash(p5_digital_cash::arbitrary_transaction(rng, &state.cash)),
+		2 => BridgeTransaction::Lock { user, amount: rng.below(60) },
+		_ => {
+			let mut bills: Vec<_> = state.cash.bills().cloned().collect();
+			bills.sort_by_key(Bill::serial);
+			bills.retain(|_| rng.coin());
+			BridgeTransaction::Release { bills, receiver: user }
+		},
+	}
+}
+
+#[test]
+fn sm_16_lock_mints_backed_bill() {
+	let start = BridgeState::new(Balances::from([(User::Alice, 100)]));
+	let lock = BridgeTransaction::Lock { user: User::Alice, amount: 30 };
+	let end = Bridge::try_next_state(&start, &lock).unwrap();
+
+	assert_eq!(end.accounts, Balances::from([(User::Alice, 70)]));
+	assert_eq!(end.cash, State::from([Bill::new(User::Alice, 30, 0)]));
+	assert_eq!(end.locked, 30);
+	assert_eq!(end.total_supply(), 100);
+}
+
+#[test]
+fn sm_16_release_credits_account() {
+	let start = BridgeState::new(Balances::from([(User::Alice, 100)]));
+	let lock = BridgeTransaction::Lock { user: User::Alice, amount: 100 };
+	let locked = Bridge::try_next_state(&start, &lock).unwrap();
+	assert!(locked.accounts.is_empty());
+
+	let release = BridgeTransaction::Release {
+		bills: vec![Bill::new(User::Alice, 100, 0)],
+		receiver: User::Bob,
+	};
+	let end = Bridge::try_next_state(&locked, &release).unwrap();
+
+	assert_eq!(end.accounts, Balances::from([(User::Bob, 100)]));
+	assert_eq!(end.cash_value(), 0);
+	assert_eq!(end.locked, 0);
+}
+
+#[test]
+fn sm_16_cannot_lock_more_than_balance() {
+	let start = BridgeState::new(Balances::from([(User::Alice, 10)]));
+	let lock = BridgeTransaction::Lock { user: User::Alice, amount: 11 };
+
+	assert_eq!(
+		Bridge::try_next_state(&start, &lock),
+		Err(BridgeError::InsufficientBalance { balance: 10, requested: 11 })
+	);
+}
+
+#[test]
+fn sm_16_cash_side_cannot_mint() {
+	let start = BridgeState::new(Balances::new());
+	let mint = BridgeTransaction::Cash(CashTransaction::Mint { minter: User::Alice, amount: 5 });
+
+	assert_eq!(Bridge::try_next_state(&start, &mint), Err(BridgeError::UnbackedMint));
+}
+
+#[test]
+fn sm_16_destroyed_cash_is_burned_from_escrow() {
+	let start = BridgeState::new(Balances::from([(User::Alice, 100)]));
+	let lock = BridgeTransaction::Lock { user: User::Alice, amount: 50 };
+	let locked = Bridge::try_next_state(&start, &lock).unwrap();
+	let transfer = BridgeTransaction::Cash(CashTransaction::Transfer {
+		spends: vec![Bill::new(User::Alice, 50, 0)],
+		receives: vec![Bill::new(User::Bob, 45, 1)],
+	});
+	let end = Bridge::try_next_state(&locked, &transfer).unwrap();
+
+	assert_eq!(end.locked, 45);
+	assert_eq!(end.total_supply(), 95);
+}
+
+#[test]
+fn sm_16_invariants_hold_for_random_transactions() {
+	use super::invariants::PropertyTest;
+
+	let start = BridgeState::new(Balances::from([(User::Alice, 100), (User::Bob, 50)]));
+	PropertyTest::<Bridge>::new(start, arbitrary_transaction)
+		.invariant("every bill is backed by locked balance", |_, _, post| {
+			post.cash_value() == post.locked as u128
+		})
+		.invariant("moving value across conserves total supply", |pre, t, post| match t {
+			BridgeTransaction::Lock { .. } | BridgeTransaction::Release { .. } =>
+				post.total_supply() == pre.total_supply(),
+			_ => true,
+		})
+		.invariant("only account minting increases total supply", |pre, t, post| match t {
+			BridgeTransaction::Account(AccountingTransaction::Mint { .. }) => true,
+			_ => post.total_supply() <= pre.total_supply(),
+		})
+		.check();
+}
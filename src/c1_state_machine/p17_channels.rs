@@ -0,0 +1,577 @@
+//! Every transfer in the accounted currency has to wait for a block. Two users who pay each other
+//! often can do much better with a payment channel. They lock some funds on chain once, then pay
+//! each other as often as they like by exchanging signed balance updates off chain. Only the final
+//! balances ever need to go on chain again.
+//!
+//! Each update carries a sequence number, and a higher sequence number means a newer update. When
+//! both parties agree, they close the channel cooperatively and are paid out immediately. For that
+//! they sign the final balances once more, as a close, because every old update still carries both
+//! signatures and must not be enough to pay out without a dispute window. When one party
+//! disappears, the other can close the channel unilaterally. The catch is that a cheater could
+//! close with an old update that pays them more. So a unilateral close only starts a dispute
+//! window, measured in blocks, during which the other party can present a newer update. When the
+//! window is over, anybody can settle the channel with the newest update that was presented.
+//!
+//! The machine learns the current block height from the `on_initialize` block hook, so dispute
+//! windows advance with the height of the chain that executes it. The blockchain of chapter 2 can't
+//! run it, because its state is fixed to a sum and product of numbers. The framework of chapter 4
+//! generalizes that blockchain to any state machine, and passes each header's height to the hook
+//! when it executes the block.
+
+use super::{
+	p4_accounted_currency::{
+		hash_balances, AccountedCurrency, AccountingError, AccountingTransaction, Balances,
+	},
+	BlockContext, BlockHooks, Signature, StateMachine, TryStateMachine, User,
+};
+use std::{
+	collections::BTreeMap,
+	hash::{Hash, Hasher},
+};
+
+/// Channels are numbered in the order they are opened.
+pub type ChannelId = u64;
+
+/// The terms of a new channel, which both parties sign before it is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelTerms {
+	/// The id the channel will have. It must be the state's next channel id, so that the signed
+	/// terms can't be replayed to open a second channel.
+	pub id: ChannelId,
+	pub parties: [User; 2],
+	/// How much each party locks into the channel
+	pub deposits: [u64; 2],
+}
+
+/// An off-chain agreement on how the channel's funds are split.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BalanceUpdate {
+	pub channel: ChannelId,
+	/// Higher sequence numbers supersede lower ones
+	pub sequence: u64,
+	/// The amount each party would be paid if the channel closed now
+	pub balances: [u64; 2],
+}
+
+impl BalanceUpdate {
+	/// The next update after the given party pays the other one the given amount, or `None` if
+	/// they can't afford it.
+	pub fn pay(&self, payer: usize, amount: u64) -> Option<BalanceUpdate> {
+		let mut balances = self.balances;
+		balances[payer] = balances[payer].checked_sub(amount)?;
+		balances[1 - payer] = balances[1 - payer].checked_add(amount)?;
+		Some(BalanceUpdate { channel: self.channel, sequence: self.sequence + 1, balances })
+	}
+}
+
+/// A balance update signed by both parties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SignedUpdate {
+	pub update: BalanceUpdate,
+	pub signatures: [Signature; 2],
+}
+
+impl SignedUpdate {
+	/// Sign the update as both of the given parties.
+	pub fn new(update: BalanceUpdate, parties: [User; 2]) -> Self {
+		SignedUpdate { update, signatures: parties.map(|party| party.sign(&update)) }
+	}
+}
+
+/// Whether a channel is in use or on its way to being closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelStatus {
+	Open,
+	/// Somebody closed the channel unilaterally. Unless a newer update is presented before the
+	/// deadline, the channel settles with this update.
+	Closing {
+		update: BalanceUpdate,
+		deadline: u64,
+	},
+}
+
+/// A channel that is open or closing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Channel {
+	pub terms: ChannelTerms,
+	pub status: ChannelStatus,
+}
+
+impl Channel {
+	/// The update that both parties implicitly agreed to when they opened the channel.
+	pub fn initial_update(&self) -> BalanceUpdate {
+		BalanceUpdate { channel: self.terms.id, sequence: 0, balances: self.terms.deposits }
+	}
+}
+
+/// This state machine models two-party payment channels on top of the accounted currency.
+pub struct PaymentChannels;
+
+/// The state of the payment channel system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelState {
+	/// The height of the block currently being executed
+	pub height: u64,
+	/// How many blocks the counterparty has to respond to a unilateral close
+	pub dispute_window: u64,
+	/// The account balances, not counting funds locked in channels
+	pub balances: Balances,
+	/// The channels that are not fully closed yet
+	pub channels: BTreeMap<ChannelId, Channel>,
+	/// The id of the next channel to be opened
+	pub next_channel: ChannelId,
+}
+
+impl Hash for ChannelState {
+	fn hash<H: Hasher>(&self, hasher: &mut H) {
+		self.height.hash(hasher);
+		self.dispute_window.hash(hasher);
+		hash_balances(&self.balances, hasher);
+		self.channels.hash(hasher);
+		self.next_channel.hash(hasher);
+	}
+}
+
+impl ChannelState {
+	/// A state with the given balances and dispute window, and no channels.
+	pub fn new(balances: Balances, dispute_window: u64) -> Self {
+		ChannelState {
+			height: 0,
+			dispute_window,
+			balances,
+			channels: BTreeMap::new(),
+			next_channel: 0,
+		}
+	}
+
+	/// Pay out the channel's funds according to the update and forget about the channel.
+	fn settle(&mut self, id: ChannelId, update: &BalanceUpdate) -> Result<(), ChannelError> {
+		let channel = self.channels.remove(&id).ok_or(ChannelError::UnknownChannel(id))?;
+		for (party, amount) in channel.terms.parties.into_iter().zip(update.balances) {
+			if amount > 0 {
+				let credit = AccountingTransaction::Mint { minter: party, amount };
+				self.balances = AccountedCurrency::try_next_state(&self.balances, &credit)
+					.map_err(ChannelError::Account)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The state transitions that users can make in the payment channel system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelTransaction {
+	/// An ordinary accounted currency transaction
+	Account(AccountingTransaction),
+	/// Lock both parties' deposits into a new channel
+	Open { terms: ChannelTerms, signatures: [Signature; 2] },
+	/// Close the channel immediately with the given balances. Both parties sign the channel id,
+	/// the word "close", and the update, so that an update signed only as a payment can't be used.
+	CooperativeClose { update: BalanceUpdate, signatures: [Signature; 2] },
+	/// Start closing the channel on one's own. Without an update, the channel closes with the
+	/// initial deposits. The closer must be one of the channel's parties, and signs the channel id
+	/// along with the update.
+	UnilateralClose {
+		closer: User,
+		channel: ChannelId,
+		update: Option<SignedUpdate>,
+		signature: Signature,
+	},
+	/// Replace the update of a closing channel with a newer one before the deadline
+	Dispute(SignedUpdate),
+	/// Pay out a closing channel once its deadline has passed
+	Settle { channel: ChannelId },
+}
+
+impl ChannelTransaction {
+	/// Close the channel cooperatively, signed by both of the given parties.
+	pub fn cooperative_close(update: BalanceUpdate, parties: [User; 2]) -> Self {
+		let signatures = parties.map(|party| party.sign(&close_payload(&update)));
+		ChannelTransaction::CooperativeClose { update, signatures }
+	}
+
+	/// Close the channel unilaterally, signed by the given closer.
+	pub fn unilateral_close(
+		closer: User,
+		channel: ChannelId,
+		update: Option<SignedUpdate>,
+	) -> Self {
+		let signature = closer.sign(&(channel, update));
+		ChannelTransaction::UnilateralClose { closer, channel, update, signature }
+	}
+}
+
+/// The reasons a payment channel transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelError {
+	/// The account side rejected the transaction.
+	Account(AccountingError),
+	/// A channel needs two different parties.
+	SameParty,
+	/// A channel with no funds in it is useless.
+	EmptyChannel,
+	/// The terms must use the next channel id.
+	WrongChannelId { expected: ChannelId, found: ChannelId },
+	/// The party can't afford their deposit.
+	InsufficientBalance { party: User, balance: u64, requested: u64 },
+	/// There is no open or closing channel with this id.
+	UnknownChannel(ChannelId),
+	/// The update is for a different channel than the one being closed.
+	WrongChannel { expected: ChannelId, found: ChannelId },
+	/// The party's signature is missing or invalid.
+	BadSignature(User),
+	/// Only the channel's parties may close it unilaterally.
+	NotAParty(User),
+	/// The update does not split exactly the channel's funds.
+	BalanceMismatch { total: u128, expected: u128 },
+	/// The channel is already closing.
+	NotOpen,
+	/// The channel is not closing, so there is nothing to dispute or settle.
+	NotClosing,
+	/// The update is not newer than the one already presented.
+	StaleUpdate { current: u64, found: u64 },
+	/// The dispute window is over.
+	DisputeWindowClosed { deadline: u64 },
+	/// The dispute window is still running.
+	DisputeWindowOpen { deadline: u64 },
+}
+
+impl StateMachine for PaymentChannels {
+	type State = ChannelState;
+	type Transition = ChannelTransaction;
+
+	fn next_state(starting_state: &ChannelState, t: &ChannelTransaction) -> ChannelState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Payment Channels".into()
+	}
+}
+
+/// What both parties sign to close a channel cooperatively.
+fn close_payload(update: &BalanceUpdate) -> (ChannelId, &'static str, BalanceUpdate) {
+	(update.channel, "close", *update)
+}
+
+/// Look up the channel that the update is for, and check that both parties signed the payload and
+/// that the update splits exactly the channel's funds.
+fn check_update<T: Hash>(
+	state: &ChannelState,
+	update: &BalanceUpdate,
+	signatures: &[Signature; 2],
+	payload: &T,
+) -> Result<Channel, ChannelError> {
+	let id = update.channel;
+	let channel = *state.channels.get(&id).ok_or(ChannelError::UnknownChannel(id))?;
+
+	for (party, signature) in channel.terms.parties.into_iter().zip(signatures) {
+		if !signature.verify(party, payload) {
+			return Err(ChannelError::BadSignature(party))
+		}
+	}
+
+	let total: u128 = update.balances.iter().map(|b| *b as u128).sum();
+	let expected: u128 = channel.terms.deposits.iter().map(|d| *d as u128).sum();
+	if total != expected {
+		return Err(ChannelError::BalanceMismatch { total, expected })
+	}
+
+	Ok(channel)
+}
+
+impl TryStateMachine for PaymentChannels {
+	type Error = ChannelError;
+
+	fn try_next_state(
+		starting_state: &ChannelState,
+		t: &ChannelTransaction,
+	) -> Result<ChannelState, ChannelError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			ChannelTransaction::Account(call) => {
+				state.balances = AccountedCurrency::try_next_state(&state.balances, call)
+					.map_err(ChannelError::Account)?;
+			},
+			ChannelTransaction::Open { terms, signatures } => {
+				if terms.parties[0] == terms.parties[1] {
+					return Err(ChannelError::SameParty)
+				}
+				if terms.deposits == [0, 0] {
+					return Err(ChannelError::EmptyChannel)
+				}
+				if terms.id != state.next_channel {
+					return Err(ChannelError::WrongChannelId {
+						expected: state.next_channel,
+						found: terms.id,
+					})
+				}
+				for (party, signature) in terms.parties.into_iter().zip(signatures) {
+					if !signature.verify(party, terms) {
+						return Err(ChannelError::BadSignature(party))
+					}
+				}
+
+				for (party, deposit) in terms.parties.into_iter().zip(terms.deposits) {
+					let balance = state.balances.get(&party).copied().unwrap_or(0);
+					if balance < deposit {
+						return Err(ChannelError::InsufficientBalance {
+							party,
+							balance,
+							requested: deposit,
+						})
+					}
+					if balance == deposit {
+						state.balances.remove(&party);
+					} else {
+						state.balances.insert(party, balance - deposit);
+					}
+				}
+
+				state
+					.channels
+					.insert(terms.id, Channel { terms: *terms, status: ChannelStatus::Open });
+				state.next_channel += 1;
+			},
+			ChannelTransaction::CooperativeClose { update, signatures } => {
+				let channel = check_update(&state, update, signatures, &close_payload(update))?;
+				if channel.status != ChannelStatus::Open {
+					return Err(ChannelError::NotOpen)
+				}
+				state.settle(channel.terms.id, update)?;
+			},
+			ChannelTransaction::UnilateralClose { closer, channel: id, update, signature } => {
+				let channel = match update {
+					Some(signed) =>
+						check_update(&state, &signed.update, &signed.signatures, &signed.update)?,
+					None => *state.channels.get(id).ok_or(ChannelError::UnknownChannel(*id))?,
+				};
+				if channel.terms.id != *id {
+					return Err(ChannelError::WrongChannel {
+						expected: *id,
+						found: channel.terms.id,
+					})
+				}
+				if !channel.terms.parties.contains(closer) {
+					return Err(ChannelError::NotAParty(*closer))
+				}
+				if !signature.verify(*closer, &(*id, *update)) {
+					return Err(ChannelError::BadSignature(*closer))
+				}
+				if channel.status != ChannelStatus::Open {
+					return Err(ChannelError::NotOpen)
+				}
+
+				let update = update.map(|signed| signed.update).unwrap_or(channel.initial_update());
+				let deadline = state.height.saturating_add(state.dispute_window);
+				let closing = ChannelStatus::Closing { update, deadline };
+				state.channels.insert(*id, Channel { status: closing, ..channel });
+			},
+			ChannelTransaction::Dispute(signed) => {
+				let channel =
+					check_update(&state, &signed.update, &signed.signatures, &signed.update)?;
+				let ChannelStatus::Closing { update, deadline } = channel.status else {
+					return Err(ChannelError::NotClosing)
+				};
+				if state.height >= deadline {
+					return Err(ChannelError::DisputeWindowClosed { deadline })
+				}
+				if signed.update.sequence <= update.sequence {
+					return Err(ChannelError::StaleUpdate {
+						current: update.sequence,
+						found: signed.update.sequence,
+					})
+				}
+
+				let closing = ChannelStatus::Closing { update: signed.update, deadline };
+				state.channels.insert(channel.terms.id, Channel { status: closing, ..channel });
+			},
+			ChannelTransaction::Settle { channel: id } => {
+				let channel = state.channels.get(id).ok_or(ChannelError::UnknownChannel(*id))?;
+				let ChannelStatus::Closing { update, deadline } = channel.status else {
+					return Err(ChannelError::NotClosing)
+				};
+				if state.height < deadline {
+					return Err(ChannelError::DisputeWindowOpen { deadline })
+				}
+				state.settle(*id, &update)?;
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// Dispute windows are measured in blocks, so we note the height at the beginning of every block.
+impl BlockHooks for PaymentChannels {
+	fn on_initialize(state: &ChannelState, block: &BlockContext) -> ChannelState {
+		ChannelState { height: block.height, ..state.clone() }
+	}
+}
+
+/// Alice and Bob each lock 50 into channel 0. The dispute window is 10 blocks.
+#[cfg(test)]
+fn opened() -> (ChannelState, ChannelTerms) {
+	let start = ChannelState::new(Balances::from([(User::Alice, 100), (User::Bob, 50)]), 10);
+	let terms = ChannelTerms { id: 0, parties: [User::Alice, User::Bob], deposits: [50, 50] };
+	let open = ChannelTransaction::Open {
+		terms,
+		signatures: [User::Alice.sign(&terms), User::Bob.sign(&terms)],
+	};
+	(PaymentChannels::try_next_state(&start, &open).unwrap(), terms)
+}
+
+#[cfg(test)]
+fn at_height(state: &ChannelState, height: u64) -> ChannelState {
+	PaymentChannels::on_initialize(state, &BlockContext { height, author: None })
+}
+
+#[test]
+fn sm_17_open_locks_deposits() {
+	let (state, terms) = opened();
+
+	assert_eq!(state.balances, Balances::from([(User::Alice, 50)]));
+	assert_eq!(state.channels[&0], Channel { terms, status: ChannelStatus::Open });
+	assert_eq!(state.next_channel, 1);
+}
+
+#[test]
+fn sm_17_open_cannot_be_replayed() {
+	let (state, terms) = opened();
+	let open = ChannelTransaction::Open {
+		terms,
+		signatures: [User::Alice.sign(&terms), User::Bob.sign(&terms)],
+	};
+
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &open),
+		Err(ChannelError::WrongChannelId { expected: 1, found: 0 })
+	);
+}
+
+#[test]
+fn sm_17_open_needs_both_signatures() {
+	let start = ChannelState::new(Balances::from([(User::Alice, 100), (User::Bob, 50)]), 10);
+	let terms = ChannelTerms { id: 0, parties: [User::Alice, User::Bob], deposits: [50, 50] };
+	let open = ChannelTransaction::Open {
+		terms,
+		signatures: [User::Alice.sign(&terms), User::Alice.sign(&terms)],
+	};
+
+	assert_eq!(
+		PaymentChannels::try_next_state(&start, &open),
+		Err(ChannelError::BadSignature(User::Bob))
+	);
+}
+
+#[test]
+fn sm_17_cooperative_close() {
+	let (state, terms) = opened();
+	let initial = state.channels[&0].initial_update();
+	let latest = initial.pay(0, 20).and_then(|u| u.pay(1, 5)).unwrap();
+	let close = ChannelTransaction::cooperative_close(latest, terms.parties);
+	let end = PaymentChannels::try_next_state(&state, &close).unwrap();
+
+	assert_eq!(end.balances, Balances::from([(User::Alice, 85), (User::Bob, 65)]));
+	assert!(end.channels.is_empty());
+}
+
+#[test]
+fn sm_17_stale_update_cannot_close_immediately() {
+	let (state, terms) = opened();
+	let old = state.channels[&0].initial_update().pay(1, 30).unwrap();
+	let stale = SignedUpdate::new(old, terms.parties);
+	let close = ChannelTransaction::CooperativeClose { update: old, signatures: stale.signatures };
+
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &close),
+		Err(ChannelError::BadSignature(User::Alice))
+	);
+}
+
+#[test]
+fn sm_17_update_must_split_deposits() {
+	let (state, terms) = opened();
+	let update = BalanceUpdate { channel: 0, sequence: 1, balances: [100, 100] };
+	let close = ChannelTransaction::cooperative_close(update, terms.parties);
+
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &close),
+		Err(ChannelError::BalanceMismatch { total: 200, expected: 100 })
+	);
+}
+
+#[test]
+fn sm_17_dispute_overrides_stale_close() {
+	let (state, terms) = opened();
+	let old = state.channels[&0].initial_update().pay(1, 30).unwrap();
+	let new = old.pay(0, 40).unwrap();
+
+	// Alice closes with the old update, which pays her more.
+	let state = at_height(&state, 5);
+	let close = ChannelTransaction::unilateral_close(
+		User::Alice,
+		0,
+		Some(SignedUpdate::new(old, terms.parties)),
+	);
+	let state = PaymentChannels::try_next_state(&state, &close).unwrap();
+	assert_eq!(state.channels[&0].status, ChannelStatus::Closing { update: old, deadline: 15 });
+
+	// Bob responds in time with the newer update.
+	let state = at_height(&state, 14);
+	let dispute = ChannelTransaction::Dispute(SignedUpdate::new(new, terms.parties));
+	let state = PaymentChannels::try_next_state(&state, &dispute).unwrap();
+
+	// Alice can't go back to the old update.
+	let again = ChannelTransaction::Dispute(SignedUpdate::new(old, terms.parties));
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &again),
+		Err(ChannelError::StaleUpdate { current: 2, found: 1 })
+	);
+
+	let settle = ChannelTransaction::Settle { channel: 0 };
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &settle),
+		Err(ChannelError::DisputeWindowOpen { deadline: 15 })
+	);
+	let end = PaymentChannels::try_next_state(&at_height(&state, 15), &settle).unwrap();
+	assert_eq!(end.balances, Balances::from([(User::Alice, 90), (User::Bob, 60)]));
+	assert!(end.channels.is_empty());
+}
+
+#[test]
+fn sm_17_dispute_after_deadline_fails() {
+	let (state, terms) = opened();
+	let close = ChannelTransaction::unilateral_close(User::Bob, 0, None);
+	let state = PaymentChannels::try_next_state(&state, &close).unwrap();
+
+	let newer = state.channels[&0].initial_update().pay(0, 10).unwrap();
+	let dispute = ChannelTransaction::Dispute(SignedUpdate::new(newer, terms.parties));
+	assert_eq!(
+		PaymentChannels::try_next_state(&at_height(&state, 10), &dispute),
+		Err(ChannelError::DisputeWindowClosed { deadline: 10 })
+	);
+}
+
+#[test]
+fn sm_17_only_a_signing_party_can_close_unilaterally() {
+	let (state, _) = opened();
+
+	let outsider = ChannelTransaction::unilateral_close(User::Charlie, 0, None);
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &outsider),
+		Err(ChannelError::NotAParty(User::Charlie))
+	);
+
+	let forged = ChannelTransaction::UnilateralClose {
+		closer: User::Bob,
+		channel: 0,
+		update: None,
+		signature: User::Charlie.sign(&(0u64, None::<SignedUpdate>)),
+	};
+	assert_eq!(
+		PaymentChannels::try_next_state(&state, &forged),
+		Err(ChannelError::BadSignature(User::Bob))
+	);
+}
@@ -0,0 +1,607 @@
+//! Every machine so far has its rules baked in. To add a new kind of transaction, somebody has to
+//! change the code of the machine itself. Smart contract platforms like Ethereum take a different
+//! approach: the machine is a small virtual computer, and users deploy their own programs to it.
+//! Each deployed program, called a contract, gets its own balance and its own storage, and anybody
+//! can call it.
+//!
+//! Our virtual machine is a simple stack machine. Instructions pop their operands off a stack of
+//! words and push their results back on. Programs are deterministic: they can only see the stack,
+//! their arguments, their own storage, and balances, so every node executing a call gets the same
+//! result.
+//!
+//! Two safety mechanisms keep contracts from doing damage:
+//! - Gas metering. Every instruction costs some gas, and every call states how much gas it may use,
+//!   up to `MAX_GAS`. A contract stuck in an endless loop simply runs out of gas.
+//! - Reverting. When a contract fails for any reason, whether by running out of gas, by a bad
+//!   instruction, or deliberately with `Revert`, all of its effects are undone, including any value
+//!   sent along with the call.
+//!
+//! Real platforms still charge for the gas of a reverted call. Our machine rejects the whole
+//! transaction instead. Fees could be layered on top just like in part 9.
+
+use super::{BlockHooks, StateMachine, TryStateMachine, User};
+use std::collections::BTreeMap;
+
+/// Contracts are numbered in the order they are deployed.
+pub type ContractId = u64;
+
+/// The most words the stack may hold at once.
+pub const MAX_STACK: usize = 1024;
+
+/// The most gas a single call may ask for. Without a cap, one call could keep every node busy for
+/// as long as the caller likes.
+pub const MAX_GAS: u64 = 1_000_000;
+
+/// Contract addresses start at this word, so that they never collide with users.
+const CONTRACT_OFFSET: u64 = 1 << 32;
+
+/// Anything that can hold a balance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Address {
+	User(User),
+	Contract(ContractId),
+}
+
+impl Address {
+	/// Encode the address as a single word so that contracts can work with it.
+	pub fn to_word(self) -> u64 {
+		match self {
+			Address::User(user) => User::ALL
+				.iter()
+				.position(|u| *u == user)
+				.expect("every user is in `User::ALL`; qed") as u64,
+			Address::Contract(id) => CONTRACT_OFFSET + id,
+		}
+	}
+
+	/// Decode an address from a word, if the word is one.
+	pub fn from_word(word: u64) -> Option<Address> {
+		match word {
+			w if w >= CONTRACT_OFFSET => Some(Address::Contract(w - CONTRACT_OFFSET)),
+			w => User::ALL.get(w as usize).map(|user| Address::User(*user)),
+		}
+	}
+}
+
+/// A single instruction. Binary operations pop `b` and then `a`, and push `a op b`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+	/// Push a constant.
+	Push(u64),
+	/// Discard the top word.
+	Pop,
+	/// Push a copy of the word this far below the top. `Dup(0)` duplicates the top word.
+	Dup(usize),
+	/// Swap the top two words.
+	Swap,
+	Add,
+	Sub,
+	Mul,
+	Div,
+	Mod,
+	/// Push 1 if `a == b`, otherwise 0.
+	Eq,
+	/// Push 1 if `a < b`, otherwise 0.
+	Lt,
+	/// Push 1 if `a > b`, otherwise 0.
+	Gt,
+	/// Push 1 if the popped word is zero, otherwise 0.
+	IsZero,
+	/// Continue at the given instruction.
+	Jump(usize),
+	/// Pop a word, and continue at the given instruction if it is not zero.
+	JumpIf(usize),
+	/// Push the call argument with the given index.
+	Arg(usize),
+	/// Push the address of the caller.
+	Caller,
+	/// Push the value sent along with the call.
+	CallValue,
+	/// Push the address of the contract itself.
+	SelfAddress,
+	/// Pop an address and push its balance.
+	Balance,
+	/// Pop a key and push the stored value, or zero if nothing is stored.
+	Load,
+	/// Pop a key, then a value, and store the value under the key.
+	Store,
+	/// Pop a receiving address, then an amount, and send the amount from the contract's balance.
+	Transfer,
+	/// Finish successfully.
+	Stop,
+	/// Fail, undoing everything the call did.
+	Revert,
+}
+
+impl Op {
+	/// The gas this instruction costs. Touching storage and balances costs more than arithmetic.
+	pub fn gas(&self) -> u64 {
+		match self {
+			Op::Store => 20,
+			Op::Load | Op::Balance => 5,
+			Op::Transfer => 25,
+			_ => 1,
+		}
+	}
+}
+
+/// A deployed contract.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Contract {
+	pub code: Vec<Op>,
+	pub storage: BTreeMap<u64, u64>,
+}
+
+/// This state machine models a smart contract platform.
+pub struct ContractVm;
+
+/// The state of the smart contract platform.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VmState {
+	/// The balances of users and contracts. Just like in the accounted currency, addresses with a
+	/// zero balance are removed.
+	pub balances: BTreeMap<Address, u64>,
+	/// The deployed contracts
+	pub contracts: BTreeMap<ContractId, Contract>,
+	/// The id of the next contract to be deployed
+	pub next_contract: ContractId,
+}
+
+impl VmState {
+	/// A state with the given user balances and no contracts.
+	pub fn new(balances: impl IntoIterator<Item = (User, u64)>) -> Self {
+		VmState {
+			balances: balances
+				.into_iter()
+				.filter(|(_, balance)| *balance > 0)
+				.map(|(user, balance)| (Address::User(user), balance))
+				.collect(),
+			..Default::default()
+		}
+	}
+
+	/// The balance of the given address.
+	pub fn balance(&self, address: &Address) -> u64 {
+		self.balances.get(address).copied().unwrap_or(0)
+	}
+
+	/// Move value from one address to another.
+	fn transfer(&mut self, from: Address, to: Address, amount: u64) -> Result<(), Trap> {
+		if amount == 0 || from == to {
+			return Ok(())
+		}
+		let balance = self.balance(&from);
+		if balance < amount {
+			return Err(Trap::InsufficientBalance { balance, requested: amount })
+		}
+		let received = self.balance(&to).checked_add(amount).ok_or(Trap::Overflow)?;
+
+		self.balances.insert(to, received);
+		if balance == amount {
+			self.balances.remove(&from);
+		} else {
+			self.balances.insert(from, balance - amount);
+		}
+		Ok(())
+	}
+}
+
+/// The state transitions that users can make on the smart contract platform
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VmTransaction {
+	/// Send value from a user to any address. Sending to a contract does not run its code.
+	Transfer { sender: User, receiver: Address, amount: u64 },
+	/// Deploy a new contract, optionally funding it from the deployer's balance
+	Deploy { deployer: User, code: Vec<Op>, value: u64 },
+	/// Run a contract's code with the given arguments, optionally sending it some value first
+	Call { caller: User, contract: ContractId, args: Vec<u64>, value: u64, gas_limit: u64 },
+}
+
+/// The reasons a contract's execution may fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+	/// The contract executed `Revert`.
+	Revert,
+	/// The call used up all of its gas.
+	OutOfGas,
+	/// An instruction needed more words than the stack holds.
+	StackUnderflow,
+	/// The stack grew beyond `MAX_STACK` words.
+	StackOverflow,
+	/// A jump went past the end of the code.
+	InvalidJump(usize),
+	/// Arithmetic overflowed or underflowed.
+	Overflow,
+	/// Division or remainder by zero.
+	DivisionByZero,
+	/// The call has no argument with this index.
+	MissingArgument(usize),
+	/// The word is not an address.
+	InvalidAddress(u64),
+	/// The balance is too small for a transfer.
+	InsufficientBalance { balance: u64, requested: u64 },
+}
+
+/// The reasons a VM transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+	/// There is no contract with this id.
+	UnknownContract(ContractId),
+	/// The call asked for more than `MAX_GAS`.
+	GasLimitTooHigh { limit: u64, max: u64 },
+	/// The transfer before running any code failed.
+	Transfer(Trap),
+	/// The contract failed at the given instruction, and everything was reverted.
+	Trapped { pc: usize, trap: Trap },
+}
+
+impl StateMachine for ContractVm {
+	type State = VmState;
+	type Transition = VmTransaction;
+
+	fn next_state(starting_state: &VmState, t: &VmTransaction) -> VmState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Contract VM".into()
+	}
+}
+
+impl TryStateMachine for ContractVm {
+	type Error = VmError;
+
+	fn try_next_state(starting_state: &VmState, t: &VmTransaction) -> Result<VmState, VmError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			VmTransaction::Transfer { sender, receiver, amount } => {
+				state
+					.transfer(Address::User(*sender), *receiver, *amount)
+					.map_err(VmError::Transfer)?;
+			},
+			VmTransaction::Deploy { deployer, code, value } => {
+				let id = state.next_contract;
+				state
+					.contracts
+					.insert(id, Contract { code: code.clone(), storage: BTreeMap::new() });
+				state.next_contract += 1;
+				state
+					.transfer(Address::User(*deployer), Address::Contract(id), *value)
+					.map_err(VmError::Transfer)?;
+			},
+			VmTransaction::Call { caller, contract, args, value, gas_limit } => {
+				if *gas_limit > MAX_GAS {
+					return Err(VmError::GasLimitTooHigh { limit: *gas_limit, max: MAX_GAS })
+				}
+				let code = state
+					.contracts
+					.get(contract)
+					.ok_or(VmError::UnknownContract(*contract))?
+					.code
+					.clone();
+				state
+					.transfer(Address::User(*caller), Address::Contract(*contract), *value)
+					.map_err(VmError::Transfer)?;
+
+				let mut execution = Execution {
+					state: &mut state,
+					contract: *contract,
+					caller: Address::User(*caller),
+					value: *value,
+					args,
+					gas_left: *gas_limit,
+					stack: Vec::new(),
+				};
+				execution.run(&code)?;
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// The VM doesn't do anything special at block boundaries.
+impl BlockHooks for ContractVm {}
+
+/// Everything a running contract can see and touch.
+struct Execution<'a> {
+	state: &'a mut VmState,
+	contract: ContractId,
+	caller: Address,
+	value: u64,
+	args: &'a [u64],
+	gas_left: u64,
+	stack: Vec<u64>,
+}
+
+impl Execution<'_> {
+	fn pop(&mut self) -> Result<u64, Trap> {
+		self.stack.pop().ok_or(Trap::StackUnderflow)
+	}
+
+	fn push(&mut self, word: u64) -> Result<(), Trap> {
+		if self.stack.len() >= MAX_STACK {
+			return Err(Trap::StackOverflow)
+		}
+		self.stack.push(word);
+		Ok(())
+	}
+
+	/// Pop `b`, then `a`, and push `f(a, b)`.
+	fn binary(&mut self, f: impl Fn(u64, u64) -> Result<u64, Trap>) -> Result<(), Trap> {
+		let b = self.pop()?;
+		let a = self.pop()?;
+		self.push(f(a, b)?)
+	}
+
+	fn storage(&mut self) -> &mut BTreeMap<u64, u64> {
+		&mut self
+			.state
+			.contracts
+			.get_mut(&self.contract)
+			.expect("the contract is only executed if it exists; qed")
+			.storage
+	}
+
+	/// Execute the code until it stops, runs off the end, or traps.
+	fn run(&mut self, code: &[Op]) -> Result<(), VmError> {
+		let mut pc = 0;
+		while let Some(op) = code.get(pc) {
+			match self.step(*op, pc, code.len()) {
+				Ok(Some(next)) => pc = next,
+				Ok(None) => return Ok(()),
+				Err(trap) => return Err(VmError::Trapped { pc, trap }),
+			}
+		}
+		Ok(())
+	}
+
+	/// Execute a single instruction. Returns the next instruction to execute, or `None` to stop.
+	fn step(&mut self, op: Op, pc: usize, len: usize) -> Result<Option<usize>, Trap> {
+		self.gas_left = self.gas_left.checked_sub(op.gas()).ok_or(Trap::OutOfGas)?;
+
+		let jump = |target: usize| {
+			if target < len {
+				Ok(Some(target))
+			} else {
+				Err(Trap::InvalidJump(target))
+			}
+		};
+
+		match op {
+			Op::Push(word) => self.push(word)?,
+			Op::Pop => {
+				self.pop()?;
+			},
+			Op::Dup(depth) => {
+				let index = depth
+					.checked_add(1)
+					.and_then(|words| self.stack.len().checked_sub(words))
+					.ok_or(Trap::StackUnderflow)?;
+				self.push(self.stack[index])?;
+			},
+			Op::Swap => {
+				let b = self.pop()?;
+				let a = self.pop()?;
+				self.push(b)?;
+				self.push(a)?;
+			},
+			Op::Add => self.binary(|a, b| a.checked_add(b).ok_or(Trap::Overflow))?,
+			Op::Sub => self.binary(|a, b| a.checked_sub(b).ok_or(Trap::Overflow))?,
+			Op::Mul => self.binary(|a, b| a.checked_mul(b).ok_or(Trap::Overflow))?,
+			Op::Div => self.binary(|a, b| a.checked_div(b).ok_or(Trap::DivisionByZero))?,
+			Op::Mod => self.binary(|a, b| a.checked_rem(b).ok_or(Trap::DivisionByZero))?,
+			Op::Eq => self.binary(|a, b| Ok((a == b) as u64))?,
+			Op::Lt => self.binary(|a, b| Ok((a < b) as u64))?,
+			Op::Gt => self.binary(|a, b| Ok((a > b) as u64))?,
+			Op::IsZero => {
+				let a = self.pop()?;
+				self.push((a == 0) as u64)?;
+			},
+			Op::Jump(target) => return jump(target),
+			Op::JumpIf(target) =>
+				if self.pop()? != 0 {
+					return jump(target)
+				},
+			Op::Arg(index) => {
+				let arg = *self.args.get(index).ok_or(Trap::MissingArgument(index))?;
+				self.push(arg)?;
+			},
+			Op::Caller => self.push(self.caller.to_word())?,
+			Op::CallValue => self.push(self.value)?,
+			Op::SelfAddress => self.push(Address::Contract(self.contract).to_word())?,
+			Op::Balance => {
+				let word = self.pop()?;
+				let address = Address::from_word(word).ok_or(Trap::InvalidAddress(word))?;
+				self.push(self.state.balance(&address))?;
+			},
+			Op::Load => {
+				let key = self.pop()?;
+				let value = self.storage().get(&key).copied().unwrap_or(0);
+				self.push(value)?;
+			},
+			Op::Store => {
+				let key = self.pop()?;
+				let value = self.pop()?;
+				if value == 0 {
+					self.storage().remove(&key);
+				} else {
+					self.storage().insert(key, value);
+				}
+			},
+			Op::Transfer => {
+				let word = self.pop()?;
+				let receiver = Address::from_word(word).ok_or(Trap::InvalidAddress(word))?;
+				let amount = self.pop()?;
+				self.state.transfer(Address::Contract(self.contract), receiver, amount)?;
+			},
+			Op::Stop => return Ok(None),
+			Op::Revert => return Err(Trap::Revert),
+		}
+
+		Ok(Some(pc + 1))
+	}
+}
+
+/// A counter that adds its first argument to the number stored under key 0.
+#[cfg(test)]
+fn counter() -> Vec<Op> {
+	vec![Op::Push(0), Op::Load, Op::Arg(0), Op::Add, Op::Push(0), Op::Store]
+}
+
+/// A vault that accepts deposits of at least 10, and pays out its whole balance to Alice when
+/// called without value.
+#[cfg(test)]
+fn vault() -> Vec<Op> {
+	vec![
+		Op::CallValue,
+		Op::IsZero,
+		Op::JumpIf(7),
+		// Deposit: revert if the value is below 10.
+		Op::CallValue,
+		Op::Push(10),
+		Op::Lt,
+		Op::JumpIf(14),
+		// Withdraw: send the whole balance to Alice.
+		Op::CallValue,
+		Op::JumpIf(13),
+		Op::SelfAddress,
+		Op::Balance,
+		Op::Push(Address::User(User::Alice).to_word()),
+		Op::Transfer,
+		Op::Stop,
+		Op::Revert,
+	]
+}
+
+#[cfg(test)]
+fn call(contract: ContractId, args: Vec<u64>, value: u64, gas_limit: u64) -> VmTransaction {
+	VmTransaction::Call { caller: User::Bob, contract, args, value, gas_limit }
+}
+
+#[test]
+fn sm_18_deploy_and_call() {
+	let start = VmState::new([(User::Alice, 100)]);
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: counter(), value: 0 };
+	let deployed = ContractVm::try_next_state(&start, &deploy).unwrap();
+	let once = ContractVm::try_next_state(&deployed, &call(0, vec![5], 0, 100)).unwrap();
+	let twice = ContractVm::try_next_state(&once, &call(0, vec![7], 0, 100)).unwrap();
+
+	assert_eq!(twice.contracts[&0].storage, BTreeMap::from([(0, 12)]));
+	assert_eq!(twice.next_contract, 1);
+}
+
+#[test]
+fn sm_18_missing_argument_traps() {
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: counter(), value: 0 };
+	let deployed = ContractVm::try_next_state(&VmState::default(), &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, 100)),
+		Err(VmError::Trapped { pc: 2, trap: Trap::MissingArgument(0) })
+	);
+}
+
+#[test]
+fn sm_18_runaway_loop_runs_out_of_gas() {
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: vec![Op::Jump(0)], value: 0 };
+	let deployed = ContractVm::try_next_state(&VmState::default(), &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, 1_000)),
+		Err(VmError::Trapped { pc: 0, trap: Trap::OutOfGas })
+	);
+}
+
+#[test]
+fn sm_18_gas_limit_is_capped() {
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: vec![Op::Jump(0)], value: 0 };
+	let deployed = ContractVm::try_next_state(&VmState::default(), &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, MAX_GAS + 1)),
+		Err(VmError::GasLimitTooHigh { limit: MAX_GAS + 1, max: MAX_GAS })
+	);
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, MAX_GAS)),
+		Err(VmError::Trapped { pc: 0, trap: Trap::OutOfGas })
+	);
+}
+
+#[test]
+fn sm_18_dup_too_deep_traps() {
+	let code = vec![Op::Push(1), Op::Dup(usize::MAX)];
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code, value: 0 };
+	let deployed = ContractVm::try_next_state(&VmState::default(), &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, 100)),
+		Err(VmError::Trapped { pc: 1, trap: Trap::StackUnderflow })
+	);
+}
+
+#[test]
+fn sm_18_contract_moves_balances() {
+	let start = VmState::new([(User::Alice, 100), (User::Bob, 100)]);
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: vault(), value: 20 };
+	let deployed = ContractVm::try_next_state(&start, &deploy).unwrap();
+	let deposited = ContractVm::try_next_state(&deployed, &call(0, vec![], 30, 100)).unwrap();
+
+	assert_eq!(deposited.balance(&Address::Contract(0)), 50);
+	assert_eq!(deposited.balance(&Address::User(User::Bob)), 70);
+
+	let withdrawn = ContractVm::try_next_state(&deposited, &call(0, vec![], 0, 100)).unwrap();
+	assert_eq!(
+		withdrawn.balances,
+		BTreeMap::from([(Address::User(User::Alice), 130), (Address::User(User::Bob), 70)])
+	);
+}
+
+#[test]
+fn sm_18_revert_undoes_value_transfer() {
+	let start = VmState::new([(User::Alice, 100), (User::Bob, 100)]);
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code: vault(), value: 0 };
+	let deployed = ContractVm::try_next_state(&start, &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 5, 100)),
+		Err(VmError::Trapped { pc: 14, trap: Trap::Revert })
+	);
+	assert_eq!(ContractVm::next_state(&deployed, &call(0, vec![], 5, 100)), deployed);
+}
+
+#[test]
+fn sm_18_arithmetic_traps() {
+	let code = vec![Op::Push(1), Op::Push(0), Op::Div];
+	let deploy = VmTransaction::Deploy { deployer: User::Alice, code, value: 0 };
+	let deployed = ContractVm::try_next_state(&VmState::default(), &deploy).unwrap();
+
+	assert_eq!(
+		ContractVm::try_next_state(&deployed, &call(0, vec![], 0, 100)),
+		Err(VmError::Trapped { pc: 2, trap: Trap::DivisionByZero })
+	);
+}
+
+#[test]
+fn sm_18_runs_as_application_layer() {
+	use super::BlockContext;
+	use crate::c4_framework::execute_block;
+
+	let start = VmState::new([(User::Alice, 100)]);
+	let body = vec![
+		VmTransaction::Deploy { deployer: User::Alice, code: counter(), value: 10 },
+		VmTransaction::Call {
+			caller: User::Alice,
+			contract: 0,
+			args: vec![3],
+			value: 0,
+			gas_limit: 100,
+		},
+	];
+	let block = BlockContext { height: 1, author: None };
+	let end = execute_block::<ContractVm>(&start, &body, &block).unwrap();
+
+	assert_eq!(end.contracts[&0].storage, BTreeMap::from([(0, 3)]));
+	assert_eq!(end.balance(&Address::Contract(0)), 10);
+}
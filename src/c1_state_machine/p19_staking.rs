@@ -0,0 +1,602 @@
+//! The Proof of Authority engines in the consensus chapter take their authority set as a given.
+//! In a Proof of Stake chain, that set is elected on-chain instead, by users who put their own
+//! tokens at risk. This module is the on-chain half of that arrangement.
+//!
+//! Users bond some of their balance, which locks it in the lockable currency from part 10. A
+//! bonded user may then either offer to validate, or nominate some validators they trust with
+//! their stake. At the beginning of every era, the staking machine runs an election over all
+//! validators and nominators and stores the winners. Those winners are exactly the authorities
+//! that `SimplePoa` or `PoaRoundRobinBySlot` need for the next era.
+//!
+//! Getting your stake back is deliberately slow. Unbonded funds stay locked for an unbonding
+//! period, so that a validator who misbehaves can still be punished after they try to leave.
+//!
+//! Two election methods are provided. Top-N simply elects the candidates with the most backing.
+//! Sequential Phragmén elects them one at a time, always preferring the candidate whose backers
+//! have been used the least so far, which spreads the stake more evenly across the elected set.
+
+use super::{
+	p10_locks::{Lock, LockError, LockId, LockState, LockTransaction, LockableCurrency},
+	BlockContext, BlockHooks, StateMachine, TryStateMachine, User,
+};
+use crate::c3_consensus::ConsensusAuthority;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The id of the lock that holds bonded funds in the currency.
+pub const STAKING_ID: LockId = *b"staking ";
+
+/// The maximum number of validators a single nominator may back.
+pub const MAX_NOMINATIONS: usize = 16;
+
+/// Fixed point precision for the loads in sequential Phragmén. We avoid floats so that every node
+/// computes exactly the same election result.
+const LOAD_SCALE: u128 = 1_000_000_000_000_000_000;
+
+/// This state machine manages bonded funds and elects the consensus authorities.
+pub struct Staking;
+
+/// Some bonded funds that are on their way out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Unlocking {
+	/// How much is being unbonded
+	pub amount: u64,
+	/// The first block height at which the funds may be withdrawn
+	pub until: u64,
+}
+
+/// The bonded funds of a single user.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Ledger {
+	/// Funds that back the user's intention and count in elections
+	pub active: u64,
+	/// Funds that have been unbonded, but are still locked
+	pub unlocking: Vec<Unlocking>,
+}
+
+impl Ledger {
+	/// All funds in this ledger, which is how much must stay locked.
+	pub fn total(&self) -> u64 {
+		self.active + self.unlocking.iter().map(|chunk| chunk.amount).sum::<u64>()
+	}
+}
+
+/// What a bonded user wants to do with their stake.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Intention {
+	/// Stand for election as a validator, backed by their own stake
+	Validate,
+	/// Back the given validators with their stake
+	Nominate(Vec<User>),
+}
+
+/// The ways in which validators may be elected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ElectionMethod {
+	/// Elect the candidates with the most approval stake
+	TopN,
+	/// Elect candidates one at a time with sequential Phragmén
+	SequentialPhragmen,
+}
+
+/// The parameters of the staking system, which are fixed at genesis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StakingConfig {
+	/// How many blocks unbonded funds stay locked
+	pub unbonding_period: u64,
+	/// How many validators are elected. An election that finds fewer than this keeps the previous
+	/// authorities in place, so that the chain never loses the authorities it needs to make
+	/// blocks.
+	pub validator_count: usize,
+	/// How many blocks an era lasts. A new election runs at the start of every era.
+	pub era_length: u64,
+	/// How validators are elected
+	pub election: ElectionMethod,
+}
+
+/// The state of the staking system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StakingState {
+	/// The underlying currency, where bonded funds are locked
+	pub currency: LockState,
+	/// The bonded funds of every user who has bonded anything
+	pub ledgers: BTreeMap<User, Ledger>,
+	/// What each bonded user wants to do with their stake. Users without an entry are chilled.
+	pub intentions: BTreeMap<User, Intention>,
+	/// The parameters of the staking system
+	pub config: StakingConfig,
+	/// The current era
+	pub era: u64,
+	/// The authorities elected for the current era, in the order they were elected
+	pub authorities: Vec<User>,
+}
+
+/// One voter in an election, with the stake they vote with and the candidates they approve of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Voter {
+	/// The stake behind the vote
+	pub stake: u64,
+	/// The candidates this voter approves of
+	pub targets: Vec<User>,
+}
+
+impl StakingState {
+	/// A fresh staking system at genesis, with the given currency, parameters, and initial
+	/// authorities.
+	pub fn new(currency: LockState, config: StakingConfig, authorities: Vec<User>) -> Self {
+		StakingState {
+			currency,
+			ledgers: BTreeMap::new(),
+			intentions: BTreeMap::new(),
+			config,
+			era: 0,
+			authorities,
+		}
+	}
+
+	/// The active bonded stake of the given user.
+	pub fn active(&self, who: User) -> u64 {
+		self.ledgers.get(&who).map(|ledger| ledger.active).unwrap_or(0)
+	}
+
+	/// The users who have offered to validate.
+	pub fn candidates(&self) -> Vec<User> {
+		self.intentions
+			.iter()
+			.filter(|(_, intention)| **intention == Intention::Validate)
+			.map(|(who, _)| *who)
+			.collect()
+	}
+
+	/// Every validator votes for themselves, and every nominator votes for their targets.
+	pub fn voters(&self) -> Vec<Voter> {
+		self.intentions
+			.iter()
+			.map(|(who, intention)| Voter {
+				stake: self.active(*who),
+				targets: match intention {
+					Intention::Validate => vec![*who],
+					Intention::Nominate(targets) => targets.clone(),
+				},
+			})
+			.collect()
+	}
+
+	/// Run an election with the configured method, without changing anything.
+	pub fn elect(&self) -> Vec<User> {
+		let candidates = self.candidates();
+		let voters = self.voters();
+		let count = self.config.validator_count;
+		match self.config.election {
+			ElectionMethod::TopN => top_n(&candidates, &voters, count),
+			ElectionMethod::SequentialPhragmen => sequential_phragmen(&candidates, &voters, count),
+		}
+	}
+
+	/// The current authorities, in the form the consensus engines expect.
+	pub fn authority_set(&self) -> Vec<ConsensusAuthority> {
+		self.authorities.iter().map(|who| ConsensusAuthority::from(*who)).collect()
+	}
+
+	/// Destroy up to the given amount of the user's bonded funds, and return how much was
+	/// destroyed. Active stake is slashed first, then unlocking funds, so that unbonding doesn't
+	/// help anyone escape a slash.
+	pub fn slash(&mut self, who: User, amount: u64) -> u64 {
+		let Some(ledger) = self.ledgers.get_mut(&who) else { return 0 };
+		let slashed = amount.min(ledger.total());
+		let mut remaining = slashed;
+		let from_active = ledger.active.min(remaining);
+		ledger.active -= from_active;
+		remaining -= from_active;
+		for chunk in ledger.unlocking.iter_mut() {
+			let from_chunk = chunk.amount.min(remaining);
+			chunk.amount -= from_chunk;
+			remaining -= from_chunk;
+		}
+		ledger.unlocking.retain(|chunk| chunk.amount > 0);
+		if ledger.active == 0 {
+			self.intentions.remove(&who);
+		}
+
+		self.currency.slash(who, slashed);
+		self.update_lock(who)
+			.expect("the free balance covered the ledger before the slash; qed");
+		slashed
+	}
+
+	/// Make the staking lock match the user's ledger, dropping the ledger once it is empty.
+	fn update_lock(&mut self, who: User) -> Result<(), StakingError> {
+		let total = self.ledgers.get(&who).map(Ledger::total).unwrap_or(0);
+		if total == 0 {
+			self.ledgers.remove(&who);
+			self.currency.remove_lock(who, STAKING_ID);
+			return Ok(())
+		}
+		let lock = Lock { amount: total, until: u64::MAX };
+		self.currency.set_lock(who, STAKING_ID, lock).map_err(StakingError::Currency)
+	}
+}
+
+/// The approval stake of every candidate: the sum of the stake of every voter who approves of it.
+fn approvals(candidates: &[User], voters: &[Voter]) -> BTreeMap<User, u128> {
+	let mut approvals: BTreeMap<User, u128> = candidates.iter().map(|c| (*c, 0)).collect();
+	for voter in voters {
+		let targets: BTreeSet<&User> = voter.targets.iter().collect();
+		for target in targets {
+			if let Some(approval) = approvals.get_mut(target) {
+				*approval += voter.stake as u128;
+			}
+		}
+	}
+	approvals
+}
+
+/// Elect the `count` candidates with the most approval stake. Ties are broken in favour of the
+/// user that sorts first. Candidates without any backing are never elected.
+pub fn top_n(candidates: &[User], voters: &[Voter], count: usize) -> Vec<User> {
+	let mut ranked: Vec<(User, u128)> = approvals(candidates, voters)
+		.into_iter()
+		.filter(|(_, approval)| *approval > 0)
+		.collect();
+	ranked.sort_by(|(a, approval_a), (b, approval_b)| approval_b.cmp(approval_a).then(a.cmp(b)));
+	ranked.into_iter().take(count).map(|(who, _)| who).collect()
+}
+
+/// Elect `count` candidates with sequential Phragmén.
+///
+/// Every voter carries a load, which starts at zero. In each round, the score of a candidate is
+/// the load its backers would end up carrying if it were elected: one unit of load, plus the
+/// loads its backers already carry weighted by their stake, divided by its approval stake. The
+/// candidate with the lowest score wins the round, and all of its backers take on that score as
+/// their new load. Voters who already helped elect someone are therefore worth less in later
+/// rounds.
+pub fn sequential_phragmen(candidates: &[User], voters: &[Voter], count: usize) -> Vec<User> {
+	let approvals = approvals(candidates, voters);
+	let mut loads = vec![0u128; voters.len()];
+	let mut elected = Vec::new();
+
+	while elected.len() < count {
+		let mut best: Option<(u128, User)> = None;
+		for (candidate, approval) in &approvals {
+			if *approval == 0 || elected.contains(candidate) {
+				continue
+			}
+			let weighted_loads = voters
+				.iter()
+				.zip(&loads)
+				.filter(|(voter, _)| voter.targets.contains(candidate))
+				.fold(0u128, |sum, (voter, load)| {
+					sum.saturating_add((voter.stake as u128).saturating_mul(*load))
+				});
+			let score = LOAD_SCALE.saturating_add(weighted_loads) / approval;
+			// Candidates are visited in order, so a strict comparison breaks ties in favour of the
+			// user that sorts first.
+			if best.is_none_or(|(best_score, _)| score < best_score) {
+				best = Some((score, *candidate));
+			}
+		}
+
+		let Some((score, winner)) = best else { break };
+		for (voter, load) in voters.iter().zip(loads.iter_mut()) {
+			if voter.targets.contains(&winner) {
+				*load = score;
+			}
+		}
+		elected.push(winner);
+	}
+
+	elected
+}
+
+/// The state transitions that users can make in the staking system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StakingTransaction {
+	/// A transaction on the underlying currency
+	Currency(LockTransaction),
+	/// Bond some more of your free balance
+	Bond { who: User, amount: u64 },
+	/// Start unbonding some of your active stake
+	Unbond { who: User, amount: u64 },
+	/// Unlock all unbonded funds whose unbonding period has passed
+	WithdrawUnbonded { who: User },
+	/// Offer to validate from the next election on
+	Validate { who: User },
+	/// Back the given validators from the next election on
+	Nominate { who: User, targets: Vec<User> },
+	/// Stop validating or nominating
+	Chill { who: User },
+}
+
+/// The reasons a staking transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StakingError {
+	/// The currency rejected the transaction.
+	Currency(LockError),
+	/// Bonding or unbonding zero is pointless.
+	ZeroAmount,
+	/// The user has no active stake.
+	NotBonded,
+	/// The user's free balance can't cover everything they tried to bond.
+	InsufficientFree { free: u64, requested: u64 },
+	/// The user tried to unbond more than their active stake.
+	InsufficientBonded { bonded: u64, requested: u64 },
+	/// None of the user's unbonding funds may be withdrawn yet.
+	NothingToWithdraw,
+	/// A nomination must name at least one validator.
+	NoTargets,
+	/// A nomination may name at most `MAX_NOMINATIONS` validators.
+	TooManyTargets,
+	/// The bonded amount would exceed `u64::MAX`.
+	Overflow,
+}
+
+impl StateMachine for Staking {
+	type State = StakingState;
+	type Transition = StakingTransaction;
+
+	fn next_state(starting_state: &StakingState, t: &StakingTransaction) -> StakingState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Staking".into()
+	}
+}
+
+impl TryStateMachine for Staking {
+	type Error = StakingError;
+
+	fn try_next_state(
+		starting_state: &StakingState,
+		t: &StakingTransaction,
+	) -> Result<StakingState, StakingError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			StakingTransaction::Currency(call) => {
+				state.currency = LockableCurrency::try_next_state(&state.currency, call)
+					.map_err(StakingError::Currency)?;
+			},
+			StakingTransaction::Bond { amount: 0, .. } |
+			StakingTransaction::Unbond { amount: 0, .. } => return Err(StakingError::ZeroAmount),
+			StakingTransaction::Bond { who, amount } => {
+				let ledger = state.ledgers.entry(*who).or_default();
+				let requested =
+					ledger.total().checked_add(*amount).ok_or(StakingError::Overflow)?;
+				let free = state.currency.account(*who).map(|account| account.free).unwrap_or(0);
+				if free < requested {
+					return Err(StakingError::InsufficientFree { free, requested })
+				}
+				ledger.active += amount;
+				state.update_lock(*who)?;
+			},
+			StakingTransaction::Unbond { who, amount } => {
+				let until = state
+					.currency
+					.height
+					.checked_add(state.config.unbonding_period)
+					.ok_or(StakingError::Overflow)?;
+				let ledger = state.ledgers.get_mut(who).ok_or(StakingError::NotBonded)?;
+				if ledger.active < *amount {
+					return Err(StakingError::InsufficientBonded {
+						bonded: ledger.active,
+						requested: *amount,
+					})
+				}
+				ledger.active -= amount;
+				ledger.unlocking.push(Unlocking { amount: *amount, until });
+				if ledger.active == 0 {
+					state.intentions.remove(who);
+				}
+			},
+			StakingTransaction::WithdrawUnbonded { who } => {
+				let height = state.currency.height;
+				let ledger = state.ledgers.get_mut(who).ok_or(StakingError::NotBonded)?;
+				let before = ledger.unlocking.len();
+				ledger.unlocking.retain(|chunk| chunk.until > height);
+				if ledger.unlocking.len() == before {
+					return Err(StakingError::NothingToWithdraw)
+				}
+				state.update_lock(*who)?;
+			},
+			StakingTransaction::Validate { who } => {
+				if state.active(*who) == 0 {
+					return Err(StakingError::NotBonded)
+				}
+				state.intentions.insert(*who, Intention::Validate);
+			},
+			StakingTransaction::Nominate { who, targets } => {
+				if state.active(*who) == 0 {
+					return Err(StakingError::NotBonded)
+				}
+				if targets.is_empty() {
+					return Err(StakingError::NoTargets)
+				}
+				if targets.len() > MAX_NOMINATIONS {
+					return Err(StakingError::TooManyTargets)
+				}
+				state.intentions.insert(*who, Intention::Nominate(targets.clone()));
+			},
+			StakingTransaction::Chill { who } => {
+				state.intentions.remove(who);
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// At the beginning of every block, the currency learns the new height. At the beginning of every
+/// era, a new set of authorities is elected from the current validators and nominators. When the
+/// election can't fill every seat, the previous authorities carry on for another era.
+impl BlockHooks for Staking {
+	fn on_initialize(state: &StakingState, block: &BlockContext) -> StakingState {
+		let mut state = state.clone();
+		state.currency = LockableCurrency::on_initialize(&state.currency, block);
+		let era_length = state.config.era_length;
+		if era_length > 0 && block.height > 0 && block.height.is_multiple_of(era_length) {
+			state.era += 1;
+			let elected = state.elect();
+			if !elected.is_empty() && elected.len() >= state.config.validator_count {
+				state.authorities = elected;
+			}
+		}
+		state
+	}
+}
+
+#[cfg(test)]
+fn test_state(election: ElectionMethod) -> StakingState {
+	let currency = LockState::new([(User::Alice, 100), (User::Bob, 100), (User::Charlie, 100)]);
+	let config =
+		StakingConfig { unbonding_period: 10, validator_count: 2, era_length: 5, election };
+	StakingState::new(currency, config, vec![User::Alice])
+}
+
+#[test]
+fn sm_19_bonding_locks_funds() {
+	let start = test_state(ElectionMethod::TopN);
+	let bond = StakingTransaction::Bond { who: User::Alice, amount: 60 };
+	let end = Staking::try_next_state(&start, &bond).unwrap();
+
+	assert_eq!(end.active(User::Alice), 60);
+	assert_eq!(end.currency.usable(User::Alice), 40);
+
+	let spend = StakingTransaction::Currency(LockTransaction::Transfer {
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 50,
+	});
+	assert_eq!(
+		Staking::try_next_state(&end, &spend),
+		Err(StakingError::Currency(LockError::InsufficientUsable { usable: 40, requested: 50 }))
+	);
+}
+
+#[test]
+fn sm_19_cannot_bond_more_than_free() {
+	let start = test_state(ElectionMethod::TopN);
+	let bond = StakingTransaction::Bond { who: User::Alice, amount: 60 };
+	let bonded = Staking::try_next_state(&start, &bond).unwrap();
+
+	assert_eq!(
+		Staking::try_next_state(&bonded, &bond),
+		Err(StakingError::InsufficientFree { free: 100, requested: 120 })
+	);
+}
+
+#[test]
+fn sm_19_unbonded_funds_stay_locked_for_the_unbonding_period() {
+	let start = test_state(ElectionMethod::TopN);
+	let bond = StakingTransaction::Bond { who: User::Alice, amount: 60 };
+	let bonded = Staking::try_next_state(&start, &bond).unwrap();
+	let at_three = Staking::on_initialize(&bonded, &BlockContext { height: 3, author: None });
+	let unbond = StakingTransaction::Unbond { who: User::Alice, amount: 60 };
+	let unbonding = Staking::try_next_state(&at_three, &unbond).unwrap();
+
+	assert_eq!(unbonding.active(User::Alice), 0);
+	assert_eq!(unbonding.currency.usable(User::Alice), 40);
+
+	let withdraw = StakingTransaction::WithdrawUnbonded { who: User::Alice };
+	let at_twelve = Staking::on_initialize(&unbonding, &BlockContext { height: 12, author: None });
+	assert_eq!(
+		Staking::try_next_state(&at_twelve, &withdraw),
+		Err(StakingError::NothingToWithdraw)
+	);
+
+	let at_thirteen =
+		Staking::on_initialize(&unbonding, &BlockContext { height: 13, author: None });
+	let end = Staking::try_next_state(&at_thirteen, &withdraw).unwrap();
+	assert_eq!(end.currency.usable(User::Alice), 100);
+	assert!(end.ledgers.is_empty());
+}
+
+#[test]
+fn sm_19_intentions_require_stake() {
+	let start = test_state(ElectionMethod::TopN);
+	let validate = StakingTransaction::Validate { who: User::Alice };
+	assert_eq!(Staking::try_next_state(&start, &validate), Err(StakingError::NotBonded));
+
+	let bond = StakingTransaction::Bond { who: User::Bob, amount: 10 };
+	let bonded = Staking::try_next_state(&start, &bond).unwrap();
+	let nominate = StakingTransaction::Nominate { who: User::Bob, targets: vec![User::Alice] };
+	let nominating = Staking::try_next_state(&bonded, &nominate).unwrap();
+	assert_eq!(
+		nominating.intentions.get(&User::Bob),
+		Some(&Intention::Nominate(vec![User::Alice]))
+	);
+
+	let chill = StakingTransaction::Chill { who: User::Bob };
+	let chilled = Staking::try_next_state(&nominating, &chill).unwrap();
+	assert!(chilled.intentions.is_empty());
+	assert_eq!(chilled.active(User::Bob), 10);
+}
+
+#[test]
+fn sm_19_phragmen_spreads_backing_where_top_n_does_not() {
+	let candidates = [User::Alice, User::Bob, User::Charlie];
+	let voters = [
+		Voter { stake: 10, targets: vec![User::Alice, User::Bob] },
+		Voter { stake: 10, targets: vec![User::Alice, User::Bob] },
+		Voter { stake: 12, targets: vec![User::Charlie] },
+	];
+
+	assert_eq!(top_n(&candidates, &voters, 2), vec![User::Alice, User::Bob]);
+	assert_eq!(sequential_phragmen(&candidates, &voters, 2), vec![User::Alice, User::Charlie]);
+}
+
+#[test]
+fn sm_19_new_era_elects_authorities() {
+	let mut state = test_state(ElectionMethod::SequentialPhragmen);
+	for t in [
+		StakingTransaction::Bond { who: User::Alice, amount: 10 },
+		StakingTransaction::Validate { who: User::Alice },
+		StakingTransaction::Bond { who: User::Bob, amount: 30 },
+		StakingTransaction::Validate { who: User::Bob },
+		StakingTransaction::Bond { who: User::Charlie, amount: 50 },
+		StakingTransaction::Nominate { who: User::Charlie, targets: vec![User::Alice] },
+	] {
+		state = Staking::try_next_state(&state, &t).unwrap();
+	}
+
+	let mid_era = Staking::on_initialize(&state, &BlockContext { height: 4, author: None });
+	assert_eq!(mid_era.era, 0);
+	assert_eq!(mid_era.authorities, vec![User::Alice]);
+
+	let next_era = Staking::on_initialize(&state, &BlockContext { height: 5, author: None });
+	assert_eq!(next_era.era, 1);
+	assert_eq!(next_era.authorities, vec![User::Alice, User::Bob]);
+	assert_eq!(next_era.authority_set(), vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob]);
+}
+
+#[test]
+fn sm_19_short_election_keeps_previous_authorities() {
+	let mut state = test_state(ElectionMethod::TopN);
+	for t in [
+		StakingTransaction::Bond { who: User::Bob, amount: 30 },
+		StakingTransaction::Validate { who: User::Bob },
+	] {
+		state = Staking::try_next_state(&state, &t).unwrap();
+	}
+
+	// Only Bob offers to validate, but two seats need filling.
+	let next_era = Staking::on_initialize(&state, &BlockContext { height: 5, author: None });
+	assert_eq!(next_era.era, 1);
+	assert_eq!(next_era.authorities, vec![User::Alice]);
+
+	// Nobody at all offers to validate.
+	let chill = StakingTransaction::Chill { who: User::Bob };
+	let state = Staking::try_next_state(&state, &chill).unwrap();
+	let next_era = Staking::on_initialize(&state, &BlockContext { height: 5, author: None });
+	assert_eq!(next_era.authorities, vec![User::Alice]);
+}
+
+#[test]
+fn sm_19_unbonding_deadline_overflow() {
+	let state = test_state(ElectionMethod::TopN);
+	let bond = StakingTransaction::Bond { who: User::Alice, amount: 10 };
+	let state = Staking::try_next_state(&state, &bond).unwrap();
+	let state = Staking::on_initialize(&state, &BlockContext { height: u64::MAX, author: None });
+	let unbond = StakingTransaction::Unbond { who: User::Alice, amount: 10 };
+
+	assert_eq!(Staking::try_next_state(&state, &unbond), Err(StakingError::Overflow));
+}
@@ -2,7 +2,7 @@
 //! In these examples, we use actually switch boards as the state machine. The state is,
 //! well, just the state of the switches.
 
-use super::StateMachine;
+use super::{explorer::Explorable, repl::Interactive, StateMachine};
 
 /// This state machine models a single light switch.
 /// The internal state, a bool, represents whether the switch is on or not.
@@ -17,6 +17,35 @@ impl StateMachine for LightSwitch {
 	fn next_state(starting_state: &bool, t: &()) -> bool {
 		todo!("Exercise 1")
 	}
+
+	fn human_name() -> String {
+		"Light Switch".into()
+	}
+}
+
+impl Interactive for LightSwitch {
+	fn initial_state() -> bool {
+		false
+	}
+
+	fn parse_transition(input: &str) -> Result<(), String> {
+		match input {
+			"toggle" => Ok(()),
+			_ => Err("the only transition is `toggle`".into()),
+		}
+	}
+
+	fn format_transition(_: &()) -> String {
+		"toggle".into()
+	}
+
+	fn format_state(state: &bool) -> String {
+		if *state { "on" } else { "off" }.into()
+	}
+
+	fn transition_help() -> String {
+		"  toggle".into()
+	}
 }
 
 /// This second  state machine models two light switches with one weird property.
@@ -24,7 +53,7 @@ impl StateMachine for LightSwitch {
 pub struct WeirdSwitchMachine;
 
 /// The state is now two switches instead of one so we use a struct.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct TwoSwitches {
 	first_switch: bool,
 	second_switch: bool,
@@ -44,6 +73,55 @@ impl StateMachine for WeirdSwitchMachine {
 	fn next_state(starting_state: &TwoSwitches, t: &Toggle) -> TwoSwitches {
 		todo!("Exercise 2")
 	}
+
+	fn human_name() -> String {
+		"Weird Switches".into()
+	}
+}
+
+impl Interactive for WeirdSwitchMachine {
+	fn initial_state() -> TwoSwitches {
+		TwoSwitches { first_switch: false, second_switch: false }
+	}
+
+	fn parse_transition(input: &str) -> Result<Toggle, String> {
+		match input {
+			"toggle first" => Ok(Toggle::FirstSwitch),
+			"toggle second" => Ok(Toggle::SecondSwitch),
+			_ => Err("expected `toggle first` or `toggle second`".into()),
+		}
+	}
+
+	fn format_transition(t: &Toggle) -> String {
+		match t {
+			Toggle::FirstSwitch => "toggle first".into(),
+			Toggle::SecondSwitch => "toggle second".into(),
+		}
+	}
+
+	fn format_state(state: &TwoSwitches) -> String {
+		format!(
+			"first: {}, second: {}",
+			LightSwitch::format_state(&state.first_switch),
+			LightSwitch::format_state(&state.second_switch),
+		)
+	}
+
+	fn transition_help() -> String {
+		"  toggle first\n  toggle second".into()
+	}
+}
+
+impl Explorable for LightSwitch {
+	fn transitions() -> Vec<()> {
+		vec![()]
+	}
+}
+
+impl Explorable for WeirdSwitchMachine {
+	fn transitions() -> Vec<Toggle> {
+		vec![Toggle::FirstSwitch, Toggle::SecondSwitch]
+	}
 }
 
 #[test]
@@ -107,3 +185,32 @@ fn sm_1_two_switches_second_goes_off() {
 		TwoSwitches { first_switch: true, second_switch: false }
 	);
 }
+
+#[test]
+fn sm_1_light_switch_explores_both_states() {
+	use super::explorer::explore;
+
+	let exploration = explore::<LightSwitch>(false, 10);
+
+	assert!(exploration.complete);
+	assert_eq!(exploration.states, vec![false, true]);
+	assert!(exploration.dead_states().is_empty());
+	assert_eq!(exploration.cycles(), vec![vec![0, 1]]);
+}
+
+#[test]
+fn sm_1_two_switches_every_state_is_reachable() {
+	use super::explorer::explore;
+
+	let start = TwoSwitches { first_switch: false, second_switch: false };
+	let exploration = explore::<WeirdSwitchMachine>(start, 10);
+	let universe = [false, true].into_iter().flat_map(|first_switch| {
+		[false, true].map(|second_switch| TwoSwitches { first_switch, second_switch })
+	});
+
+	assert!(exploration.complete);
+	assert_eq!(exploration.states.len(), 4);
+	assert!(exploration.unreachable_states(universe).is_empty());
+	assert!(exploration.dead_states().is_empty());
+	assert_eq!(exploration.cycles(), vec![vec![0, 1, 2, 3]]);
+}
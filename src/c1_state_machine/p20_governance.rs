@@ -0,0 +1,553 @@
+//! A chain that wants to live for a long time must be able to change its own rules. Rather than
+//! relying on a central authority, or on every node operator upgrading at once, many chains let
+//! their token holders decide on-chain. This module implements such a governance system.
+//!
+//! Any user may submit a proposal. A proposal either wraps a transition of the governed module,
+//! which users can't submit directly, or schedules a change to the consensus rules at a future
+//! fork height, like the forks from the consensus chapter. Every proposal is put to a referendum,
+//! in which token holders vote aye or nay with their accounted currency balance. Since balances
+//! are voting weight, nobody may mint more of them; the supply is fixed at genesis. When the voting
+//! period ends, the votes are tallied, and approved proposals are enacted after an enactment
+//! delay, which gives everyone who disagrees time to react.
+//!
+//! How many votes it takes to approve a proposal is a policy decision, so the tally rule is
+//! pluggable. A simple majority is the obvious choice, but it lets a small but motivated group
+//! decide for everyone when turnout is low. Supermajorities and turnout biased rules require more
+//! agreement in that case.
+
+use super::{
+	p4_accounted_currency::{AccountedCurrency, AccountingError, AccountingTransaction, Balances},
+	BlockContext, BlockHooks, StateMachine, TryStateMachine, User,
+};
+use crate::c3_consensus::ConsensusAuthority;
+use std::{collections::BTreeMap, marker::PhantomData};
+
+/// Referenda are identified by the order in which they were proposed.
+pub type ReferendumId = u64;
+
+/// A rule that decides whether a referendum passed.
+pub trait Tally {
+	/// Given the balance voting aye, the balance voting nay, and the total issuance, decide
+	/// whether the proposal is approved.
+	fn approved(aye: u128, nay: u128, electorate: u128) -> bool;
+}
+
+/// More aye than nay votes.
+pub struct SimpleMajority;
+
+impl Tally for SimpleMajority {
+	fn approved(aye: u128, nay: u128, _electorate: u128) -> bool {
+		aye > nay
+	}
+}
+
+/// At least two thirds of the turnout voted aye.
+pub struct Supermajority;
+
+impl Tally for Supermajority {
+	fn approved(aye: u128, nay: u128, _electorate: u128) -> bool {
+		aye > 0 && aye * 3 >= (aye + nay) * 2
+	}
+}
+
+/// A low turnout requires a larger majority to approve. With full turnout, a simple majority is
+/// enough. Good for proposals that should only pass when many holders care about them.
+pub struct PositiveTurnoutBias;
+
+impl Tally for PositiveTurnoutBias {
+	fn approved(aye: u128, nay: u128, electorate: u128) -> bool {
+		aye * integer_sqrt(aye + nay) > nay * integer_sqrt(electorate)
+	}
+}
+
+/// A low turnout requires a larger majority to reject. With full turnout, a simple majority is
+/// enough. Good for proposals that should pass unless many holders object.
+pub struct NegativeTurnoutBias;
+
+impl Tally for NegativeTurnoutBias {
+	fn approved(aye: u128, nay: u128, electorate: u128) -> bool {
+		aye * integer_sqrt(electorate) > nay * integer_sqrt(aye + nay)
+	}
+}
+
+/// The largest integer whose square does not exceed `n`.
+fn integer_sqrt(n: u128) -> u128 {
+	if n < 2 {
+		return n
+	}
+	let mut x = n;
+	let mut y = x.div_ceil(2);
+	while y < x {
+		x = y;
+		y = (x + n / x) / 2;
+	}
+	x
+}
+
+/// A change to the consensus rules that can be made by a fork.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConsensusChange {
+	/// Hand block authoring to a new set of authorities, as in `change_authorities`
+	Authorities(Vec<ConsensusAuthority>),
+	/// Change the proof of work difficulty, as in `change_difficulty`
+	Difficulty(u64),
+}
+
+/// A consensus change that has been enacted, and that nodes must apply from the fork height on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ScheduledFork {
+	/// The first block height at which the new consensus rules apply
+	pub fork_height: u64,
+	/// What changes at the fork
+	pub change: ConsensusChange,
+}
+
+/// Something that token holders may decide to do.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Proposal<T> {
+	/// Execute a transition of the governed module
+	Call(T),
+	/// Change the consensus rules at the given height
+	Fork(ScheduledFork),
+}
+
+/// A holder's vote in a referendum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Vote {
+	/// In favour of the proposal
+	Aye,
+	/// Against the proposal
+	Nay,
+}
+
+/// Where a referendum is in its life cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReferendumStatus {
+	/// Holders may vote until the given height
+	Voting { ends: u64 },
+	/// The proposal was approved, and will be enacted at the given height
+	Approved { enactment: u64 },
+	/// The proposal was rejected
+	Rejected,
+	/// The proposal was enacted
+	Enacted,
+	/// The proposal was approved, but the governed module rejected the call at enactment
+	Failed,
+}
+
+/// A proposal that has been put to a vote.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Referendum<T> {
+	/// The user who submitted the proposal
+	pub proposer: User,
+	/// What happens if the proposal is approved
+	pub proposal: Proposal<T>,
+	/// The votes cast so far. Each holder has a single vote, which they may change while voting
+	/// is open.
+	pub votes: BTreeMap<User, Vote>,
+	/// Where the referendum is in its life cycle
+	pub status: ReferendumStatus,
+}
+
+/// The parameters of the governance system, which are fixed at genesis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GovernanceConfig {
+	/// How many blocks holders may vote on a proposal
+	pub voting_period: u64,
+	/// How many blocks pass between approval and enactment
+	pub enactment_delay: u64,
+}
+
+/// This state machine governs another state machine `SM`, and decides referenda with the tally
+/// rule `T`.
+pub struct Governance<SM, T>(PhantomData<(SM, T)>);
+
+/// The state of the governance system, where `S` is the state of the governed module and `T` its
+/// transition type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GovernanceState<S, T> {
+	/// The height of the block currently being executed
+	pub height: u64,
+	/// The balances that holders vote with
+	pub balances: Balances,
+	/// The state of the governed module
+	pub runtime: S,
+	/// The parameters of the governance system
+	pub config: GovernanceConfig,
+	/// Every referendum ever proposed
+	pub referenda: BTreeMap<ReferendumId, Referendum<T>>,
+	/// Every consensus change that has been enacted, in order of enactment
+	pub forks: Vec<ScheduledFork>,
+}
+
+impl<S, T> GovernanceState<S, T> {
+	/// A fresh governance system at genesis.
+	pub fn new(balances: Balances, runtime: S, config: GovernanceConfig) -> Self {
+		GovernanceState {
+			height: 0,
+			balances,
+			runtime,
+			config,
+			referenda: BTreeMap::new(),
+			forks: Vec::new(),
+		}
+	}
+
+	/// The balance voting aye and the balance voting nay in the given referendum, counted with the
+	/// current balances.
+	pub fn turnout(&self, referendum: &Referendum<T>) -> (u128, u128) {
+		let mut aye = 0;
+		let mut nay = 0;
+		for (voter, vote) in &referendum.votes {
+			let balance = self.balances.get(voter).copied().unwrap_or(0) as u128;
+			match vote {
+				Vote::Aye => aye += balance,
+				Vote::Nay => nay += balance,
+			}
+		}
+		(aye, nay)
+	}
+
+	/// The total balance of all holders.
+	pub fn electorate(&self) -> u128 {
+		self.balances.values().map(|balance| *balance as u128).sum()
+	}
+}
+
+/// The state transitions that users can make in the governance system. There is deliberately no
+/// way to call the governed module directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GovernanceTransaction<T> {
+	/// A transaction on the currency that holders vote with. Minting is not allowed, since it
+	/// would create voting weight out of thin air.
+	Currency(AccountingTransaction),
+	/// Put a proposal to a vote
+	Propose { proposer: User, proposal: Proposal<T> },
+	/// Vote in a referendum, replacing any earlier vote by the same holder
+	Vote { voter: User, referendum: ReferendumId, vote: Vote },
+}
+
+/// The reasons a governance transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GovernanceError {
+	/// The currency rejected the transaction.
+	Currency(AccountingError),
+	/// Balances are voting weight, so they can't be minted.
+	MintingDisabled,
+	/// The voting period or enactment delay would run past `u64::MAX`.
+	Overflow,
+	/// There is no referendum with this id.
+	UnknownReferendum,
+	/// Voting on this referendum has ended.
+	VotingClosed,
+	/// Only holders may vote.
+	NoBalance,
+	/// A fork must be far enough in the future that it can't happen before the proposal could be
+	/// enacted.
+	ForkTooSoon { fork_height: u64, earliest: u64 },
+}
+
+impl<SM, T> StateMachine for Governance<SM, T>
+where
+	SM: TryStateMachine,
+	SM::State: Clone,
+	SM::Transition: Clone,
+	T: Tally,
+{
+	type State = GovernanceState<SM::State, SM::Transition>;
+	type Transition = GovernanceTransaction<SM::Transition>;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		format!("Governance of {}", SM::human_name())
+	}
+}
+
+impl<SM, T> TryStateMachine for Governance<SM, T>
+where
+	SM: TryStateMachine,
+	SM::State: Clone,
+	SM::Transition: Clone,
+	T: Tally,
+{
+	type Error = GovernanceError;
+
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, GovernanceError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			GovernanceTransaction::Currency(AccountingTransaction::Mint { .. }) =>
+				return Err(GovernanceError::MintingDisabled),
+			GovernanceTransaction::Currency(call) => {
+				state.balances = AccountedCurrency::try_next_state(&state.balances, call)
+					.map_err(GovernanceError::Currency)?;
+			},
+			GovernanceTransaction::Propose { proposer, proposal } => {
+				let ends = state
+					.height
+					.checked_add(state.config.voting_period)
+					.ok_or(GovernanceError::Overflow)?;
+				// Checking the enactment height here means the tally never has to.
+				let enactment = ends
+					.checked_add(state.config.enactment_delay)
+					.ok_or(GovernanceError::Overflow)?;
+				if let Proposal::Fork(fork) = proposal {
+					let earliest = enactment.checked_add(1).ok_or(GovernanceError::Overflow)?;
+					if fork.fork_height < earliest {
+						return Err(GovernanceError::ForkTooSoon {
+							fork_height: fork.fork_height,
+							earliest,
+						})
+					}
+				}
+				let id = state.referenda.len() as ReferendumId;
+				let referendum = Referendum {
+					proposer: *proposer,
+					proposal: proposal.clone(),
+					votes: BTreeMap::new(),
+					status: ReferendumStatus::Voting { ends },
+				};
+				state.referenda.insert(id, referendum);
+			},
+			GovernanceTransaction::Vote { voter, referendum, vote } => {
+				if state.balances.get(voter).copied().unwrap_or(0) == 0 {
+					return Err(GovernanceError::NoBalance)
+				}
+				let referendum = state
+					.referenda
+					.get_mut(referendum)
+					.ok_or(GovernanceError::UnknownReferendum)?;
+				if !matches!(referendum.status, ReferendumStatus::Voting { .. }) {
+					return Err(GovernanceError::VotingClosed)
+				}
+				referendum.votes.insert(*voter, *vote);
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// At the beginning of every block, the governed module runs its own hooks. Then referenda whose
+/// voting period is over are tallied, and approved proposals whose enactment delay is over are
+/// enacted.
+impl<SM, T> BlockHooks for Governance<SM, T>
+where
+	SM: TryStateMachine + BlockHooks,
+	SM::State: Clone,
+	SM::Transition: Clone,
+	T: Tally,
+{
+	fn on_initialize(state: &Self::State, block: &BlockContext) -> Self::State {
+		let mut state = state.clone();
+		state.height = block.height;
+		state.runtime = SM::on_initialize(&state.runtime, block);
+
+		let electorate = state.electorate();
+		let ids: Vec<ReferendumId> = state.referenda.keys().copied().collect();
+		for id in ids {
+			let referendum = &state.referenda[&id];
+			let status = match referendum.status {
+				ReferendumStatus::Voting { ends } if ends <= block.height => {
+					let (aye, nay) = state.turnout(referendum);
+					if T::approved(aye, nay, electorate) {
+						ReferendumStatus::Approved {
+							enactment: ends + state.config.enactment_delay,
+						}
+					} else {
+						ReferendumStatus::Rejected
+					}
+				},
+				status => status,
+			};
+			let status = match status {
+				ReferendumStatus::Approved { enactment } if enactment <= block.height =>
+					match &referendum.proposal {
+						Proposal::Call(call) => match SM::try_next_state(&state.runtime, call) {
+							Ok(runtime) => {
+								state.runtime = runtime;
+								ReferendumStatus::Enacted
+							},
+							Err(_) => ReferendumStatus::Failed,
+						},
+						Proposal::Fork(fork) => {
+							state.forks.push(fork.clone());
+							ReferendumStatus::Enacted
+						},
+					},
+				status => status,
+			};
+			state.referenda.get_mut(&id).expect("id was taken from the map; qed").status = status;
+		}
+
+		state
+	}
+
+	fn on_finalize(state: &Self::State, block: &BlockContext) -> Self::State {
+		let mut state = state.clone();
+		state.runtime = SM::on_finalize(&state.runtime, block);
+		state
+	}
+}
+
+#[cfg(test)]
+use super::p10_locks::{LockState, LockTransaction, LockableCurrency};
+
+/// Governance of a lockable currency, where holders decide by simple majority.
+#[cfg(test)]
+type Council = Governance<LockableCurrency, SimpleMajority>;
+
+#[cfg(test)]
+fn council_state() -> GovernanceState<LockState, LockTransaction> {
+	let balances = Balances::from([(User::Alice, 50), (User::Bob, 30), (User::Charlie, 20)]);
+	let config = GovernanceConfig { voting_period: 10, enactment_delay: 5 };
+	GovernanceState::new(balances, LockState::default(), config)
+}
+
+#[cfg(test)]
+fn at(
+	state: &GovernanceState<LockState, LockTransaction>,
+	height: u64,
+) -> GovernanceState<LockState, LockTransaction> {
+	Council::on_initialize(state, &BlockContext { height, author: None })
+}
+
+#[test]
+fn sm_20_approved_call_is_enacted_after_delay() {
+	let mint = LockTransaction::Mint { minter: User::Charlie, amount: 7 };
+	let mut state = council_state();
+	for t in [
+		GovernanceTransaction::Propose { proposer: User::Charlie, proposal: Proposal::Call(mint) },
+		GovernanceTransaction::Vote { voter: User::Alice, referendum: 0, vote: Vote::Aye },
+		GovernanceTransaction::Vote { voter: User::Bob, referendum: 0, vote: Vote::Nay },
+	] {
+		state = Council::try_next_state(&state, &t).unwrap();
+	}
+
+	let tallied = at(&state, 10);
+	assert_eq!(tallied.referenda[&0].status, ReferendumStatus::Approved { enactment: 15 });
+	assert_eq!(tallied.runtime.usable(User::Charlie), 0);
+
+	let waiting = at(&tallied, 14);
+	assert_eq!(waiting.runtime.usable(User::Charlie), 0);
+
+	let enacted = at(&waiting, 15);
+	assert_eq!(enacted.referenda[&0].status, ReferendumStatus::Enacted);
+	assert_eq!(enacted.runtime.usable(User::Charlie), 7);
+}
+
+#[test]
+fn sm_20_rejected_call_is_never_enacted() {
+	let mint = LockTransaction::Mint { minter: User::Charlie, amount: 7 };
+	let mut state = council_state();
+	for t in [
+		GovernanceTransaction::Propose { proposer: User::Charlie, proposal: Proposal::Call(mint) },
+		GovernanceTransaction::Vote { voter: User::Alice, referendum: 0, vote: Vote::Nay },
+		GovernanceTransaction::Vote { voter: User::Charlie, referendum: 0, vote: Vote::Aye },
+	] {
+		state = Council::try_next_state(&state, &t).unwrap();
+	}
+
+	let end = at(&at(&state, 10), 15);
+	assert_eq!(end.referenda[&0].status, ReferendumStatus::Rejected);
+	assert_eq!(end.runtime, LockState { height: 15, ..LockState::default() });
+}
+
+#[test]
+fn sm_20_votes_close_after_voting_period() {
+	let mint = LockTransaction::Mint { minter: User::Charlie, amount: 7 };
+	let propose =
+		GovernanceTransaction::Propose { proposer: User::Charlie, proposal: Proposal::Call(mint) };
+	let state = at(&Council::try_next_state(&council_state(), &propose).unwrap(), 10);
+	let vote = GovernanceTransaction::Vote { voter: User::Alice, referendum: 0, vote: Vote::Aye };
+
+	assert_eq!(Council::try_next_state(&state, &vote), Err(GovernanceError::VotingClosed));
+}
+
+#[test]
+fn sm_20_minting_during_a_vote_is_rejected() {
+	let mint = LockTransaction::Mint { minter: User::Charlie, amount: 7 };
+	let mut state = council_state();
+	for t in [
+		GovernanceTransaction::Propose { proposer: User::Charlie, proposal: Proposal::Call(mint) },
+		GovernanceTransaction::Vote { voter: User::Alice, referendum: 0, vote: Vote::Nay },
+		GovernanceTransaction::Vote { voter: User::Bob, referendum: 0, vote: Vote::Aye },
+		GovernanceTransaction::Vote { voter: User::Charlie, referendum: 0, vote: Vote::Aye },
+	] {
+		state = Council::try_next_state(&state, &t).unwrap();
+	}
+
+	// Charlie tries to tip the tied vote by minting more weight for themselves.
+	let weight = GovernanceTransaction::Currency(AccountingTransaction::Mint {
+		minter: User::Charlie,
+		amount: 100,
+	});
+	assert_eq!(Council::try_next_state(&state, &weight), Err(GovernanceError::MintingDisabled));
+	assert_eq!(Council::next_state(&state, &weight), state);
+
+	let end = at(&state, 10);
+	assert_eq!(end.turnout(&end.referenda[&0]), (50, 50));
+	assert_eq!(end.referenda[&0].status, ReferendumStatus::Rejected);
+}
+
+#[test]
+fn sm_20_proposal_near_the_end_of_time_is_rejected() {
+	let mint = LockTransaction::Mint { minter: User::Charlie, amount: 7 };
+	let propose =
+		GovernanceTransaction::Propose { proposer: User::Charlie, proposal: Proposal::Call(mint) };
+	let state = at(&council_state(), u64::MAX - 12);
+
+	assert_eq!(Council::try_next_state(&state, &propose), Err(GovernanceError::Overflow));
+}
+
+#[test]
+fn sm_20_enacted_fork_is_scheduled() {
+	let fork = ScheduledFork {
+		fork_height: 100,
+		change: ConsensusChange::Authorities(vec![ConsensusAuthority::Bob]),
+	};
+	let too_soon = ScheduledFork { fork_height: 15, ..fork.clone() };
+	let propose = |fork| GovernanceTransaction::Propose {
+		proposer: User::Bob,
+		proposal: Proposal::Fork(fork),
+	};
+	assert_eq!(
+		Council::try_next_state(&council_state(), &propose(too_soon)),
+		Err(GovernanceError::ForkTooSoon { fork_height: 15, earliest: 16 })
+	);
+
+	let mut state = council_state();
+	for t in [
+		propose(fork.clone()),
+		GovernanceTransaction::Vote { voter: User::Bob, referendum: 0, vote: Vote::Aye },
+	] {
+		state = Council::try_next_state(&state, &t).unwrap();
+	}
+	let end = at(&at(&state, 10), 15);
+	assert_eq!(end.forks, vec![fork]);
+}
+
+#[test]
+fn sm_20_tally_rules() {
+	// A third of the electorate turns out, and splits 60:40.
+	let (aye, nay, electorate) = (60, 40, 300);
+	assert!(SimpleMajority::approved(aye, nay, electorate));
+	assert!(!Supermajority::approved(aye, nay, electorate));
+	assert!(!PositiveTurnoutBias::approved(aye, nay, electorate));
+	assert!(NegativeTurnoutBias::approved(aye, nay, electorate));
+
+	// The same split with full turnout is a simple majority under the biased rules too.
+	let (aye, nay, electorate) = (180, 120, 300);
+	assert!(PositiveTurnoutBias::approved(aye, nay, electorate));
+	assert!(NegativeTurnoutBias::approved(aye, nay, electorate));
+
+	// A third of the electorate rejecting something is not enough under a negative bias.
+	assert!(NegativeTurnoutBias::approved(40, 60, 300));
+	assert!(!SimpleMajority::approved(40, 60, 300));
+}
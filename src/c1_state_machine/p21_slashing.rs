@@ -0,0 +1,527 @@
+//! Staking only secures a chain if misbehaving validators actually lose their stake. In this module
+//! we punish the most clear-cut kind of misbehaviour there is: equivocation.
+//!
+//! An authority equivocates when it signs two different blocks for the same slot. Honest
+//! authorities never do this, and the two signed headers together are proof that it happened, which
+//! anyone can check with the consensus engine itself. So anyone who sees both headers may report
+//! them on-chain. The offender loses a fraction of their bonded funds, is disabled for the rest of
+//! the era, and is chilled, so they won't be elected again unless they ask to be. Part of the
+//! slashed funds go to the reporter, to make watching for equivocations worthwhile. The rest are
+//! burned. Every offence is remembered, so the same evidence can't be reported twice, and nobody
+//! may report themselves to claim back part of their own slash.
+//!
+//! Disabled authorities must not author any more blocks. The engine that validates new blocks
+//! should therefore be built with `Slashing::engine`, which leaves them out.
+//!
+//! The machine is generic over the consensus engine whose headers it judges. The engine is built
+//! from the authorities that staking elected for the current era, so a header is only valid
+//! evidence if a current authority could really have authored it.
+
+use super::{
+	p10_locks::LockError,
+	p19_staking::{Ledger, Staking, StakingError, StakingState, StakingTransaction},
+	BlockContext, BlockHooks, StateMachine, TryStateMachine, User,
+};
+use crate::c3_consensus::{Consensus, ConsensusAuthority, Header};
+use std::{collections::BTreeSet, marker::PhantomData};
+
+/// This state machine adds slashing for equivocation to staking, judging headers of the consensus
+/// engine `C`.
+pub struct Slashing<C>(PhantomData<C>);
+
+/// A header together with the digest of its parent, which the consensus engine needs to validate
+/// it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SealedHeader<D> {
+	/// The consensus digest of the header's parent
+	pub parent_digest: D,
+	/// The header itself, including its seal
+	pub header: Header<D>,
+}
+
+/// Proof that an authority signed two different blocks for the same slot.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Equivocation<D> {
+	/// One of the blocks
+	pub first: SealedHeader<D>,
+	/// The other block
+	pub second: SealedHeader<D>,
+}
+
+/// The parameters of the slashing system, which are fixed at genesis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SlashingConfig {
+	/// The percentage of the offender's bonded funds that is slashed
+	slash_percent: u64,
+	/// The percentage of the slashed funds that goes to the reporter
+	reward_percent: u64,
+}
+
+impl SlashingConfig {
+	/// A configuration with the given percentages. Returns `None` if either is above 100, since
+	/// nobody can lose more than they bonded, and the reporter can't get more than was slashed.
+	pub fn new(slash_percent: u64, reward_percent: u64) -> Option<Self> {
+		if slash_percent > 100 || reward_percent > 100 {
+			return None
+		}
+		Some(SlashingConfig { slash_percent, reward_percent })
+	}
+
+	/// The percentage of the offender's bonded funds that is slashed.
+	pub fn slash_percent(&self) -> u64 {
+		self.slash_percent
+	}
+
+	/// The percentage of the slashed funds that goes to the reporter.
+	pub fn reward_percent(&self) -> u64 {
+		self.reward_percent
+	}
+}
+
+/// The state of the slashing system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SlashingState {
+	/// The staking system whose validators are punished
+	pub staking: StakingState,
+	/// The parameters of the slashing system
+	pub config: SlashingConfig,
+	/// The authorities that were caught equivocating in the current era
+	pub disabled: BTreeSet<User>,
+	/// Every offender that has been punished, along with the slot of their offence
+	pub reported: BTreeSet<(User, u64)>,
+}
+
+impl SlashingState {
+	/// A slashing system on top of the given staking system, with nobody disabled.
+	pub fn new(staking: StakingState, config: SlashingConfig) -> Self {
+		SlashingState { staking, config, disabled: BTreeSet::new(), reported: BTreeSet::new() }
+	}
+
+	/// The authorities that may still author blocks in the current era.
+	pub fn active_authorities(&self) -> Vec<ConsensusAuthority> {
+		self.staking
+			.authorities
+			.iter()
+			.filter(|who| !self.disabled.contains(who))
+			.map(|who| ConsensusAuthority::from(*who))
+			.collect()
+	}
+}
+
+/// The state transitions that users can make in the slashing system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SlashingTransaction<D> {
+	/// A transaction on the underlying staking system
+	Staking(StakingTransaction),
+	/// Report an equivocation, and claim the reward
+	Report { reporter: User, evidence: Equivocation<D> },
+}
+
+/// The reasons a slashing transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SlashingError {
+	/// The staking system rejected the transaction.
+	Staking(StakingError),
+	/// Signing the same block twice is not an offence.
+	IdenticalHeaders,
+	/// The consensus engine does not consider one of the headers valid.
+	InvalidHeader,
+	/// The consensus engine does not say who authored one of the headers.
+	UnknownAuthor,
+	/// The headers were authored by different authorities.
+	DifferentAuthors,
+	/// The headers were authored for different slots.
+	DifferentSlots,
+	/// The offender is not an authority in the current era.
+	NotAnAuthority,
+	/// Offenders may not report themselves.
+	SelfReport,
+	/// This offence has already been punished.
+	AlreadyReported,
+	/// The offender has already been punished in the current era.
+	AlreadyDisabled,
+	/// The reporter can't be rewarded.
+	Reward(LockError),
+}
+
+impl<C> StateMachine for Slashing<C>
+where
+	C: Consensus + From<Vec<ConsensusAuthority>>,
+{
+	type State = SlashingState;
+	type Transition = SlashingTransaction<C::Digest>;
+
+	fn next_state(starting_state: &SlashingState, t: &Self::Transition) -> SlashingState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		format!("Staking with Slashing for {}", C::human_name())
+	}
+}
+
+impl<C> Slashing<C>
+where
+	C: Consensus + From<Vec<ConsensusAuthority>>,
+{
+	/// A consensus engine for the authorities that may still author blocks in the current era.
+	pub fn engine(state: &SlashingState) -> C {
+		C::from(state.active_authorities())
+	}
+
+	/// Check the evidence with a consensus engine for the current authorities, and return the
+	/// offender along with the slot of the offence. Disabled authorities are included, since their
+	/// earlier blocks are still valid evidence.
+	pub fn offender(
+		state: &SlashingState,
+		evidence: &Equivocation<C::Digest>,
+	) -> Result<(User, u64), SlashingError> {
+		let Equivocation { first, second } = evidence;
+		if first.header == second.header {
+			return Err(SlashingError::IdenticalHeaders)
+		}
+
+		let engine = C::from(state.staking.authority_set());
+		for sealed in [first, second] {
+			if !engine.validate(&sealed.parent_digest, &sealed.header) {
+				return Err(SlashingError::InvalidHeader)
+			}
+		}
+
+		let author = |sealed: &SealedHeader<C::Digest>| {
+			C::author(&sealed.header.consensus_digest).ok_or(SlashingError::UnknownAuthor)
+		};
+		let offender = author(first)?;
+		if author(second)? != offender {
+			return Err(SlashingError::DifferentAuthors)
+		}
+		let slot = C::slot(&first.header);
+		if C::slot(&second.header) != slot {
+			return Err(SlashingError::DifferentSlots)
+		}
+
+		Ok((offender.into(), slot))
+	}
+}
+
+impl<C> TryStateMachine for Slashing<C>
+where
+	C: Consensus + From<Vec<ConsensusAuthority>>,
+{
+	type Error = SlashingError;
+
+	fn try_next_state(
+		starting_state: &SlashingState,
+		t: &Self::Transition,
+	) -> Result<SlashingState, SlashingError> {
+		let mut state = starting_state.clone();
+
+		match t {
+			SlashingTransaction::Staking(call) => {
+				state.staking = Staking::try_next_state(&state.staking, call)
+					.map_err(SlashingError::Staking)?;
+			},
+			SlashingTransaction::Report { reporter, evidence } => {
+				let (offender, slot) = Self::offender(&state, evidence)?;
+				if offender == *reporter {
+					return Err(SlashingError::SelfReport)
+				}
+				if !state.staking.authorities.contains(&offender) {
+					return Err(SlashingError::NotAnAuthority)
+				}
+				if !state.reported.insert((offender, slot)) {
+					return Err(SlashingError::AlreadyReported)
+				}
+				if !state.disabled.insert(offender) {
+					return Err(SlashingError::AlreadyDisabled)
+				}
+
+				let bonded = state.staking.ledgers.get(&offender).map(Ledger::total).unwrap_or(0);
+				let amount = (bonded as u128 * state.config.slash_percent as u128 / 100) as u64;
+				let slashed = state.staking.slash(offender, amount);
+				state.staking.intentions.remove(&offender);
+
+				let reward = (slashed as u128 * state.config.reward_percent as u128 / 100) as u64;
+				if reward > 0 {
+					state
+						.staking
+						.currency
+						.deposit(*reporter, reward)
+						.map_err(SlashingError::Reward)?;
+				}
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// At the beginning of every block, staking runs its own hooks. When that starts a new era with
+/// newly elected authorities, the disabled authorities are forgiven, since the new authorities
+/// were elected without them. When the election comes up short and the previous authorities carry
+/// on, so do their punishments.
+impl<C> BlockHooks for Slashing<C>
+where
+	C: Consensus + From<Vec<ConsensusAuthority>>,
+{
+	fn on_initialize(state: &SlashingState, block: &BlockContext) -> SlashingState {
+		let mut state = state.clone();
+		let era = state.staking.era;
+		let authorities = state.staking.authorities.clone();
+		state.staking = Staking::on_initialize(&state.staking, block);
+		if state.staking.era != era && state.staking.authorities != authorities {
+			state.disabled.clear();
+		}
+		state
+	}
+}
+
+/// A proof of authority engine for the tests below, in which any authority may sign any block.
+#[cfg(test)]
+struct AnyAuthority {
+	authorities: Vec<ConsensusAuthority>,
+}
+
+#[cfg(test)]
+impl From<Vec<ConsensusAuthority>> for AnyAuthority {
+	fn from(authorities: Vec<ConsensusAuthority>) -> Self {
+		AnyAuthority { authorities }
+	}
+}
+
+#[cfg(test)]
+impl Consensus for AnyAuthority {
+	type Digest = ConsensusAuthority;
+
+	fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+		self.authorities.contains(&header.consensus_digest)
+	}
+
+	fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+		Some(Header {
+			parent: partial_header.parent,
+			height: partial_header.height,
+			state_root: partial_header.state_root,
+			extrinsics_root: partial_header.extrinsics_root,
+			consensus_digest: *self.authorities.first()?,
+		})
+	}
+
+	fn author(digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		Some(*digest)
+	}
+}
+
+#[cfg(test)]
+fn sealed(
+	height: u64,
+	state_root: u64,
+	author: ConsensusAuthority,
+) -> SealedHeader<ConsensusAuthority> {
+	let header =
+		Header { parent: 0, height, state_root, extrinsics_root: 0, consensus_digest: author };
+	SealedHeader { parent_digest: author, header }
+}
+
+#[cfg(test)]
+fn slashing_state() -> SlashingState {
+	use super::{
+		p10_locks::LockState,
+		p19_staking::{ElectionMethod, StakingConfig},
+	};
+
+	let currency = LockState::new([(User::Alice, 100), (User::Bob, 100)]);
+	let config = StakingConfig {
+		unbonding_period: 10,
+		validator_count: 2,
+		era_length: 5,
+		election: ElectionMethod::TopN,
+	};
+	let mut staking = StakingState::new(currency, config, vec![User::Alice, User::Bob]);
+	for t in [
+		StakingTransaction::Bond { who: User::Alice, amount: 80 },
+		StakingTransaction::Validate { who: User::Alice },
+	] {
+		staking = Staking::try_next_state(&staking, &t).unwrap();
+	}
+	SlashingState::new(staking, SlashingConfig::new(10, 50).unwrap())
+}
+
+#[cfg(test)]
+fn report(
+	first: SealedHeader<ConsensusAuthority>,
+	second: SealedHeader<ConsensusAuthority>,
+) -> SlashingTransaction<ConsensusAuthority> {
+	SlashingTransaction::Report {
+		reporter: User::Charlie,
+		evidence: Equivocation { first, second },
+	}
+}
+
+#[test]
+fn sm_21_percentages_are_at_most_100() {
+	assert!(SlashingConfig::new(100, 100).is_some());
+	assert_eq!(SlashingConfig::new(101, 50), None);
+	assert_eq!(SlashingConfig::new(10, 101), None);
+}
+
+#[test]
+fn sm_21_equivocation_is_slashed() {
+	let start = slashing_state();
+	let evidence =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 2, ConsensusAuthority::Alice));
+	let end = Slashing::<AnyAuthority>::try_next_state(&start, &evidence).unwrap();
+
+	assert_eq!(end.staking.active(User::Alice), 72);
+	assert_eq!(end.staking.currency.account(User::Alice).unwrap().free, 92);
+	assert_eq!(end.staking.currency.usable(User::Alice), 20);
+	assert_eq!(end.staking.currency.usable(User::Charlie), 4);
+	assert_eq!(end.active_authorities(), vec![ConsensusAuthority::Bob]);
+	assert!(end.staking.intentions.is_empty());
+}
+
+#[test]
+fn sm_21_same_header_twice_is_not_an_offence() {
+	let start = slashing_state();
+	let evidence =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 1, ConsensusAuthority::Alice));
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&start, &evidence),
+		Err(SlashingError::IdenticalHeaders)
+	);
+}
+
+#[test]
+fn sm_21_evidence_must_be_for_one_slot_and_author() {
+	let start = slashing_state();
+	let heights =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(4, 2, ConsensusAuthority::Alice));
+	let authors =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 2, ConsensusAuthority::Bob));
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&start, &heights),
+		Err(SlashingError::DifferentSlots)
+	);
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&start, &authors),
+		Err(SlashingError::DifferentAuthors)
+	);
+}
+
+#[test]
+fn sm_21_evidence_is_validated_by_the_consensus_engine() {
+	let start = slashing_state();
+	let evidence = report(
+		sealed(3, 1, ConsensusAuthority::Charlie),
+		sealed(3, 2, ConsensusAuthority::Charlie),
+	);
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&start, &evidence),
+		Err(SlashingError::InvalidHeader)
+	);
+}
+
+#[test]
+fn sm_21_offender_is_disabled_until_the_next_election() {
+	use super::p10_locks::LockTransaction;
+
+	let start = slashing_state();
+	let evidence =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 2, ConsensusAuthority::Alice));
+	let again =
+		report(sealed(4, 1, ConsensusAuthority::Alice), sealed(4, 2, ConsensusAuthority::Alice));
+	let mut state = Slashing::<AnyAuthority>::try_next_state(&start, &evidence).unwrap();
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&state, &again),
+		Err(SlashingError::AlreadyDisabled)
+	);
+
+	for t in [
+		StakingTransaction::Currency(LockTransaction::Mint { minter: User::Charlie, amount: 100 }),
+		StakingTransaction::Bond { who: User::Charlie, amount: 40 },
+		StakingTransaction::Validate { who: User::Charlie },
+		StakingTransaction::Bond { who: User::Bob, amount: 50 },
+		StakingTransaction::Validate { who: User::Bob },
+	] {
+		state = Slashing::<AnyAuthority>::try_next_state(&state, &SlashingTransaction::Staking(t))
+			.unwrap();
+	}
+	let next_era =
+		Slashing::<AnyAuthority>::on_initialize(&state, &BlockContext { height: 5, author: None });
+	assert!(next_era.disabled.is_empty());
+	assert_eq!(next_era.staking.authorities, vec![User::Bob, User::Charlie]);
+}
+
+#[test]
+fn sm_21_offender_stays_disabled_while_authorities_carry_over() {
+	let start = slashing_state();
+	let evidence =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 2, ConsensusAuthority::Alice));
+	let slashed = Slashing::<AnyAuthority>::try_next_state(&start, &evidence).unwrap();
+
+	// The offender was chilled and nobody else validates, so the election comes up short and the
+	// previous authorities carry on.
+	let next_era = Slashing::<AnyAuthority>::on_initialize(
+		&slashed,
+		&BlockContext { height: 5, author: None },
+	);
+	assert_eq!(next_era.staking.authorities, start.staking.authorities);
+	assert_eq!(next_era.disabled, BTreeSet::from([User::Alice]));
+
+	let engine = Slashing::<AnyAuthority>::engine(&next_era);
+	let by_alice = sealed(6, 1, ConsensusAuthority::Alice);
+	let by_bob = sealed(6, 1, ConsensusAuthority::Bob);
+	assert!(!engine.validate(&by_alice.parent_digest, &by_alice.header));
+	assert!(engine.validate(&by_bob.parent_digest, &by_bob.header));
+}
+
+#[test]
+fn sm_21_offence_cannot_be_reported_again_in_a_later_era() {
+	use super::p10_locks::LockTransaction;
+
+	let start = slashing_state();
+	let evidence =
+		report(sealed(3, 1, ConsensusAuthority::Alice), sealed(3, 2, ConsensusAuthority::Alice));
+	let mut state = Slashing::<AnyAuthority>::try_next_state(&start, &evidence).unwrap();
+
+	// Alice asks to validate again and is re-elected alongside Charlie.
+	for t in [
+		StakingTransaction::Currency(LockTransaction::Mint { minter: User::Charlie, amount: 100 }),
+		StakingTransaction::Bond { who: User::Charlie, amount: 90 },
+		StakingTransaction::Validate { who: User::Charlie },
+		StakingTransaction::Validate { who: User::Alice },
+	] {
+		state = Slashing::<AnyAuthority>::try_next_state(&state, &SlashingTransaction::Staking(t))
+			.unwrap();
+	}
+	let next_era =
+		Slashing::<AnyAuthority>::on_initialize(&state, &BlockContext { height: 5, author: None });
+	assert_eq!(next_era.staking.authorities, vec![User::Charlie, User::Alice]);
+	assert!(next_era.disabled.is_empty());
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&next_era, &evidence),
+		Err(SlashingError::AlreadyReported)
+	);
+}
+
+#[test]
+fn sm_21_offender_cannot_report_themselves() {
+	let start = slashing_state();
+	let evidence = SlashingTransaction::Report {
+		reporter: User::Alice,
+		evidence: Equivocation {
+			first: sealed(3, 1, ConsensusAuthority::Alice),
+			second: sealed(3, 2, ConsensusAuthority::Alice),
+		},
+	};
+
+	assert_eq!(
+		Slashing::<AnyAuthority>::try_next_state(&start, &evidence),
+		Err(SlashingError::SelfReport)
+	);
+}
@@ -0,0 +1,669 @@
+//! Some decisions are too important to leave to a single key. A treasury, a team, or a cautious
+//! individual may want every call to be approved by several people first. Multisignature accounts
+//! do exactly this: an account is controlled by a set of signatories, and any call made from it
+//! needs approval from at least `threshold` of them.
+//!
+//! There is no setup step. A multisig account's id is derived deterministically from its
+//! signatories and threshold, so everybody can compute it up front, and the account comes into
+//! existence with the first deposit or proposal made for it. One signatory proposes a call, the
+//! others approve it, and the call is executed as soon as the approval that meets the threshold
+//! arrives. Until then, the proposer may change their mind and cancel.
+//!
+//! Every account holds a balance of its own. Anybody may pay into it from their balance in the
+//! wrapped module, and the funds can only leave it again through a payment that the signatories
+//! approved. No single signatory can move them, unless the threshold is one.
+//!
+//! The calls are either such payments, or transitions of any other state machine, such as the
+//! accounted currency or a bigger machine built with the combinators from the `compose` module.
+//! A transition of the wrapped module still acts on behalf of a single user, its signer from
+//! part 8. So its signer must be one of the account's signatories, and it only runs once the
+//! signer is among the approvals. A pending call is identified by its hash, so the same account
+//! may have several calls pending at once.
+
+use super::{
+	p10_locks::{LockError, LockState, LockableCurrency},
+	p4_accounted_currency::{AccountedCurrency, AccountingError, AccountingTransaction, Balances},
+	p8_nonces::Authorized,
+	BlockContext, BlockHooks, StateMachine, TryStateMachine, User,
+};
+use crate::hash;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	hash::Hash,
+	marker::PhantomData,
+};
+
+/// Multisig accounts are identified by the hash of their signatories and threshold.
+pub type MultisigId = u64;
+
+/// Pending calls are identified by their hash.
+pub type CallHash = u64;
+
+/// A state machine with a currency, which multisig accounts can be paid in and pay out.
+pub trait Currency: TryStateMachine {
+	/// Take the amount from the user's balance, failing if they can't afford it.
+	fn withdraw(state: &Self::State, user: User, amount: u64) -> Result<Self::State, Self::Error>;
+
+	/// Add the amount to the user's balance.
+	fn deposit(state: &Self::State, user: User, amount: u64) -> Result<Self::State, Self::Error>;
+}
+
+/// Funds leave the accounted currency by being burned, and come back by being minted.
+impl Currency for AccountedCurrency {
+	fn withdraw(state: &Balances, user: User, amount: u64) -> Result<Balances, AccountingError> {
+		// Burning more than the balance burns the whole balance, which is not enough here.
+		let balance = state.get(&user).copied().unwrap_or(0);
+		if balance < amount {
+			return Err(AccountingError::InsufficientBalance { balance, requested: amount })
+		}
+		Self::try_next_state(state, &AccountingTransaction::Burn { burner: user, amount })
+	}
+
+	fn deposit(state: &Balances, user: User, amount: u64) -> Result<Balances, AccountingError> {
+		Self::try_next_state(state, &AccountingTransaction::Mint { minter: user, amount })
+	}
+}
+
+/// Funds leave the usable balance of the lockable currency, and come back as free balance.
+impl Currency for LockableCurrency {
+	fn withdraw(state: &LockState, user: User, amount: u64) -> Result<LockState, LockError> {
+		let mut state = state.clone();
+		state.withdraw(user, amount)?;
+		Ok(state)
+	}
+
+	fn deposit(state: &LockState, user: User, amount: u64) -> Result<LockState, LockError> {
+		let mut state = state.clone();
+		state.deposit(user, amount)?;
+		Ok(state)
+	}
+}
+
+/// A k-of-n multisignature account.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MultisigAccount {
+	/// The users who may propose and approve calls
+	pub signatories: BTreeSet<User>,
+	/// How many signatories must approve a call before it is executed
+	pub threshold: usize,
+}
+
+impl MultisigAccount {
+	/// An account with the given signatories and threshold. Duplicate signatories are ignored.
+	/// Returns `None` if the threshold is zero or larger than the number of signatories.
+	pub fn new(signatories: impl IntoIterator<Item = User>, threshold: usize) -> Option<Self> {
+		let signatories: BTreeSet<User> = signatories.into_iter().collect();
+		if threshold == 0 || threshold > signatories.len() {
+			return None
+		}
+		Some(MultisigAccount { signatories, threshold })
+	}
+
+	/// The id of this account. The signatories are kept sorted, so the order in which they were
+	/// given doesn't matter.
+	pub fn id(&self) -> MultisigId {
+		hash(self)
+	}
+}
+
+/// Something a multisig account can be asked to do, where `T` is the wrapped module's transition
+/// type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MultisigCall<T> {
+	/// Pay some of the account's own balance to a user
+	Pay { receiver: User, amount: u64 },
+	/// Make a transition of the wrapped module, on behalf of its signer
+	Runtime(T),
+}
+
+/// A call that is waiting for approvals.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PendingCall<T> {
+	/// The signatory who proposed the call, and who may cancel it
+	pub proposer: User,
+	/// The call to execute once the threshold is met
+	pub call: MultisigCall<T>,
+	/// The signatories who have approved the call so far, including the proposer
+	pub approvals: BTreeSet<User>,
+}
+
+/// This state machine adds multisig accounts whose calls are transitions of `SM`.
+pub struct Multisig<SM>(PhantomData<SM>);
+
+/// The state of the multisig machine, where `S` is the state of the wrapped module and `T` its
+/// transition type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MultisigState<S, T> {
+	/// The state of the wrapped module
+	pub runtime: S,
+	/// Every multisig account that has ever been used
+	pub accounts: BTreeMap<MultisigId, MultisigAccount>,
+	/// The balance of every multisig account that holds anything
+	pub balances: BTreeMap<MultisigId, u64>,
+	/// The calls that are waiting for approvals
+	pub pending: BTreeMap<(MultisigId, CallHash), PendingCall<T>>,
+}
+
+impl<S, T> MultisigState<S, T> {
+	/// A state without any multisig accounts, wrapping the given module state.
+	pub fn new(runtime: S) -> Self {
+		MultisigState {
+			runtime,
+			accounts: BTreeMap::new(),
+			balances: BTreeMap::new(),
+			pending: BTreeMap::new(),
+		}
+	}
+
+	/// The balance of the given multisig account.
+	pub fn balance(&self, account: MultisigId) -> u64 {
+		self.balances.get(&account).copied().unwrap_or(0)
+	}
+}
+
+/// The state transitions that users can make with multisig accounts.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MultisigTransaction<T> {
+	/// Pay into the account with the given signatories and threshold from the sender's balance in
+	/// the wrapped module. Anybody may do this.
+	Deposit { sender: User, signatories: Vec<User>, threshold: usize, amount: u64 },
+	/// Propose a call from the account with the given signatories and threshold. This counts as
+	/// the proposer's approval.
+	Propose { proposer: User, signatories: Vec<User>, threshold: usize, call: MultisigCall<T> },
+	/// Approve a pending call
+	Approve { approver: User, account: MultisigId, call_hash: CallHash },
+	/// Withdraw a pending call. Only the proposer may do this.
+	Cancel { proposer: User, account: MultisigId, call_hash: CallHash },
+}
+
+/// The reasons a multisig transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MultisigError<E> {
+	/// The threshold must be at least one, and at most the number of signatories.
+	InvalidThreshold { threshold: usize, signatories: usize },
+	/// Paying zero is pointless.
+	ZeroAmount,
+	/// Only signatories may propose and approve calls.
+	NotASignatory,
+	/// The call is signed by a user who is not a signatory of the account.
+	ForeignSigner(User),
+	/// The same call is already pending for this account.
+	AlreadyPending,
+	/// There is no such call pending for this account.
+	UnknownCall,
+	/// Each signatory may only approve a call once.
+	AlreadyApproved,
+	/// Only the proposer may cancel a call.
+	NotProposer,
+	/// The account can't afford the payment.
+	InsufficientBalance { balance: u64, requested: u64 },
+	/// The account's balance would exceed `u64::MAX`.
+	Overflow,
+	/// The wrapped module rejected a call, or a payment into or out of an account.
+	Call(E),
+}
+
+impl<SM> StateMachine for Multisig<SM>
+where
+	SM: Currency + Authorized,
+	SM::State: Clone,
+	SM::Transition: Clone + Hash,
+{
+	type State = MultisigState<SM::State, SM::Transition>;
+	type Transition = MultisigTransaction<SM::Transition>;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		format!("Multisig {}", SM::human_name())
+	}
+}
+
+impl<SM> Multisig<SM>
+where
+	SM: Currency + Authorized,
+	SM::State: Clone,
+	SM::Transition: Clone + Hash,
+{
+	/// The account with the given signatories and threshold, which is remembered from now on.
+	fn account(
+		state: &mut MultisigState<SM::State, SM::Transition>,
+		signatories: &[User],
+		threshold: usize,
+	) -> Result<MultisigAccount, MultisigError<SM::Error>> {
+		let account = MultisigAccount::new(signatories.iter().copied(), threshold)
+			.ok_or(MultisigError::InvalidThreshold { threshold, signatories: signatories.len() })?;
+		state.accounts.insert(account.id(), account.clone());
+		Ok(account)
+	}
+
+	/// Execute the pending call if it has enough approvals, including the signer's of a wrapped
+	/// transition, and drop it from the pending calls.
+	fn execute_if_approved(
+		state: &mut MultisigState<SM::State, SM::Transition>,
+		key: (MultisigId, CallHash),
+	) -> Result<(), MultisigError<SM::Error>> {
+		let threshold = state.accounts[&key.0].threshold;
+		let pending = &state.pending[&key];
+		if pending.approvals.len() < threshold {
+			return Ok(())
+		}
+
+		match &pending.call {
+			MultisigCall::Pay { receiver, amount } => {
+				let balance = state.balance(key.0);
+				if balance < *amount {
+					return Err(MultisigError::InsufficientBalance { balance, requested: *amount })
+				}
+				state.runtime =
+					SM::deposit(&state.runtime, *receiver, *amount).map_err(MultisigError::Call)?;
+				if balance == *amount {
+					state.balances.remove(&key.0);
+				} else {
+					state.balances.insert(key.0, balance - amount);
+				}
+			},
+			MultisigCall::Runtime(call) => {
+				if !pending.approvals.contains(&SM::signer(call)) {
+					return Ok(())
+				}
+				state.runtime =
+					SM::try_next_state(&state.runtime, call).map_err(MultisigError::Call)?;
+			},
+		}
+		state.pending.remove(&key);
+		Ok(())
+	}
+}
+
+impl<SM> TryStateMachine for Multisig<SM>
+where
+	SM: Currency + Authorized,
+	SM::State: Clone,
+	SM::Transition: Clone + Hash,
+{
+	type Error = MultisigError<SM::Error>;
+
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error> {
+		let mut state = starting_state.clone();
+
+		match t {
+			MultisigTransaction::Deposit { sender, signatories, threshold, amount } => {
+				if *amount == 0 {
+					return Err(MultisigError::ZeroAmount)
+				}
+				let id = Self::account(&mut state, signatories, *threshold)?.id();
+				let balance =
+					state.balance(id).checked_add(*amount).ok_or(MultisigError::Overflow)?;
+				state.runtime =
+					SM::withdraw(&state.runtime, *sender, *amount).map_err(MultisigError::Call)?;
+				state.balances.insert(id, balance);
+			},
+			MultisigTransaction::Propose { proposer, signatories, threshold, call } => {
+				let account = Self::account(&mut state, signatories, *threshold)?;
+				if !account.signatories.contains(proposer) {
+					return Err(MultisigError::NotASignatory)
+				}
+				match call {
+					MultisigCall::Pay { amount: 0, .. } => return Err(MultisigError::ZeroAmount),
+					MultisigCall::Pay { .. } => {},
+					MultisigCall::Runtime(call) => {
+						let signer = SM::signer(call);
+						if !account.signatories.contains(&signer) {
+							return Err(MultisigError::ForeignSigner(signer))
+						}
+					},
+				}
+				let key = (account.id(), hash(call));
+				if state.pending.contains_key(&key) {
+					return Err(MultisigError::AlreadyPending)
+				}
+				let pending = PendingCall {
+					proposer: *proposer,
+					call: call.clone(),
+					approvals: BTreeSet::from([*proposer]),
+				};
+				state.pending.insert(key, pending);
+				Self::execute_if_approved(&mut state, key)?;
+			},
+			MultisigTransaction::Approve { approver, account, call_hash } => {
+				let key = (*account, *call_hash);
+				let pending = state.pending.get_mut(&key).ok_or(MultisigError::UnknownCall)?;
+				if !state.accounts[account].signatories.contains(approver) {
+					return Err(MultisigError::NotASignatory)
+				}
+				if !pending.approvals.insert(*approver) {
+					return Err(MultisigError::AlreadyApproved)
+				}
+				Self::execute_if_approved(&mut state, key)?;
+			},
+			MultisigTransaction::Cancel { proposer, account, call_hash } => {
+				let key = (*account, *call_hash);
+				let pending = state.pending.get(&key).ok_or(MultisigError::UnknownCall)?;
+				if pending.proposer != *proposer {
+					return Err(MultisigError::NotProposer)
+				}
+				state.pending.remove(&key);
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+/// The wrapped module runs its own hooks. Pending calls don't expire, so there is nothing else to
+/// do at block boundaries.
+impl<SM> BlockHooks for Multisig<SM>
+where
+	SM: Currency + Authorized + BlockHooks,
+	SM::State: Clone,
+	SM::Transition: Clone + Hash,
+{
+	fn on_initialize(state: &Self::State, block: &BlockContext) -> Self::State {
+		MultisigState { runtime: SM::on_initialize(&state.runtime, block), ..state.clone() }
+	}
+
+	fn on_finalize(state: &Self::State, block: &BlockContext) -> Self::State {
+		MultisigState { runtime: SM::on_finalize(&state.runtime, block), ..state.clone() }
+	}
+}
+
+#[cfg(test)]
+use super::p10_locks::LockTransaction;
+
+/// Multisig accounts whose calls are made on a lockable currency.
+#[cfg(test)]
+type Treasury = Multisig<LockableCurrency>;
+
+#[cfg(test)]
+fn treasury() -> MultisigState<LockState, LockTransaction> {
+	MultisigState::new(LockState::default())
+}
+
+#[cfg(test)]
+fn propose_mint(proposer: User, threshold: usize) -> MultisigTransaction<LockTransaction> {
+	MultisigTransaction::Propose {
+		proposer,
+		signatories: vec![User::Alice, User::Bob, User::Charlie],
+		threshold,
+		call: MultisigCall::Runtime(LockTransaction::Mint { minter: User::Bob, amount: 10 }),
+	}
+}
+
+#[cfg(test)]
+fn two_of_three() -> (MultisigId, CallHash) {
+	let account = MultisigAccount::new(User::ALL, 2).unwrap();
+	let call = MultisigCall::Runtime(LockTransaction::Mint { minter: User::Bob, amount: 10 });
+	(account.id(), hash(&call))
+}
+
+/// Alice, Bob, and Charlie have 100 each, and Charlie has paid 60 of it into their 2-of-3 account.
+#[cfg(test)]
+fn funded_treasury() -> (MultisigState<LockState, LockTransaction>, MultisigId) {
+	let mut state = MultisigState::new(LockState::new(User::ALL.map(|user| (user, 100))));
+	let deposit = MultisigTransaction::Deposit {
+		sender: User::Charlie,
+		signatories: User::ALL.to_vec(),
+		threshold: 2,
+		amount: 60,
+	};
+	state = Treasury::try_next_state(&state, &deposit).unwrap();
+	(state, MultisigAccount::new(User::ALL, 2).unwrap().id())
+}
+
+#[test]
+fn sm_22_account_id_is_deterministic() {
+	let account = MultisigAccount::new([User::Alice, User::Bob], 2).unwrap();
+	let reordered = MultisigAccount::new([User::Bob, User::Alice, User::Bob], 2).unwrap();
+	let one_of_two = MultisigAccount::new([User::Alice, User::Bob], 1).unwrap();
+
+	assert_eq!(account.id(), reordered.id());
+	assert_ne!(account.id(), one_of_two.id());
+	assert_eq!(MultisigAccount::new([User::Alice, User::Bob], 3), None);
+	assert_eq!(MultisigAccount::new([User::Alice, User::Bob], 0), None);
+}
+
+#[test]
+fn sm_22_call_executes_at_threshold() {
+	let (account, call_hash) = two_of_three();
+	let start = treasury();
+	let proposed = Treasury::try_next_state(&start, &propose_mint(User::Alice, 2)).unwrap();
+
+	assert_eq!(proposed.runtime, LockState::default());
+	assert_eq!(proposed.pending.len(), 1);
+
+	let approve = MultisigTransaction::Approve { approver: User::Bob, account, call_hash };
+	let end = Treasury::try_next_state(&proposed, &approve).unwrap();
+
+	assert_eq!(end.runtime.usable(User::Bob), 10);
+	assert!(end.pending.is_empty());
+}
+
+#[test]
+fn sm_22_threshold_of_one_executes_immediately() {
+	let start = treasury();
+	let end = Treasury::try_next_state(&start, &propose_mint(User::Bob, 1)).unwrap();
+
+	assert_eq!(end.runtime.usable(User::Bob), 10);
+	assert!(end.pending.is_empty());
+}
+
+#[test]
+fn sm_22_approvals_come_from_distinct_signatories() {
+	let (account, call_hash) = two_of_three();
+	let start = treasury();
+	let proposed = Treasury::try_next_state(&start, &propose_mint(User::Alice, 2)).unwrap();
+	let again = MultisigTransaction::Approve { approver: User::Alice, account, call_hash };
+
+	assert_eq!(Treasury::try_next_state(&proposed, &again), Err(MultisigError::AlreadyApproved));
+
+	let outsider = MultisigTransaction::Propose {
+		proposer: User::Charlie,
+		signatories: vec![User::Alice, User::Bob],
+		threshold: 1,
+		call: MultisigCall::Runtime(LockTransaction::Mint { minter: User::Charlie, amount: 10 }),
+	};
+	assert_eq!(Treasury::try_next_state(&start, &outsider), Err(MultisigError::NotASignatory));
+}
+
+#[test]
+fn sm_22_only_proposer_may_cancel() {
+	let (account, call_hash) = two_of_three();
+	let start = treasury();
+	let proposed = Treasury::try_next_state(&start, &propose_mint(User::Alice, 2)).unwrap();
+
+	let by_bob = MultisigTransaction::Cancel { proposer: User::Bob, account, call_hash };
+	assert_eq!(Treasury::try_next_state(&proposed, &by_bob), Err(MultisigError::NotProposer));
+
+	let by_alice = MultisigTransaction::Cancel { proposer: User::Alice, account, call_hash };
+	let cancelled = Treasury::try_next_state(&proposed, &by_alice).unwrap();
+	assert!(cancelled.pending.is_empty());
+
+	let approve = MultisigTransaction::Approve { approver: User::Bob, account, call_hash };
+	assert_eq!(Treasury::try_next_state(&cancelled, &approve), Err(MultisigError::UnknownCall));
+}
+
+#[test]
+fn sm_22_rejected_call_stays_pending() {
+	use super::p10_locks::LockError;
+
+	let call = MultisigCall::Runtime(LockTransaction::Transfer {
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 10,
+	});
+	let account = MultisigAccount::new([User::Alice, User::Bob], 2).unwrap().id();
+	let call_hash = hash(&call);
+	let propose = MultisigTransaction::Propose {
+		proposer: User::Alice,
+		signatories: vec![User::Alice, User::Bob],
+		threshold: 2,
+		call,
+	};
+	let proposed = Treasury::try_next_state(&treasury(), &propose).unwrap();
+	let approve = MultisigTransaction::Approve { approver: User::Bob, account, call_hash };
+
+	assert_eq!(
+		Treasury::try_next_state(&proposed, &approve),
+		Err(MultisigError::Call(LockError::UnknownAccount))
+	);
+	assert_eq!(proposed.pending.len(), 1);
+}
+
+#[test]
+fn sm_22_calls_into_composed_modules() {
+	use super::compose::{Either, Product};
+
+	let call: Either<LockTransaction, LockTransaction> =
+		Either::Second(LockTransaction::Mint { minter: User::Bob, amount: 5 });
+	let propose = MultisigTransaction::Propose {
+		proposer: User::Bob,
+		signatories: vec![User::Bob],
+		threshold: 1,
+		call: MultisigCall::Runtime(call),
+	};
+	let start = MultisigState::new((LockState::default(), LockState::default()));
+	let end =
+		Multisig::<Product<LockableCurrency, LockableCurrency>>::try_next_state(&start, &propose)
+			.unwrap();
+
+	assert_eq!(end.runtime.0, LockState::default());
+	assert_eq!(end.runtime.1.usable(User::Bob), 5);
+}
+
+#[test]
+fn sm_22_account_cannot_spend_from_a_non_signatory() {
+	let mut start = treasury();
+	start.runtime = LockableCurrency::try_next_state(
+		&start.runtime,
+		&LockTransaction::Mint { minter: User::Alice, amount: 100 },
+	)
+	.unwrap();
+
+	let drain = MultisigTransaction::Propose {
+		proposer: User::Charlie,
+		signatories: vec![User::Charlie],
+		threshold: 1,
+		call: MultisigCall::Runtime(LockTransaction::Transfer {
+			sender: User::Alice,
+			receiver: User::Charlie,
+			amount: 100,
+		}),
+	};
+
+	assert_eq!(
+		Treasury::try_next_state(&start, &drain),
+		Err(MultisigError::ForeignSigner(User::Alice))
+	);
+}
+
+#[test]
+fn sm_22_call_waits_for_its_signer() {
+	let call = MultisigCall::Runtime(LockTransaction::Transfer {
+		sender: User::Alice,
+		receiver: User::Charlie,
+		amount: 10,
+	});
+	let account = MultisigAccount::new([User::Alice, User::Charlie], 1).unwrap().id();
+	let call_hash = hash(&call);
+	let mut start = treasury();
+	start.runtime = LockableCurrency::try_next_state(
+		&start.runtime,
+		&LockTransaction::Mint { minter: User::Alice, amount: 10 },
+	)
+	.unwrap();
+	let propose = MultisigTransaction::Propose {
+		proposer: User::Charlie,
+		signatories: vec![User::Alice, User::Charlie],
+		threshold: 1,
+		call,
+	};
+	let proposed = Treasury::try_next_state(&start, &propose).unwrap();
+
+	assert_eq!(proposed.runtime, start.runtime);
+	assert_eq!(proposed.pending.len(), 1);
+
+	let approve = MultisigTransaction::Approve { approver: User::Alice, account, call_hash };
+	let end = Treasury::try_next_state(&proposed, &approve).unwrap();
+
+	assert_eq!(end.runtime.usable(User::Alice), 0);
+	assert_eq!(end.runtime.usable(User::Charlie), 10);
+	assert!(end.pending.is_empty());
+}
+
+#[test]
+fn sm_22_account_holds_its_own_funds() {
+	let (state, account) = funded_treasury();
+
+	assert_eq!(state.balance(account), 60);
+	assert_eq!(state.runtime.usable(User::Charlie), 40);
+
+	let pay = MultisigCall::Pay { receiver: User::Alice, amount: 60 };
+	let call_hash = hash(&pay);
+	let propose = MultisigTransaction::Propose {
+		proposer: User::Alice,
+		signatories: User::ALL.to_vec(),
+		threshold: 2,
+		call: pay,
+	};
+	let proposed = Treasury::try_next_state(&state, &propose).unwrap();
+	let approve = MultisigTransaction::Approve { approver: User::Bob, account, call_hash };
+	let end = Treasury::try_next_state(&proposed, &approve).unwrap();
+
+	assert_eq!(end.balance(account), 0);
+	assert_eq!(end.runtime.usable(User::Alice), 160);
+	assert!(end.pending.is_empty());
+}
+
+#[test]
+fn sm_22_two_of_three_funds_do_not_move_with_one_approval() {
+	let (state, account) = funded_treasury();
+	let pay = MultisigCall::Pay { receiver: User::Alice, amount: 60 };
+
+	// Alice alone can propose the payment, but it waits for a second approval.
+	let propose = MultisigTransaction::Propose {
+		proposer: User::Alice,
+		signatories: User::ALL.to_vec(),
+		threshold: 2,
+		call: pay.clone(),
+	};
+	let proposed = Treasury::try_next_state(&state, &propose).unwrap();
+	assert_eq!(proposed.balance(account), 60);
+	assert_eq!(proposed.runtime, state.runtime);
+
+	// An account of her own is a different account, which holds nothing.
+	let alone = MultisigTransaction::Propose {
+		proposer: User::Alice,
+		signatories: vec![User::Alice],
+		threshold: 1,
+		call: pay,
+	};
+	assert_eq!(
+		Treasury::try_next_state(&state, &alone),
+		Err(MultisigError::InsufficientBalance { balance: 0, requested: 60 })
+	);
+}
+
+#[test]
+fn sm_22_deposit_must_be_affordable() {
+	let start = MultisigState::new(Balances::from([(User::Alice, 50)]));
+	let deposit = MultisigTransaction::Deposit {
+		sender: User::Alice,
+		signatories: vec![User::Alice, User::Bob],
+		threshold: 2,
+		amount: 100,
+	};
+
+	assert_eq!(
+		Multisig::<AccountedCurrency>::try_next_state(&start, &deposit),
+		Err(MultisigError::Call(AccountingError::InsufficientBalance {
+			balance: 50,
+			requested: 100
+		}))
+	);
+}
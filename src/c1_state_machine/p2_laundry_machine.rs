@@ -2,14 +2,14 @@
 //! they're ready to be worn again. Or course washing and wearing clothes takes its toll on the
 //! clothes, and eventually they get tattered.
 
-use super::StateMachine;
+use super::{explorer::Explorable, repl::Interactive, StateMachine};
 
 /// This state machine models the typical life cycle of clothes as they make their way through the
 /// laundry cycle several times before ultimately becoming tattered.
 pub struct ClothesMachine;
 
 /// Models a piece of clothing throughout its lifecycle.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub enum ClothesState {
 	/// Clean clothes ready to be worn. With some given life left.
 	Clean(u64),
@@ -24,6 +24,7 @@ pub enum ClothesState {
 }
 
 /// Something you can do with clothes
+#[derive(Debug, Clone)]
 pub enum ClothesAction {
 	/// Wearing clothes decreases their life by 1 and makes them dirty.
 	Wear,
@@ -42,6 +43,53 @@ impl StateMachine for ClothesMachine {
 	fn next_state(starting_state: &ClothesState, t: &ClothesAction) -> ClothesState {
 		todo!("Exercise 3")
 	}
+
+	fn human_name() -> String {
+		"Clothes".into()
+	}
+}
+
+impl Interactive for ClothesMachine {
+	/// Brand new clothes that can survive a few trips through the laundry.
+	fn initial_state() -> ClothesState {
+		ClothesState::Clean(10)
+	}
+
+	fn parse_transition(input: &str) -> Result<ClothesAction, String> {
+		match input {
+			"wear" => Ok(ClothesAction::Wear),
+			"wash" => Ok(ClothesAction::Wash),
+			"dry" => Ok(ClothesAction::Dry),
+			_ => Err("expected `wear`, `wash`, or `dry`".into()),
+		}
+	}
+
+	fn format_transition(t: &ClothesAction) -> String {
+		match t {
+			ClothesAction::Wear => "wear".into(),
+			ClothesAction::Wash => "wash".into(),
+			ClothesAction::Dry => "dry".into(),
+		}
+	}
+
+	fn format_state(state: &ClothesState) -> String {
+		match state {
+			ClothesState::Clean(life) => format!("clean ({life} life left)"),
+			ClothesState::Dirty(life) => format!("dirty ({life} life left)"),
+			ClothesState::Wet(life) => format!("wet ({life} life left)"),
+			ClothesState::Tattered => "tattered".into(),
+		}
+	}
+
+	fn transition_help() -> String {
+		"  wear\n  wash\n  dry".into()
+	}
+}
+
+impl Explorable for ClothesMachine {
+	fn transitions() -> Vec<ClothesAction> {
+		vec![ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry]
+	}
 }
 
 #[test]
@@ -211,3 +259,43 @@ fn sm_2_dry_dirty_until_tattered() {
 	let expected = ClothesState::Tattered;
 	assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_2_explore_clothes_lifecycle() {
+	use super::explorer::explore;
+
+	let exploration = explore::<ClothesMachine>(ClothesState::Clean(3), 100);
+	let universe = (1..=3)
+		.flat_map(|life| {
+			[ClothesState::Clean(life), ClothesState::Dirty(life), ClothesState::Wet(life)]
+		})
+		.chain([ClothesState::Tattered]);
+
+	assert!(exploration.complete);
+
+	// Once clothes are tattered, there is no going back.
+	let dead: Vec<_> =
+		exploration.dead_states().into_iter().map(|i| &exploration.states[i]).collect();
+	assert_eq!(dead, vec![&ClothesState::Tattered]);
+
+	// Every action wears the clothes out a little, so the machine can never return to a state.
+	assert!(exploration.cycles().is_empty());
+
+	// Doing anything at all costs life, so the clothes can't be dirty or wet at full life.
+	let mut unreachable = exploration.unreachable_states(universe);
+	unreachable.sort_by_key(|state| format!("{state:?}"));
+	assert_eq!(unreachable, vec![ClothesState::Dirty(3), ClothesState::Wet(3)]);
+}
+
+#[test]
+fn sm_2_explore_clothes_to_dot() {
+	use super::explorer::explore;
+
+	let dot = explore::<ClothesMachine>(ClothesState::Clean(1), 100).to_dot();
+
+	assert!(dot.starts_with("digraph \"Clothes\" {"));
+	assert!(dot.contains("s0 [label=\"clean (1 life left)\", style=bold];"));
+	assert!(dot.contains("s1 [label=\"tattered\", peripheries=2];"));
+	assert!(dot.contains("s0 -> s1 [label=\"wear\"];"));
+	assert!(dot.contains("s1 -> s1 [label=\"dry\"];"));
+}
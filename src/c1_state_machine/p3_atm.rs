@@ -2,7 +2,11 @@
 //! The atm may fail to give you cash if it is empty or you haven't swiped your card, or you have
 //! entered the wrong pin.
 
-use super::StateMachine;
+use super::{
+	explorer::Explorable,
+	repl::{parse_u64, split_command, Interactive},
+	StateMachine,
+};
 
 /// The keys on the ATM keypad
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
@@ -24,7 +28,7 @@ pub enum Action {
 }
 
 /// The various states of authentication possible with the ATM
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 enum Auth {
 	/// No session has begun yet. Waiting for the user to swipe their card
 	Waiting,
@@ -42,7 +46,7 @@ enum Auth {
 /// and the ATM automatically goes back to the main menu. If your pin is correct,
 /// the ATM waits for you to key in an amount of money to withdraw. Withdraws
 /// are bounded only by the cash in the machine (there is no account balance).
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Atm {
 	/// How much money is in the ATM
 	cash_inside: u64,
@@ -61,6 +65,95 @@ impl StateMachine for Atm {
 	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
 		todo!("Exercise 4")
 	}
+
+	fn human_name() -> String {
+		"ATM".into()
+	}
+}
+
+impl Key {
+	fn parse(input: &str) -> Result<Key, String> {
+		match input {
+			"1" => Ok(Key::One),
+			"2" => Ok(Key::Two),
+			"3" => Ok(Key::Three),
+			"4" => Ok(Key::Four),
+			"enter" => Ok(Key::Enter),
+			_ => Err(format!("`{input}` is not a key. The keys are 1, 2, 3, 4, and enter")),
+		}
+	}
+
+	fn name(&self) -> &'static str {
+		match self {
+			Key::One => "1",
+			Key::Two => "2",
+			Key::Three => "3",
+			Key::Four => "4",
+			Key::Enter => "enter",
+		}
+	}
+}
+
+impl Interactive for Atm {
+	/// An ATM stocked with some cash, waiting for a customer.
+	fn initial_state() -> Atm {
+		Atm { cash_inside: 100, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() }
+	}
+
+	/// Cards can be swiped either with a pin made of the digits 1 through 4 (from which the pin
+	/// hash is calculated), or with a raw pin hash prefixed by `#`.
+	fn parse_transition(input: &str) -> Result<Action, String> {
+		match split_command(input) {
+			("swipe", args) if args.len() == 1 => match args[0].strip_prefix('#') {
+				Some(pin_hash) => Ok(Action::SwipeCard(parse_u64(pin_hash)?)),
+				None => {
+					let pin = args[0]
+						.chars()
+						.map(|c| Key::parse(&c.to_string()))
+						.collect::<Result<Vec<_>, _>>()?;
+					Ok(Action::SwipeCard(crate::hash(&pin)))
+				},
+			},
+			("press", args) if args.len() == 1 => Ok(Action::PressKey(Key::parse(args[0])?)),
+			_ => Err("expected `swipe <pin>`, `swipe #<pin hash>`, or `press <key>`".into()),
+		}
+	}
+
+	fn format_transition(t: &Action) -> String {
+		match t {
+			Action::SwipeCard(pin_hash) => format!("swipe #{pin_hash}"),
+			Action::PressKey(key) => format!("press {}", key.name()),
+		}
+	}
+
+	fn format_state(state: &Atm) -> String {
+		let auth = match state.expected_pin_hash {
+			Auth::Waiting => "waiting for a card".to_string(),
+			Auth::Authenticating(pin_hash) => format!("waiting for the pin with hash {pin_hash}"),
+			Auth::Authenticated => "waiting for a withdrawal amount".to_string(),
+		};
+		let keys: Vec<_> = state.keystroke_register.iter().map(Key::name).collect();
+
+		format!("cash inside: {}, {auth}, keys pressed: [{}]", state.cash_inside, keys.join(" "))
+	}
+
+	fn transition_help() -> String {
+		"  swipe <pin>         e.g. `swipe 1234`\n  swipe #<pin hash>\n  press <1|2|3|4|enter>"
+			.into()
+	}
+}
+
+/// The ATM can be driven with infinitely many different cards and key sequences. For exploration
+/// we stick to a single card whose pin is `1`, and the keys `1` and `enter`. Even so, the
+/// keystroke register can grow without bound, so explorations of the ATM are always partial.
+impl Explorable for Atm {
+	fn transitions() -> Vec<Action> {
+		vec![
+			Action::SwipeCard(crate::hash(&vec![Key::One])),
+			Action::PressKey(Key::One),
+			Action::PressKey(Key::Enter),
+		]
+	}
 }
 
 #[test]
@@ -247,3 +340,22 @@ fn sm_3_withdraw_acceptable_amount() {
 
 	assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_3_explore_atm() {
+	use super::explorer::explore;
+
+	let start =
+		Atm { cash_inside: 2, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() };
+	let exploration = explore::<Atm>(start, 200);
+
+	// Keys can be pressed forever, so the exploration never finishes.
+	assert!(!exploration.complete);
+	assert_eq!(exploration.states.len(), 200);
+
+	// Withdrawing all the cash is possible
+	assert!(exploration.states.iter().any(|atm| atm.cash_inside == 0));
+
+	// But no state is a trap. At worst, swiping or pressing enter leads somewhere new.
+	assert!(exploration.dead_states().is_empty());
+}
@@ -8,8 +8,17 @@
 //! In this module we design a state machine that tracks the currency balances of several users.
 //! Each user is associated with an account balance and users are able to send money to other users.
 
-use super::{StateMachine, User};
-use std::collections::HashMap;
+use super::{
+	diff::StateDiff,
+	invariants::Rng,
+	repl::{parse_u64, split_command, Interactive},
+	BlockHooks, StateMachine, TryStateMachine, User,
+};
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt,
+	hash::{Hash, Hasher},
+};
 
 /// This state machine models a multi-user currency system. It tracks the balance of each
 /// user and allows users to send funds to one another.
@@ -21,9 +30,19 @@ pub struct AccountedCurrency;
 /// There exists an existential deposit of at least 1. That is
 /// to say that an account gets removed from the map entirely
 /// when its balance falls back to 0.
-type Balances = HashMap<User, u64>;
+pub type Balances = HashMap<User, u64>;
+
+/// Feed the balances into the given hasher. A `HashMap` can't be hashed directly because its
+/// iteration order is arbitrary, so the accounts are hashed in sorted order. Machines whose state
+/// holds balances use this to implement `Hash`, so that a blockchain can commit to their state.
+pub fn hash_balances<H: Hasher>(balances: &Balances, hasher: &mut H) {
+	let mut accounts: Vec<_> = balances.iter().collect();
+	accounts.sort();
+	accounts.hash(hasher);
+}
 
 /// The state transitions that users can make in an accounted currency system
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum AccountingTransaction {
 	/// Create some new money for the given minter in the given amount
 	Mint { minter: User, amount: u64 },
@@ -43,6 +62,191 @@ impl StateMachine for AccountedCurrency {
 	fn next_state(starting_state: &Balances, t: &AccountingTransaction) -> Balances {
 		todo!("Exercise 1")
 	}
+
+	fn human_name() -> String {
+		"Accounted Currency".into()
+	}
+}
+
+/// The reasons an accounting transaction may be rejected.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AccountingError {
+	/// Minting, burning, or transferring zero tokens has no effect and is not allowed.
+	ZeroAmount,
+	/// The burner does not have an account.
+	UnknownBurner,
+	/// The sender does not have an account.
+	UnknownSender,
+	/// A user may not transfer tokens to themselves.
+	SelfTransfer,
+	/// The sender's balance is smaller than the amount they tried to send.
+	InsufficientBalance { balance: u64, requested: u64 },
+	/// The transaction would push an account balance beyond `u64::MAX`.
+	Overflow,
+}
+
+/// The fallible version of the accounted currency. It follows the exact same rules as above, but
+/// reports why a transaction was rejected rather than silently leaving the state unchanged.
+///
+/// Once this is written, `next_state` can simply fall back to the starting state whenever this
+/// returns an error.
+impl TryStateMachine for AccountedCurrency {
+	type Error = AccountingError;
+
+	fn try_next_state(
+		starting_state: &Balances,
+		t: &AccountingTransaction,
+	) -> Result<Balances, AccountingError> {
+		todo!("Exercise 2")
+	}
+}
+
+/// The accounted currency doesn't do anything special at block boundaries.
+impl BlockHooks for AccountedCurrency {}
+
+/// The changes between two sets of balances.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct BalancesDiff {
+	/// The change in balance of every user whose balance changed.
+	pub deltas: BTreeMap<User, i128>,
+	/// Users who did not have an account before, but do now.
+	pub created: Vec<User>,
+	/// Users who had an account before, but were removed because their balance reached zero.
+	pub reaped: Vec<User>,
+}
+
+impl BalancesDiff {
+	/// Whether nothing changed at all.
+	pub fn is_empty(&self) -> bool {
+		self.deltas.is_empty()
+	}
+
+	/// The net change in total issuance.
+	pub fn issuance_delta(&self) -> i128 {
+		self.deltas.values().sum()
+	}
+}
+
+impl fmt::Display for BalancesDiff {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.is_empty() {
+			return write!(f, "no changes")
+		}
+		let changes: Vec<_> = self
+			.deltas
+			.iter()
+			.map(|(user, delta)| {
+				let note = if self.created.contains(user) {
+					" (created)"
+				} else if self.reaped.contains(user) {
+					" (reaped)"
+				} else {
+					""
+				};
+				format!("{user}: {delta:+}{note}")
+			})
+			.collect();
+		write!(f, "{}", changes.join(", "))
+	}
+}
+
+impl StateDiff for AccountedCurrency {
+	type Diff = BalancesDiff;
+
+	fn diff(old: &Balances, new: &Balances) -> BalancesDiff {
+		let mut diff = BalancesDiff::default();
+
+		for user in old.keys().chain(new.keys()) {
+			let before = old.get(user).copied();
+			let after = new.get(user).copied();
+			let delta = after.unwrap_or(0) as i128 - before.unwrap_or(0) as i128;
+			if delta != 0 {
+				diff.deltas.insert(*user, delta);
+			}
+			match (before, after) {
+				(None, Some(_)) if !diff.created.contains(user) => diff.created.push(*user),
+				(Some(_), None) if !diff.reaped.contains(user) => diff.reaped.push(*user),
+				_ => {},
+			}
+		}
+		diff.created.sort();
+		diff.reaped.sort();
+
+		diff
+	}
+}
+
+impl Interactive for AccountedCurrency {
+	fn initial_state() -> Balances {
+		Balances::new()
+	}
+
+	fn parse_transition(input: &str) -> Result<AccountingTransaction, String> {
+		match split_command(input) {
+			("mint", args) if args.len() == 2 => Ok(AccountingTransaction::Mint {
+				minter: args[0].parse()?,
+				amount: parse_u64(args[1])?,
+			}),
+			("burn", args) if args.len() == 2 => Ok(AccountingTransaction::Burn {
+				burner: args[0].parse()?,
+				amount: parse_u64(args[1])?,
+			}),
+			("transfer", args) if args.len() == 3 => Ok(AccountingTransaction::Transfer {
+				sender: args[0].parse()?,
+				receiver: args[1].parse()?,
+				amount: parse_u64(args[2])?,
+			}),
+			_ => Err("expected `mint`, `burn`, or `transfer`. Type `help` for details".into()),
+		}
+	}
+
+	fn format_transition(t: &AccountingTransaction) -> String {
+		match t {
+			AccountingTransaction::Mint { minter, amount } => format!("mint {minter} {amount}"),
+			AccountingTransaction::Burn { burner, amount } => format!("burn {burner} {amount}"),
+			AccountingTransaction::Transfer { sender, receiver, amount } =>
+				format!("transfer {sender} {receiver} {amount}"),
+		}
+	}
+
+	fn format_state(state: &Balances) -> String {
+		let mut balances: Vec<_> = state.iter().collect();
+		balances.sort();
+		let balances: Vec<_> = balances
+			.into_iter()
+			.map(|(user, balance)| format!("{user}: {balance}"))
+			.collect();
+
+		format!("{{{}}}", balances.join(", "))
+	}
+
+	fn describe_change(pre: &Balances, post: &Balances) -> Option<String> {
+		Some(Self::diff(pre, post).to_string())
+	}
+
+	fn transition_help() -> String {
+		"  mint <minter> <amount>\n  burn <burner> <amount>\n  transfer <sender> <receiver> <amount>"
+			.into()
+	}
+}
+
+/// Generate a random accounting transaction for property testing. Amounts are kept small so that
+/// both valid and invalid burns and transfers are common.
+pub fn arbitrary_transaction(rng: &mut Rng, _: &Balances) -> AccountingTransaction {
+	let user = *rng.choose(&User::ALL).expect("there are several users; qed");
+	let other = *rng.choose(&User::ALL).expect("there are several users; qed");
+	let amount = rng.below(60);
+
+	match rng.below(3) {
+		0 => AccountingTransaction::Mint { minter: user, amount },
+		1 => AccountingTransaction::Burn { burner: user, amount },
+		_ => AccountingTransaction::Transfer { sender: user, receiver: other, amount },
+	}
+}
+
+/// The total amount of money in existence.
+fn total_issuance(balances: &Balances) -> u128 {
+	balances.values().map(|balance| *balance as u128).sum()
 }
 
 #[test]
@@ -265,3 +469,175 @@ fn sm_4_transfer() {
 
 	assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_4_try_empty_mint_is_rejected() {
+	let start = HashMap::new();
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Mint { minter: User::Alice, amount: 0 },
+	);
+
+	assert_eq!(end, Err(AccountingError::ZeroAmount));
+}
+
+#[test]
+fn sm_4_try_mint_overflow_is_rejected() {
+	let start = HashMap::from([(User::Alice, u64::MAX)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Mint { minter: User::Alice, amount: 1 },
+	);
+
+	assert_eq!(end, Err(AccountingError::Overflow));
+}
+
+#[test]
+fn sm_4_try_burn_more_than_balance() {
+	let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Burn { burner: User::Bob, amount: 100 },
+	);
+
+	assert_eq!(end, Ok(HashMap::from([(User::Alice, 100)])));
+}
+
+#[test]
+fn sm_4_try_unknown_burner_is_rejected() {
+	let start = HashMap::from([(User::Alice, 100)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Burn { burner: User::Bob, amount: 50 },
+	);
+
+	assert_eq!(end, Err(AccountingError::UnknownBurner));
+}
+
+#[test]
+fn sm_4_try_send_to_same_user_is_rejected() {
+	let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Bob, amount: 10 },
+	);
+
+	assert_eq!(end, Err(AccountingError::SelfTransfer));
+}
+
+#[test]
+fn sm_4_try_insufficient_balance_is_rejected() {
+	let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Alice, amount: 60 },
+	);
+
+	assert_eq!(end, Err(AccountingError::InsufficientBalance { balance: 50, requested: 60 }));
+}
+
+#[test]
+fn sm_4_try_unknown_sender_is_rejected() {
+	let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Transfer {
+			sender: User::Charlie,
+			receiver: User::Alice,
+			amount: 50,
+		},
+	);
+
+	assert_eq!(end, Err(AccountingError::UnknownSender));
+}
+
+#[test]
+fn sm_4_try_transfer_reaps_sender() {
+	let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let end = AccountedCurrency::try_next_state(
+		&start,
+		&AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Charlie, amount: 50 },
+	);
+
+	assert_eq!(end, Ok(HashMap::from([(User::Alice, 100), (User::Charlie, 50)])));
+}
+
+#[test]
+fn sm_4_parse_transitions() {
+	let transactions = [
+		AccountingTransaction::Mint { minter: User::Alice, amount: 100 },
+		AccountingTransaction::Burn { burner: User::Bob, amount: 5 },
+		AccountingTransaction::Transfer { sender: User::Charlie, receiver: User::Alice, amount: 7 },
+	];
+
+	for t in transactions {
+		let text = AccountedCurrency::format_transition(&t);
+		assert_eq!(AccountedCurrency::parse_transition(&text), Ok(t));
+	}
+}
+
+#[test]
+fn sm_4_format_state_is_sorted() {
+	let state = HashMap::from([(User::Charlie, 3), (User::Alice, 1), (User::Bob, 2)]);
+
+	assert_eq!(AccountedCurrency::format_state(&state), "{alice: 1, bob: 2, charlie: 3}");
+}
+
+#[test]
+fn sm_4_invariants_hold_for_random_transactions() {
+	use super::invariants::PropertyTest;
+
+	PropertyTest::<AccountedCurrency>::new(HashMap::new(), arbitrary_transaction)
+		.invariant("total supply only changes on mint or burn", |pre, t, post| match t {
+			AccountingTransaction::Transfer { .. } => total_issuance(pre) == total_issuance(post),
+			_ => true,
+		})
+		.invariant("mint never decreases total supply", |pre, t, post| match t {
+			AccountingTransaction::Mint { amount, .. } =>
+				total_issuance(post) == total_issuance(pre) ||
+					total_issuance(post) == total_issuance(pre) + *amount as u128,
+			_ => true,
+		})
+		.invariant("burn never increases total supply", |pre, t, post| match t {
+			AccountingTransaction::Burn { .. } => total_issuance(post) <= total_issuance(pre),
+			_ => true,
+		})
+		.invariant("no account is left with a zero balance", |_, _, post| {
+			post.values().all(|balance| *balance > 0)
+		})
+		.check();
+}
+
+#[test]
+fn sm_4_diff_of_identical_states_is_empty() {
+	let state = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let diff = AccountedCurrency::diff(&state, &state);
+
+	assert!(diff.is_empty());
+	assert_eq!(diff.to_string(), "no changes");
+}
+
+#[test]
+fn sm_4_diff_transfer_creates_and_reaps() {
+	let old = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+	let new = HashMap::from([(User::Alice, 100), (User::Charlie, 50)]);
+	let diff = AccountedCurrency::diff(&old, &new);
+
+	assert_eq!(diff.deltas, BTreeMap::from([(User::Bob, -50), (User::Charlie, 50)]));
+	assert_eq!(diff.created, vec![User::Charlie]);
+	assert_eq!(diff.reaped, vec![User::Bob]);
+	assert_eq!(diff.issuance_delta(), 0);
+	assert_eq!(diff.to_string(), "bob: -50 (reaped), charlie: +50 (created)");
+}
+
+#[test]
+fn sm_4_diff_mint() {
+	let old = HashMap::from([(User::Alice, 100)]);
+	let new = HashMap::from([(User::Alice, 150)]);
+	let diff = AccountedCurrency::diff(&old, &new);
+
+	assert_eq!(diff.deltas, BTreeMap::from([(User::Alice, 50)]));
+	assert!(diff.created.is_empty());
+	assert!(diff.reaped.is_empty());
+	assert_eq!(diff.issuance_delta(), 50);
+}
@@ -3,8 +3,17 @@
 //! cash bills. Each bill has an amount and an owner, and can be spent in its entirety.
 //! When a state transition spends bills, new bills are created in lesser or equal amount.
 
-use super::{StateMachine, User};
-use std::collections::HashSet;
+use super::{
+	diff::StateDiff,
+	invariants::Rng,
+	repl::{parse_u64, split_command, Interactive},
+	BlockHooks, StateMachine, TryStateMachine, User,
+};
+use std::{
+	collections::HashSet,
+	fmt,
+	hash::{Hash, Hasher},
+};
 
 /// This state machine models a multi-user currency system. It tracks a set of bills in
 /// circulation, and updates that set when money is transferred.
@@ -13,7 +22,7 @@ pub struct DigitalCashSystem;
 /// A single bill in the digital cash system. Each bill has an owner who is allowed to spent
 /// it and an amount that it is worth. It also has serial number to ensure that each bill
 /// is unique.
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct Bill {
 	owner: User,
 	amount: u64,
@@ -30,6 +39,17 @@ pub struct State {
 	next_serial: u64,
 }
 
+/// A `HashSet` can't be hashed directly because its iteration order is arbitrary, so the bills
+/// are hashed in sorted order. This lets a blockchain commit to the state with a state root.
+impl Hash for State {
+	fn hash<H: Hasher>(&self, hasher: &mut H) {
+		let mut bills: Vec<_> = self.bills.iter().collect();
+		bills.sort();
+		bills.hash(hasher);
+		self.next_serial.hash(hasher);
+	}
+}
+
 impl State {
 	pub fn new() -> Self {
 		State { bills: HashSet::<Bill>::new(), next_serial: 0 }
@@ -43,6 +63,11 @@ impl State {
 		self.next_serial
 	}
 
+	/// The bills currently in circulation, in no particular order.
+	pub fn bills(&self) -> impl Iterator<Item = &Bill> {
+		self.bills.iter()
+	}
+
 	fn increment_serial(&mut self) {
 		self.next_serial += 1
 	}
@@ -71,6 +96,7 @@ impl<const N: usize> From<[Bill; N]> for State {
 }
 
 /// The state transitions that users can make in a digital cash system
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum CashTransaction {
 	/// Mint a single new bill owned by the minter
 	Mint { minter: User, amount: u64 },
@@ -90,6 +116,239 @@ impl StateMachine for DigitalCashSystem {
 	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
 		todo!("Exercise 1")
 	}
+
+	fn human_name() -> String {
+		"Digital Cash".into()
+	}
+}
+
+/// The reasons a cash transaction may be rejected.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CashError {
+	/// A transfer must spend at least one bill.
+	NoSpends,
+	/// The spent bill is not in circulation. Either it never existed, it was already spent, or
+	/// the owner or amount given does not match the circulating bill with that serial.
+	UnknownBill(Bill),
+	/// The same bill appears more than once in the spends of a single transfer.
+	DoubleSpend(Bill),
+	/// Bills may not be created with a value of zero.
+	ZeroValueOutput,
+	/// Newly created bills must use consecutive serial numbers starting from the state's
+	/// `next_serial`.
+	SerialMismatch { expected: u64, found: u64 },
+	/// The total value of the bills being spent or received exceeds `u64::MAX`.
+	Overflow,
+	/// The bills received are worth more than the bills spent.
+	OutputsExceedInputs { spent: u64, received: u64 },
+}
+
+/// The fallible version of the digital cash system. It follows the exact same rules as above,
+/// but reports why a transaction was rejected rather than silently leaving the state unchanged.
+///
+/// Once this is written, `next_state` can simply fall back to the starting state whenever this
+/// returns an error.
+impl TryStateMachine for DigitalCashSystem {
+	type Error = CashError;
+
+	fn try_next_state(starting_state: &State, t: &CashTransaction) -> Result<State, CashError> {
+		todo!("Exercise 2")
+	}
+}
+
+/// The digital cash system doesn't do anything special at block boundaries.
+impl BlockHooks for DigitalCashSystem {}
+
+impl Bill {
+	pub fn new(owner: User, amount: u64, serial: u64) -> Self {
+		Bill { owner, amount, serial }
+	}
+
+	pub fn owner(&self) -> User {
+		self.owner
+	}
+
+	pub fn amount(&self) -> u64 {
+		self.amount
+	}
+
+	pub fn serial(&self) -> u64 {
+		self.serial
+	}
+
+	/// Parse a bill written as `owner:amount:serial`.
+	fn parse(input: &str) -> Result<Bill, String> {
+		match input.split(':').collect::<Vec<_>>()[..] {
+			[owner, amount, serial] => Ok(Bill {
+				owner: owner.parse()?,
+				amount: parse_u64(amount)?,
+				serial: parse_u64(serial)?,
+			}),
+			_ => Err(format!("`{input}` is not a bill. Bills are written `owner:amount:serial`")),
+		}
+	}
+
+	fn format(&self) -> String {
+		format!("{}:{}:{}", self.owner, self.amount, self.serial)
+	}
+}
+
+/// The changes between two digital cash states.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CashDiff {
+	/// Bills that are in circulation now, but were not before, ordered by serial.
+	pub created: Vec<Bill>,
+	/// Bills that were in circulation before, but are not any more, ordered by serial.
+	pub destroyed: Vec<Bill>,
+	/// The old and new next serial number, if it changed.
+	pub next_serial: Option<(u64, u64)>,
+}
+
+impl CashDiff {
+	/// Whether nothing changed at all.
+	pub fn is_empty(&self) -> bool {
+		self.created.is_empty() && self.destroyed.is_empty() && self.next_serial.is_none()
+	}
+
+	/// The net change in the total value of bills in circulation.
+	pub fn value_delta(&self) -> i128 {
+		let created: i128 = self.created.iter().map(|bill| bill.amount as i128).sum();
+		let destroyed: i128 = self.destroyed.iter().map(|bill| bill.amount as i128).sum();
+		created - destroyed
+	}
+}
+
+impl fmt::Display for CashDiff {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.is_empty() {
+			return write!(f, "no changes")
+		}
+		let mut changes: Vec<_> =
+			self.destroyed.iter().map(|b| format!("-{}", b.format())).collect();
+		changes.extend(self.created.iter().map(|bill| format!("+{}", bill.format())));
+		if let Some((old, new)) = self.next_serial {
+			changes.push(format!("next serial: {old} -> {new}"));
+		}
+		write!(f, "{}", changes.join(", "))
+	}
+}
+
+impl StateDiff for DigitalCashSystem {
+	type Diff = CashDiff;
+
+	fn diff(old: &State, new: &State) -> CashDiff {
+		let mut created: Vec<_> = new.bills.difference(&old.bills).cloned().collect();
+		let mut destroyed: Vec<_> = old.bills.difference(&new.bills).cloned().collect();
+		created.sort_by_key(|bill| bill.serial);
+		destroyed.sort_by_key(|bill| bill.serial);
+		let next_serial =
+			(old.next_serial != new.next_serial).then_some((old.next_serial, new.next_serial));
+
+		CashDiff { created, destroyed, next_serial }
+	}
+}
+
+impl Interactive for DigitalCashSystem {
+	fn initial_state() -> State {
+		State::new()
+	}
+
+	fn parse_transition(input: &str) -> Result<CashTransaction, String> {
+		match split_command(input) {
+			("mint", args) if args.len() == 2 =>
+				Ok(CashTransaction::Mint { minter: args[0].parse()?, amount: parse_u64(args[1])? }),
+			("transfer", args) => {
+				let arrow = args
+					.iter()
+					.position(|arg| *arg == "->")
+					.ok_or("a transfer must separate spends from receives with `->`")?;
+				let spends =
+					args[..arrow].iter().map(|bill| Bill::parse(bill)).collect::<Result<_, _>>()?;
+				let receives = args[arrow + 1..]
+					.iter()
+					.map(|bill| Bill::parse(bill))
+					.collect::<Result<_, _>>()?;
+				Ok(CashTransaction::Transfer { spends, receives })
+			},
+			_ => Err("expected `mint` or `transfer`. Type `help` for details".into()),
+		}
+	}
+
+	fn format_transition(t: &CashTransaction) -> String {
+		match t {
+			CashTransaction::Mint { minter, amount } => format!("mint {minter} {amount}"),
+			CashTransaction::Transfer { spends, receives } => {
+				let mut words = vec!["transfer".to_string()];
+				words.extend(spends.iter().map(Bill::format));
+				words.push("->".into());
+				words.extend(receives.iter().map(Bill::format));
+				words.join(" ")
+			},
+		}
+	}
+
+	fn format_state(state: &State) -> String {
+		let mut bills: Vec<_> = state.bills.iter().collect();
+		bills.sort_by_key(|bill| bill.serial);
+		let bills: Vec<_> = bills.into_iter().map(Bill::format).collect();
+
+		format!("bills: [{}], next serial: {}", bills.join(" "), state.next_serial)
+	}
+
+	fn describe_change(pre: &State, post: &State) -> Option<String> {
+		Some(Self::diff(pre, post).to_string())
+	}
+
+	fn transition_help() -> String {
+		"  mint <minter> <amount>\n  transfer <spent bills> -> <received bills>\n  \
+		 Bills are written `owner:amount:serial`, e.g. `transfer alice:20:0 -> bob:15:1`"
+			.into()
+	}
+}
+
+/// Generate a random cash transaction for property testing. Transfers usually spend bills that
+/// really exist and create bills with the correct serial numbers, but now and then they spend a
+/// made-up bill, create too much money, or use the wrong serial, so that invalid transactions are
+/// exercised too.
+pub fn arbitrary_transaction(rng: &mut Rng, state: &State) -> CashTransaction {
+	let mut bills: Vec<_> = state.bills.iter().cloned().collect();
+	bills.sort_by_key(|bill| bill.serial);
+
+	if bills.is_empty() || rng.below(4) == 0 {
+		let minter = *rng.choose(&User::ALL).expect("there are several users; qed");
+		return CashTransaction::Mint { minter, amount: rng.below(50) }
+	}
+
+	let mut spends = Vec::new();
+	for bill in bills {
+		if rng.coin() {
+			spends.push(bill);
+		}
+	}
+	if rng.below(8) == 0 {
+		spends.push(Bill { owner: User::Charlie, amount: rng.below(50), serial: rng.below(20) });
+	}
+
+	let mut budget: u64 = spends.iter().map(|bill| bill.amount).sum();
+	if rng.below(8) == 0 {
+		budget += 10;
+	}
+	let mut receives = Vec::new();
+	let mut serial = state.next_serial + u64::from(rng.below(8) == 0);
+	while budget > 0 && rng.below(4) != 0 {
+		let owner = *rng.choose(&User::ALL).expect("there are several users; qed");
+		let amount = rng.below(budget + 1);
+		budget -= amount;
+		receives.push(Bill { owner, amount, serial });
+		serial += 1;
+	}
+
+	CashTransaction::Transfer { spends, receives }
+}
+
+/// The total value of all bills in circulation.
+fn total_value(state: &State) -> u128 {
+	state.bills.iter().map(|bill| bill.amount as u128).sum()
 }
 
 #[test]
@@ -358,3 +617,254 @@ fn sm_5_spending_from_charlie_to_all() {
 	expected.set_serial(62);
 	assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_5_try_mint_zero_fails() {
+	let start = State::new();
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Mint { minter: User::Alice, amount: 0 },
+	);
+
+	assert_eq!(end, Err(CashError::ZeroValueOutput));
+}
+
+#[test]
+fn sm_5_try_empty_spend_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![],
+			receives: vec![Bill { owner: User::Alice, amount: 15, serial: 1 }],
+		},
+	);
+
+	assert_eq!(end, Err(CashError::NoSpends));
+}
+
+#[test]
+fn sm_5_try_empty_receive_succeeds() {
+	let start = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 20, serial: 0 }],
+			receives: vec![],
+		},
+	);
+	let mut expected = State::from([]);
+	expected.set_serial(1);
+
+	assert_eq!(end, Ok(expected));
+}
+
+#[test]
+fn sm_5_try_overflow_receives_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 42, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 42, serial: 0 }],
+			receives: vec![
+				Bill { owner: User::Alice, amount: u64::MAX, serial: 1 },
+				Bill { owner: User::Alice, amount: 42, serial: 2 },
+			],
+		},
+	);
+
+	assert_eq!(end, Err(CashError::Overflow));
+}
+
+#[test]
+fn sm_5_try_output_value_0_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 20, serial: 0 }],
+			receives: vec![Bill { owner: User::Bob, amount: 0, serial: 1 }],
+		},
+	);
+
+	assert_eq!(end, Err(CashError::ZeroValueOutput));
+}
+
+#[test]
+fn sm_5_try_serial_number_already_seen_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 20, serial: 0 }],
+			receives: vec![Bill { owner: User::Alice, amount: 18, serial: 0 }],
+		},
+	);
+
+	assert_eq!(end, Err(CashError::SerialMismatch { expected: 1, found: 0 }));
+}
+
+#[test]
+fn sm_5_try_spending_bill_with_incorrect_amount_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 40, serial: 0 }],
+			receives: vec![Bill { owner: User::Bob, amount: 40, serial: 1 }],
+		},
+	);
+
+	assert_eq!(
+		end,
+		Err(CashError::UnknownBill(Bill { owner: User::Alice, amount: 40, serial: 0 }))
+	);
+}
+
+#[test]
+fn sm_5_try_spending_same_bill_fails() {
+	let start = State::from([Bill { owner: User::Alice, amount: 40, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![
+				Bill { owner: User::Alice, amount: 40, serial: 0 },
+				Bill { owner: User::Alice, amount: 40, serial: 0 },
+			],
+			receives: vec![Bill { owner: User::Bob, amount: 20, serial: 1 }],
+		},
+	);
+
+	assert_eq!(
+		end,
+		Err(CashError::DoubleSpend(Bill { owner: User::Alice, amount: 40, serial: 0 }))
+	);
+}
+
+#[test]
+fn sm_5_try_spending_more_than_bill_fails() {
+	let start = State::from([
+		Bill { owner: User::Alice, amount: 40, serial: 0 },
+		Bill { owner: User::Charlie, amount: 42, serial: 1 },
+	]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![
+				Bill { owner: User::Alice, amount: 40, serial: 0 },
+				Bill { owner: User::Charlie, amount: 42, serial: 1 },
+			],
+			receives: vec![
+				Bill { owner: User::Bob, amount: 20, serial: 2 },
+				Bill { owner: User::Bob, amount: 20, serial: 3 },
+				Bill { owner: User::Alice, amount: 52, serial: 4 },
+			],
+		},
+	);
+
+	assert_eq!(end, Err(CashError::OutputsExceedInputs { spent: 82, received: 92 }));
+}
+
+#[test]
+fn sm_5_try_spending_from_alice_to_all() {
+	let start = State::from([Bill { owner: User::Alice, amount: 42, serial: 0 }]);
+	let end = DigitalCashSystem::try_next_state(
+		&start,
+		&CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 42, serial: 0 }],
+			receives: vec![
+				Bill { owner: User::Alice, amount: 10, serial: 1 },
+				Bill { owner: User::Bob, amount: 10, serial: 2 },
+				Bill { owner: User::Charlie, amount: 10, serial: 3 },
+			],
+		},
+	);
+	let mut expected = State::from([
+		Bill { owner: User::Alice, amount: 10, serial: 1 },
+		Bill { owner: User::Bob, amount: 10, serial: 2 },
+		Bill { owner: User::Charlie, amount: 10, serial: 3 },
+	]);
+	expected.set_serial(4);
+
+	assert_eq!(end, Ok(expected));
+}
+
+#[test]
+fn sm_5_parse_transitions() {
+	let transactions = [
+		CashTransaction::Mint { minter: User::Alice, amount: 20 },
+		CashTransaction::Transfer {
+			spends: vec![
+				Bill { owner: User::Alice, amount: 20, serial: 0 },
+				Bill { owner: User::Bob, amount: 5, serial: 1 },
+			],
+			receives: vec![Bill { owner: User::Charlie, amount: 25, serial: 2 }],
+		},
+		CashTransaction::Transfer {
+			spends: vec![Bill { owner: User::Alice, amount: 20, serial: 0 }],
+			receives: vec![],
+		},
+	];
+
+	for t in transactions {
+		let text = DigitalCashSystem::format_transition(&t);
+		assert_eq!(DigitalCashSystem::parse_transition(&text), Ok(t));
+	}
+}
+
+#[test]
+fn sm_5_invariants_hold_for_random_transactions() {
+	use super::invariants::PropertyTest;
+
+	PropertyTest::<DigitalCashSystem>::new(State::new(), arbitrary_transaction)
+		.invariant("no two bills share a serial", |_, _, post| {
+			let serials: HashSet<_> = post.bills.iter().map(|bill| bill.serial).collect();
+			serials.len() == post.bills.len()
+		})
+		.invariant("every serial is below the next serial", |_, _, post| {
+			post.bills.iter().all(|bill| bill.serial < post.next_serial)
+		})
+		.invariant("serials are never reused", |pre, _, post| post.next_serial >= pre.next_serial)
+		.invariant("transfers never create money", |pre, t, post| match t {
+			CashTransaction::Transfer { .. } => total_value(post) <= total_value(pre),
+			_ => true,
+		})
+		.invariant("no bill is worth zero", |_, _, post| post.bills.iter().all(|b| b.amount > 0))
+		.check();
+}
+
+#[test]
+fn sm_5_diff_of_identical_states_is_empty() {
+	let state = State::from([Bill { owner: User::Alice, amount: 20, serial: 0 }]);
+	let diff = DigitalCashSystem::diff(&state, &state);
+
+	assert!(diff.is_empty());
+	assert_eq!(diff.to_string(), "no changes");
+}
+
+#[test]
+fn sm_5_diff_transfer() {
+	let old = State::from([
+		Bill { owner: User::Alice, amount: 42, serial: 0 },
+		Bill { owner: User::Bob, amount: 7, serial: 1 },
+	]);
+	let mut new = State::from([
+		Bill { owner: User::Bob, amount: 7, serial: 1 },
+		Bill { owner: User::Alice, amount: 30, serial: 2 },
+		Bill { owner: User::Charlie, amount: 10, serial: 3 },
+	]);
+	new.set_serial(4);
+	let diff = DigitalCashSystem::diff(&old, &new);
+
+	assert_eq!(diff.destroyed, vec![Bill { owner: User::Alice, amount: 42, serial: 0 }]);
+	assert_eq!(
+		diff.created,
+		vec![
+			Bill { owner: User::Alice, amount: 30, serial: 2 },
+			Bill { owner: User::Charlie, amount: 10, serial: 3 },
+		]
+	);
+	assert_eq!(diff.next_serial, Some((2, 4)));
+	assert_eq!(diff.value_delta(), -2);
+	assert_eq!(diff.to_string(), "-alice:42:0, +alice:30:2, +charlie:10:3, next serial: 2 -> 4");
+}
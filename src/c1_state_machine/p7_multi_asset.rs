@@ -0,0 +1,673 @@
+//! The accounted currency from part 4 tracks balances of a single currency. Many real-world chains
+//! host several fungible assets side by side. There is usually one native asset that is built in
+//! from genesis, and users can create additional assets of their own, such as stablecoins or
+//! tokens that represent shares in a project.
+//!
+//! In this module we generalize the accounted currency to any number of assets. Each asset has
+//! its own issuer, its own existential deposit, and its own total issuance. The native asset has
+//! no issuer, so anyone may mint it, which makes it behave exactly like the accounted currency.
+//!
+//! The rules for minting, burning, and transferring are the same for every asset, so rather than
+//! repeating them, the balances of each asset are updated by the accounted currency from part 4.
+//! This module only adds issuers and existential deposits on top.
+
+use super::{
+	invariants::Rng,
+	p4_accounted_currency::{AccountedCurrency, AccountingError, AccountingTransaction, Balances},
+	repl::{parse_u64, split_command, Interactive},
+	BlockHooks, StateMachine, TryStateMachine, User,
+};
+use std::collections::BTreeMap;
+
+/// Assets are identified by a simple number.
+pub type AssetId = u32;
+
+/// The id of the native asset which exists from genesis.
+pub const NATIVE_ASSET: AssetId = 0;
+
+/// This state machine models a multi-user, multi-asset currency system.
+pub struct MultiAssetCurrency;
+
+/// Everything the system knows about an asset other than the individual balances.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AssetDetails {
+	/// The only user allowed to mint this asset. When there is no issuer, anyone may mint.
+	pub issuer: Option<User>,
+	/// The smallest balance an account may hold. Accounts whose balance falls below this amount
+	/// are removed entirely.
+	pub existential_deposit: u64,
+	/// The total amount of this asset held across all accounts.
+	pub total_issuance: u128,
+}
+
+/// The state of the multi-asset currency system.
+///
+/// Both maps are ordered so that equal states always hash identically.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MultiAssetState {
+	/// Every asset that has been created so far.
+	assets: BTreeMap<AssetId, AssetDetails>,
+	/// The balance of each user in each asset. Zero balances are never stored.
+	balances: BTreeMap<(AssetId, User), u64>,
+}
+
+impl MultiAssetState {
+	/// A fresh state where the native asset exists, but nobody holds any of it.
+	pub fn new() -> Self {
+		let native = AssetDetails { issuer: None, existential_deposit: 1, total_issuance: 0 };
+		MultiAssetState {
+			assets: BTreeMap::from([(NATIVE_ASSET, native)]),
+			balances: BTreeMap::new(),
+		}
+	}
+
+	/// The details of the given asset, if it exists.
+	pub fn asset(&self, asset: AssetId) -> Option<&AssetDetails> {
+		self.assets.get(&asset)
+	}
+
+	/// The balance of the given user in the given asset.
+	pub fn balance(&self, asset: AssetId, user: User) -> u64 {
+		self.balances.get(&(asset, user)).copied().unwrap_or(0)
+	}
+
+	/// The total issuance of the given asset, or zero if the asset does not exist.
+	pub fn total_issuance(&self, asset: AssetId) -> u128 {
+		self.asset(asset).map(|details| details.total_issuance).unwrap_or(0)
+	}
+
+	/// The balances of the native asset, in the same form that the accounted currency uses.
+	pub fn native_balances(&self) -> Balances {
+		self.asset_balances(NATIVE_ASSET)
+	}
+}
+
+impl Default for MultiAssetState {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Build a state whose native asset balances are exactly those of an accounted currency state.
+impl From<Balances> for MultiAssetState {
+	fn from(native: Balances) -> Self {
+		let mut state = MultiAssetState::new();
+		for (user, balance) in native {
+			if balance > 0 {
+				state.balances.insert((NATIVE_ASSET, user), balance);
+				state.details_mut(NATIVE_ASSET).total_issuance += balance as u128;
+			}
+		}
+		state
+	}
+}
+
+/// The state transitions that users can make in a multi-asset currency system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MultiAssetTransaction {
+	/// Create a new asset with the given id. Only the issuer will be able to mint it.
+	CreateAsset { asset: AssetId, issuer: User, existential_deposit: u64 },
+	/// Create some new money of the given asset for the minter. For assets with an issuer, the
+	/// minter must be the issuer.
+	Mint { asset: AssetId, minter: User, amount: u64 },
+	/// Destroy some money from the given account. If the remaining balance would fall below the
+	/// existential deposit, the entire balance is burned and the account is removed.
+	Burn { asset: AssetId, burner: User, amount: u64 },
+	/// Send some of the given asset from one account to another
+	Transfer { asset: AssetId, sender: User, receiver: User, amount: u64 },
+}
+
+/// Every accounted currency transaction is a multi-asset transaction on the native asset.
+impl From<AccountingTransaction> for MultiAssetTransaction {
+	fn from(t: AccountingTransaction) -> Self {
+		let asset = NATIVE_ASSET;
+		match t {
+			AccountingTransaction::Mint { minter, amount } =>
+				MultiAssetTransaction::Mint { asset, minter, amount },
+			AccountingTransaction::Burn { burner, amount } =>
+				MultiAssetTransaction::Burn { asset, burner, amount },
+			AccountingTransaction::Transfer { sender, receiver, amount } =>
+				MultiAssetTransaction::Transfer { asset, sender, receiver, amount },
+		}
+	}
+}
+
+/// The reasons a multi-asset transaction may be rejected.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MultiAssetError {
+	/// There is no asset with this id.
+	UnknownAsset(AssetId),
+	/// There is already an asset with this id.
+	AssetExists(AssetId),
+	/// Existential deposits must be at least 1, otherwise zero balances would be kept around.
+	ZeroExistentialDeposit,
+	/// Only the issuer of this asset may mint it.
+	NotIssuer { issuer: User },
+	/// The transaction would leave an account with a positive balance below the existential
+	/// deposit of the asset.
+	BelowExistentialDeposit { existential_deposit: u64, balance: u64 },
+	/// The transaction was rejected for the same reason the accounted currency would reject it.
+	Accounting(AccountingError),
+}
+
+impl From<AccountingError> for MultiAssetError {
+	fn from(e: AccountingError) -> Self {
+		MultiAssetError::Accounting(e)
+	}
+}
+
+impl StateMachine for MultiAssetCurrency {
+	type State = MultiAssetState;
+	type Transition = MultiAssetTransaction;
+
+	fn next_state(starting_state: &MultiAssetState, t: &MultiAssetTransaction) -> MultiAssetState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Multi-Asset Currency".into()
+	}
+}
+
+impl MultiAssetState {
+	/// The details of an asset that is known to exist.
+	fn details_mut(&mut self, asset: AssetId) -> &mut AssetDetails {
+		self.assets
+			.get_mut(&asset)
+			.expect("asset existence is checked before mutation; qed")
+	}
+
+	/// The balances of an asset, in the same form that the accounted currency uses.
+	fn asset_balances(&self, asset: AssetId) -> Balances {
+		self.balances
+			.iter()
+			.filter(|((a, _), _)| *a == asset)
+			.map(|((_, user), balance)| (*user, *balance))
+			.collect()
+	}
+
+	/// Apply an accounted currency transaction to the balances of an asset that is known to
+	/// exist, and enforce the asset's existential deposit on the accounts it touched.
+	fn apply(&mut self, asset: AssetId, t: &AccountingTransaction) -> Result<(), MultiAssetError> {
+		let existential_deposit = self.assets[&asset].existential_deposit;
+		let mut balances = AccountedCurrency::try_next_state(&self.asset_balances(asset), t)?;
+
+		let touched = match *t {
+			AccountingTransaction::Mint { minter, .. } => vec![minter],
+			AccountingTransaction::Burn { burner, .. } => {
+				// Dust that would remain below the existential deposit is burned as well.
+				if balances.get(&burner).is_some_and(|balance| *balance < existential_deposit) {
+					balances.remove(&burner);
+				}
+				vec![burner]
+			},
+			AccountingTransaction::Transfer { sender, receiver, .. } => vec![receiver, sender],
+		};
+		for user in touched {
+			match balances.get(&user) {
+				Some(&balance) if balance < existential_deposit =>
+					return Err(MultiAssetError::BelowExistentialDeposit {
+						existential_deposit,
+						balance,
+					}),
+				_ => {},
+			}
+		}
+
+		self.balances.retain(|(a, _), _| *a != asset);
+		self.balances
+			.extend(balances.iter().map(|(user, balance)| ((asset, *user), *balance)));
+		self.details_mut(asset).total_issuance =
+			balances.values().map(|balance| *balance as u128).sum();
+		Ok(())
+	}
+}
+
+impl TryStateMachine for MultiAssetCurrency {
+	type Error = MultiAssetError;
+
+	fn try_next_state(
+		starting_state: &MultiAssetState,
+		t: &MultiAssetTransaction,
+	) -> Result<MultiAssetState, MultiAssetError> {
+		let mut state = starting_state.clone();
+
+		match *t {
+			MultiAssetTransaction::CreateAsset { asset, issuer, existential_deposit } => {
+				if state.assets.contains_key(&asset) {
+					return Err(MultiAssetError::AssetExists(asset))
+				}
+				if existential_deposit == 0 {
+					return Err(MultiAssetError::ZeroExistentialDeposit)
+				}
+				let details =
+					AssetDetails { issuer: Some(issuer), existential_deposit, total_issuance: 0 };
+				state.assets.insert(asset, details);
+			},
+			MultiAssetTransaction::Mint { asset, minter, amount } => {
+				let details = state.asset(asset).ok_or(MultiAssetError::UnknownAsset(asset))?;
+				match details.issuer {
+					Some(issuer) if issuer != minter =>
+						return Err(MultiAssetError::NotIssuer { issuer }),
+					_ => {},
+				}
+				state.apply(asset, &AccountingTransaction::Mint { minter, amount })?;
+			},
+			MultiAssetTransaction::Burn { asset, burner, amount } => {
+				state.asset(asset).ok_or(MultiAssetError::UnknownAsset(asset))?;
+				state.apply(asset, &AccountingTransaction::Burn { burner, amount })?;
+			},
+			MultiAssetTransaction::Transfer { asset, sender, receiver, amount } => {
+				state.asset(asset).ok_or(MultiAssetError::UnknownAsset(asset))?;
+				state
+					.apply(asset, &AccountingTransaction::Transfer { sender, receiver, amount })?;
+			},
+		}
+
+		Ok(state)
+	}
+}
+
+impl BlockHooks for MultiAssetCurrency {}
+
+/// Assets are entered by number, or as `native` for the native asset.
+fn parse_asset(input: &str) -> Result<AssetId, String> {
+	match input {
+		"native" => Ok(NATIVE_ASSET),
+		other => other.parse().map_err(|_| format!("`{other}` is not a valid asset id")),
+	}
+}
+
+fn format_asset(asset: AssetId) -> String {
+	match asset {
+		NATIVE_ASSET => "native".into(),
+		other => other.to_string(),
+	}
+}
+
+impl Interactive for MultiAssetCurrency {
+	fn initial_state() -> MultiAssetState {
+		MultiAssetState::new()
+	}
+
+	fn parse_transition(input: &str) -> Result<MultiAssetTransaction, String> {
+		match split_command(input) {
+			("create", args) if args.len() == 3 => Ok(MultiAssetTransaction::CreateAsset {
+				asset: parse_asset(args[0])?,
+				issuer: args[1].parse()?,
+				existential_deposit: parse_u64(args[2])?,
+			}),
+			("mint", args) if args.len() == 3 => Ok(MultiAssetTransaction::Mint {
+				asset: parse_asset(args[0])?,
+				minter: args[1].parse()?,
+				amount: parse_u64(args[2])?,
+			}),
+			("burn", args) if args.len() == 3 => Ok(MultiAssetTransaction::Burn {
+				asset: parse_asset(args[0])?,
+				burner: args[1].parse()?,
+				amount: parse_u64(args[2])?,
+			}),
+			("transfer", args) if args.len() == 4 => Ok(MultiAssetTransaction::Transfer {
+				asset: parse_asset(args[0])?,
+				sender: args[1].parse()?,
+				receiver: args[2].parse()?,
+				amount: parse_u64(args[3])?,
+			}),
+			_ =>
+				Err("expected `create`, `mint`, `burn`, or `transfer`. Type `help` for details"
+					.into()),
+		}
+	}
+
+	fn format_transition(t: &MultiAssetTransaction) -> String {
+		match t {
+			MultiAssetTransaction::CreateAsset { asset, issuer, existential_deposit } =>
+				format!("create {} {issuer} {existential_deposit}", format_asset(*asset)),
+			MultiAssetTransaction::Mint { asset, minter, amount } =>
+				format!("mint {} {minter} {amount}", format_asset(*asset)),
+			MultiAssetTransaction::Burn { asset, burner, amount } =>
+				format!("burn {} {burner} {amount}", format_asset(*asset)),
+			MultiAssetTransaction::Transfer { asset, sender, receiver, amount } =>
+				format!("transfer {} {sender} {receiver} {amount}", format_asset(*asset)),
+		}
+	}
+
+	fn format_state(state: &MultiAssetState) -> String {
+		let assets: Vec<_> = state
+			.assets
+			.iter()
+			.map(|(asset, details)| {
+				let issuer =
+					details.issuer.map(|issuer| format!("issuer {issuer}, ")).unwrap_or_default();
+				let balances: Vec<_> = state
+					.balances
+					.iter()
+					.filter(|((a, _), _)| a == asset)
+					.map(|((_, user), balance)| format!("{user}: {balance}"))
+					.collect();
+				format!(
+					"{} ({issuer}ed {}, issuance {}): {{{}}}",
+					format_asset(*asset),
+					details.existential_deposit,
+					details.total_issuance,
+					balances.join(", ")
+				)
+			})
+			.collect();
+
+		assets.join("\n")
+	}
+
+	fn transition_help() -> String {
+		"  create <asset> <issuer> <existential deposit>\n  mint <asset> <minter> <amount>\n  burn \
+		 <asset> <burner> <amount>\n  transfer <asset> <sender> <receiver> <amount>\nThe native \
+		 asset is called `native`."
+			.into()
+	}
+}
+
+/// Generate a random multi-asset transaction for property testing. Only a few asset ids are used
+/// so that transactions often refer to assets that actually exist.
+pub fn arbitrary_transaction(rng: &mut Rng, state: &MultiAssetState) -> MultiAssetTransaction {
+	let asset = rng.below(3) as AssetId;
+	let user = *rng.choose(&User::ALL).expect("there are several users; qed");
+	let other = *rng.choose(&User::ALL).expect("there are several users; qed");
+	let amount = rng.below(60);
+
+	match rng.below(4) {
+		0 => MultiAssetTransaction::CreateAsset {
+			asset,
+			issuer: user,
+			existential_deposit: rng.below(10),
+		},
+		// Usually mint as the issuer so that minting actually succeeds now and then
+		1 => MultiAssetTransaction::Mint {
+			asset,
+			minter: state.asset(asset).and_then(|details| details.issuer).unwrap_or(user),
+			amount,
+		},
+		2 => MultiAssetTransaction::Burn { asset, burner: user, amount },
+		_ => MultiAssetTransaction::Transfer { asset, sender: user, receiver: other, amount },
+	}
+}
+
+#[test]
+fn sm_7_create_asset() {
+	let start = MultiAssetState::new();
+	let create = MultiAssetTransaction::CreateAsset {
+		asset: 1,
+		issuer: User::Alice,
+		existential_deposit: 5,
+	};
+	let end = MultiAssetCurrency::try_next_state(&start, &create).unwrap();
+
+	assert_eq!(
+		end.asset(1),
+		Some(&AssetDetails {
+			issuer: Some(User::Alice),
+			existential_deposit: 5,
+			total_issuance: 0
+		})
+	);
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&end, &create),
+		Err(MultiAssetError::AssetExists(1))
+	);
+}
+
+#[test]
+fn sm_7_create_asset_with_zero_existential_deposit_fails() {
+	let start = MultiAssetState::new();
+	let create = MultiAssetTransaction::CreateAsset {
+		asset: 1,
+		issuer: User::Alice,
+		existential_deposit: 0,
+	};
+
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &create),
+		Err(MultiAssetError::ZeroExistentialDeposit)
+	);
+}
+
+#[test]
+fn sm_7_only_issuer_may_mint() {
+	let mut start = MultiAssetState::new();
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 0 },
+	);
+	let by_bob = MultiAssetTransaction::Mint { asset: 1, minter: User::Bob, amount: 10 };
+	let by_alice = MultiAssetTransaction::Mint { asset: 1, minter: User::Alice, amount: 10 };
+
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &by_bob),
+		Err(MultiAssetError::NotIssuer { issuer: User::Alice })
+	);
+
+	let end = MultiAssetCurrency::try_next_state(&start, &by_alice).unwrap();
+	assert_eq!(end.balance(1, User::Alice), 10);
+	assert_eq!(end.total_issuance(1), 10);
+}
+
+#[test]
+fn sm_7_mint_below_existential_deposit_fails() {
+	let mut start = MultiAssetState::new();
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 0 },
+	);
+	let mint = MultiAssetTransaction::Mint { asset: 1, minter: User::Alice, amount: 4 };
+
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &mint),
+		Err(MultiAssetError::BelowExistentialDeposit { existential_deposit: 5, balance: 4 })
+	);
+}
+
+#[test]
+fn sm_7_unknown_asset() {
+	let start = MultiAssetState::new();
+	let mint = MultiAssetTransaction::Mint { asset: 7, minter: User::Alice, amount: 4 };
+
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &mint),
+		Err(MultiAssetError::UnknownAsset(7))
+	);
+}
+
+#[test]
+fn sm_7_transfer_leaving_dust_fails() {
+	let mut start = MultiAssetState::new();
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 20 },
+	);
+	start.balances.insert((1, User::Alice), 20);
+
+	let leaves_dust = MultiAssetTransaction::Transfer {
+		asset: 1,
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 17,
+	};
+	let receives_dust = MultiAssetTransaction::Transfer {
+		asset: 1,
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 3,
+	};
+
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &leaves_dust),
+		Err(MultiAssetError::BelowExistentialDeposit { existential_deposit: 5, balance: 3 })
+	);
+	assert_eq!(
+		MultiAssetCurrency::try_next_state(&start, &receives_dust),
+		Err(MultiAssetError::BelowExistentialDeposit { existential_deposit: 5, balance: 3 })
+	);
+}
+
+#[test]
+fn sm_7_transfer_entire_balance_reaps_sender() {
+	let mut start = MultiAssetState::new();
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 20 },
+	);
+	start.balances.insert((1, User::Alice), 20);
+	let transfer = MultiAssetTransaction::Transfer {
+		asset: 1,
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 20,
+	};
+	let end = MultiAssetCurrency::try_next_state(&start, &transfer).unwrap();
+
+	assert_eq!(end.balances, BTreeMap::from([((1, User::Bob), 20)]));
+	assert_eq!(end.total_issuance(1), 20);
+}
+
+#[test]
+fn sm_7_burn_removes_dust() {
+	let mut start = MultiAssetState::new();
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 20 },
+	);
+	start.balances.insert((1, User::Alice), 20);
+	let burn = MultiAssetTransaction::Burn { asset: 1, burner: User::Alice, amount: 17 };
+	let end = MultiAssetCurrency::try_next_state(&start, &burn).unwrap();
+
+	assert_eq!(end.balance(1, User::Alice), 0);
+	assert!(end.balances.is_empty());
+	assert_eq!(end.total_issuance(1), 0);
+}
+
+#[test]
+fn sm_7_assets_are_independent() {
+	let mut start = MultiAssetState::from(Balances::from([(User::Alice, 100)]));
+	start.assets.insert(
+		1,
+		AssetDetails { issuer: Some(User::Alice), existential_deposit: 5, total_issuance: 20 },
+	);
+	start.balances.insert((1, User::Alice), 20);
+	let transfer = MultiAssetTransaction::Transfer {
+		asset: 1,
+		sender: User::Alice,
+		receiver: User::Bob,
+		amount: 10,
+	};
+	let end = MultiAssetCurrency::try_next_state(&start, &transfer).unwrap();
+
+	assert_eq!(end.native_balances(), Balances::from([(User::Alice, 100)]));
+	assert_eq!(end.balance(1, User::Alice), 10);
+	assert_eq!(end.balance(1, User::Bob), 10);
+}
+
+#[test]
+fn sm_7_native_asset_passes_the_accounted_currency_cases() {
+	use super::p4_accounted_currency::AccountingTransaction::{Burn, Mint, Transfer};
+	use User::{Alice, Bob, Charlie};
+
+	// The starting balances, transaction, and expected balances of each `sm_4` test
+	let cases = [
+		(vec![], Mint { minter: Alice, amount: 100 }, vec![(Alice, 100)]),
+		(vec![(Alice, 100)], Mint { minter: Bob, amount: 50 }, vec![(Alice, 100), (Bob, 50)]),
+		(vec![(Alice, 100)], Mint { minter: Alice, amount: 50 }, vec![(Alice, 150)]),
+		(vec![], Mint { minter: Alice, amount: 0 }, vec![]),
+		(vec![(Alice, 100)], Burn { burner: Alice, amount: 50 }, vec![(Alice, 50)]),
+		(vec![(Alice, 100), (Bob, 50)], Burn { burner: Bob, amount: 50 }, vec![(Alice, 100)]),
+		(vec![(Alice, 100)], Burn { burner: Bob, amount: 50 }, vec![(Alice, 100)]),
+		(vec![(Alice, 100), (Bob, 50)], Burn { burner: Bob, amount: 100 }, vec![(Alice, 100)]),
+		(vec![(Alice, 100)], Burn { burner: Alice, amount: 0 }, vec![(Alice, 100)]),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Alice, receiver: Bob, amount: 10 },
+			vec![(Alice, 90), (Bob, 60)],
+		),
+		(
+			vec![(Alice, 90), (Bob, 60)],
+			Transfer { sender: Bob, receiver: Alice, amount: 50 },
+			vec![(Alice, 140), (Bob, 10)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Bob, receiver: Bob, amount: 10 },
+			vec![(Alice, 100), (Bob, 50)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Bob, receiver: Alice, amount: 60 },
+			vec![(Alice, 100), (Bob, 50)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Charlie, receiver: Alice, amount: 50 },
+			vec![(Alice, 100), (Bob, 50)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Alice, receiver: Charlie, amount: 50 },
+			vec![(Alice, 50), (Bob, 50), (Charlie, 50)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Bob, receiver: Alice, amount: 50 },
+			vec![(Alice, 150)],
+		),
+		(
+			vec![(Alice, 100), (Bob, 50)],
+			Transfer { sender: Bob, receiver: Charlie, amount: 50 },
+			vec![(Alice, 100), (Charlie, 50)],
+		),
+	];
+
+	for (start, t, expected) in cases {
+		let start = MultiAssetState::from(Balances::from_iter(start));
+		let end = MultiAssetCurrency::next_state(&start, &t.clone().into());
+
+		assert_eq!(end, MultiAssetState::from(Balances::from_iter(expected)), "{t:?}");
+	}
+}
+
+#[test]
+fn sm_7_parse_transitions() {
+	for input in
+		["create 1 alice 5", "mint native bob 10", "burn 1 alice 3", "transfer 2 charlie alice 7"]
+	{
+		let t = MultiAssetCurrency::parse_transition(input).unwrap();
+		assert_eq!(MultiAssetCurrency::format_transition(&t), input);
+	}
+	assert!(MultiAssetCurrency::parse_transition("mint dollars bob 10").is_err());
+}
+
+#[test]
+fn sm_7_invariants_hold_for_random_transactions() {
+	use super::invariants::PropertyTest;
+
+	PropertyTest::<MultiAssetCurrency>::new(MultiAssetState::new(), arbitrary_transaction)
+		.invariant("total issuance matches the sum of balances", |_, _, post| {
+			post.assets.iter().all(|(asset, details)| {
+				let total: u128 = post
+					.balances
+					.iter()
+					.filter(|((a, _), _)| a == asset)
+					.map(|(_, balance)| *balance as u128)
+					.sum();
+				total == details.total_issuance
+			})
+		})
+		.invariant("no balance is below its existential deposit", |_, _, post| {
+			post.balances.iter().all(|((asset, _), balance)| {
+				post.asset(*asset)
+					.is_some_and(|details| *balance >= details.existential_deposit)
+			})
+		})
+		.invariant("transfers never change total issuance", |pre, t, post| match t {
+			MultiAssetTransaction::Transfer { asset, .. } =>
+				pre.total_issuance(*asset) == post.total_issuance(*asset),
+			_ => true,
+		})
+		.check();
+}
@@ -0,0 +1,398 @@
+//! The accounted currency has no notion of who authorized a transaction, or whether it has
+//! already been applied. If the same transfer is included in two different blocks, it is simply
+//! executed twice. This is known as a replay attack, and every real-world blockchain has to
+//! protect against it.
+//!
+//! Account-based chains like Ethereum and Polkadot use nonces for this. Each account has a nonce
+//! which starts at zero and counts the transactions that account has sent. Every transaction is
+//! wrapped in a signed envelope which carries the sender's next nonce. A transaction is only
+//! valid if its nonce is exactly the sender's current nonce, so once it is applied (and the nonce
+//! incremented) it can never be applied again.
+//!
+//! Nonces also tell transaction pools the order in which each sender's transactions must be
+//! included, which we explore at the bottom of this module.
+
+use super::{
+	p4_accounted_currency::{AccountedCurrency, AccountingTransaction},
+	p7_multi_asset::{MultiAssetCurrency, MultiAssetTransaction},
+	BlockContext, BlockHooks, Signature, StateMachine, TryStateMachine, User,
+};
+use std::{collections::BTreeMap, hash::Hash, marker::PhantomData};
+
+/// A state machine whose transitions are each authorized by a single user.
+pub trait Authorized: StateMachine {
+	/// The user who must sign this transition for it to be valid.
+	fn signer(t: &Self::Transition) -> User;
+}
+
+/// Mints, burns, and transfers are authorized by the minter, burner, and sender respectively.
+impl Authorized for AccountedCurrency {
+	fn signer(t: &AccountingTransaction) -> User {
+		match t {
+			AccountingTransaction::Mint { minter, .. } => *minter,
+			AccountingTransaction::Burn { burner, .. } => *burner,
+			AccountingTransaction::Transfer { sender, .. } => *sender,
+		}
+	}
+}
+
+impl Authorized for MultiAssetCurrency {
+	fn signer(t: &MultiAssetTransaction) -> User {
+		match t {
+			MultiAssetTransaction::CreateAsset { issuer, .. } => *issuer,
+			MultiAssetTransaction::Mint { minter, .. } => *minter,
+			MultiAssetTransaction::Burn { burner, .. } => *burner,
+			MultiAssetTransaction::Transfer { sender, .. } => *sender,
+		}
+	}
+}
+
+/// A transaction wrapped in a signed envelope.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SignedTransaction<T> {
+	/// The user who sent the transaction
+	pub sender: User,
+	/// The sender's nonce at the time this transaction is meant to be applied
+	pub nonce: u64,
+	/// The sender's signature over the sender, nonce, and call
+	pub signature: Signature,
+	/// The transaction to apply
+	pub call: T,
+}
+
+impl<T: Hash> SignedTransaction<T> {
+	/// Wrap the call in an envelope signed by the sender.
+	pub fn new(sender: User, nonce: u64, call: T) -> Self {
+		let signature = sender.sign(&(sender, nonce, &call));
+		SignedTransaction { sender, nonce, signature, call }
+	}
+
+	/// Check that the signature matches the rest of the envelope.
+	pub fn has_valid_signature(&self) -> bool {
+		self.signature.verify(self.sender, &(self.sender, self.nonce, &self.call))
+	}
+}
+
+/// The state of a nonced machine: the inner machine's state along with every account's nonce.
+///
+/// Nonces are never removed, even when an account is reaped by the inner machine. Otherwise the
+/// account's nonce would restart at zero and its old transactions could be replayed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NoncedState<S> {
+	/// The state of the inner machine
+	pub inner: S,
+	/// The next nonce expected from each account. Accounts that never sent a transaction are
+	/// absent, and expected to send nonce zero next.
+	pub nonces: BTreeMap<User, u64>,
+}
+
+impl<S> NoncedState<S> {
+	/// Start with the given inner state, and no transactions sent yet.
+	pub fn new(inner: S) -> Self {
+		NoncedState { inner, nonces: BTreeMap::new() }
+	}
+
+	/// The nonce the given user must use for their next transaction.
+	pub fn nonce(&self, user: User) -> u64 {
+		self.nonces.get(&user).copied().unwrap_or(0)
+	}
+}
+
+/// Wraps an authorized state machine so that every transition must be signed by its signer, and
+/// can only be applied once.
+pub struct Nonced<SM>(PhantomData<SM>);
+
+/// A replay protected accounted currency.
+pub type NoncedAccountedCurrency = Nonced<AccountedCurrency>;
+
+/// The reasons a signed transaction may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NonceError<E> {
+	/// The signature does not match the envelope.
+	BadSignature,
+	/// The sender is not the user who must authorize the call.
+	WrongSigner { expected: User, found: User },
+	/// The nonce was already used. This is typically a replayed transaction.
+	StaleNonce { expected: u64, found: u64 },
+	/// The nonce is ahead of the sender's current nonce. An earlier transaction is missing.
+	FutureNonce { expected: u64, found: u64 },
+	/// The inner machine rejected the call.
+	Inner(E),
+}
+
+impl<SM> StateMachine for Nonced<SM>
+where
+	SM: Authorized + TryStateMachine,
+	SM::State: Clone,
+	SM::Transition: Hash,
+{
+	type State = NoncedState<SM::State>;
+	type Transition = SignedTransaction<SM::Transition>;
+
+	fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		format!("Nonced {}", SM::human_name())
+	}
+}
+
+impl<SM> TryStateMachine for Nonced<SM>
+where
+	SM: Authorized + TryStateMachine,
+	SM::State: Clone,
+	SM::Transition: Hash,
+{
+	type Error = NonceError<SM::Error>;
+
+	fn try_next_state(
+		starting_state: &Self::State,
+		t: &Self::Transition,
+	) -> Result<Self::State, Self::Error> {
+		if !t.has_valid_signature() {
+			return Err(NonceError::BadSignature)
+		}
+		let expected = SM::signer(&t.call);
+		if t.sender != expected {
+			return Err(NonceError::WrongSigner { expected, found: t.sender })
+		}
+		let expected = starting_state.nonce(t.sender);
+		if t.nonce < expected {
+			return Err(NonceError::StaleNonce { expected, found: t.nonce })
+		}
+		if t.nonce > expected {
+			return Err(NonceError::FutureNonce { expected, found: t.nonce })
+		}
+
+		let inner =
+			SM::try_next_state(&starting_state.inner, &t.call).map_err(NonceError::Inner)?;
+		let mut nonces = starting_state.nonces.clone();
+		nonces.insert(t.sender, expected + 1);
+
+		Ok(NoncedState { inner, nonces })
+	}
+}
+
+/// The inner machine's hooks run as usual. Nonces don't change at block boundaries.
+impl<SM> BlockHooks for Nonced<SM>
+where
+	SM: Authorized + TryStateMachine + BlockHooks,
+	SM::State: Clone,
+	SM::Transition: Hash,
+{
+	fn on_initialize(state: &Self::State, block: &BlockContext) -> Self::State {
+		NoncedState { inner: SM::on_initialize(&state.inner, block), nonces: state.nonces.clone() }
+	}
+
+	fn on_finalize(state: &Self::State, block: &BlockContext) -> Self::State {
+		NoncedState { inner: SM::on_finalize(&state.inner, block), nonces: state.nonces.clone() }
+	}
+}
+
+/// Prepare the contents of a transaction pool for inclusion in a block.
+///
+/// Transactions with stale nonces can never become valid again, so they are dropped along with
+/// any duplicates of the same sender and nonce (the first one seen wins). The remaining
+/// transactions are returned in an order that can be applied one after another: each sender's
+/// transactions by increasing nonce, starting from their current nonce. Transactions beyond a
+/// gap in a sender's nonces are not ready yet, and are returned separately so the pool can keep
+/// them for later.
+///
+/// Returns the ready transactions followed by the future ones.
+pub fn order_by_nonce<T>(
+	nonces: &BTreeMap<User, u64>,
+	pool: Vec<SignedTransaction<T>>,
+) -> (Vec<SignedTransaction<T>>, Vec<SignedTransaction<T>>) {
+	let mut by_sender = BTreeMap::<User, BTreeMap<u64, SignedTransaction<T>>>::new();
+	for t in pool {
+		let current = nonces.get(&t.sender).copied().unwrap_or(0);
+		if t.nonce >= current {
+			by_sender.entry(t.sender).or_default().entry(t.nonce).or_insert(t);
+		}
+	}
+
+	let mut ready = Vec::new();
+	let mut future = Vec::new();
+	for (sender, transactions) in by_sender {
+		let mut next = nonces.get(&sender).copied().unwrap_or(0);
+		for (nonce, t) in transactions {
+			if nonce == next {
+				ready.push(t);
+				next += 1;
+			} else {
+				future.push(t);
+			}
+		}
+	}
+
+	(ready, future)
+}
+
+#[test]
+fn sm_8_signed_transfer_increments_nonce() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::from([(User::Alice, 100)]));
+	let transfer = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+	);
+	let end = NoncedAccountedCurrency::try_next_state(&start, &transfer).unwrap();
+
+	assert_eq!(end.inner.get(&User::Bob), Some(&40));
+	assert_eq!(end.nonce(User::Alice), 1);
+	assert_eq!(end.nonce(User::Bob), 0);
+}
+
+#[test]
+fn sm_8_replayed_transaction_is_rejected() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::from([(User::Alice, 100)]));
+	let transfer = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+	);
+	let once = NoncedAccountedCurrency::try_next_state(&start, &transfer).unwrap();
+
+	assert_eq!(
+		NoncedAccountedCurrency::try_next_state(&once, &transfer),
+		Err(NonceError::StaleNonce { expected: 1, found: 0 })
+	);
+	assert_eq!(NoncedAccountedCurrency::next_state(&once, &transfer), once);
+}
+
+#[test]
+fn sm_8_future_nonce_is_rejected() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::new());
+	let mint = SignedTransaction::new(
+		User::Alice,
+		3,
+		AccountingTransaction::Mint { minter: User::Alice, amount: 40 },
+	);
+
+	assert_eq!(
+		NoncedAccountedCurrency::try_next_state(&start, &mint),
+		Err(NonceError::FutureNonce { expected: 0, found: 3 })
+	);
+}
+
+#[test]
+fn sm_8_tampered_transaction_is_rejected() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::from([(User::Alice, 100)]));
+	let mut transfer = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 1 },
+	);
+	transfer.call =
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 99 };
+
+	assert_eq!(
+		NoncedAccountedCurrency::try_next_state(&start, &transfer),
+		Err(NonceError::BadSignature)
+	);
+}
+
+#[test]
+fn sm_8_transaction_signed_by_someone_else_is_rejected() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::from([(User::Alice, 100)]));
+	let transfer = SignedTransaction::new(
+		User::Bob,
+		0,
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+	);
+
+	assert_eq!(
+		NoncedAccountedCurrency::try_next_state(&start, &transfer),
+		Err(NonceError::WrongSigner { expected: User::Alice, found: User::Bob })
+	);
+}
+
+#[test]
+fn sm_8_failed_call_does_not_use_up_nonce() {
+	use super::p4_accounted_currency::{AccountingError, Balances};
+
+	let start = NoncedState::new(Balances::new());
+	let burn = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Burn { burner: User::Alice, amount: 40 },
+	);
+
+	assert_eq!(
+		NoncedAccountedCurrency::try_next_state(&start, &burn),
+		Err(NonceError::Inner(AccountingError::UnknownBurner))
+	);
+
+	let after_failure = NoncedAccountedCurrency::next_state(&start, &burn);
+	assert_eq!(after_failure.nonce(User::Alice), 0);
+
+	let mint = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Mint { minter: User::Alice, amount: 40 },
+	);
+	let end = NoncedAccountedCurrency::try_next_state(&after_failure, &mint).unwrap();
+	assert_eq!(end.nonce(User::Alice), 1);
+	assert_eq!(end.inner, Balances::from([(User::Alice, 40)]));
+}
+
+#[test]
+fn sm_8_nonce_survives_account_reaping() {
+	use super::p4_accounted_currency::Balances;
+
+	let start = NoncedState::new(Balances::from([(User::Alice, 40)]));
+	let transfer = SignedTransaction::new(
+		User::Alice,
+		0,
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+	);
+	let reaped = NoncedAccountedCurrency::try_next_state(&start, &transfer).unwrap();
+	let refunded = NoncedState { inner: start.inner.clone(), nonces: reaped.nonces.clone() };
+
+	assert!(!reaped.inner.contains_key(&User::Alice));
+	assert_eq!(reaped.nonce(User::Alice), 1);
+	assert!(NoncedAccountedCurrency::try_next_state(&refunded, &transfer).is_err());
+}
+
+#[test]
+fn sm_8_pool_orders_and_deduplicates_by_nonce() {
+	use super::p4_accounted_currency::Balances;
+
+	let mint = |user, nonce, amount| {
+		SignedTransaction::new(user, nonce, AccountingTransaction::Mint { minter: user, amount })
+	};
+	let nonces = BTreeMap::from([(User::Alice, 2)]);
+	let pool = vec![
+		mint(User::Alice, 3, 1),
+		mint(User::Bob, 0, 1),
+		mint(User::Alice, 1, 1),
+		mint(User::Alice, 2, 1),
+		mint(User::Alice, 2, 2),
+		mint(User::Bob, 2, 1),
+	];
+	let (ready, future) = order_by_nonce(&nonces, pool);
+
+	assert_eq!(
+		ready,
+		vec![mint(User::Alice, 2, 1), mint(User::Alice, 3, 1), mint(User::Bob, 0, 1)]
+	);
+	assert_eq!(future, vec![mint(User::Bob, 2, 1)]);
+
+	// The ready transactions can all be applied in order
+	let start = NoncedState { inner: Balances::new(), nonces };
+	let end = ready
+		.iter()
+		.try_fold(start, |state, t| NoncedAccountedCurrency::try_next_state(&state, t));
+	assert!(end.is_ok());
+}
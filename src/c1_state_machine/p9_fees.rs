@@ -0,0 +1,417 @@
+//! Neither of our currency systems charges any fees. That makes them easy to spam, and gives
+//! block authors no reason to include anybody's transactions. Real-world chains solve both
+//! problems by charging a fee for each transaction and paying it to the author of the block that
+//! includes the transaction. Many chains additionally create some brand new money in every block
+//! as a reward for the author, which is how the currency is issued in the first place.
+//!
+//! The two currency models charge fees differently:
+//! - In the accounted currency every transaction states its fee explicitly, and the fee is taken
+//!   from the signer's account before the transaction executes.
+//! - In digital cash the fee is implicit. Any difference between the value of the bills spent and
+//!   received, which part 5 simply destroyed, now goes to the block author.
+//!
+//! Transactions don't know which block they are in, so fees are collected in the state while the
+//! block executes. At the end of the block the `on_finalize` hook pays the collected fees, along
+//! with the block reward, to the block author.
+
+use super::{
+	p4_accounted_currency::{
+		hash_balances, AccountedCurrency, AccountingError, AccountingTransaction, Balances,
+	},
+	p5_digital_cash::{Bill, CashError, CashTransaction, DigitalCashSystem, State},
+	p8_nonces::Authorized,
+	BlockContext, BlockHooks, StateMachine, TryStateMachine,
+};
+use std::hash::{Hash, Hasher};
+
+/// The reasons a transaction may be rejected by one of the fee charging currencies.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FeeError<E> {
+	/// The signer's balance is not enough to pay the fee.
+	CannotPayFee { balance: u64, fee: u64 },
+	/// The fees collected in this block would exceed `u64::MAX`.
+	Overflow,
+	/// The inner currency rejected the transaction.
+	Inner(E),
+}
+
+/// An accounted currency that charges an explicit fee for every transaction.
+pub struct FeeAccountedCurrency;
+
+/// The state of an accounted currency with fees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeeBalances {
+	/// The balance of every account
+	pub balances: Balances,
+	/// Fees collected in the current block which have not been paid to the author yet
+	pub pending_fees: u64,
+	/// The amount of new money created for the author of every block
+	pub block_reward: u64,
+}
+
+impl Hash for FeeBalances {
+	fn hash<H: Hasher>(&self, hasher: &mut H) {
+		hash_balances(&self.balances, hasher);
+		self.pending_fees.hash(hasher);
+		self.block_reward.hash(hasher);
+	}
+}
+
+impl FeeBalances {
+	/// A state with the given balances and a fixed reward for every block.
+	pub fn new(balances: Balances, block_reward: u64) -> Self {
+		FeeBalances { balances, pending_fees: 0, block_reward }
+	}
+}
+
+/// A transaction along with the fee its signer is willing to pay for it
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct PaidTransaction {
+	pub fee: u64,
+	pub call: AccountingTransaction,
+}
+
+impl StateMachine for FeeAccountedCurrency {
+	type State = FeeBalances;
+	type Transition = PaidTransaction;
+
+	fn next_state(starting_state: &FeeBalances, t: &PaidTransaction) -> FeeBalances {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Accounted Currency With Fees".into()
+	}
+}
+
+impl TryStateMachine for FeeAccountedCurrency {
+	type Error = FeeError<AccountingError>;
+
+	fn try_next_state(
+		starting_state: &FeeBalances,
+		t: &PaidTransaction,
+	) -> Result<FeeBalances, Self::Error> {
+		let mut state = starting_state.clone();
+		let signer = AccountedCurrency::signer(&t.call);
+
+		if t.fee > 0 {
+			let balance = state.balances.get(&signer).copied().unwrap_or(0);
+			if balance < t.fee {
+				return Err(FeeError::CannotPayFee { balance, fee: t.fee })
+			}
+			if balance == t.fee {
+				state.balances.remove(&signer);
+			} else {
+				state.balances.insert(signer, balance - t.fee);
+			}
+			state.pending_fees = state.pending_fees.checked_add(t.fee).ok_or(FeeError::Overflow)?;
+		}
+
+		state.balances =
+			AccountedCurrency::try_next_state(&state.balances, &t.call).map_err(FeeError::Inner)?;
+
+		Ok(state)
+	}
+}
+
+/// At the end of each block the author receives the collected fees and the block reward. When the
+/// author is unknown, the fees are burned and no reward is created.
+///
+/// The author's balance may not be able to hold the payout. Fees that don't fit stay pending, and
+/// are paid to the author of a later block. The reward is only created if it fits as well.
+impl BlockHooks for FeeAccountedCurrency {
+	fn on_finalize(state: &FeeBalances, block: &BlockContext) -> FeeBalances {
+		let mut state = state.clone();
+		let Some(author) = block.author else {
+			state.pending_fees = 0;
+			return state
+		};
+
+		let balance = state.balances.get(&author).copied().unwrap_or(0);
+		let Some(with_fees) = balance.checked_add(state.pending_fees) else { return state };
+		let paid = with_fees.checked_add(state.block_reward).unwrap_or(with_fees);
+		state.pending_fees = 0;
+		if paid > 0 {
+			state.balances.insert(author, paid);
+		}
+
+		state
+	}
+}
+
+/// A digital cash system that pays the difference between spent and received bills to the block
+/// author.
+pub struct FeeDigitalCash;
+
+/// The state of a digital cash system with fees.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FeeCashState {
+	/// The bills in circulation
+	pub cash: State,
+	/// Fees collected in the current block which have not been paid to the author yet
+	pub pending_fees: u64,
+	/// The amount of new money created for the author of every block
+	pub block_reward: u64,
+}
+
+impl FeeCashState {
+	/// A state with the given bills and a fixed reward for every block.
+	pub fn new(cash: State, block_reward: u64) -> Self {
+		FeeCashState { cash, pending_fees: 0, block_reward }
+	}
+}
+
+impl StateMachine for FeeDigitalCash {
+	type State = FeeCashState;
+	type Transition = CashTransaction;
+
+	fn next_state(starting_state: &FeeCashState, t: &CashTransaction) -> FeeCashState {
+		Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+	}
+
+	fn human_name() -> String {
+		"Digital Cash With Fees".into()
+	}
+}
+
+impl TryStateMachine for FeeDigitalCash {
+	type Error = FeeError<CashError>;
+
+	fn try_next_state(
+		starting_state: &FeeCashState,
+		t: &CashTransaction,
+	) -> Result<FeeCashState, Self::Error> {
+		let mut state = starting_state.clone();
+		state.cash = DigitalCashSystem::try_next_state(&state.cash, t).map_err(FeeError::Inner)?;
+
+		// The inner system already checked that the sums don't overflow, and that no more is
+		// received than spent.
+		if let CashTransaction::Transfer { spends, receives } = t {
+			let spent: u64 = spends.iter().map(Bill::amount).sum();
+			let received: u64 = receives.iter().map(Bill::amount).sum();
+			state.pending_fees =
+				state.pending_fees.checked_add(spent - received).ok_or(FeeError::Overflow)?;
+		}
+
+		Ok(state)
+	}
+}
+
+/// At the end of each block, a single new bill worth the collected fees and the block reward is
+/// created for the author. This is often called a coinbase. When the author is unknown, the fees
+/// are burned and no reward is created.
+///
+/// When the fees and the reward together are worth more than a single bill can hold, they are paid
+/// as two separate bills instead.
+impl BlockHooks for FeeDigitalCash {
+	fn on_finalize(state: &FeeCashState, block: &BlockContext) -> FeeCashState {
+		let mut state = state.clone();
+		let payouts = match state.pending_fees.checked_add(state.block_reward) {
+			Some(payout) => vec![payout],
+			None => vec![state.pending_fees, state.block_reward],
+		};
+		state.pending_fees = 0;
+
+		if let Some(author) = block.author {
+			for amount in payouts.into_iter().filter(|amount| *amount > 0) {
+				let coinbase = CashTransaction::Mint { minter: author, amount };
+				state.cash = DigitalCashSystem::try_next_state(&state.cash, &coinbase)
+					.expect("minting a non-zero bill always succeeds; qed");
+			}
+		}
+
+		state
+	}
+}
+
+#[test]
+fn sm_9_fee_is_taken_from_signer() {
+	use super::User;
+
+	let start = FeeBalances::new(Balances::from([(User::Alice, 100)]), 0);
+	let transfer = PaidTransaction {
+		fee: 5,
+		call: AccountingTransaction::Transfer {
+			sender: User::Alice,
+			receiver: User::Bob,
+			amount: 50,
+		},
+	};
+	let end = FeeAccountedCurrency::try_next_state(&start, &transfer).unwrap();
+
+	assert_eq!(end.balances, Balances::from([(User::Alice, 45), (User::Bob, 50)]));
+	assert_eq!(end.pending_fees, 5);
+}
+
+#[test]
+fn sm_9_cannot_pay_fee() {
+	use super::User;
+
+	let start = FeeBalances::new(Balances::from([(User::Alice, 3)]), 0);
+	let burn = PaidTransaction {
+		fee: 5,
+		call: AccountingTransaction::Burn { burner: User::Alice, amount: 1 },
+	};
+
+	assert_eq!(
+		FeeAccountedCurrency::try_next_state(&start, &burn),
+		Err(FeeError::CannotPayFee { balance: 3, fee: 5 })
+	);
+}
+
+#[test]
+fn sm_9_fee_counts_towards_transfer_balance() {
+	use super::User;
+
+	let start = FeeBalances::new(Balances::from([(User::Alice, 100)]), 0);
+	let transfer = PaidTransaction {
+		fee: 5,
+		call: AccountingTransaction::Transfer {
+			sender: User::Alice,
+			receiver: User::Bob,
+			amount: 100,
+		},
+	};
+
+	assert_eq!(
+		FeeAccountedCurrency::try_next_state(&start, &transfer),
+		Err(FeeError::Inner(AccountingError::InsufficientBalance { balance: 95, requested: 100 }))
+	);
+}
+
+#[test]
+fn sm_9_author_receives_fees_and_reward() {
+	use super::User;
+
+	let mut start = FeeBalances::new(Balances::from([(User::Alice, 100)]), 10);
+	start.pending_fees = 7;
+	let block = BlockContext { height: 1, author: Some(User::Charlie) };
+	let end = FeeAccountedCurrency::on_finalize(&start, &block);
+
+	assert_eq!(end.balances, Balances::from([(User::Alice, 100), (User::Charlie, 17)]));
+	assert_eq!(end.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_fees_burned_without_author() {
+	use super::User;
+
+	let mut start = FeeBalances::new(Balances::from([(User::Alice, 100)]), 10);
+	start.pending_fees = 7;
+	let block = BlockContext { height: 1, author: None };
+	let end = FeeAccountedCurrency::on_finalize(&start, &block);
+
+	assert_eq!(end.balances, Balances::from([(User::Alice, 100)]));
+	assert_eq!(end.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_fees_stay_pending_when_the_author_cannot_hold_them() {
+	use super::User;
+
+	let mut start = FeeBalances::new(Balances::from([(User::Charlie, u64::MAX - 5)]), 10);
+	start.pending_fees = 7;
+	let by_charlie = BlockContext { height: 1, author: Some(User::Charlie) };
+	let unpaid = FeeAccountedCurrency::on_finalize(&start, &by_charlie);
+
+	assert_eq!(unpaid, start);
+
+	let by_bob = BlockContext { height: 2, author: Some(User::Bob) };
+	let paid = FeeAccountedCurrency::on_finalize(&unpaid, &by_bob);
+
+	assert_eq!(paid.balances[&User::Bob], 17);
+	assert_eq!(paid.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_reward_is_not_created_when_the_author_cannot_hold_it() {
+	use super::User;
+
+	let mut start = FeeBalances::new(Balances::from([(User::Charlie, u64::MAX - 10)]), 10);
+	start.pending_fees = 7;
+	let block = BlockContext { height: 1, author: Some(User::Charlie) };
+	let end = FeeAccountedCurrency::on_finalize(&start, &block);
+
+	assert_eq!(end.balances, Balances::from([(User::Charlie, u64::MAX - 3)]));
+	assert_eq!(end.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_cash_discrepancy_is_collected_as_fee() {
+	use super::User;
+
+	let start = FeeCashState::new(State::from([Bill::new(User::Alice, 20, 0)]), 0);
+	let transfer = CashTransaction::Transfer {
+		spends: vec![Bill::new(User::Alice, 20, 0)],
+		receives: vec![Bill::new(User::Bob, 15, 1)],
+	};
+	let end = FeeDigitalCash::try_next_state(&start, &transfer).unwrap();
+
+	let mut expected = State::from([Bill::new(User::Bob, 15, 1)]);
+	expected.set_serial(2);
+
+	assert_eq!(end.pending_fees, 5);
+	assert_eq!(end.cash, expected);
+}
+
+#[test]
+fn sm_9_author_receives_coinbase_bill() {
+	use super::User;
+
+	let mut start = FeeCashState::new(State::from([Bill::new(User::Bob, 15, 0)]), 10);
+	start.pending_fees = 5;
+	let block = BlockContext { height: 1, author: Some(User::Charlie) };
+	let end = FeeDigitalCash::on_finalize(&start, &block);
+
+	assert_eq!(
+		end.cash,
+		State::from([Bill::new(User::Bob, 15, 0), Bill::new(User::Charlie, 15, 1)])
+	);
+	assert_eq!(end.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_block_pays_its_author() {
+	use super::User;
+	use crate::c4_framework::execute_block;
+
+	let start = FeeBalances::new(Balances::from([(User::Alice, 100), (User::Bob, 50)]), 10);
+	let body = [
+		PaidTransaction {
+			fee: 5,
+			call: AccountingTransaction::Transfer {
+				sender: User::Alice,
+				receiver: User::Bob,
+				amount: 20,
+			},
+		},
+		PaidTransaction {
+			fee: 2,
+			call: AccountingTransaction::Burn { burner: User::Bob, amount: 8 },
+		},
+	];
+	let block = BlockContext { height: 1, author: Some(User::Charlie) };
+	let end = execute_block::<FeeAccountedCurrency>(&start, &body, &block).unwrap();
+
+	assert_eq!(
+		end.balances,
+		Balances::from([(User::Alice, 75), (User::Bob, 60), (User::Charlie, 17)])
+	);
+	assert_eq!(end.pending_fees, 0);
+}
+
+#[test]
+fn sm_9_large_coinbase_is_split_into_two_bills() {
+	use super::User;
+
+	let mut start = FeeCashState::new(State::new(), 10);
+	start.pending_fees = u64::MAX;
+	let block = BlockContext { height: 1, author: Some(User::Charlie) };
+	let end = FeeDigitalCash::on_finalize(&start, &block);
+
+	assert_eq!(
+		end.cash,
+		State::from([Bill::new(User::Charlie, u64::MAX, 0), Bill::new(User::Charlie, 10, 1)])
+	);
+	assert_eq!(end.pending_fees, 0);
+}
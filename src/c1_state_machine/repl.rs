@@ -0,0 +1,307 @@
+//! A read-eval-print loop for driving the state machines in this chapter by hand.
+//!
+//! The repl loads a single machine, chosen by its `human_name`, and starts it from that machine's
+//! initial state. Each line the user enters is either one of the commands below, or a transition
+//! which is parsed by the machine itself, applied, and the resulting state printed.
+//!
+//! - `help` - Explain the transition syntax of the loaded machine.
+//! - `state` - Print the current state.
+//! - `history` - Print every transition applied so far.
+//! - `undo` - Revert the most recent transition.
+//! - `quit` or `exit` - Leave the repl.
+//!
+//! The repl knows nothing about any specific machine. Machines plug in by implementing the
+//! `Interactive` trait (usually in the same file where the machine is defined) and being listed in
+//! the `machines` registry below. The `bfs-repl` binary is just a thin wrapper around
+//! `run_by_name`.
+
+use super::{
+	p1_switches::{LightSwitch, WeirdSwitchMachine},
+	p2_laundry_machine::ClothesMachine,
+	p3_atm::Atm,
+	p4_accounted_currency::AccountedCurrency,
+	p5_digital_cash::DigitalCashSystem,
+	p7_multi_asset::MultiAssetCurrency,
+	StateMachine,
+};
+use std::io::{self, BufRead, Write};
+
+/// A state machine that can be driven interactively from a text prompt.
+///
+/// This is the text contract between a state machine and the tools that drive it. It is
+/// deliberately a separate trait rather than a `FromStr` bound on the transition type so that
+/// machines can use foreign types such as `()` or `bool` for their transitions and states.
+pub trait Interactive: StateMachine {
+	/// The state the machine starts from when it is loaded into the repl.
+	fn initial_state() -> Self::State;
+
+	/// Parse a transition from a single line of user input. The error should explain to the user
+	/// what went wrong.
+	fn parse_transition(input: &str) -> Result<Self::Transition, String>;
+
+	/// Render a transition as text. The output must be accepted by `parse_transition` and parse
+	/// back into an identical transition.
+	fn format_transition(t: &Self::Transition) -> String;
+
+	/// Render a state as text for the user to read.
+	///
+	/// Two equal states must always be rendered identically, so implementations must not depend
+	/// on things like the iteration order of a `HashMap`.
+	fn format_state(state: &Self::State) -> String;
+
+	/// Describe what changed between two states, for example as a `StateDiff`. This is printed
+	/// after each transition. Machines whose states are small enough to read at a glance don't
+	/// need to bother.
+	fn describe_change(_pre: &Self::State, _post: &Self::State) -> Option<String> {
+		None
+	}
+
+	/// A short description of the transition syntax accepted by `parse_transition`.
+	fn transition_help() -> String;
+}
+
+/// An interactive session with a single state machine. The session remembers every state it has
+/// passed through so that transitions can be undone.
+pub struct Session<SM: Interactive> {
+	/// Every state the session has been in, beginning with the initial state. Never empty.
+	states: Vec<SM::State>,
+	/// Every transition applied so far. The transition at index `i` took the machine from
+	/// `states[i]` to `states[i + 1]`.
+	transitions: Vec<SM::Transition>,
+}
+
+impl<SM: Interactive> Session<SM> {
+	/// Begin a new session from the machine's initial state.
+	pub fn new() -> Self {
+		Self::from_state(SM::initial_state())
+	}
+
+	/// Begin a new session from the given state.
+	pub fn from_state(state: SM::State) -> Self {
+		Session { states: vec![state], transitions: Vec::new() }
+	}
+
+	/// The state the machine is currently in.
+	pub fn current(&self) -> &SM::State {
+		self.states
+			.last()
+			.expect("a session always contains at least the initial state; qed")
+	}
+
+	/// Apply a transition to the current state, and return the new current state.
+	pub fn apply(&mut self, t: SM::Transition) -> &SM::State {
+		let next = SM::next_state(self.current(), &t);
+		self.states.push(next);
+		self.transitions.push(t);
+		self.current()
+	}
+
+	/// Revert the most recent transition. Returns the reverted transition, or `None` if there is
+	/// nothing left to undo.
+	pub fn undo(&mut self) -> Option<SM::Transition> {
+		let t = self.transitions.pop()?;
+		self.states.pop();
+		Some(t)
+	}
+
+	/// The state before the most recent transition, if there was one.
+	pub fn previous(&self) -> Option<&SM::State> {
+		self.states.len().checked_sub(2).map(|i| &self.states[i])
+	}
+
+	/// All transitions applied so far, oldest first.
+	pub fn history(&self) -> &[SM::Transition] {
+		&self.transitions
+	}
+}
+
+impl<SM: Interactive> Default for Session<SM> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Run the repl for a specific state machine until the input is exhausted or the user quits.
+pub fn run<SM: Interactive>(input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+	let mut session = Session::<SM>::new();
+
+	writeln!(output, "Loaded {}. Type `help` for usage.", SM::human_name())?;
+	writeln!(output, "{}", SM::format_state(session.current()))?;
+
+	let mut line = String::new();
+	loop {
+		write!(output, "> ")?;
+		output.flush()?;
+
+		line.clear();
+		if input.read_line(&mut line)? == 0 {
+			return Ok(())
+		}
+
+		match line.trim() {
+			"" => {},
+			"quit" | "exit" => return Ok(()),
+			"help" => {
+				writeln!(output, "Commands: help, state, history, undo, quit")?;
+				writeln!(output, "Transitions:\n{}", SM::transition_help())?;
+			},
+			"state" => writeln!(output, "{}", SM::format_state(session.current()))?,
+			"history" =>
+				for (i, t) in session.history().iter().enumerate() {
+					writeln!(output, "{i}: {}", SM::format_transition(t))?;
+				},
+			"undo" => match session.undo() {
+				Some(t) => {
+					writeln!(output, "Undid `{}`", SM::format_transition(&t))?;
+					writeln!(output, "{}", SM::format_state(session.current()))?;
+				},
+				None => writeln!(output, "Nothing to undo")?,
+			},
+			other => match SM::parse_transition(other) {
+				Ok(t) => {
+					session.apply(t);
+					let (pre, post) = (session.previous(), session.current());
+					if let Some(change) = pre.and_then(|pre| SM::describe_change(pre, post)) {
+						writeln!(output, "Changes: {change}")?;
+					}
+					writeln!(output, "{}", SM::format_state(post))?;
+				},
+				Err(e) => writeln!(output, "Invalid transition: {e}")?,
+			},
+		}
+	}
+}
+
+/// A function that runs the repl for one specific machine.
+type Runner = fn(&mut dyn BufRead, &mut dyn Write) -> io::Result<()>;
+
+/// The registry of machines that can be loaded into the repl. To make a new machine available,
+/// implement `Interactive` for it and add it here.
+fn machines() -> Vec<(String, Runner)> {
+	vec![
+		(LightSwitch::human_name(), run::<LightSwitch>),
+		(WeirdSwitchMachine::human_name(), run::<WeirdSwitchMachine>),
+		(ClothesMachine::human_name(), run::<ClothesMachine>),
+		(Atm::human_name(), run::<Atm>),
+		(AccountedCurrency::human_name(), run::<AccountedCurrency>),
+		(DigitalCashSystem::human_name(), run::<DigitalCashSystem>),
+		(MultiAssetCurrency::human_name(), run::<MultiAssetCurrency>),
+	]
+}
+
+/// The human names of all the machines that can be loaded into the repl.
+pub fn machine_names() -> Vec<String> {
+	machines().into_iter().map(|(name, _)| name).collect()
+}
+
+/// Names are matched ignoring case, whitespace, and punctuation so that users can type
+/// `accounted-currency` rather than quoting `"Accounted Currency"`.
+fn normalize(name: &str) -> String {
+	name.chars()
+		.filter(|c| c.is_alphanumeric())
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+/// Run the repl for the machine with the given human name.
+pub fn run_by_name(
+	name: &str,
+	input: &mut dyn BufRead,
+	output: &mut dyn Write,
+) -> Result<(), String> {
+	let (_, runner) = machines()
+		.into_iter()
+		.find(|(candidate, _)| normalize(candidate) == normalize(name))
+		.ok_or_else(|| {
+			format!("Unknown state machine `{name}`. Available: {}", machine_names().join(", "))
+		})?;
+
+	runner(input, output).map_err(|e| e.to_string())
+}
+
+/// Split a line of input into its first word and the remaining words.
+pub(crate) fn split_command(input: &str) -> (&str, Vec<&str>) {
+	let mut words = input.split_whitespace();
+	let command = words.next().unwrap_or("");
+	(command, words.collect())
+}
+
+/// Parse an amount or other unsigned integer argument.
+pub(crate) fn parse_u64(input: &str) -> Result<u64, String> {
+	input.parse().map_err(|_| format!("`{input}` is not a valid unsigned integer"))
+}
+
+#[test]
+fn sm_repl_finds_machines_by_normalized_name() {
+	let mut input = io::Cursor::new("quit\n");
+	let mut output = Vec::new();
+
+	assert!(run_by_name("accounted-currency", &mut input, &mut output).is_ok());
+	assert!(String::from_utf8(output).unwrap().starts_with("Loaded Accounted Currency."));
+}
+
+#[test]
+fn sm_repl_unknown_machine() {
+	let mut input = io::Cursor::new("");
+	let mut output = Vec::new();
+
+	let error = run_by_name("Vending Machine", &mut input, &mut output).unwrap_err();
+	assert!(error.contains("Light Switch"));
+	assert!(error.contains("Digital Cash"));
+}
+
+#[test]
+fn sm_repl_machine_names_are_unique() {
+	let mut names = machine_names();
+	names.sort();
+	names.dedup();
+
+	assert_eq!(names.len(), machines().len());
+}
+
+#[test]
+fn sm_repl_undo_with_empty_history() {
+	let mut session = Session::<AccountedCurrency>::new();
+
+	assert!(session.undo().is_none());
+	assert!(session.current().is_empty());
+}
+
+#[test]
+fn sm_repl_reports_invalid_transitions() {
+	let mut input = io::Cursor::new("mint alice\nundo\n");
+	let mut output = Vec::new();
+	run::<AccountedCurrency>(&mut input, &mut output).unwrap();
+	let output = String::from_utf8(output).unwrap();
+
+	assert!(output.contains("Invalid transition"));
+	assert!(output.contains("Nothing to undo"));
+}
+
+#[test]
+fn sm_repl_apply_and_undo() {
+	use super::test_machines::CappedCounter;
+
+	let mut session = Session::<CappedCounter>::new();
+	session.apply(2);
+	session.apply(3);
+
+	assert_eq!(session.history().len(), 2);
+	assert_eq!(session.undo(), Some(3));
+	assert_eq!(session.history().len(), 1);
+	assert_eq!(*session.current(), 2);
+}
+
+#[test]
+fn sm_repl_prints_each_new_state() {
+	use super::test_machines::CappedCounter;
+
+	let mut input = io::Cursor::new("4\n5\nhistory\nundo\n");
+	let mut output = Vec::new();
+	run::<CappedCounter>(&mut input, &mut output).unwrap();
+	let output = String::from_utf8(output).unwrap();
+
+	assert!(output.starts_with("Loaded Capped Counter."));
+	assert!(output.contains("0: 4\n1: 5\n"));
+	assert!(output.contains("Undid `5`\n4\n"));
+}
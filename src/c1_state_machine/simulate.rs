@@ -0,0 +1,107 @@
+//! Before submitting a transaction, wallets and other tools usually want to know whether it would
+//! succeed, and what it would change. This module answers both questions by applying transitions
+//! to a borrowed state and reporting the outcome, without ever committing anything.
+//!
+//! Since state machines are pure functions of the starting state, "without committing" comes for
+//! free: the caller's state is only ever borrowed, and the post state is handed back for the
+//! caller to keep or throw away.
+//!
+//! Simulating against the state at some earlier block is out of scope for now. These functions
+//! accept any state, but looking up the state at a given block needs a state database, which the
+//! client in chapter 5 doesn't have yet.
+
+use super::{diff::StateDiff, TryStateMachine};
+
+/// The outcome of a successful simulation.
+pub struct Simulation<SM: StateDiff> {
+	/// The state the machine would be in if the simulated transitions were committed.
+	pub post_state: SM::State,
+	/// What the simulated transitions would change.
+	pub diff: SM::Diff,
+}
+
+/// Simulate a single transition against the given state.
+///
+/// Returns the machine's reason for rejecting the transition if it is invalid.
+pub fn simulate<SM>(state: &SM::State, t: &SM::Transition) -> Result<Simulation<SM>, SM::Error>
+where
+	SM: TryStateMachine + StateDiff,
+{
+	let post_state = SM::try_next_state(state, t)?;
+	let diff = SM::diff(state, &post_state);
+
+	Ok(Simulation { post_state, diff })
+}
+
+/// Simulate a batch of transitions applied in order against the given state. The batch is
+/// atomic, just like the extrinsics in a block: if any transition is rejected, the whole batch is.
+///
+/// On failure, the index of the first rejected transition is returned along with the machine's
+/// reason for rejecting it. On success, the diff covers the entire batch.
+pub fn simulate_batch<SM>(
+	state: &SM::State,
+	transitions: &[SM::Transition],
+) -> Result<Simulation<SM>, (usize, SM::Error)>
+where
+	SM: TryStateMachine + StateDiff,
+	SM::State: Clone,
+{
+	let post_state =
+		transitions.iter().enumerate().try_fold(state.clone(), |state, (index, t)| {
+			SM::try_next_state(&state, t).map_err(|error| (index, error))
+		})?;
+	let diff = SM::diff(state, &post_state);
+
+	Ok(Simulation { post_state, diff })
+}
+
+#[test]
+fn sm_simulate_valid_transition() {
+	use super::test_machines::CappedCounter;
+
+	let state = 4;
+	let simulation = simulate::<CappedCounter>(&state, &3).unwrap();
+
+	assert_eq!(simulation.post_state, 7);
+	assert_eq!(simulation.diff, 3);
+	assert_eq!(state, 4);
+}
+
+#[test]
+fn sm_simulate_invalid_transition_reports_reason() {
+	use super::test_machines::{CappedCounter, CounterError};
+
+	let error = simulate::<CappedCounter>(&8, &3).err().unwrap();
+
+	assert_eq!(error, CounterError::AboveCap { total: 11 });
+}
+
+#[test]
+fn sm_simulate_batch_reports_failing_index() {
+	use super::test_machines::{CappedCounter, CounterError};
+
+	let (index, error) = simulate_batch::<CappedCounter>(&0, &[2, 5, 4]).err().unwrap();
+
+	assert_eq!(index, 2);
+	assert_eq!(error, CounterError::AboveCap { total: 11 });
+}
+
+#[test]
+fn sm_simulate_batch_diff_covers_whole_batch() {
+	use super::test_machines::CappedCounter;
+
+	let simulation = simulate_batch::<CappedCounter>(&1, &[2, 3, 4]).unwrap();
+
+	assert_eq!(simulation.post_state, 10);
+	assert_eq!(simulation.diff, 9);
+}
+
+#[test]
+fn sm_simulate_empty_batch_changes_nothing() {
+	use super::p5_digital_cash::{DigitalCashSystem, State};
+
+	let state = State::new();
+	let simulation = simulate_batch::<DigitalCashSystem>(&state, &[]).unwrap();
+
+	assert!(simulation.diff.is_empty());
+}
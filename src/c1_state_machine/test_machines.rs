@@ -0,0 +1,77 @@
+//! A small state machine shared by the tests of the tools in this chapter and the framework
+//! built on it. Unlike the machines in the numbered parts of this chapter, it is complete, so the
+//! tools can be tested before the exercises are.
+
+use super::{
+	diff::StateDiff,
+	repl::{parse_u64, Interactive},
+	BlockHooks, StateMachine, TryStateMachine,
+};
+
+/// A counter that refuses to go above ten.
+pub(crate) struct CappedCounter;
+
+/// The reasons the capped counter may reject an increment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CounterError {
+	/// The increment would take the counter above ten.
+	AboveCap { total: u64 },
+}
+
+impl StateMachine for CappedCounter {
+	type State = u64;
+	type Transition = u64;
+
+	fn next_state(starting_state: &u64, t: &u64) -> u64 {
+		Self::try_next_state(starting_state, t).unwrap_or(*starting_state)
+	}
+
+	fn human_name() -> String {
+		"Capped Counter".into()
+	}
+}
+
+impl TryStateMachine for CappedCounter {
+	type Error = CounterError;
+
+	fn try_next_state(starting_state: &u64, t: &u64) -> Result<u64, CounterError> {
+		match starting_state + t {
+			total if total > 10 => Err(CounterError::AboveCap { total }),
+			total => Ok(total),
+		}
+	}
+}
+
+/// The capped counter doesn't do anything special at block boundaries.
+impl BlockHooks for CappedCounter {}
+
+/// The capped counter's changes are simply how much it went up or down.
+impl StateDiff for CappedCounter {
+	type Diff = i64;
+
+	fn diff(old: &u64, new: &u64) -> i64 {
+		*new as i64 - *old as i64
+	}
+}
+
+impl Interactive for CappedCounter {
+	fn initial_state() -> u64 {
+		0
+	}
+
+	fn parse_transition(input: &str) -> Result<u64, String> {
+		parse_u64(input)
+	}
+
+	fn format_transition(t: &u64) -> String {
+		t.to_string()
+	}
+
+	fn format_state(state: &u64) -> String {
+		state.to_string()
+	}
+
+	fn transition_help() -> String {
+		"<amount> - Add the amount to the counter, as long as it stays at or below ten".into()
+	}
+}
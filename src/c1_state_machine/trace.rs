@@ -0,0 +1,354 @@
+//! When a long run of transitions ends up somewhere unexpected, it is hard to tell which transition
+//! was responsible by looking at the final state alone. This module provides a trace recorder that
+//! wraps any state machine and remembers every transition along with the states before and after
+//! it.
+//!
+//! A recorded trace can be navigated like a debugger's timeline. It is possible to step backwards
+//! and forwards, jump straight to any step, and re-execute the trace from any snapshot to check
+//! that the machine still produces the same states. Recording a new transition after stepping
+//! backwards discards the old future, just like typing after an undo in a text editor.
+//!
+//! Traces can be saved to disk in a plain text format and replayed later. Replaying re-executes
+//! every transition and confirms that each resulting state matches the one that was recorded,
+//! which is a convenient way to confirm that a machine is deterministic.
+
+use super::{repl::Interactive, StateMachine};
+use std::{fs, io, path::Path};
+
+/// A single recorded step of a state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step<State, Transition> {
+	/// The state before the transition
+	pub pre_state: State,
+	/// The transition that was applied
+	pub transition: Transition,
+	/// The state after the transition
+	pub post_state: State,
+}
+
+/// The ways that loading or replaying a trace can fail.
+#[derive(Debug)]
+pub enum ReplayError {
+	/// The trace file could not be read or written.
+	Io(io::Error),
+	/// A line of the trace file could not be understood.
+	Parse { line: usize, message: String },
+	/// The trace was recorded with a different state machine.
+	WrongMachine { expected: String, found: String },
+	/// The replay was started from a different state than the recording.
+	InitialStateMismatch { recorded: String, found: String },
+	/// Re-executing the step at this index produced a different state than was recorded.
+	Diverged { step: usize, recorded: String, found: String },
+}
+
+impl From<io::Error> for ReplayError {
+	fn from(e: io::Error) -> Self {
+		ReplayError::Io(e)
+	}
+}
+
+/// A recorded history of a state machine's execution along with a cursor into that history.
+///
+/// The cursor ranges from 0 (before the first step, at the initial state) to `len()` (after the
+/// last step).
+pub struct Trace<SM: StateMachine> {
+	initial_state: SM::State,
+	steps: Vec<Step<SM::State, SM::Transition>>,
+	cursor: usize,
+}
+
+impl<SM: StateMachine> Trace<SM>
+where
+	SM::State: Clone + PartialEq,
+	SM::Transition: Clone,
+{
+	/// Begin recording from the given state.
+	pub fn new(initial_state: SM::State) -> Self {
+		Trace { initial_state, steps: Vec::new(), cursor: 0 }
+	}
+
+	/// The state the recording began from.
+	pub fn initial_state(&self) -> &SM::State {
+		&self.initial_state
+	}
+
+	/// All of the recorded steps.
+	pub fn steps(&self) -> &[Step<SM::State, SM::Transition>] {
+		&self.steps
+	}
+
+	/// The number of recorded steps.
+	pub fn len(&self) -> usize {
+		self.steps.len()
+	}
+
+	/// Whether any steps have been recorded.
+	pub fn is_empty(&self) -> bool {
+		self.steps.is_empty()
+	}
+
+	/// The position of the cursor.
+	pub fn position(&self) -> usize {
+		self.cursor
+	}
+
+	/// The state at the cursor.
+	pub fn current(&self) -> &SM::State {
+		self.state_at(self.cursor)
+			.expect("the cursor never moves past the end of the trace; qed")
+	}
+
+	/// The state after the given number of steps, or `None` if the trace is not that long.
+	pub fn state_at(&self, index: usize) -> Option<&SM::State> {
+		match index {
+			0 => Some(&self.initial_state),
+			i => self.steps.get(i - 1).map(|step| &step.post_state),
+		}
+	}
+
+	/// Apply a transition at the cursor, record it, and return the resulting state.
+	///
+	/// If the cursor is not at the end of the trace, the steps after the cursor are discarded
+	/// first.
+	pub fn record(&mut self, transition: SM::Transition) -> &SM::State {
+		self.steps.truncate(self.cursor);
+		let pre_state = self.current().clone();
+		let post_state = SM::next_state(&pre_state, &transition);
+		self.steps.push(Step { pre_state, transition, post_state });
+		self.cursor += 1;
+		self.current()
+	}
+
+	/// Move the cursor one step back. Returns false if it is already at the beginning.
+	pub fn step_back(&mut self) -> bool {
+		if self.cursor == 0 {
+			return false
+		}
+		self.cursor -= 1;
+		true
+	}
+
+	/// Move the cursor one step forward. Returns false if it is already at the end.
+	pub fn step_forward(&mut self) -> bool {
+		if self.cursor == self.steps.len() {
+			return false
+		}
+		self.cursor += 1;
+		true
+	}
+
+	/// Move the cursor to the given position and return the state there. Returns `None` and
+	/// leaves the cursor where it was if the position is beyond the end of the trace.
+	pub fn jump_to(&mut self, position: usize) -> Option<&SM::State> {
+		if position > self.steps.len() {
+			return None
+		}
+		self.cursor = position;
+		Some(self.current())
+	}
+
+	/// Re-execute every recorded transition from the snapshot at the given position onward, and
+	/// check that each one still produces the recorded post state.
+	///
+	/// Returns the index of the first step whose re-execution disagrees with the recording.
+	pub fn reexecute_from(&self, position: usize) -> Result<(), usize> {
+		let mut state = match self.state_at(position) {
+			Some(state) => state.clone(),
+			None => return Err(position),
+		};
+
+		for (i, step) in self.steps.iter().enumerate().skip(position) {
+			state = SM::next_state(&state, &step.transition);
+			if state != step.post_state {
+				return Err(i)
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl<SM: Interactive> Trace<SM>
+where
+	SM::State: Clone + PartialEq,
+	SM::Transition: Clone,
+{
+	/// Render the trace in its plain text file format. Each step is written as the transition
+	/// followed by the resulting state.
+	pub fn to_text(&self) -> String {
+		let mut text = format!("machine: {}\n", SM::human_name());
+		text += &format!("initial: {}\n", SM::format_state(&self.initial_state));
+		for step in &self.steps {
+			text += &format!("transition: {}\n", SM::format_transition(&step.transition));
+			text += &format!("post: {}\n", SM::format_state(&step.post_state));
+		}
+		text
+	}
+
+	/// Replay a trace from its text format, starting from the given initial state. Every
+	/// transition is re-executed, and the result is checked against the recorded state.
+	///
+	/// The cursor of the returned trace is at the end.
+	pub fn replay_text(text: &str, initial_state: SM::State) -> Result<Self, ReplayError> {
+		let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+		let mut expect = |key: &str| -> Result<Option<(usize, String)>, ReplayError> {
+			let Some((i, line)) = lines.next() else { return Ok(None) };
+			match line.strip_prefix(key).and_then(|rest| rest.strip_prefix(": ")) {
+				Some(value) => Ok(Some((i + 1, value.to_string()))),
+				None =>
+					Err(ReplayError::Parse { line: i + 1, message: format!("expected `{key}:`") }),
+			}
+		};
+		let missing =
+			|key: &str| ReplayError::Parse { line: 0, message: format!("missing `{key}:`") };
+
+		let (_, machine) = expect("machine")?.ok_or_else(|| missing("machine"))?;
+		if machine != SM::human_name() {
+			return Err(ReplayError::WrongMachine { expected: SM::human_name(), found: machine })
+		}
+
+		let (_, recorded) = expect("initial")?.ok_or_else(|| missing("initial"))?;
+		let found = SM::format_state(&initial_state);
+		if recorded != found {
+			return Err(ReplayError::InitialStateMismatch { recorded, found })
+		}
+
+		let mut trace = Trace::new(initial_state);
+		while let Some((line, transition)) = expect("transition")? {
+			let transition = SM::parse_transition(&transition)
+				.map_err(|message| ReplayError::Parse { line, message })?;
+			let (_, recorded) = expect("post")?.ok_or_else(|| missing("post"))?;
+			let found = SM::format_state(trace.record(transition));
+			if recorded != found {
+				return Err(ReplayError::Diverged { step: trace.len() - 1, recorded, found })
+			}
+		}
+
+		Ok(trace)
+	}
+
+	/// Save the trace to a file.
+	pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+		fs::write(path, self.to_text())
+	}
+
+	/// Load and replay a trace from a file, starting from the given initial state.
+	pub fn replay_file(
+		path: impl AsRef<Path>,
+		initial_state: SM::State,
+	) -> Result<Self, ReplayError> {
+		Self::replay_text(&fs::read_to_string(path)?, initial_state)
+	}
+}
+
+#[test]
+fn sm_trace_navigation() {
+	use super::test_machines::CappedCounter;
+
+	let mut trace = Trace::<CappedCounter>::new(0);
+	trace.record(5);
+	trace.record(3);
+	trace.record(2);
+
+	assert_eq!(trace.len(), 3);
+	assert_eq!(trace.current(), &10);
+
+	assert!(trace.step_back());
+	assert_eq!(trace.current(), &8);
+
+	assert_eq!(trace.jump_to(1), Some(&5));
+	assert!(trace.step_back());
+	assert!(!trace.step_back());
+	assert_eq!(trace.current(), &0);
+
+	assert!(trace.jump_to(4).is_none());
+	assert_eq!(trace.position(), 0);
+
+	assert_eq!(trace.jump_to(3), Some(&10));
+	assert!(!trace.step_forward());
+}
+
+#[test]
+fn sm_trace_recording_after_stepping_back_discards_the_future() {
+	use super::test_machines::CappedCounter;
+
+	let mut trace = Trace::<CappedCounter>::new(0);
+	trace.record(1);
+	trace.record(2);
+	trace.step_back();
+	trace.record(4);
+
+	assert_eq!(trace.len(), 2);
+	assert_eq!(trace.steps()[1].transition, 4);
+	assert_eq!(trace.steps()[1].pre_state, 1);
+	assert_eq!(trace.current(), &5);
+}
+
+#[test]
+fn sm_trace_reexecute_from_snapshot() {
+	use super::test_machines::CappedCounter;
+
+	let mut trace = Trace::<CappedCounter>::new(0);
+	trace.record(1);
+	trace.record(2);
+	trace.record(3);
+
+	assert_eq!(trace.reexecute_from(0), Ok(()));
+	assert_eq!(trace.reexecute_from(2), Ok(()));
+
+	// Tamper with the recording to simulate a non-deterministic machine
+	trace.steps[0].post_state = 2;
+	assert_eq!(trace.reexecute_from(0), Err(0));
+	assert_eq!(trace.reexecute_from(2), Ok(()));
+}
+
+#[test]
+fn sm_trace_save_and_replay() {
+	use super::test_machines::CappedCounter;
+
+	let mut trace = Trace::<CappedCounter>::new(5);
+	trace.record(3);
+	trace.record(2);
+
+	let path = std::env::temp_dir().join("bfs-sm-trace-save-and-replay.trace");
+	trace.save(&path).unwrap();
+	let replayed = Trace::<CappedCounter>::replay_file(&path, 5).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(replayed.steps(), trace.steps());
+	assert_eq!(replayed.position(), 2);
+}
+
+#[test]
+fn sm_trace_replay_detects_divergence() {
+	use super::test_machines::CappedCounter;
+
+	let text = "machine: Capped Counter\n\
+	            initial: 0\n\
+	            transition: 4\n\
+	            post: 4\n\
+	            transition: 3\n\
+	            post: 8\n";
+
+	let result = Trace::<CappedCounter>::replay_text(text, 0);
+	assert!(matches!(result, Err(ReplayError::Diverged { step: 1, .. })));
+}
+
+#[test]
+fn sm_trace_replay_checks_machine_and_initial_state() {
+	use super::{
+		p2_laundry_machine::{ClothesMachine, ClothesState},
+		p4_accounted_currency::AccountedCurrency,
+		User,
+	};
+	use std::collections::HashMap;
+
+	let text = "machine: Accounted Currency\ninitial: {}\n";
+
+	let wrong_machine = Trace::<ClothesMachine>::replay_text(text, ClothesState::Clean(4));
+	assert!(matches!(wrong_machine, Err(ReplayError::WrongMachine { .. })));
+
+	let wrong_state =
+		Trace::<AccountedCurrency>::replay_text(text, HashMap::from([(User::Alice, 1)]));
+	assert!(matches!(wrong_state, Err(ReplayError::InitialStateMismatch { .. })));
+}
@@ -71,6 +71,20 @@ impl Header {
 	}
 }
 
+/// The reasons a chain of blocks may be invalid. Unlike the bare `bool` returned by
+/// `verify_sub_chain`, this tells the caller (typically a client importing blocks) which block
+/// was bad and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockError {
+	/// The header at this height does not correctly extend its parent header.
+	InvalidHeader { height: u64 },
+	/// The body at this height does not match the extrinsics root in its header.
+	ExtrinsicsRootMismatch { height: u64 },
+	/// The state at this height does not match the state root in its header. For the first block
+	/// this means the given pre-state is wrong.
+	StateRootMismatch { height: u64 },
+}
+
 /// A complete Block is a header and the extrinsics.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Block {
@@ -107,6 +121,19 @@ impl Block {
 	pub fn verify_sub_chain(&self, pre_state: &State, chain: &[Block]) -> bool {
 		todo!("Exercise 7")
 	}
+
+	/// Verify that all the given blocks form a valid chain from this block to the tip, just like
+	/// `verify_sub_chain`. Rather than a bare `bool`, this reports the first problem found, or
+	/// returns the post state of the tip if the chain is valid.
+	///
+	/// Once this is written, `verify_sub_chain` can simply check whether this returns `Ok`.
+	pub fn try_verify_sub_chain(
+		&self,
+		pre_state: &State,
+		chain: &[Block],
+	) -> Result<State, BlockError> {
+		todo!("Exercise 9")
+	}
 }
 
 /// Create an invalid child block of the given block. The returned block should have an
@@ -259,3 +286,52 @@ fn bc_6_student_invalid_block_really_is_invalid() {
 	// Make sure that the block is not valid when executed.
 	assert!(!gb.verify_sub_chain(&state, &[b1]));
 }
+
+#[test]
+fn bc_6_try_verify_three_blocks() {
+	let state_1 = State { sum: 6, product: 9 };
+	let g = Block::genesis(&state_1);
+	let b1 = g.child(&state_1, vec![1]);
+	let state_2 = State { sum: 7, product: 9 };
+	let b2 = b1.child(&state_2, vec![2]);
+
+	assert_eq!(g.try_verify_sub_chain(&state_1, &[b1, b2]), Ok(State { sum: 9, product: 18 }));
+}
+
+#[test]
+fn bc_6_try_verify_reports_wrong_pre_state() {
+	let state = State { sum: 6, product: 9 };
+	let g = Block::genesis(&state);
+
+	assert_eq!(
+		g.try_verify_sub_chain(&State { sum: 0, product: 0 }, &[]),
+		Err(BlockError::StateRootMismatch { height: 0 })
+	);
+}
+
+#[test]
+fn bc_6_try_verify_reports_invalid_header() {
+	let state = State { sum: 6, product: 9 };
+	let b0 = Block::genesis(&state);
+	let mut b1 = b0.child(&state, vec![1, 2, 3]);
+	b1.header.parent = 0;
+
+	assert_eq!(
+		b0.try_verify_sub_chain(&state, &[b1]),
+		Err(BlockError::InvalidHeader { height: 1 })
+	);
+}
+
+#[test]
+fn bc_6_try_verify_reports_wrong_body() {
+	let state = State { sum: 6, product: 9 };
+	let b0 = Block::genesis(&state);
+	let b1 = b0.child(&state, vec![1, 2, 3]);
+	let mut b2 = b1.child(&State { sum: 12, product: 54 }, vec![4]);
+	b2.body = vec![];
+
+	assert_eq!(
+		b0.try_verify_sub_chain(&state, &[b1, b2]),
+		Err(BlockError::ExtrinsicsRootMismatch { height: 2 })
+	);
+}
@@ -10,6 +10,8 @@ mod p4_even_only;
 mod p5_interleave;
 mod p6_forking;
 
+use crate::c1_state_machine::User;
+
 type Hash = u64;
 
 /// A Block Header similar to prior chapters of this tutorial.
@@ -22,11 +24,11 @@ type Hash = u64;
 /// the complete blocks.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Header<Digest> {
-	parent: Hash,
-	height: u64,
-	state_root: Hash,
-	extrinsics_root: Hash,
-	consensus_digest: Digest,
+	pub(crate) parent: Hash,
+	pub(crate) height: u64,
+	pub(crate) state_root: Hash,
+	pub(crate) extrinsics_root: Hash,
+	pub(crate) consensus_digest: Digest,
 }
 /// A Consensus Engine. Responsible for Sealing blocks and verifying their seals
 ///
@@ -77,6 +79,23 @@ pub trait Consensus {
 		todo!("Exercise 1")
 	}
 
+	/// The authority who authored a block with the given digest. Execution uses this to pay fees
+	/// and rewards to block authors.
+	///
+	/// Not every consensus engine identifies block authors. For example, a proof of work digest
+	/// says nothing about who did the work. Such engines keep this default.
+	fn author(_digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		None
+	}
+
+	/// The slot in which the block with the given header was authored. An authority who signs two
+	/// different blocks for the same slot is equivocating, which staking punishes by slashing.
+	///
+	/// Engines without slots allow one block per height, so by default the slot is the height.
+	fn slot(header: &Header<Self::Digest>) -> u64 {
+		header.height
+	}
+
 	/// A human-readable name for this engine. This may be used in user-facing
 	/// programs error reporting. This is not in any way related to
 	/// the correctness of the consensus logic.
@@ -109,3 +128,25 @@ pub enum ConsensusAuthority {
 	Bob,
 	Charlie,
 }
+
+/// Each consensus authority is also a user of the state machine, so that it can be paid.
+impl From<ConsensusAuthority> for User {
+	fn from(authority: ConsensusAuthority) -> Self {
+		match authority {
+			ConsensusAuthority::Alice => User::Alice,
+			ConsensusAuthority::Bob => User::Bob,
+			ConsensusAuthority::Charlie => User::Charlie,
+		}
+	}
+}
+
+/// Users can become consensus authorities, for example by being elected through staking.
+impl From<User> for ConsensusAuthority {
+	fn from(user: User) -> Self {
+		match user {
+			User::Alice => ConsensusAuthority::Alice,
+			User::Bob => ConsensusAuthority::Bob,
+			User::Charlie => ConsensusAuthority::Charlie,
+		}
+	}
+}
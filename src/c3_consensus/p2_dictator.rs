@@ -27,4 +27,9 @@ impl Consensus for DictatorConsensus {
 	fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
 		todo!("Exercise 2")
 	}
+
+	/// The digest is the signature of the author.
+	fn author(digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		Some(*digest)
+	}
 }
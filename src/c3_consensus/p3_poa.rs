@@ -17,6 +17,13 @@ struct SimplePoa {
 	authorities: Vec<ConsensusAuthority>,
 }
 
+/// The authorities may come from anywhere, for example from an on-chain staking election.
+impl From<Vec<ConsensusAuthority>> for SimplePoa {
+	fn from(authorities: Vec<ConsensusAuthority>) -> Self {
+		SimplePoa { authorities }
+	}
+}
+
 impl Consensus for SimplePoa {
 	type Digest = ConsensusAuthority;
 
@@ -31,6 +38,10 @@ impl Consensus for SimplePoa {
 	) -> Option<Header<Self::Digest>> {
 		todo!("Exercise 2")
 	}
+
+	fn author(digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		Some(*digest)
+	}
 }
 
 /// A Proof of Authority consensus engine. Only one authority is valid at each block height.
@@ -40,6 +51,12 @@ struct PoaRoundRobinByHeight {
 	authorities: Vec<ConsensusAuthority>,
 }
 
+impl From<Vec<ConsensusAuthority>> for PoaRoundRobinByHeight {
+	fn from(authorities: Vec<ConsensusAuthority>) -> Self {
+		PoaRoundRobinByHeight { authorities }
+	}
+}
+
 impl Consensus for PoaRoundRobinByHeight {
 	type Digest = ConsensusAuthority;
 
@@ -54,6 +71,10 @@ impl Consensus for PoaRoundRobinByHeight {
 	) -> Option<Header<Self::Digest>> {
 		todo!("Exercise 4")
 	}
+
+	fn author(digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		Some(*digest)
+	}
 }
 
 /// Both of the previous PoA schemes have the weakness that a single dishonest authority can corrupt
@@ -69,6 +90,12 @@ struct PoaRoundRobinBySlot {
 	authorities: Vec<ConsensusAuthority>,
 }
 
+impl From<Vec<ConsensusAuthority>> for PoaRoundRobinBySlot {
+	fn from(authorities: Vec<ConsensusAuthority>) -> Self {
+		PoaRoundRobinBySlot { authorities }
+	}
+}
+
 /// A digest used for PoaRoundRobinBySlot. The digest contains the slot number as well as the
 /// signature. In addition to checking that the right signer has signed for the slot, you must check
 /// that the slot is always strictly increasing. But remember that slots may be skipped.
@@ -92,4 +119,21 @@ impl Consensus for PoaRoundRobinBySlot {
 	) -> Option<Header<Self::Digest>> {
 		todo!("Exercise 6")
 	}
+
+	fn author(digest: &Self::Digest) -> Option<ConsensusAuthority> {
+		Some(digest.signature)
+	}
+
+	fn slot(header: &Header<Self::Digest>) -> u64 {
+		header.consensus_digest.slot
+	}
+}
+
+#[test]
+fn cs_3_engines_take_authorities_from_anywhere() {
+	let elected = vec![ConsensusAuthority::Charlie, ConsensusAuthority::Alice];
+
+	assert_eq!(SimplePoa::from(elected.clone()).authorities, elected);
+	assert_eq!(PoaRoundRobinByHeight::from(elected.clone()).authorities, elected);
+	assert_eq!(PoaRoundRobinBySlot::from(elected.clone()).authorities, elected);
 }
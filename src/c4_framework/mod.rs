@@ -10,8 +10,11 @@
 ///
 /// Let's refactor our blockchain to take advantage of these two abstractions
 /// In doing so, we create a blockchain framework
-use crate::c1_state_machine::StateMachine;
-use crate::c3_consensus::{Consensus, Header};
+use crate::c1_state_machine::{BlockContext, BlockHooks, StateMachine, TryStateMachine, User};
+use crate::{
+	c3_consensus::{Consensus, Header},
+	hash,
+};
 type Hash = u64;
 
 impl<Digest> Header<Digest> {
@@ -58,6 +61,101 @@ impl<C: Consensus, SM: StateMachine> Block<C, SM> {
 	}
 }
 
+/// The reasons a block may be rejected when checking a chain. Unlike the bare `bool` returned by
+/// `verify_sub_chain`, this tells the caller (typically a client importing blocks) which block was
+/// bad and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockError<E> {
+	/// The header at this height does not correctly extend its parent header.
+	InvalidHeader { height: u64 },
+	/// The body at this height does not match the extrinsics root in its header.
+	ExtrinsicsRootMismatch { height: u64 },
+	/// The state machine rejected the extrinsic at the given index in the body.
+	InvalidExtrinsic { height: u64, index: usize, error: E },
+	/// Executing the body did not produce the state root committed to in the header.
+	StateRootMismatch { height: u64 },
+}
+
+/// Execute the given extrinsics in order starting from the given pre-state.
+///
+/// Execution stops at the first extrinsic that the state machine rejects, and the index of that
+/// extrinsic is returned along with the machine's reason for rejecting it.
+pub fn execute_extrinsics<SM: TryStateMachine>(
+	pre_state: &SM::State,
+	extrinsics: &[SM::Transition],
+) -> Result<SM::State, (usize, SM::Error)>
+where
+	SM::State: Clone,
+{
+	extrinsics
+		.iter()
+		.enumerate()
+		.try_fold(pre_state.clone(), |state, (index, extrinsic)| {
+			SM::try_next_state(&state, extrinsic).map_err(|error| (index, error))
+		})
+}
+
+/// Execute an entire block body starting from the given pre-state. This runs the state machine's
+/// block hooks around the extrinsics, so that the machine can, for example, pay the block author.
+pub fn execute_block<SM: TryStateMachine + BlockHooks>(
+	pre_state: &SM::State,
+	extrinsics: &[SM::Transition],
+	block: &BlockContext,
+) -> Result<SM::State, (usize, SM::Error)>
+where
+	SM::State: Clone,
+{
+	let state = SM::on_initialize(pre_state, block);
+	let state = execute_extrinsics::<SM>(&state, extrinsics)?;
+	Ok(SM::on_finalize(&state, block))
+}
+
+impl<C: Consensus, SM: TryStateMachine + BlockHooks> Block<C, SM>
+where
+	SM::State: Clone + std::hash::Hash,
+	SM::Transition: std::hash::Hash,
+{
+	/// Verify that all the given blocks form a valid chain from this block to the tip, just like
+	/// `verify_sub_chain`. Rather than a bare `bool`, this reports the first problem found, or
+	/// returns the post state of the tip if the chain is valid.
+	///
+	/// Every extrinsic must be accepted by the state machine for its block to be valid. Each block
+	/// is executed with its author as identified by the consensus engine.
+	pub fn try_verify_sub_chain(
+		&self,
+		pre_state: &SM::State,
+		chain: &[Self],
+	) -> Result<SM::State, BlockError<SM::Error>> {
+		if self.header.state_root != hash(pre_state) {
+			return Err(BlockError::StateRootMismatch { height: self.header.height })
+		}
+
+		let mut parent = self;
+		let mut state = pre_state.clone();
+		for block in chain {
+			let height = block.header.height;
+			if !parent.header.verify_child(&block.header) {
+				return Err(BlockError::InvalidHeader { height })
+			}
+			if block.header.extrinsics_root != hash(&block.body) {
+				return Err(BlockError::ExtrinsicsRootMismatch { height })
+			}
+			let context = BlockContext {
+				height,
+				author: C::author(&block.header.consensus_digest).map(User::from),
+			};
+			state = execute_block::<SM>(&state, &block.body, &context)
+				.map_err(|(index, error)| BlockError::InvalidExtrinsic { height, index, error })?;
+			if block.header.state_root != hash(&state) {
+				return Err(BlockError::StateRootMismatch { height })
+			}
+			parent = block;
+		}
+
+		Ok(state)
+	}
+}
+
 /// Create and return a block chain that is n blocks long starting from the given genesis state.
 /// The blocks should not contain any transactions.
 fn create_empty_chain<C: Consensus, SM: StateMachine>(
@@ -67,7 +165,54 @@ fn create_empty_chain<C: Consensus, SM: StateMachine>(
 	todo!("Exercise 8")
 }
 
-//TODO tests
+#[cfg(test)]
+use crate::c1_state_machine::test_machines::{CappedCounter, CounterError};
+
+/// A block with the given state root and no extrinsics, as if it were the genesis block.
+#[cfg(test)]
+fn block_with_state_root(state_root: Hash) -> Block<(), CappedCounter> {
+	Block {
+		header: Header {
+			parent: 0,
+			height: 0,
+			state_root,
+			extrinsics_root: hash(&Vec::<u64>::new()),
+			consensus_digest: (),
+		},
+		body: Vec::new(),
+	}
+}
+
+#[test]
+fn framework_execute_extrinsics_applies_all_in_order() {
+	assert_eq!(execute_extrinsics::<CappedCounter>(&0, &[2, 3, 4]), Ok(9));
+	assert_eq!(execute_extrinsics::<CappedCounter>(&5, &[]), Ok(5));
+}
+
+#[test]
+fn framework_execute_extrinsics_reports_first_rejection() {
+	assert_eq!(
+		execute_extrinsics::<CappedCounter>(&0, &[4, 4, 4, 1, 9]),
+		Err((2, CounterError::AboveCap { total: 12 }))
+	);
+}
+
+#[test]
+fn framework_try_verify_empty_sub_chain_returns_pre_state() {
+	let genesis = block_with_state_root(hash(&7u64));
+
+	assert_eq!(genesis.try_verify_sub_chain(&7, &[]), Ok(7));
+}
+
+#[test]
+fn framework_try_verify_sub_chain_rejects_wrong_pre_state() {
+	let genesis = block_with_state_root(hash(&7u64));
+
+	assert_eq!(
+		genesis.try_verify_sub_chain(&8, &[]),
+		Err(BlockError::StateRootMismatch { height: 0 })
+	);
+}
 
 //TODO maybe this shouldn't be a whole chapter. Maybe it is the first
 // section in the chapter on building a client
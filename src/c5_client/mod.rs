@@ -11,6 +11,7 @@
 // Exercise for later: Client does a hard fork at a particular block height. The fork logic is to change runtimes.
 
 use std::collections::HashMap;
+use crate::c2_blockchain::BlockError;
 use super::p2_blockchain::p4_batched_extrinsics::{Block, Header};
 //TODO use the latest one once that lesson is written
 // use super::p5_rich_state::{Block, Header};
@@ -32,7 +33,9 @@ pub struct FullClient {
 //TODO maybe make a trait `Client` and implement it for light client too.
 // Let's see how many of the same methods make sense.
 impl FullClient {
-    fn import_block(&mut self, b: Block) -> Result<Hash, String> {
+    // When a block is invalid, the error says which block was bad and why, so the client can
+    // report it (or stop listening to the peer that sent it) rather than just refusing it.
+    fn import_block(&mut self, b: Block) -> Result<Hash, BlockError> {
         todo!()
     }
 
@@ -49,6 +52,9 @@ impl FullClient {
 
     fn best_block()-> Hash {todo!()}
 
+    // Signed transactions carry the sender's nonce. The pool should use
+    // `c1_state_machine::p8_nonces::order_by_nonce` to drop stale and duplicate transactions
+    // and to keep each sender's transactions in nonce order when authoring blocks.
     fn submit_transaction(t: Transaction) -> Result<Hash, String> {todo!()}
 
     //TODO maybe this method gets introduced later on and we see how it allows pruning
@@ -5,7 +5,7 @@ use std::{
 	hash::{Hash, Hasher},
 };
 
-mod c1_state_machine;
+pub mod c1_state_machine;
 mod c2_blockchain;
 mod c3_consensus;
 mod c4_framework;